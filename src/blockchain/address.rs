@@ -0,0 +1,138 @@
+use super::crypto::*;
+
+use std::fmt;
+use std::str::FromStr;
+
+// Version byte of every address, which base58 renders as a leading 'B'. Another network or
+// another kind of address would get a byte of its own.
+pub const ADDRESS_VERSION: u8 = 0x19;
+// Addresses of multisig policies, starting with an 'M'.
+pub const MULTISIG_VERSION: u8 = 0x32;
+// Addresses of locking scripts, starting with an 'S'.
+pub const SCRIPT_VERSION: u8 = 0x3f;
+
+pub const HASH_SIZE: usize = 20;
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressError {
+    Encoding(String),
+    Length(usize),
+    UnknownVersion(u8),
+    // Most likely a typo: base58check catches any single wrong character.
+    Checksum,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Encoding(reason) => write!(f, "address is not valid base58: {}", reason),
+            AddressError::Length(length) => write!(
+                f,
+                "address decodes to {} bytes, expected {}",
+                length,
+                1 + HASH_SIZE + CHECKSUM_SIZE
+            ),
+            AddressError::UnknownVersion(version) => {
+                write!(f, "unknown address version {}", version)
+            }
+            AddressError::Checksum => write!(f, "address checksum does not match, check for typos"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+fn double_sha256(bytes: &[u8]) -> Vec<u8> {
+    let once = crypto_hash::digest(crypto_hash::Algorithm::SHA256, bytes);
+    crypto_hash::digest(crypto_hash::Algorithm::SHA256, &once)
+}
+
+// Base58check of the version byte and the hash of a public key: outputs pay to the hash, and
+// the key itself is only revealed by the transaction spending them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub version: u8,
+    pub hash: [u8; HASH_SIZE],
+}
+
+impl Address {
+    // The hash covers the key type too, so the same bytes read as another kind of key land
+    // elsewhere.
+    pub fn from_public_key(key_type: KeyType, public_key: &[u8]) -> Self {
+        let mut bytes = vec![key_type.to_byte()];
+        bytes.extend(public_key);
+        let mut hash = [0; HASH_SIZE];
+        hash.copy_from_slice(&double_sha256(&bytes)[..HASH_SIZE]);
+        Self {
+            version: ADDRESS_VERSION,
+            hash,
+        }
+    }
+
+    // The hash of an encoded policy instead of a key: spending takes the signatures it asks for.
+    pub fn from_multisig(policy: &[u8]) -> Self {
+        let mut hash = [0; HASH_SIZE];
+        hash.copy_from_slice(&double_sha256(policy)[..HASH_SIZE]);
+        Self {
+            version: MULTISIG_VERSION,
+            hash,
+        }
+    }
+
+    // The hash of a locking script: spending runs it.
+    pub fn from_script(script: &[u8]) -> Self {
+        let mut hash = [0; HASH_SIZE];
+        hash.copy_from_slice(&double_sha256(script)[..HASH_SIZE]);
+        Self {
+            version: SCRIPT_VERSION,
+            hash,
+        }
+    }
+
+    pub fn is_multisig(&self) -> bool {
+        self.version == MULTISIG_VERSION
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.version == SCRIPT_VERSION
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(address)
+            .into_vec()
+            .map_err(|e| AddressError::Encoding(e.to_string()))?;
+        if bytes.len() != 1 + HASH_SIZE + CHECKSUM_SIZE {
+            return Err(AddressError::Length(bytes.len()));
+        }
+
+        let (payload, checksum) = bytes.split_at(1 + HASH_SIZE);
+        if double_sha256(payload)[..CHECKSUM_SIZE] != *checksum {
+            return Err(AddressError::Checksum);
+        }
+        if ![ADDRESS_VERSION, MULTISIG_VERSION, SCRIPT_VERSION].contains(&payload[0]) {
+            return Err(AddressError::UnknownVersion(payload[0]));
+        }
+
+        let mut hash = [0; HASH_SIZE];
+        hash.copy_from_slice(&payload[1..]);
+        Ok(Self {
+            version: payload[0],
+            hash,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = vec![self.version];
+        bytes.extend(self.hash);
+        let checksum = double_sha256(&bytes);
+        bytes.extend(&checksum[..CHECKSUM_SIZE]);
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
@@ -1,14 +1,48 @@
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::merkle::*;
 use super::signedtransaction::*;
+use super::target::*;
 use super::*;
 
+// The part of a block covered by the proof of work. Transactions are only committed to through
+// their merkle root, so headers can be checked (and inclusion proofs verified) on their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u128,
+    pub previous_block: String,
+    pub timestamp: u128,
+    pub merkle_root: String,
+    pub target: Target,
+    pub nonce: u128,
+}
+
+impl Hashable for BlockHeader {
+    fn hash(&self) -> String {
+        let mut bytes = vec![];
+        bytes.extend(&self.index.to_be_bytes());
+        extend_prefixed(&mut bytes, self.previous_block.as_bytes());
+        bytes.extend(&self.timestamp.to_be_bytes());
+        extend_prefixed(&mut bytes, self.merkle_root.as_bytes());
+        bytes.extend(&self.target.to_be_bytes());
+        bytes.extend(&self.nonce.to_be_bytes());
+        crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
+    }
+}
+
+impl BlockHeader {
+    pub fn verify_inclusion(&self, proof: &MerkleProof) -> bool {
+        proof.verify(&self.merkle_root)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub index: u128,
     pub previous_block: String,
     pub timestamp: u128,
+    pub target: Target,
     pub nonce: u128,
     pub transactions: Vec<SignedTransaction>,
 }
@@ -22,35 +56,56 @@ impl Block {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_nanos(),
+            target: Target::MAX,
             transactions,
             nonce: 0,
         }
     }
 
     pub fn hash(&self) -> String {
-        let mut bytes = vec![];
-        bytes.extend(&self.index.to_be_bytes());
-        bytes.extend(self.previous_block.bytes());
-        bytes.extend(&self.timestamp.to_be_bytes());
+        self.header().hash()
+    }
 
-        self.transactions
-            .iter()
-            .for_each(|tx| bytes.extend(tx.hash().bytes()));
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            previous_block: self.previous_block.clone(),
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root(),
+            target: self.target,
+            nonce: self.nonce,
+        }
+    }
 
-        bytes.extend(&self.nonce.to_be_bytes());
-        crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
+    // Looks for a nonce meeting the target. The merkle root does not change meanwhile, so it
+    // is only computed once.
+    pub fn solve(&mut self) {
+        let mut header = self.header();
+        while !header.target.is_met_by(&header.hash()) {
+            header.nonce += 1;
+        }
+        self.nonce = header.nonce;
+    }
+
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let txids: Vec<String> = self.transactions.iter().map(|tx| tx.hash()).collect();
+        MerkleTree::new(&txids)
+    }
+
+    pub fn merkle_root(&self) -> String {
+        self.merkle_tree().root()
+    }
+
+    pub fn merkle_proof(&self, txid: &str) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|tx| tx.hash() == txid)?;
+        self.merkle_tree().proof(index, txid)
     }
 
     pub fn find_tx<P, T>(&self, value: &T, predicate: P) -> Option<&SignedTransaction>
     where
         P: Fn(&T, &SignedTransaction) -> bool,
     {
-        for tx in self.transactions.iter() {
-            if predicate(value, tx) {
-                return Some(tx);
-            }
-        }
-        None
+        self.transactions.iter().find(|tx| predicate(value, tx))
     }
 }
 
@@ -64,20 +119,15 @@ impl fmt::Display for Block {
             self.timestamp,
             &self.hash()[..10],
             self.nonce,
-            &self.previous_block[..10],
+            abbreviate(&self.previous_block, 10),
         )?;
 
         writeln!(f)?;
         writeln!(f, "Transactions:")?;
-        for i in 0..self.transactions.len() - 1 {
-            writeln!(f, "\t{}: {} ", i, self.transactions[i]).unwrap();
+        for (i, tx) in self.transactions.iter().enumerate() {
+            writeln!(f, "\t{}: {} ", i, tx)?;
         }
 
-        writeln!(
-            f,
-            "\t{}: {} ",
-            self.transactions.len() - 1,
-            self.transactions.last().unwrap()
-        )
+        Ok(())
     }
 }
@@ -1,101 +1,682 @@
+use super::address::*;
 use super::block::*;
+use super::crypto::*;
+use super::error::*;
+use super::merkle::*;
+use super::multisig::*;
+use super::params::*;
+use super::script::*;
 use super::signedtransaction::*;
+use super::store::*;
+use super::target::*;
 use super::transaction::*;
+use super::tree::*;
+use super::utxo::*;
 use super::*;
 
-use openssl::rsa::{Padding, Rsa};
 use std::fmt;
 
+use primitive_types::U256;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const METADATA_FILE: &str = "chain.json";
+// Blocks of the tree off the active chain, in a block store of their own.
+const BRANCHES_DIR: &str = "branches";
 
 #[derive(PartialEq, Debug)]
-pub enum BlockChainOperationResult {
-    BlockChainOk,
-    BlockChainUpdated,
-    BlockChainKept,
-    HashMismatchError,
-    ProofOfWorkError,
-    IndexMismatchError,
-    DoubleSpendingError,
-    TxIdNotFoundError,
-    InTxOwnershipError,
-    InTxTooSmallForTransaction,
-    InTxTooSmallForTransactionSet,
-    SignatureError,
-    SourceBlockIsNewerError,
+pub enum ConsensusOutcome {
+    Updated(ReorgReport),
+    Kept,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChainMetadata {
+    params: ChainParams,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BlockChain {
     pub chain: Vec<Block>,
-    difficulty: usize,
+    params: ChainParams,
+    #[serde(skip)]
+    store: Option<BlockStore>,
+    // Side-branch blocks, and the blocks a reorganization disconnected. Heights repeat in it,
+    // so it is only ever looked up by hash.
+    #[serde(skip)]
+    branches: Option<BlockStore>,
+    #[serde(skip)]
+    utxos: UtxoSet,
+    #[serde(skip)]
+    undo: Vec<BlockUndo>,
+    #[serde(skip)]
+    tree: BlockTree,
+    #[serde(skip)]
+    headers: HeaderTree,
 }
 
 impl BlockChain {
+    // Chain with a fixed target of `difficulty` leading hex zeros (the retargeting window is
+    // still applied, but never goes easier than that).
     pub fn new(difficulty: usize) -> Self {
+        BlockChain::with_params(ChainParams::with_difficulty(difficulty))
+    }
+
+    pub fn with_params(params: ChainParams) -> Self {
         BlockChain {
             chain: vec![],
-            difficulty,
+            params,
+            store: None,
+            branches: None,
+            utxos: UtxoSet::new(),
+            undo: vec![],
+            tree: BlockTree::new(),
+            headers: HeaderTree::new(),
+        }
+    }
+
+    // Initializes an on-disk chain at `path` and opens it. An existing chain is left alone:
+    // its parameters would be overwritten under the blocks mined with them.
+    pub fn create(path: &Path, params: ChainParams) -> Result<Self, ChainError> {
+        if Self::exists(path) {
+            return Err(ChainError::AlreadyExists(path.to_path_buf()));
+        }
+        let metadata =
+            serde_json::to_vec_pretty(&ChainMetadata { params }).map_err(StoreError::from)?;
+        fs::create_dir_all(path).map_err(StoreError::from)?;
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path.join(METADATA_FILE))
+        {
+            Ok(mut file) => file.write_all(&metadata).map_err(StoreError::from)?,
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(ChainError::AlreadyExists(path.to_path_buf()))
+            }
+            Err(error) => return Err(StoreError::from(error).into()),
+        }
+        Self::open(path)
+    }
+
+    // Reloads a chain written by `create`, re-verifying every block as it is connected. The
+    // side branches are accepted again after it, so a heavier one still wins.
+    pub fn open(path: &Path) -> Result<Self, ChainError> {
+        let metadata = fs::read(path.join(METADATA_FILE)).map_err(StoreError::from)?;
+        let metadata: ChainMetadata =
+            serde_json::from_slice(&metadata).map_err(StoreError::from)?;
+        let (store, blocks) = BlockStore::open(path)?;
+
+        let mut chain = BlockChain::with_params(metadata.params);
+
+        for block in blocks {
+            chain.push_verified(block)?;
+        }
+
+        log::debug!(
+            "Loaded {} blocks from {}",
+            chain.chain.len(),
+            path.display()
+        );
+
+        chain.store = Some(store);
+
+        let (branches, side_blocks) = BlockStore::open(&path.join(BRANCHES_DIR))?;
+        chain.branches = Some(branches);
+        for block in side_blocks {
+            let hash = block.hash();
+            if let Err(error) = chain.accept_block(block) {
+                log::warn!("Dropping stored side-branch block {}: {}", hash, error);
+            }
+        }
+
+        Ok(chain)
+    }
+
+    // Whether `create` has already been run for `path`.
+    pub fn exists(path: &Path) -> bool {
+        path.join(METADATA_FILE).is_file()
+    }
+
+    pub fn store(&self) -> Option<&BlockStore> {
+        self.store.as_ref()
+    }
+
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    pub fn tree(&self) -> &BlockTree {
+        &self.tree
+    }
+
+    pub fn headers(&self) -> &HeaderTree {
+        &self.headers
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Coins a block at `height` may mint on top of the fees it collects.
+    pub fn block_subsidy(&self, height: u128) -> u128 {
+        let halvings = height / self.params.halving_interval.max(1);
+        if halvings >= 128 {
+            return 0;
         }
+        self.params.block_subsidy >> halvings
     }
 
-    pub fn check_chain(&self) -> BlockChainOperationResult {
+    // First height at which the outputs of `entry` may be spent.
+    pub fn maturity_height(&self, entry: &UtxoEntry) -> u128 {
+        if !entry.is_coinbase {
+            return entry.block_id;
+        }
+        entry.block_id.saturating_add(self.params.coinbase_maturity)
+    }
+
+    pub fn block_work(&self, block: &Block) -> U256 {
+        block.target.work()
+    }
+
+    pub fn cumulative_work(&self) -> U256 {
+        match self.get_last_hash() {
+            Some(hash) => self
+                .tree
+                .get(&hash)
+                .map_or(U256::zero(), |node| node.cumulative_work),
+            None => U256::zero(),
+        }
+    }
+
+    // Target the block must carry, given its ancestors in the tree. The target only changes
+    // every `adjustment_interval` blocks: it is scaled by how long the previous window took
+    // compared to `target_block_time` per block.
+    pub fn expected_target(&self, block: &Block) -> Result<Target, ChainError> {
+        self.target_after(block.index, &block.previous_block)
+            .map_err(|parent| ChainError::UnknownParent {
+                block: block.index,
+                hash: block.hash(),
+                parent,
+            })
+    }
+
+    // Ancestors are looked up among the headers, which cover every block of the tree as well
+    // as the headers still waiting for their block. Fails with the first missing ancestor.
+    fn target_after(&self, index: u128, previous_block: &str) -> Result<Target, String> {
+        if index == 0 {
+            return Ok(self.params.initial_target);
+        }
+
+        let parent = self
+            .headers
+            .get(previous_block)
+            .ok_or_else(|| previous_block.to_string())?;
+
+        let interval = self.params.adjustment_interval.max(2);
+        if !index.is_multiple_of(interval) {
+            return Ok(parent.header.target);
+        }
+
+        let mut first = parent;
+        for _ in 1..interval {
+            first = self
+                .headers
+                .get(&first.header.previous_block)
+                .ok_or_else(|| first.header.previous_block.clone())?;
+        }
+
+        let actual = parent
+            .header
+            .timestamp
+            .saturating_sub(first.header.timestamp);
+        let expected = self.params.target_block_time.saturating_mul(interval - 1);
+        let target = parent.header.target.retarget(
+            actual,
+            expected,
+            self.params.max_adjustment_factor,
+            self.params.pow_limit,
+        );
+
+        log::debug!(
+            "Retargeting at block #{}: window took {}ns, expected {}ns. New target {}",
+            index,
+            actual,
+            expected,
+            target
+        );
+
+        Ok(target)
+    }
+
+    // Median timestamp of the `MEDIAN_TIME_SPAN` blocks up to `previous_block`, fewer of them
+    // near genesis. Fails with the first missing ancestor.
+    fn median_time_past(&self, previous_block: &str) -> Result<u128, String> {
+        let mut timestamps = vec![];
+        let mut cursor = previous_block;
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            let node = self.headers.get(cursor).ok_or_else(|| cursor.to_string())?;
+            timestamps.push(node.header.timestamp);
+            if node.header.index == 0 {
+                break;
+            }
+            cursor = &node.header.previous_block;
+        }
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    // The median time past keeps timestamps moving forward without trusting any single miner,
+    // and the drift bound keeps them from running ahead: the retargeting window is measured
+    // with them.
+    fn check_timestamp(&self, header: &BlockHeader, hash: &str) -> Result<(), ChainError> {
+        if header.index > 0 {
+            let median = self
+                .median_time_past(&header.previous_block)
+                .map_err(|parent| ChainError::UnknownParent {
+                    block: header.index,
+                    hash: hash.to_string(),
+                    parent,
+                })?;
+            if header.timestamp <= median {
+                return Err(ChainError::TimestampTooOld {
+                    block: header.index,
+                    timestamp: header.timestamp,
+                    median,
+                });
+            }
+        }
+
+        let limit = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            .saturating_add(MAX_FUTURE_DRIFT);
+        if header.timestamp > limit {
+            return Err(ChainError::TimestampTooNew {
+                block: header.index,
+                timestamp: header.timestamp,
+                limit,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Blocks are validated against the UTXO set as it was right before them, so the whole
+    // chain is replayed from genesis on a scratch copy.
+    pub fn check_chain(&self) -> Result<(), ChainError> {
+        let mut replay = BlockChain::with_params(self.params.clone());
         for block in self.chain.iter() {
-            let error = self.check_block(block);
-            if error != BlockChainOperationResult::BlockChainOk {
-                return error;
+            replay.push_verified(block.clone())?;
+        }
+        Ok(())
+    }
+
+    fn push_verified(&mut self, block: Block) -> Result<(), ChainError> {
+        if block.index != self.chain.len() as u128 {
+            return Err(ChainError::IndexMismatch {
+                expected: self.chain.len() as u128,
+                found: block.index,
+            });
+        }
+
+        self.check_block(&block)?;
+
+        let hash = block.hash();
+        let work = self
+            .cumulative_work()
+            .saturating_add(self.block_work(&block));
+        self.headers.insert(block.header(), hash.clone(), work);
+        self.tree.insert(block.clone(), hash, work);
+        self.connect_block(block)
+    }
+
+    fn connect_block(&mut self, block: Block) -> Result<(), ChainError> {
+        if let Some(store) = self.store.as_mut() {
+            store.append(&block)?;
+        }
+
+        let undo = self.utxos.apply_block(&block);
+        self.undo.push(undo);
+        self.chain.push(block);
+        Ok(())
+    }
+
+    fn persist_side_block(&mut self, block: &Block) -> Result<(), ChainError> {
+        if let Some(branches) = self.branches.as_mut() {
+            if !branches.contains(&block.hash()) {
+                branches.append(block)?;
             }
         }
-        BlockChainOperationResult::BlockChainOk
+        Ok(())
+    }
+
+    fn disconnect_tip(&mut self) -> Result<Option<Block>, ChainError> {
+        let block = match self.chain.pop() {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        if let Some(undo) = self.undo.pop() {
+            self.utxos.rollback_block(undo);
+        }
+
+        if let Some(store) = self.store.as_mut() {
+            store.truncate(block.index)?;
+        }
+
+        Ok(Some(block))
+    }
+
+    // Adds a block to the tree. Blocks on a competing branch are only stored (and validated
+    // later, if ever needed); the active chain switches to a branch as soon as it carries
+    // strictly more cumulative work than the current tip.
+    pub fn accept_block(&mut self, block: Block) -> Result<BlockAcceptance, ChainError> {
+        let hash = block.hash();
+        if self.tree.contains(&hash) {
+            return Ok(BlockAcceptance::AlreadyKnown);
+        }
+
+        self.check_proof(&block)?;
+
+        let parent_work = if block.index == 0 {
+            U256::zero()
+        } else {
+            match self.tree.get(&block.previous_block) {
+                Some(parent) if parent.block.index + 1 == block.index => parent.cumulative_work,
+                Some(parent) => {
+                    return Err(ChainError::IndexMismatch {
+                        expected: parent.block.index + 1,
+                        found: block.index,
+                    })
+                }
+                None => {
+                    return Err(ChainError::UnknownParent {
+                        block: block.index,
+                        hash,
+                        parent: block.previous_block.clone(),
+                    })
+                }
+            }
+        };
+
+        self.check_timestamp(&block.header(), &hash)?;
+        self.check_target(&block)?;
+
+        let cumulative_work = parent_work.saturating_add(self.block_work(&block));
+        let side_branch = cumulative_work <= self.cumulative_work() && !self.chain.is_empty();
+        if side_branch {
+            self.persist_side_block(&block)?;
+        }
+        self.headers
+            .insert(block.header(), hash.clone(), cumulative_work);
+        self.tree.insert(block, hash.clone(), cumulative_work);
+
+        if side_branch {
+            log::debug!("Block {} stored on a side branch", hash);
+            return Ok(BlockAcceptance::SideBranch);
+        }
+
+        self.reorganize(&hash).map(BlockAcceptance::MainChain)
+    }
+
+    // Adds a header to the header tree once its proof of work, its parent and its target check
+    // out, so that a whole header chain can be validated before any block is downloaded.
+    // Returns whether the header was new.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<bool, ChainError> {
+        let hash = header.hash();
+        if self.headers.contains(&hash) {
+            return Ok(false);
+        }
+
+        if !header.target.is_met_by(&hash) {
+            return Err(ChainError::ProofOfWork {
+                block: header.index,
+                hash,
+                target: header.target.to_hex(),
+            });
+        }
+
+        let parent_work = if header.index == 0 {
+            U256::zero()
+        } else {
+            match self.headers.get(&header.previous_block) {
+                Some(parent) if parent.header.index + 1 == header.index => parent.cumulative_work,
+                Some(parent) => {
+                    return Err(ChainError::IndexMismatch {
+                        expected: parent.header.index + 1,
+                        found: header.index,
+                    })
+                }
+                None => {
+                    return Err(ChainError::UnknownParent {
+                        block: header.index,
+                        hash,
+                        parent: header.previous_block.clone(),
+                    })
+                }
+            }
+        };
+
+        self.check_timestamp(&header, &hash)?;
+
+        let expected = self
+            .target_after(header.index, &header.previous_block)
+            .map_err(|parent| ChainError::UnknownParent {
+                block: header.index,
+                hash: hash.clone(),
+                parent,
+            })?;
+        if header.target != expected {
+            return Err(ChainError::TargetMismatch {
+                block: header.index,
+                expected: expected.to_hex(),
+                found: header.target.to_hex(),
+            });
+        }
+
+        let cumulative_work = parent_work.saturating_add(header.target.work());
+        self.headers.insert(header, hash, cumulative_work);
+        Ok(true)
+    }
+
+    pub fn best_header(&self) -> Option<&HeaderNode> {
+        self.headers.best()
+    }
+
+    // Blocks of the best header chain we do not have yet, lowest first, at most `max` of them.
+    pub fn blocks_to_download(&self, max: usize) -> Vec<(String, u128)> {
+        let mut missing = vec![];
+        let mut cursor = self.headers.best().map(|node| node.hash.clone());
+        while let Some(node) = cursor.and_then(|hash| self.headers.get(&hash)) {
+            if self.tree.contains(&node.hash) {
+                break;
+            }
+            missing.push((node.hash.clone(), node.header.index));
+            cursor = (node.header.index > 0).then(|| node.header.previous_block.clone());
+        }
+
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    // Moves the active chain to `new_tip`: disconnects our blocks down to the fork point and
+    // connects the branch, fully validating each block against the UTXO set. If any of them is
+    // invalid the branch is dropped from the tree and the previous chain is restored.
+    fn reorganize(&mut self, new_tip: &str) -> Result<ReorgReport, ChainError> {
+        let mut branch = vec![];
+        let mut cursor = new_tip.to_string();
+        while let Some(node) = self.tree.get(&cursor) {
+            let height = node.block.index as usize;
+            let is_active = self
+                .chain
+                .get(height)
+                .is_some_and(|block| block.hash() == cursor);
+            if is_active {
+                break;
+            }
+
+            branch.push(node.block.clone());
+            if height == 0 {
+                break;
+            }
+            cursor = node.block.previous_block.clone();
+        }
+        branch.reverse();
+
+        let fork_height = branch.first().map_or(self.chain.len() as u128, |b| b.index);
+
+        let mut disconnected = vec![];
+        while self.chain.len() as u128 > fork_height {
+            if let Some(block) = self.disconnect_tip()? {
+                disconnected.push(block);
+            }
+        }
+        disconnected.reverse();
+
+        if !disconnected.is_empty() {
+            log::info!(
+                "Reorganizing: {} blocks disconnected above height {}",
+                disconnected.len(),
+                fork_height
+            );
+        }
+
+        let connected = branch.len();
+        for block in branch {
+            if let Err(error) = self.check_block(&block) {
+                log::warn!(
+                    "Invalid block #{} on the new branch: {}",
+                    block.index,
+                    error
+                );
+                self.tree.invalidate(&block.hash());
+                self.headers.invalidate(&block.hash());
+
+                while self.chain.len() as u128 > fork_height {
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected {
+                    self.connect_block(block)?;
+                }
+                return Err(error);
+            }
+
+            self.connect_block(block)?;
+        }
+
+        // off the active chain now, but a branch overtaking this one may need them back
+        for block in disconnected.iter() {
+            self.persist_side_block(block)?;
+        }
+
+        let mined: Vec<String> = self.chain[fork_height as usize..]
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(|tx| tx.hash()))
+            .collect();
+
+        let orphaned_transactions = disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|tx| !tx.transaction.is_coinbase() && !mined.contains(&tx.hash()))
+            .cloned()
+            .collect();
+
+        Ok(ReorgReport {
+            fork_height,
+            depth: disconnected.len(),
+            connected,
+            orphaned_transactions,
+        })
     }
 
-    pub fn check_proof(&self, block: &Block) -> BlockChainOperationResult {
+    pub fn check_proof(&self, block: &Block) -> Result<(), ChainError> {
         let proof_of_work = block.hash();
         log::trace!("Checking nonce: {} -> PoW: {}", block.nonce, proof_of_work);
-        if proof_of_work[..self.difficulty] != "0".repeat(self.difficulty) {
-            return BlockChainOperationResult::ProofOfWorkError;
+        if !block.target.is_met_by(&proof_of_work) {
+            return Err(ChainError::ProofOfWork {
+                block: block.index,
+                hash: proof_of_work,
+                target: block.target.to_hex(),
+            });
         }
 
-        BlockChainOperationResult::BlockChainOk
+        Ok(())
     }
 
-    pub fn check_block(&self, block: &Block) -> BlockChainOperationResult {
-        if self.check_proof(block) != BlockChainOperationResult::BlockChainOk {
-            return BlockChainOperationResult::ProofOfWorkError;
+    pub fn check_target(&self, block: &Block) -> Result<(), ChainError> {
+        let expected = self.expected_target(block)?;
+        if block.target != expected {
+            log::warn!(
+                "Block #{} claims target {}, expected {}",
+                block.index,
+                block.target,
+                expected
+            );
+            return Err(ChainError::TargetMismatch {
+                block: block.index,
+                expected: expected.to_hex(),
+                found: block.target.to_hex(),
+            });
         }
 
-        let block_txs_state = self.validate_block_transactions(&block);
-        if block_txs_state != BlockChainOperationResult::BlockChainOk {
-            return block_txs_state;
-        }
+        Ok(())
+    }
+
+    pub fn check_block(&self, block: &Block) -> Result<(), ChainError> {
+        self.check_proof(block)?;
+        self.check_target(block)?;
+        self.validate_block_transactions(block)?;
 
         // The Genesis block has no parent, so no ascendance check can be perform.
         if block.index == 0 {
-            return BlockChainOperationResult::BlockChainOk;
+            return Ok(());
         }
 
-        // TODO block index as usize??
-        let previous_block = &self.chain[block.index as usize - 1];
-
-        if block.index != previous_block.index + 1 {
-            return BlockChainOperationResult::IndexMismatchError;
-        }
+        let previous_block = match self.chain.get(block.index as usize - 1) {
+            Some(previous_block) if block.index == previous_block.index + 1 => previous_block,
+            _ => {
+                return Err(ChainError::IndexMismatch {
+                    expected: self.chain.len() as u128,
+                    found: block.index,
+                })
+            }
+        };
 
-        if block.previous_block != previous_block.hash() {
-            return BlockChainOperationResult::HashMismatchError;
+        let previous_hash = previous_block.hash();
+        if block.previous_block != previous_hash {
+            return Err(ChainError::HashMismatch {
+                block: block.index,
+                expected: previous_hash,
+                found: block.previous_block.clone(),
+            });
         }
 
-        BlockChainOperationResult::BlockChainOk
+        Ok(())
     }
 
-    pub fn consensus(&mut self, another: BlockChain) -> BlockChainOperationResult{
-        if self.chain.len() < another.chain.len() && another.check_chain() == BlockChainOperationResult::BlockChainOk {
-            self.chain = another.chain;
-            return BlockChainOperationResult::BlockChainUpdated;
+    // Feeds every block of `another` into the block tree; the heaviest valid branch wins.
+    pub fn consensus(&mut self, another: BlockChain) -> Result<ConsensusOutcome, ChainError> {
+        let mut report: Option<ReorgReport> = None;
+
+        for block in another.chain {
+            if let BlockAcceptance::MainChain(update) = self.accept_block(block)? {
+                match report.as_mut() {
+                    Some(report) => report.merge(update, &self.chain),
+                    None => report = Some(update),
+                }
+            }
         }
 
-        BlockChainOperationResult::BlockChainKept
+        match report {
+            Some(report) => Ok(ConsensusOutcome::Updated(report)),
+            None => Ok(ConsensusOutcome::Kept),
+        }
     }
 
     /*
@@ -135,154 +716,349 @@ impl BlockChain {
     }
     */
 
-    pub fn find_txid_in_block(
-        &self,
-        index: u128,
-        txid: &str,
-    ) -> Option<&SignedTransaction> {
+    // Header of the active-chain block holding `txid`, along with the proof that it commits to
+    // the transaction.
+    pub fn transaction_proof(&self, txid: &str) -> Option<(BlockHeader, MerkleProof)> {
+        self.chain.iter().rev().find_map(|block| {
+            block
+                .merkle_proof(txid)
+                .map(|proof| (block.header(), proof))
+        })
+    }
+
+    pub fn find_txid_in_block(&self, index: u128, txid: &str) -> Option<&SignedTransaction> {
         self.chain[index as usize]
             .transactions
             .iter()
             .find(|source_tx| txid == source_tx.hash())
     }
 
-    pub fn validate_block_transactions(&self, block: &Block) -> BlockChainOperationResult {
+    pub fn validate_block_transactions(&self, block: &Block) -> Result<(), ChainError> {
         log::debug!("================== Validating block ======================");
-        let transactions = &block.transactions;
-        let mut input_hash = HashMap::new();
-        for (tx_index, signed_tx) in transactions.iter().enumerate() {
+        let minted = match block.transactions.first() {
+            Some(coinbase) if coinbase.transaction.is_coinbase() => {
+                coinbase.transaction.output_total()
+            }
+            _ => {
+                log::warn!("The first transaction is not a coinbase. FAIL");
+                return Err(ChainError::MissingCoinbase { block: block.index });
+            }
+        };
+
+        let mut spent_in_block = HashSet::new();
+        let mut fees: u128 = 0;
+        for (position, signed_tx) in block.transactions.iter().enumerate().skip(1) {
             log::debug!("Validating transaction");
             log::debug!("{}", signed_tx);
 
             let tx = &signed_tx.transaction;
-            if tx.intx == String::from("0").repeat(64) {
-                //This is a coinbase transaction
-                log::debug!("Coinbase Transaction. No input check needed.");
-                return BlockChainOperationResult::BlockChainOk;
+            if tx.is_coinbase() {
+                log::warn!("Coinbase transaction at position {}. FAIL", position);
+                return Err(ChainError::MisplacedCoinbase {
+                    block: block.index,
+                    txid: signed_tx.hash(),
+                    position,
+                });
             }
 
-            log::debug!("Source BLOCK {}", self.chain[tx.input_block_id as usize]);
-
-            let intx = self.find_txid_in_block(tx.input_block_id, &tx.intx);
-
-            if intx.is_none() {
-                log::warn!("Input TXID not found in source block: FAIL");
-                return BlockChainOperationResult::TxIdNotFoundError;
+            if tx.lock_height > block.index {
+                log::warn!("Transaction locked until block #{}. FAIL", tx.lock_height);
+                return Err(ChainError::TransactionLocked {
+                    block: block.index,
+                    txid: signed_tx.hash(),
+                    lock_height: tx.lock_height,
+                });
             }
 
-            let intx = intx.unwrap();
+            let mut inputs = vec![];
+            for input in tx.inputs.iter() {
+                // spent before this block or never created: both look the same from the UTXO
+                // set, and telling them apart would take a walk through the whole chain
+                let entry = match self.utxos.get(input) {
+                    Some(entry) if spent_in_block.insert(input.clone()) => entry,
+                    Some(_) => {
+                        log::warn!(
+                            "Double-spending detected: {} was already consumed. FAIL",
+                            input
+                        );
+                        return Err(ChainError::DoubleSpending {
+                            block: block.index,
+                            txid: signed_tx.hash(),
+                            input: input.clone(),
+                        });
+                    }
+                    None => {
+                        log::warn!("Input {} is not an unspent output: FAIL", input);
+                        return Err(ChainError::MissingInput {
+                            block: block.index,
+                            txid: signed_tx.hash(),
+                            input: input.clone(),
+                        });
+                    }
+                };
+                log::debug!("Input {}", entry);
+
+                let maturity_height = self.maturity_height(entry);
+                if block.index < maturity_height {
+                    log::warn!(
+                        "Coinbase output {} cannot be spent before block #{}. FAIL",
+                        input,
+                        maturity_height
+                    );
+                    return Err(ChainError::ImmatureCoinbase {
+                        block: block.index,
+                        txid: signed_tx.hash(),
+                        input: input.clone(),
+                        maturity_height,
+                    });
+                }
+
+                inputs.push(entry);
+            }
 
-            let is_valid_transaction = BlockChain::validate_transaction_signature(signed_tx);
-            if is_valid_transaction != BlockChainOperationResult::BlockChainOk {
+            let owners: Vec<&str> = inputs
+                .iter()
+                .map(|entry| entry.recipient.as_str())
+                .collect();
+            if let Err(reason) = BlockChain::validate_transaction_signature(signed_tx, &owners) {
+                log::warn!("Invalid signature: {}", reason);
                 log::warn!("==================BLOCK IS INVALID======================");
-                return is_valid_transaction;
+                return Err(ChainError::Signature {
+                    block: block.index,
+                    txid: signed_tx.hash(),
+                    reason,
+                });
             }
 
             log::debug!("Signature is valid");
             log::debug!("Validating INPUTS");
 
-            let is_valid_transaction =
-                self.validate_transaction_inputs(block, tx_index, &intx.transaction);
-
-            if is_valid_transaction != BlockChainOperationResult::BlockChainOk {
-                log::warn!("==================BLOCK IS INVALID======================");
-                return is_valid_transaction;
+            match self.validate_transaction_inputs(block, signed_tx, &inputs) {
+                Ok(fee) => fees = fees.saturating_add(fee),
+                Err(error) => {
+                    log::warn!("==================BLOCK IS INVALID======================");
+                    return Err(error);
+                }
             }
 
             log::debug!("Transaction INPUTS. OK");
+        }
 
-            let funds_available = input_hash
-                .entry(&tx.intx)
-                .or_insert(intx.transaction.amount);
-            if funds_available.checked_sub(tx.amount).is_none() {
-                log::warn!("Remaining INTX funds ({}) are smaller that the transaction requested {}. (INTX < Sum(UXTOS))", *funds_available, tx.amount);
-                log::warn!("==================BLOCK IS INVALID======================");
-                return BlockChainOperationResult::InTxTooSmallForTransactionSet;
+        // Outputs are keyed by txid: a transaction repeating one still unspent, like a coinbase
+        // copied from an earlier block, would overwrite its outputs, and disconnecting the block
+        // would then drop them for good.
+        let mut txids = HashSet::new();
+        for signed_tx in block.transactions.iter() {
+            let txid = signed_tx.hash();
+            let outputs = signed_tx.transaction.outputs.len() as u32;
+            if (0..outputs).any(|vout| self.utxos.contains(&OutPoint::new(&txid, vout)))
+                || !txids.insert(txid.clone())
+            {
+                log::warn!("Transaction {} creates existing outputs. FAIL", txid);
+                return Err(ChainError::DuplicateTransaction {
+                    block: block.index,
+                    txid,
+                });
             }
+        }
 
-            *funds_available -= tx.amount;
-            log::debug!("Transaction set INTX remaining funds:\n{:?}", input_hash);
+        let allowed = self.block_subsidy(block.index).saturating_add(fees);
+        if minted > allowed {
+            log::warn!(
+                "Coinbase mints {} coins, only {} allowed (subsidy + fees). FAIL",
+                minted,
+                allowed
+            );
+            log::warn!("==================BLOCK IS INVALID======================");
+            return Err(ChainError::CoinbaseTooLarge {
+                block: block.index,
+                allowed,
+                found: minted,
+            });
         }
 
         log::debug!("==================BLOCK IS VALID======================");
-        BlockChainOperationResult::BlockChainOk
+        Ok(())
     }
 
+    // Every field of the transaction comes from a peer, so any decoding or crypto failure is
+    // reported as a rejected signature instead of being unwrapped. Input i is unlocked by the
+    // i-th unlocking data, checked against `owners[i]`, the address its output was paid to;
+    // every one of them signs the whole transaction.
     pub fn validate_transaction_signature(
         signed_tx: &SignedTransaction,
-    ) -> BlockChainOperationResult {
-        let sender = &signed_tx.transaction.sender;
+        owners: &[&str],
+    ) -> Result<(), SignatureError> {
+        let inputs = signed_tx.transaction.inputs.len();
+        if signed_tx.unlocking.len() != inputs || owners.len() != inputs {
+            log::warn!("Wrong number of unlocking data: FAIL");
+            return Err(SignatureError::UnlockingCount {
+                inputs,
+                found: signed_tx.unlocking.len(),
+            });
+        }
+
         let transaction_hash = signed_tx.transaction.hash();
-        let transaction_signature_decoded = bs58::decode(&signed_tx.signature).into_vec().unwrap();
+        let lock_height = signed_tx.transaction.lock_height;
+        for (unlocking, owner) in signed_tx.unlocking.iter().zip(owners) {
+            BlockChain::validate_unlocking(unlocking, owner, &transaction_hash, lock_height)?;
+        }
+        Ok(())
+    }
+
+    // The revealed public key must hash to the address spent from, and its key type selects the
+    // signature scheme.
+    fn validate_unlocking(
+        unlocking: &Unlocking,
+        owner: &str,
+        transaction_hash: &str,
+        lock_height: u128,
+    ) -> Result<(), SignatureError> {
+        match owner.parse::<Address>() {
+            Ok(address) if address.is_multisig() => {
+                return BlockChain::validate_multisig_signatures(
+                    unlocking,
+                    owner,
+                    transaction_hash,
+                );
+            }
+            Ok(address) if address.is_script() => {
+                return BlockChain::validate_transaction_script(
+                    unlocking,
+                    owner,
+                    transaction_hash,
+                    lock_height,
+                );
+            }
+            _ => {}
+        }
 
-        let decoded_key = bs58::decode(sender).into_vec().unwrap();
-        let rsa_public = Rsa::public_key_from_der(&decoded_key).unwrap();
-        let mut buf: Vec<u8> = vec![0u8; 2048];
-        let _len = rsa_public
-            .public_decrypt(&transaction_signature_decoded, &mut buf, Padding::PKCS1)
-            .unwrap();
-        let decrypted_hash = String::from_utf8(buf).unwrap();
+        let transaction_signature_decoded = bs58::decode(&unlocking.signature)
+            .into_vec()
+            .map_err(|e| SignatureError::SignatureEncoding(e.to_string()))?;
 
-        if transaction_hash == decrypted_hash {
+        let public_key = PublicKey::decode(&unlocking.public_key)?;
+        if public_key.address() != owner {
+            log::warn!("Public key does not match the address spent from: FAIL");
+            return Err(SignatureError::KeyMismatch);
+        }
+
+        if let Err(error) =
+            public_key.verify(transaction_hash.as_bytes(), &transaction_signature_decoded)
+        {
             log::warn!("Invalid signature: FAIL");
-            return BlockChainOperationResult::SignatureError;
+            return Err(error);
         }
 
-        BlockChainOperationResult::BlockChainOk
+        Ok(())
     }
 
-    pub fn validate_transaction_inputs(
-        &self,
-        tx_block: &Block,
-        tx_index: usize,
-        intx: &Transaction,
-    ) -> BlockChainOperationResult {
-        let tx = &tx_block.transactions[tx_index].transaction;
-        log::debug!("######### Validating transaction: #########");
-        log::debug!("{}", tx);
-        let source_block = &tx.input_block_id;
-        let source_txid = &tx.intx;
-
-        if intx.recipient != tx.sender {
-            log::warn!("TXOUT does not belong to sender: FAIL");
-            return BlockChainOperationResult::InTxOwnershipError;
+    // Spending from a multisig address reveals its policy in place of a public key, and exactly
+    // as many signatures as the policy requires, in the order of their keys.
+    fn validate_multisig_signatures(
+        unlocking: &Unlocking,
+        owner: &str,
+        transaction_hash: &str,
+    ) -> Result<(), SignatureError> {
+        let policy =
+            MultisigPolicy::decode(&unlocking.public_key).map_err(SignatureError::Policy)?;
+        if policy.address() != owner {
+            log::warn!("Policy does not match the address spent from: FAIL");
+            return Err(SignatureError::KeyMismatch);
         }
 
-        log::debug!("TXOUT belongs to SENDER: OK.");
-
-        for block in &self.chain[*source_block as usize..] {
-            let already_used = block.transactions.iter().find(|stx| {
-                let tx = &stx.transaction;
-                &tx.intx == source_txid && tx_block.index != block.index
+        let signatures = decode_signatures(&unlocking.signature)?;
+        if signatures.len() != policy.required() {
+            log::warn!("Wrong number of signatures: FAIL");
+            return Err(SignatureError::SignatureCount {
+                required: policy.required(),
+                found: signatures.len(),
             });
+        }
 
-            if let Some(previous_tx) = already_used {
-                log::warn!(
-                    "Double-spending detected: TXID was consumed in block {} by TX: {}. FAIL",
-                    block.index,
-                    previous_tx,
-                );
-                return BlockChainOperationResult::DoubleSpendingError;
+        let mut previous = None;
+        for (index, signature) in signatures {
+            if index >= policy.keys().len() || previous.is_some_and(|previous| index <= previous) {
+                return Err(SignatureError::Malformed(String::from(
+                    "signatures are not in the order of the keys",
+                )));
+            }
+            if let Err(error) = PublicKey::decode(&policy.keys()[index])?
+                .verify(transaction_hash.as_bytes(), &signature)
+            {
+                log::warn!("Invalid signature: FAIL");
+                return Err(error);
             }
+            previous = Some(index);
         }
 
-        log::debug!("TXOUT is an UXTO. OK.");
+        Ok(())
+    }
+
+    // Spending from a script address reveals its locking script in place of a public key, and an
+    // unlocking script in place of a signature.
+    fn validate_transaction_script(
+        unlocking: &Unlocking,
+        owner: &str,
+        transaction_hash: &str,
+        lock_height: u128,
+    ) -> Result<(), SignatureError> {
+        let locking = Script::decode(&unlocking.public_key).map_err(SignatureError::Script)?;
+        if locking.address() != owner {
+            log::warn!("Locking script does not match the address spent from: FAIL");
+            return Err(SignatureError::KeyMismatch);
+        }
+        let unlocking = Script::decode(&unlocking.signature).map_err(SignatureError::Script)?;
 
-        if tx.amount > intx.amount {
+        let context = ScriptContext {
+            message: transaction_hash.as_bytes(),
+            lock_height,
+        };
+        if let Err(error) = verify_script(&unlocking, &locking, context) {
+            log::warn!("Script failed: {}: FAIL", error);
+            return Err(SignatureError::Script(error));
+        }
+        Ok(())
+    }
+
+    pub fn validate_transaction_inputs(
+        &self,
+        tx_block: &Block,
+        signed_tx: &SignedTransaction,
+        inputs: &[&UtxoEntry],
+    ) -> Result<u128, ChainError> {
+        let tx = &signed_tx.transaction;
+        log::debug!("######### Validating transaction: #########");
+        log::debug!("{}", tx);
+
+        let available = inputs
+            .iter()
+            .fold(0u128, |total, input| total.saturating_add(input.amount));
+
+        let requested = tx.output_total();
+        if requested > available {
             log::warn!(
-                "UXTO is too small ({}) for the amount requested ({}). FAIL",
-                intx.amount,
-                tx.amount
+                "UXTOs are too small ({}) for the amount requested ({}). FAIL",
+                available,
+                requested
             );
-            return BlockChainOperationResult::InTxTooSmallForTransaction;
+            return Err(ChainError::InTxTooSmallForTransaction {
+                block: tx_block.index,
+                txid: signed_tx.hash(),
+                available,
+                requested,
+            });
         }
 
-        log::debug!("UXTO has enough funds. OK.");
+        log::debug!("UXTOs have enough funds. OK.");
 
-        BlockChainOperationResult::BlockChainOk
+        // whatever is not paid out goes to the miner
+        Ok(available - requested)
     }
 
-    pub fn mine_block(&mut self, mut new_block: Block) -> BlockChainOperationResult {
+    // Places `new_block` on top of our tip, with the target it has to meet there, once its
+    // transactions check out. Only the nonce is left to find, which needs no access to the chain.
+    pub fn prepare_block(&self, mut new_block: Block) -> Result<Block, ChainError> {
         new_block.previous_block = match self.get_last_hash() {
             Some(previous_hash) => previous_hash,
             None => "0".repeat(64),
@@ -298,21 +1074,30 @@ impl BlockChain {
             new_block.index
         );
 
-        let is_valid = self.validate_block_transactions(&new_block);
-
-        if is_valid != BlockChainOperationResult::BlockChainOk {
-            return is_valid;
+        if new_block.index > 0 {
+            let median = self
+                .median_time_past(&new_block.previous_block)
+                .map_err(|parent| ChainError::UnknownParent {
+                    block: new_block.index,
+                    hash: new_block.hash(),
+                    parent,
+                })?;
+            // a clock running behind the chain would not make a valid block
+            new_block.timestamp = new_block.timestamp.max(median + 1);
         }
 
+        self.validate_block_transactions(&new_block)?;
+        new_block.target = self.expected_target(&new_block)?;
+        Ok(new_block)
+    }
+
+    pub fn mine_block(&mut self, new_block: Block) -> Result<(), ChainError> {
+        let mut new_block = self.prepare_block(new_block)?;
+
         log::debug!("Mining for block #{}:", &new_block.index);
         log::debug!("{}", &new_block);
 
-        loop {
-            if self.check_proof(&new_block) == BlockChainOperationResult::BlockChainOk {
-                break;
-            }
-            new_block.nonce += 1;
-        }
+        new_block.solve();
 
         log::debug!(
             "Nonce found: {:x} => H[B] = {}",
@@ -320,16 +1105,71 @@ impl BlockChain {
             new_block.hash()
         );
 
-        // necessary?
-        let is_valid = self.check_block(&new_block);
+        // the block extends our own tip, so it always becomes part of the active chain
+        self.accept_block(new_block)?;
+        Ok(())
+    }
+
+    // Hashes of active-chain blocks, from the tip back to genesis: the first ten one by one,
+    // then doubling the step. A peer finds our fork point with it in a logarithmic number of
+    // entries.
+    pub fn locator(&self) -> Vec<String> {
+        self.locator_from(self.get_last_hash())
+    }
+
+    // Same as `locator`, from any header down to its genesis.
+    pub fn locator_from(&self, tip: Option<String>) -> Vec<String> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut cursor = tip.and_then(|hash| self.headers.get(&hash));
+        while let Some(node) = cursor {
+            locator.push(node.hash.clone());
+            if node.header.index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
 
-        if is_valid != BlockChainOperationResult::BlockChainOk {
-            return is_valid;
+            let height = node.header.index.saturating_sub(step);
+            let mut ancestor = node;
+            while ancestor.header.index > height {
+                match self.headers.get(&ancestor.header.previous_block) {
+                    Some(parent) => ancestor = parent,
+                    None => return locator,
+                }
+            }
+            cursor = Some(ancestor);
         }
+        locator
+    }
 
-        self.chain.push(new_block);
+    // Active-chain blocks following the first `locator` entry we know of, up to `max` of them.
+    // Without any common block the peer gets our chain from genesis.
+    pub fn blocks_after(&self, locator: &[String], max: usize) -> &[Block] {
+        let start = locator
+            .iter()
+            .find_map(|hash| {
+                let node = self.headers.get(hash)?;
+                let height = node.header.index as usize;
+                let is_active = self
+                    .chain
+                    .get(height)
+                    .is_some_and(|block| block.hash() == *hash);
+                is_active.then_some(height + 1)
+            })
+            .unwrap_or(0);
+
+        let start = start.min(self.chain.len());
+        let end = start.saturating_add(max).min(self.chain.len());
+        &self.chain[start..end]
+    }
 
-        BlockChainOperationResult::BlockChainOk
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        self.blocks_after(locator, max)
+            .iter()
+            .map(|block| block.header())
+            .collect()
     }
 
     pub fn get_last_index(&self) -> Option<u128> {
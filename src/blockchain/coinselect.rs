@@ -0,0 +1,336 @@
+use super::wallet::*;
+
+use openssl::rand::rand_bytes;
+use std::cmp::Reverse;
+
+// Branch and bound gives up after exploring this many combinations.
+pub const BNB_MAX_TRIES: usize = 100_000;
+
+// What the selected outputs have to pay for, in coins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelectionTarget {
+    // The payment, plus the fee of the transaction before any input is added to it.
+    pub amount: u128,
+    // Fee each input adds to the transaction.
+    pub input_fee: u128,
+    // Fee of a change output. Leftovers up to this much go to the miner instead.
+    pub change_cost: u128,
+}
+
+impl SelectionTarget {
+    pub fn new(amount: u128) -> Self {
+        Self {
+            amount,
+            input_fee: 0,
+            change_cost: 0,
+        }
+    }
+
+    // What spending `uxto` brings in once its own fee is paid: outputs worth less than their
+    // fee only make a transaction poorer.
+    pub fn effective_value(&self, uxto: &UXTO) -> u128 {
+        uxto.amount.saturating_sub(self.input_fee)
+    }
+
+    // Coins left over after paying for `selected` and the fees it adds.
+    pub fn leftover(&self, selected: &[UXTO]) -> Option<u128> {
+        let fees = self.input_fee.checked_mul(selected.len() as u128)?;
+        let needed = self.amount.checked_add(fees)?;
+        selected
+            .iter()
+            .fold(0u128, |sum, uxto| sum.saturating_add(uxto.amount))
+            .checked_sub(needed)
+    }
+
+    // The change output worth adding, if any.
+    pub fn change(&self, selected: &[UXTO]) -> Option<u128> {
+        self.leftover(selected)
+            .filter(|leftover| *leftover > self.change_cost)
+            .map(|leftover| leftover - self.change_cost)
+    }
+}
+
+// Picks which outputs, all sent to the same address, fund a transaction.
+pub trait CoinSelector {
+    // Some of `candidates` covering `target`, or None if they cannot.
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>>;
+}
+
+// The outputs worth spending, in `candidates` order, with their effective values.
+fn economic(candidates: &[UXTO], target: &SelectionTarget) -> Vec<(u128, UXTO)> {
+    candidates
+        .iter()
+        .map(|uxto| (target.effective_value(uxto), uxto.clone()))
+        .filter(|(value, _)| *value > 0)
+        .collect()
+}
+
+// Takes `pool` in order until the target is covered.
+fn accumulate(pool: Vec<(u128, UXTO)>, target: &SelectionTarget) -> Option<Vec<UXTO>> {
+    let mut sum: u128 = 0;
+    let mut selected = vec![];
+    for (value, uxto) in pool {
+        if sum >= target.amount {
+            break;
+        }
+        sum = sum.saturating_add(value);
+        selected.push(uxto);
+    }
+    (sum >= target.amount).then_some(selected)
+}
+
+// Biggest outputs first: the fewest inputs, and so the smallest fee.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>> {
+        let mut pool = economic(candidates, target);
+        pool.sort_by_key(|(value, _)| Reverse(*value));
+        accumulate(pool, target)
+    }
+}
+
+// Smallest outputs first, consolidating them while fees are low.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>> {
+        let mut pool = economic(candidates, target);
+        pool.sort_by_key(|(value, _)| *value);
+        accumulate(pool, target)
+    }
+}
+
+// Depth-first search for outputs matching the target closely enough to do without change: what
+// they exceed it by has to stay within the cost of a change output. Of the matches found, the
+// one wasting the least is kept. Finds nothing rather than settle for change.
+#[derive(Debug, Clone, Copy)]
+pub struct BranchAndBound {
+    pub max_tries: usize,
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        Self {
+            max_tries: BNB_MAX_TRIES,
+        }
+    }
+}
+
+// A node of the search: whether to spend the candidate at `depth` is decided next. `path` is
+// how many of the chosen candidates lead to it, `picked` whether the last decision spent one.
+struct Node {
+    depth: usize,
+    sum: u128,
+    remaining: u128,
+    path: usize,
+    picked: bool,
+}
+
+struct Search<'a> {
+    values: &'a [u128],
+    target: u128,
+    upper_bound: u128,
+    tries: usize,
+    chosen: Vec<usize>,
+    best: Option<(u128, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    // Walks the tree with a stack of its own, the branch spending a candidate first: a wallet
+    // may have more outputs than the thread has stack for one frame each.
+    fn explore(&mut self, total: u128) {
+        let mut pending = vec![Node {
+            depth: 0,
+            sum: 0,
+            remaining: total,
+            path: 0,
+            picked: false,
+        }];
+
+        while let Some(node) = pending.pop() {
+            if self.tries == 0 {
+                break;
+            }
+            if node.sum > self.upper_bound || node.sum.saturating_add(node.remaining) < self.target
+            {
+                continue;
+            }
+            self.tries -= 1;
+
+            self.chosen.truncate(node.path);
+            if node.picked {
+                self.chosen.push(node.depth - 1);
+            }
+
+            if node.sum >= self.target {
+                // anything added would only waste more
+                let waste = node.sum - self.target;
+                if self.best.as_ref().is_none_or(|(best, _)| waste < *best) {
+                    self.best = Some((waste, self.chosen.clone()));
+                }
+                continue;
+            }
+            if node.depth == self.values.len() {
+                continue;
+            }
+
+            let value = self.values[node.depth];
+            let child = |sum, picked| Node {
+                depth: node.depth + 1,
+                sum,
+                remaining: node.remaining - value,
+                path: self.chosen.len(),
+                picked,
+            };
+            pending.push(child(node.sum, false));
+            pending.push(child(node.sum.saturating_add(value), true));
+        }
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>> {
+        let mut pool = economic(candidates, target);
+        // large values first, so that overshooting branches are cut early
+        pool.sort_by_key(|(value, _)| Reverse(*value));
+        let values: Vec<u128> = pool.iter().map(|(value, _)| *value).collect();
+
+        let mut search = Search {
+            values: &values,
+            target: target.amount,
+            upper_bound: target.amount.saturating_add(target.change_cost),
+            tries: self.max_tries,
+            chosen: vec![],
+            best: None,
+        };
+        let total = values
+            .iter()
+            .fold(0u128, |total, value| total.saturating_add(*value));
+        search.explore(total);
+
+        search.best.map(|(_, chosen)| {
+            chosen
+                .into_iter()
+                .map(|index| pool[index].1.clone())
+                .collect()
+        })
+    }
+}
+
+// splitmix64, to shuffle with a reproducible seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+// Random outputs until the target is covered, then more of them while that brings the total
+// closer to twice the target without passing three times it. The change it leaves is about the
+// size of the payment, which keeps the outputs of the wallet in line with what it spends.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomImprove {
+    pub seed: u64,
+}
+
+impl RandomImprove {
+    pub fn new() -> Self {
+        let mut seed = [0; 8];
+        rand_bytes(&mut seed).unwrap();
+        Self::with_seed(u64::from_be_bytes(seed))
+    }
+
+    // The same seed always selects the same outputs from the same candidates.
+    pub fn with_seed(seed: u64) -> Self {
+        Self { seed }
+    }
+}
+
+impl Default for RandomImprove {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>> {
+        let mut pool = economic(candidates, target);
+        Rng(self.seed).shuffle(&mut pool);
+
+        let mut sum: u128 = 0;
+        let mut selected = vec![];
+        let mut rest = vec![];
+        for (value, uxto) in pool {
+            if sum < target.amount {
+                sum = sum.saturating_add(value);
+                selected.push(uxto);
+            } else {
+                rest.push((value, uxto));
+            }
+        }
+        if sum < target.amount {
+            return None;
+        }
+
+        let ideal = target.amount.saturating_mul(2);
+        let maximum = target.amount.saturating_mul(3);
+        for (value, uxto) in rest {
+            let improved = sum.saturating_add(value);
+            if improved <= maximum && improved.abs_diff(ideal) < sum.abs_diff(ideal) {
+                sum = improved;
+                selected.push(uxto);
+            }
+        }
+        Some(selected)
+    }
+}
+
+// Branch and bound for a transaction without change, largest first when there is no such match.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultSelector;
+
+impl CoinSelector for DefaultSelector {
+    fn select(&self, candidates: &[UXTO], target: &SelectionTarget) -> Option<Vec<UXTO>> {
+        BranchAndBound::default()
+            .select(candidates, target)
+            .or_else(|| LargestFirst.select(candidates, target))
+    }
+}
+
+// The selectors by name, for callers that pick one at run time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelection {
+    #[default]
+    Default,
+    LargestFirst,
+    SmallestFirst,
+    BranchAndBound,
+    RandomImprove,
+}
+
+impl CoinSelection {
+    pub fn selector(self) -> Box<dyn CoinSelector> {
+        match self {
+            CoinSelection::Default => Box::new(DefaultSelector),
+            CoinSelection::LargestFirst => Box::new(LargestFirst),
+            CoinSelection::SmallestFirst => Box::new(SmallestFirst),
+            CoinSelection::BranchAndBound => Box::new(BranchAndBound::default()),
+            CoinSelection::RandomImprove => Box::new(RandomImprove::new()),
+        }
+    }
+}
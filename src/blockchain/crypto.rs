@@ -0,0 +1,337 @@
+use super::address::*;
+use super::error::*;
+
+use k256::ecdsa::signature::{Signer as _, Verifier as _};
+use openssl::pkey::{Id as PKeyId, PKey, Private, Public};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Public keys revealed by transactions are base58(PUBLIC_KEY_VERSION | key type | raw key).
+pub const PUBLIC_KEY_VERSION: u8 = 1;
+
+const ED25519_SIGNATURE_SIZE: usize = 64;
+const SECP256K1_PUBLIC_KEY_SIZE: usize = 33;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+impl KeyType {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            KeyType::Ed25519 => 1,
+            KeyType::Secp256k1 => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(KeyType::Ed25519),
+            2 => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyType::Ed25519 => write!(f, "ed25519"),
+            KeyType::Secp256k1 => write!(f, "secp256k1"),
+        }
+    }
+}
+
+pub trait Signer {
+    fn key_type(&self) -> KeyType;
+    fn public_key(&self) -> Vec<u8>;
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+
+    fn address(&self) -> String {
+        Address::from_public_key(self.key_type(), &self.public_key()).to_string()
+    }
+
+    fn encoded_public_key(&self) -> String {
+        encode_public_key(self.key_type(), &self.public_key())
+    }
+}
+
+pub trait Verifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), SignatureError>;
+}
+
+pub fn encode_public_key(key_type: KeyType, public_key: &[u8]) -> String {
+    let mut bytes = vec![PUBLIC_KEY_VERSION, key_type.to_byte()];
+    bytes.extend(public_key);
+    bs58::encode(bytes).into_string()
+}
+
+#[derive(Clone)]
+pub struct Ed25519Signer {
+    key: PKey<Private>,
+}
+
+impl Ed25519Signer {
+    pub fn generate() -> Self {
+        Self {
+            key: PKey::generate_ed25519().unwrap(),
+        }
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Option<Self> {
+        PKey::private_key_from_raw_bytes(secret, PKeyId::ED25519)
+            .ok()
+            .map(|key| Self { key })
+    }
+
+    pub fn secret(&self) -> Vec<u8> {
+        self.key.raw_private_key().unwrap()
+    }
+}
+
+impl Signer for Ed25519Signer {
+    fn key_type(&self) -> KeyType {
+        KeyType::Ed25519
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.key.raw_public_key().unwrap()
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        openssl::sign::Signer::new_without_digest(&self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(message))
+            .unwrap()
+    }
+}
+
+pub struct Ed25519Verifier {
+    key: PKey<Public>,
+}
+
+impl Ed25519Verifier {
+    pub fn from_bytes(public_key: &[u8]) -> Result<Self, SignatureError> {
+        let key = PKey::public_key_from_raw_bytes(public_key, PKeyId::ED25519)
+            .map_err(|e| SignatureError::InvalidPublicKey(e.to_string()))?;
+        Ok(Self { key })
+    }
+}
+
+impl Verifier for Ed25519Verifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+        if signature.len() != ED25519_SIGNATURE_SIZE {
+            return Err(SignatureError::Malformed(format!(
+                "expected {} bytes, got {}",
+                ED25519_SIGNATURE_SIZE,
+                signature.len()
+            )));
+        }
+
+        let is_valid = openssl::sign::Verifier::new_without_digest(&self.key)
+            .and_then(|mut verifier| verifier.verify_oneshot(signature, message))
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+
+        if !is_valid {
+            return Err(SignatureError::Mismatch);
+        }
+
+        Ok(())
+    }
+}
+
+// ECDSA nonces are derived from the key and the message (RFC 6979), so signing is
+// deterministic just like Ed25519.
+#[derive(Clone)]
+pub struct Secp256k1Signer {
+    key: k256::ecdsa::SigningKey,
+}
+
+impl Secp256k1Signer {
+    pub fn generate() -> Self {
+        loop {
+            let mut secret = [0u8; 32];
+            openssl::rand::rand_bytes(&mut secret).unwrap();
+            if let Ok(key) = k256::ecdsa::SigningKey::from_slice(&secret) {
+                return Self { key };
+            }
+        }
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Option<Self> {
+        k256::ecdsa::SigningKey::from_slice(secret)
+            .ok()
+            .map(|key| Self { key })
+    }
+
+    pub fn secret(&self) -> Vec<u8> {
+        self.key.to_bytes().to_vec()
+    }
+}
+
+impl Signer for Secp256k1Signer {
+    fn key_type(&self) -> KeyType {
+        KeyType::Secp256k1
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.key
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let signature: k256::ecdsa::Signature = self.key.sign(message);
+        signature.to_bytes().to_vec()
+    }
+}
+
+pub struct Secp256k1Verifier {
+    key: k256::ecdsa::VerifyingKey,
+}
+
+impl Secp256k1Verifier {
+    // Only the compressed SEC1 encoding is taken: the key is part of the signed transaction,
+    // and another encoding of it would give the transaction another txid.
+    pub fn from_bytes(public_key: &[u8]) -> Result<Self, SignatureError> {
+        if public_key.len() != SECP256K1_PUBLIC_KEY_SIZE {
+            return Err(SignatureError::InvalidPublicKey(format!(
+                "expected a compressed key of {} bytes, got {}",
+                SECP256K1_PUBLIC_KEY_SIZE,
+                public_key.len()
+            )));
+        }
+        let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+            .map_err(|e| SignatureError::InvalidPublicKey(e.to_string()))?;
+        Ok(Self { key })
+    }
+}
+
+impl Verifier for Secp256k1Verifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+        let signature = k256::ecdsa::Signature::from_slice(signature)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+
+        self.key
+            .verify(message, &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+#[derive(Clone)]
+pub enum KeyPair {
+    Ed25519(Ed25519Signer),
+    Secp256k1(Secp256k1Signer),
+}
+
+impl KeyPair {
+    pub fn generate(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::Ed25519 => KeyPair::Ed25519(Ed25519Signer::generate()),
+            KeyType::Secp256k1 => KeyPair::Secp256k1(Secp256k1Signer::generate()),
+        }
+    }
+
+    // Rebuilds a key pair from the secret exported by `secret`.
+    pub fn from_secret(key_type: KeyType, secret: &[u8]) -> Option<Self> {
+        match key_type {
+            KeyType::Ed25519 => Ed25519Signer::from_secret(secret).map(KeyPair::Ed25519),
+            KeyType::Secp256k1 => Secp256k1Signer::from_secret(secret).map(KeyPair::Secp256k1),
+        }
+    }
+
+    pub fn secret(&self) -> Vec<u8> {
+        match self {
+            KeyPair::Ed25519(signer) => signer.secret(),
+            KeyPair::Secp256k1(signer) => signer.secret(),
+        }
+    }
+
+    fn signer(&self) -> &dyn Signer {
+        match self {
+            KeyPair::Ed25519(signer) => signer,
+            KeyPair::Secp256k1(signer) => signer,
+        }
+    }
+}
+
+impl Signer for KeyPair {
+    fn key_type(&self) -> KeyType {
+        self.signer().key_type()
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.signer().public_key()
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signer().sign(message)
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair({}, {})", self.key_type(), self.address())
+    }
+}
+
+pub enum PublicKey {
+    Ed25519(Ed25519Verifier),
+    Secp256k1(Secp256k1Verifier),
+}
+
+impl PublicKey {
+    // Parses a key encoded by `encode_public_key`.
+    pub fn decode(encoded: &str) -> Result<Self, SignatureError> {
+        let bytes = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|e| SignatureError::KeyEncoding(e.to_string()))?;
+
+        match bytes.as_slice() {
+            [PUBLIC_KEY_VERSION, key_type, public_key @ ..] => {
+                match KeyType::from_byte(*key_type) {
+                    Some(KeyType::Ed25519) => {
+                        Ok(PublicKey::Ed25519(Ed25519Verifier::from_bytes(public_key)?))
+                    }
+                    Some(KeyType::Secp256k1) => Ok(PublicKey::Secp256k1(
+                        Secp256k1Verifier::from_bytes(public_key)?,
+                    )),
+                    None => Err(SignatureError::UnknownKeyType(*key_type)),
+                }
+            }
+            [PUBLIC_KEY_VERSION] | [] => Err(SignatureError::KeyEncoding(String::from(
+                "public key is too short",
+            ))),
+            [version, ..] => Err(SignatureError::UnsupportedVersion(*version)),
+        }
+    }
+
+    // Computed from the key in its canonical form, compressed for secp256k1.
+    pub fn address(&self) -> String {
+        let (key_type, public_key) = match self {
+            PublicKey::Ed25519(verifier) => (KeyType::Ed25519, verifier.key.raw_public_key()),
+            PublicKey::Secp256k1(verifier) => (
+                KeyType::Secp256k1,
+                Ok(verifier.key.to_encoded_point(true).as_bytes().to_vec()),
+            ),
+        };
+        // a key that decoded has a raw form
+        Address::from_public_key(key_type, &public_key.unwrap()).to_string()
+    }
+
+    fn verifier(&self) -> &dyn Verifier {
+        match self {
+            PublicKey::Ed25519(verifier) => verifier,
+            PublicKey::Secp256k1(verifier) => verifier,
+        }
+    }
+}
+
+impl Verifier for PublicKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+        self.verifier().verify(message, signature)
+    }
+}
@@ -0,0 +1,442 @@
+use super::multisig::*;
+use super::script::*;
+use super::store::*;
+use super::transaction::*;
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ChainError {
+    ProofOfWork {
+        block: u128,
+        hash: String,
+        target: String,
+    },
+    TargetMismatch {
+        block: u128,
+        expected: String,
+        found: String,
+    },
+    IndexMismatch {
+        expected: u128,
+        found: u128,
+    },
+    HashMismatch {
+        block: u128,
+        expected: String,
+        found: String,
+    },
+    UnknownParent {
+        block: u128,
+        hash: String,
+        parent: String,
+    },
+    // Not after the median time past of its parent.
+    TimestampTooOld {
+        block: u128,
+        timestamp: u128,
+        median: u128,
+    },
+    TimestampTooNew {
+        block: u128,
+        timestamp: u128,
+        limit: u128,
+    },
+    // Below the lock height of the transaction.
+    TransactionLocked {
+        block: u128,
+        txid: String,
+        lock_height: u128,
+    },
+    // The input is not unspent: it was spent by an earlier block, or never existed.
+    MissingInput {
+        block: u128,
+        txid: String,
+        input: OutPoint,
+    },
+    // The input is spent twice within the block.
+    DoubleSpending {
+        block: u128,
+        txid: String,
+        input: OutPoint,
+    },
+    InTxTooSmallForTransaction {
+        block: u128,
+        txid: String,
+        available: u128,
+        requested: u128,
+    },
+    Signature {
+        block: u128,
+        txid: String,
+        reason: SignatureError,
+    },
+    CoinbaseTooLarge {
+        block: u128,
+        allowed: u128,
+        found: u128,
+    },
+    MissingCoinbase {
+        block: u128,
+    },
+    MisplacedCoinbase {
+        block: u128,
+        txid: String,
+        position: usize,
+    },
+    ImmatureCoinbase {
+        block: u128,
+        txid: String,
+        input: OutPoint,
+        maturity_height: u128,
+    },
+    DuplicateTransaction {
+        block: u128,
+        txid: String,
+    },
+    Storage(StoreError),
+    AlreadyExists(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    SignatureEncoding(String),
+    KeyEncoding(String),
+    UnsupportedVersion(u8),
+    UnknownKeyType(u8),
+    InvalidPublicKey(String),
+    // Not as many unlocking data as inputs.
+    UnlockingCount { inputs: usize, found: usize },
+    // The revealed public key is not the one the address of the output spent was made from.
+    KeyMismatch,
+    Malformed(String),
+    Mismatch,
+    // The policy revealed to spend from a multisig address.
+    Policy(MultisigError),
+    SignatureCount { required: usize, found: usize },
+    // The locking script revealed to spend from a script address rejected the unlocking one.
+    Script(ScriptError),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::SignatureEncoding(reason) => {
+                write!(f, "signature is not valid base58: {}", reason)
+            }
+            SignatureError::KeyEncoding(reason) => {
+                write!(f, "public key is not valid base58: {}", reason)
+            }
+            SignatureError::UnsupportedVersion(version) => {
+                write!(f, "unsupported public key version {}", version)
+            }
+            SignatureError::UnknownKeyType(key_type) => {
+                write!(f, "unknown key type {}", key_type)
+            }
+            SignatureError::InvalidPublicKey(reason) => {
+                write!(f, "invalid public key: {}", reason)
+            }
+            SignatureError::UnlockingCount { inputs, found } => {
+                write!(f, "{} inputs, but unlocking data for {}", inputs, found)
+            }
+            SignatureError::KeyMismatch => {
+                write!(f, "public key does not belong to the address spent from")
+            }
+            SignatureError::Malformed(reason) => {
+                write!(f, "signature is malformed: {}", reason)
+            }
+            SignatureError::Mismatch => write!(f, "signature does not match the transaction"),
+            SignatureError::Policy(error) => write!(f, "invalid multisig policy: {}", error),
+            SignatureError::SignatureCount { required, found } => {
+                write!(f, "expected {} signatures, found {}", required, found)
+            }
+            SignatureError::Script(error) => write!(f, "script failed: {}", error),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyKnown(String),
+    Coinbase(String),
+    Signature {
+        txid: String,
+        reason: SignatureError,
+    },
+    MissingInput {
+        txid: String,
+        input: OutPoint,
+    },
+    // Cannot go in the next block.
+    TransactionLocked {
+        txid: String,
+        lock_height: u128,
+    },
+    Conflict {
+        txid: String,
+        input: OutPoint,
+        spent_by: String,
+    },
+    InsufficientInputs {
+        txid: String,
+        available: u128,
+        requested: u128,
+    },
+    ImmatureCoinbase {
+        txid: String,
+        input: OutPoint,
+        maturity_height: u128,
+    },
+    // Below the minimum fee rate of the mempool.
+    FeeTooLow {
+        txid: String,
+        fee: u128,
+        required: u128,
+    },
+    // The mempool is full of transactions paying a better fee rate.
+    Full {
+        txid: String,
+    },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown(txid) => write!(f, "tx {} is already in the mempool", txid),
+            MempoolError::Coinbase(txid) => {
+                write!(
+                    f,
+                    "tx {}: coinbase transactions are only valid in blocks",
+                    txid
+                )
+            }
+            MempoolError::Signature { txid, reason } => {
+                write!(f, "tx {}: invalid signature: {}", txid, reason)
+            }
+            MempoolError::MissingInput { txid, input } => {
+                write!(f, "tx {}: input {} is not an unspent output", txid, input)
+            }
+            MempoolError::TransactionLocked { txid, lock_height } => write!(
+                f,
+                "tx {}: transaction is locked until block #{}",
+                txid, lock_height
+            ),
+            MempoolError::Conflict {
+                txid,
+                input,
+                spent_by,
+            } => write!(
+                f,
+                "tx {}: input {} is already spent by pooled tx {}",
+                txid, input, spent_by
+            ),
+            MempoolError::InsufficientInputs {
+                txid,
+                available,
+                requested,
+            } => write!(
+                f,
+                "tx {}: inputs hold {} coins, outputs pay {}",
+                txid, available, requested
+            ),
+            MempoolError::ImmatureCoinbase {
+                txid,
+                input,
+                maturity_height,
+            } => write!(
+                f,
+                "tx {}: coinbase output {} cannot be spent before block #{}",
+                txid, input, maturity_height
+            ),
+            MempoolError::FeeTooLow {
+                txid,
+                fee,
+                required,
+            } => write!(
+                f,
+                "tx {}: pays {} coins of fee, at least {} required",
+                txid, fee, required
+            ),
+            MempoolError::Full { txid } => write!(
+                f,
+                "tx {}: the mempool is full of transactions paying a better fee rate",
+                txid
+            ),
+        }
+    }
+}
+
+impl Error for MempoolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MempoolError::Signature { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl From<StoreError> for ChainError {
+    fn from(error: StoreError) -> Self {
+        ChainError::Storage(error)
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::ProofOfWork {
+                block,
+                hash,
+                target,
+            } => write!(
+                f,
+                "block #{}: hash {} is above its target {}",
+                block, hash, target
+            ),
+            ChainError::TargetMismatch {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "block #{}: claims target {}, but the retargeting rule requires {}",
+                block, found, expected
+            ),
+            ChainError::IndexMismatch { expected, found } => write!(
+                f,
+                "block index mismatch: expected #{}, found #{}",
+                expected, found
+            ),
+            ChainError::HashMismatch {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "block #{}: previous hash is {}, but parent hashes to {}",
+                block, found, expected
+            ),
+            ChainError::UnknownParent {
+                block,
+                hash,
+                parent,
+            } => write!(
+                f,
+                "block #{} ({}) builds on unknown block {}",
+                block, hash, parent
+            ),
+            ChainError::TimestampTooOld {
+                block,
+                timestamp,
+                median,
+            } => write!(
+                f,
+                "block #{}: timestamp {} is not after the median time past {}",
+                block, timestamp, median
+            ),
+            ChainError::TimestampTooNew {
+                block,
+                timestamp,
+                limit,
+            } => write!(
+                f,
+                "block #{}: timestamp {} is too far in the future, at most {} allowed",
+                block, timestamp, limit
+            ),
+            ChainError::TransactionLocked {
+                block,
+                txid,
+                lock_height,
+            } => write!(
+                f,
+                "block #{}, tx {}: transaction is locked until block #{}",
+                block, txid, lock_height
+            ),
+            ChainError::MissingInput { block, txid, input } => write!(
+                f,
+                "block #{}, tx {}: input {} is not an unspent output",
+                block, txid, input
+            ),
+            ChainError::DoubleSpending { block, txid, input } => write!(
+                f,
+                "block #{}, tx {}: input {} is spent twice in the block",
+                block, txid, input
+            ),
+            ChainError::InTxTooSmallForTransaction {
+                block,
+                txid,
+                available,
+                requested,
+            } => write!(
+                f,
+                "block #{}, tx {}: inputs hold {} coins, outputs pay {}",
+                block, txid, available, requested
+            ),
+            ChainError::Signature {
+                block,
+                txid,
+                reason,
+            } => write!(
+                f,
+                "block #{}, tx {}: invalid signature: {}",
+                block, txid, reason
+            ),
+            ChainError::CoinbaseTooLarge {
+                block,
+                allowed,
+                found,
+            } => write!(
+                f,
+                "block #{}: coinbase mints {} coins, subsidy and fees allow {}",
+                block, found, allowed
+            ),
+            ChainError::MissingCoinbase { block } => write!(
+                f,
+                "block #{}: the first transaction must be a coinbase",
+                block
+            ),
+            ChainError::MisplacedCoinbase {
+                block,
+                txid,
+                position,
+            } => write!(
+                f,
+                "block #{}, tx {}: coinbase at position {}, only the first transaction may be one",
+                block, txid, position
+            ),
+            ChainError::ImmatureCoinbase {
+                block,
+                txid,
+                input,
+                maturity_height,
+            } => write!(
+                f,
+                "block #{}, tx {}: coinbase output {} cannot be spent before block #{}",
+                block, txid, input, maturity_height
+            ),
+            ChainError::DuplicateTransaction { block, txid } => write!(
+                f,
+                "block #{}, tx {}: transaction outputs already exist unspent",
+                block, txid
+            ),
+            ChainError::Storage(error) => write!(f, "{}", error),
+            ChainError::AlreadyExists(path) => {
+                write!(f, "a chain already exists at {}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for ChainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChainError::Signature { reason, .. } => Some(reason),
+            ChainError::Storage(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use super::crypto::*;
+
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::elliptic_curve::PrimeField;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use std::fmt;
+use std::str::FromStr;
+
+// Child indices from this one up are hardened: derived from the parent private key only.
+pub const HARDENED: u32 = 1 << 31;
+
+// Consecutive unused addresses after which discovery stops looking for more.
+pub const GAP_LIMIT: usize = 20;
+
+// Coin type of the default paths, the one BIP44 reserves for test networks.
+const COIN_TYPE: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HdError {
+    InvalidMnemonic(String),
+    InvalidPath(String),
+    // ed25519 keys only have hardened children.
+    NonHardenedEd25519(u32),
+    // The (astronomically unlikely) child that BIP32 says to skip.
+    InvalidChild(u32),
+}
+
+impl fmt::Display for HdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HdError::InvalidMnemonic(reason) => write!(f, "invalid mnemonic: {}", reason),
+            HdError::InvalidPath(path) => write!(f, "invalid derivation path {}", path),
+            HdError::NonHardenedEd25519(index) => {
+                write!(f, "ed25519 keys cannot derive non-hardened child {}", index)
+            }
+            HdError::InvalidChild(index) => write!(f, "child {} is not a valid key", index),
+        }
+    }
+}
+
+impl std::error::Error for HdError {}
+
+// A BIP32 path such as m/44'/1'/0'/0, hardened indices carrying the HARDENED bit.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl DerivationPath {
+    // m/44'/1'/0'/0 for secp256k1. ed25519 derivation is hardened only, so its change level is
+    // hardened as well and so are the address indices below it.
+    pub fn default_for(key_type: KeyType) -> Self {
+        let change = match key_type {
+            KeyType::Ed25519 => HARDENED,
+            KeyType::Secp256k1 => 0,
+        };
+        DerivationPath(vec![44 | HARDENED, COIN_TYPE | HARDENED, HARDENED, change])
+    }
+
+    pub fn child(&self, index: u32) -> Self {
+        let mut path = self.0.clone();
+        path.push(index);
+        DerivationPath(path)
+    }
+
+    // The chain next to this one: m/44'/1'/0'/1 for m/44'/1'/0'/0, BIP44's change chain. The
+    // master key, or a last index that cannot grow, has none.
+    pub fn internal(&self) -> Option<Self> {
+        let (last, parent) = self.0.split_last()?;
+        if last & !HARDENED == !HARDENED {
+            return None;
+        }
+        let mut path = parent.to_vec();
+        path.push(last + 1);
+        Some(DerivationPath(path))
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = HdError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = || HdError::InvalidPath(path.to_string());
+        let mut levels = path.split('/');
+        if levels.next() != Some("m") {
+            return Err(invalid());
+        }
+
+        levels
+            .map(|level| {
+                let (index, hardened) = match level.strip_suffix(['\'', 'h']) {
+                    Some(index) => (index, HARDENED),
+                    None => (level, 0),
+                };
+                match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | hardened),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(DerivationPath)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            match index & HARDENED {
+                0 => write!(f, "/{}", index)?,
+                _ => write!(f, "/{}'", index & !HARDENED)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let key = PKey::hmac(key).unwrap();
+    let mut signer = openssl::sign::Signer::new(MessageDigest::sha512(), &key).unwrap();
+    signer.update(data).unwrap();
+    let mut output = [0; 64];
+    output.copy_from_slice(&signer.sign_to_vec().unwrap());
+    output
+}
+
+// A private key plus the chain code its children are derived with: BIP32 for secp256k1,
+// SLIP-0010 for ed25519.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    pub key_type: KeyType,
+    secret: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(key_type: KeyType, seed: &[u8]) -> Self {
+        let domain: &[u8] = match key_type {
+            KeyType::Ed25519 => b"ed25519 seed",
+            KeyType::Secp256k1 => b"Bitcoin seed",
+        };
+        Self::from_hmac(key_type, hmac_sha512(domain, seed))
+    }
+
+    fn from_hmac(key_type: KeyType, output: [u8; 64]) -> Self {
+        let mut secret = [0; 32];
+        let mut chain_code = [0; 32];
+        secret.copy_from_slice(&output[..32]);
+        chain_code.copy_from_slice(&output[32..]);
+        Self {
+            key_type,
+            secret,
+            chain_code,
+        }
+    }
+
+    pub fn secret(&self) -> &[u8; 32] {
+        &self.secret
+    }
+
+    pub fn chain_code(&self) -> &[u8; 32] {
+        &self.chain_code
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, HdError> {
+        let mut data = Vec::with_capacity(37);
+        if index & HARDENED != 0 {
+            data.push(0);
+            data.extend_from_slice(&self.secret);
+        } else {
+            match self.key_type {
+                KeyType::Ed25519 => return Err(HdError::NonHardenedEd25519(index)),
+                KeyType::Secp256k1 => {
+                    let secret = k256::SecretKey::from_slice(&self.secret)
+                        .map_err(|_| HdError::InvalidChild(index))?;
+                    let point = secret.public_key().to_encoded_point(true);
+                    data.extend_from_slice(point.as_bytes());
+                }
+            }
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let mut child = Self::from_hmac(self.key_type, hmac_sha512(&self.chain_code, &data));
+        if self.key_type == KeyType::Secp256k1 {
+            // the child key is the parent key tweaked by the left half, modulo the curve order
+            let tweak = Option::<k256::Scalar>::from(k256::Scalar::from_repr(child.secret.into()))
+                .ok_or(HdError::InvalidChild(index))?;
+            let parent = Option::<k256::Scalar>::from(k256::Scalar::from_repr(self.secret.into()))
+                .ok_or(HdError::InvalidChild(index))?;
+            let key = tweak + parent;
+            if bool::from(key.is_zero()) {
+                return Err(HdError::InvalidChild(index));
+            }
+            child.secret = key.to_repr().into();
+        }
+        Ok(child)
+    }
+
+    pub fn derive(&self, path: &DerivationPath) -> Result<Self, HdError> {
+        path.0
+            .iter()
+            .try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn key_pair(&self) -> KeyPair {
+        // any 32 bytes are an ed25519 secret, and derive_child only returns valid scalars
+        KeyPair::from_secret(self.key_type, &self.secret).unwrap()
+    }
+}
+
+impl fmt::Debug for ExtendedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ExtendedKey({}, {})",
+            self.key_type,
+            self.key_pair().address()
+        )
+    }
+}
+
+// A new BIP39 recovery phrase of `words` words (12, 15, 18, 21 or 24).
+pub fn generate_mnemonic(words: usize) -> Result<String, HdError> {
+    if !(12..=24).contains(&words) || !words.is_multiple_of(3) {
+        return Err(HdError::InvalidMnemonic(format!(
+            "{} words requested, expected 12, 15, 18, 21 or 24",
+            words
+        )));
+    }
+    let mut entropy = vec![0; words / 3 * 4];
+    rand_bytes(&mut entropy).unwrap();
+    bip39::Mnemonic::from_entropy(&entropy)
+        .map(|mnemonic| mnemonic.to_string())
+        .map_err(|error| HdError::InvalidMnemonic(error.to_string()))
+}
+
+// The 64-byte seed of a recovery phrase, after checking its words and checksum.
+pub fn mnemonic_to_seed(phrase: &str, passphrase: &str) -> Result<Vec<u8>, HdError> {
+    let phrase = phrase
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mnemonic = bip39::Mnemonic::parse_normalized(&phrase)
+        .map_err(|error| HdError::InvalidMnemonic(error.to_string()))?;
+    Ok(mnemonic.to_seed(passphrase).to_vec())
+}
@@ -0,0 +1,347 @@
+use super::crypto::*;
+
+use openssl::pkcs5;
+use openssl::rand::rand_bytes;
+use openssl::symm::{self, Cipher};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+
+// Keystores asking scrypt for more memory than this are refused rather than exhausting ours.
+const MAX_SCRYPT_MEMORY: u64 = 512 * 1024 * 1024;
+// Nor do they get to make an unlock run for hours: the time scrypt takes grows with every one
+// of its parameters.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 16;
+const MAX_SCRYPT_P: u32 = 4;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Serialization(serde_json::Error),
+    UnsupportedVersion(u32),
+    // Either the passphrase is wrong or the file was tampered with: AES-GCM cannot tell apart.
+    WrongPassphrase,
+    // The scrypt parameters are out of the bounds we are willing to run.
+    KdfCost { log_n: u8, r: u32, p: u32 },
+    InvalidKey,
+    NotEncrypted,
+    Locked,
+    Crypto(openssl::error::ErrorStack),
+}
+
+impl From<io::Error> for KeystoreError {
+    fn from(error: io::Error) -> Self {
+        KeystoreError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for KeystoreError {
+    fn from(error: serde_json::Error) -> Self {
+        KeystoreError::Serialization(error)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for KeystoreError {
+    fn from(error: openssl::error::ErrorStack) -> Self {
+        KeystoreError::Crypto(error)
+    }
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeystoreError::Io(error) => write!(f, "keystore I/O error: {}", error),
+            KeystoreError::Serialization(error) => write!(f, "malformed keystore: {}", error),
+            KeystoreError::UnsupportedVersion(version) => {
+                write!(f, "unsupported keystore version {}", version)
+            }
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase"),
+            KeystoreError::KdfCost { log_n, r, p } => write!(
+                f,
+                "scrypt cost out of bounds: 2^{} rounds, r = {}, p = {}",
+                log_n, r, p
+            ),
+            KeystoreError::InvalidKey => write!(f, "keystore does not hold the key of its address"),
+            KeystoreError::NotEncrypted => write!(f, "wallet has no passphrase"),
+            KeystoreError::Locked => write!(f, "wallet is locked"),
+            KeystoreError::Crypto(error) => write!(f, "keystore encryption failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+// scrypt cost parameters, stored along with the salt so they can be raised for new keystores
+// without breaking older ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+impl KdfParams {
+    // 2^15 rounds with r = 8: 32 MiB and a fraction of a second per unlock.
+    pub fn new() -> Result<Self, KeystoreError> {
+        Self::with_cost(15, 8, 1)
+    }
+
+    pub fn with_cost(log_n: u8, r: u32, p: u32) -> Result<Self, KeystoreError> {
+        let mut salt = [0; SALT_SIZE];
+        rand_bytes(&mut salt)?;
+        Ok(Self {
+            log_n,
+            r,
+            p,
+            salt: hex::encode(salt),
+        })
+    }
+
+    pub fn derive_key(&self, passphrase: &str) -> Result<SealingKey, KeystoreError> {
+        if !(1..=MAX_SCRYPT_LOG_N).contains(&self.log_n)
+            || !(1..=MAX_SCRYPT_R).contains(&self.r)
+            || !(1..=MAX_SCRYPT_P).contains(&self.p)
+        {
+            return Err(KeystoreError::KdfCost {
+                log_n: self.log_n,
+                r: self.r,
+                p: self.p,
+            });
+        }
+        let salt = hex::decode(&self.salt).map_err(|_| KeystoreError::InvalidKey)?;
+        let mut key = [0; KEY_SIZE];
+        pkcs5::scrypt(
+            passphrase.as_bytes(),
+            &salt,
+            1 << self.log_n,
+            self.r as u64,
+            self.p as u64,
+            MAX_SCRYPT_MEMORY,
+            &mut key,
+        )?;
+        Ok(SealingKey(key))
+    }
+}
+
+// The AES key a passphrase derives. An unlocked wallet keeps it to seal its keystore again when
+// the fields in the clear change, without asking for the passphrase.
+#[derive(Clone)]
+pub struct SealingKey([u8; KEY_SIZE]);
+
+impl fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SealingKey(..)")
+    }
+}
+
+// Keystore of a hierarchical deterministic wallet: the encrypted secret is the seed, and the
+// addresses derived from it so far are kept in the clear to be watched while the wallet is
+// locked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HdKeystore {
+    pub path: String,
+    pub addresses: Vec<String>,
+    // Those of the change chain, missing from keystores written before there was one.
+    #[serde(default)]
+    pub change_addresses: Vec<String>,
+}
+
+// A private key (or an HD seed) encrypted with AES-256-GCM under a key derived from a
+// passphrase with scrypt. The key type, the address and the HD addresses are in the clear, and
+// authenticated along with the secret, so that a locked wallet still knows which coins are its
+// own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub key_type: KeyType,
+    pub address: String,
+    pub kdf: KdfParams,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub tag: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hd: Option<HdKeystore>,
+}
+
+impl Keystore {
+    pub fn encrypt(keys: &KeyPair, passphrase: &str) -> Result<Self, KeystoreError> {
+        Self::encrypt_with(keys, passphrase, KdfParams::new()?)
+    }
+
+    pub fn encrypt_with(
+        keys: &KeyPair,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<Self, KeystoreError> {
+        let key = kdf.derive_key(passphrase)?;
+        Self::encrypt_secret(
+            keys.key_type(),
+            &keys.address(),
+            &keys.secret(),
+            None,
+            kdf,
+            &key,
+        )
+    }
+
+    // `address` is the one `secret` stands for: the key's own, or the first one of a seed.
+    // `key` is the one `kdf` derives from the passphrase.
+    pub fn encrypt_secret(
+        key_type: KeyType,
+        address: &str,
+        secret: &[u8],
+        hd: Option<HdKeystore>,
+        kdf: KdfParams,
+        key: &SealingKey,
+    ) -> Result<Self, KeystoreError> {
+        let mut keystore = Keystore {
+            version: KEYSTORE_VERSION,
+            key_type,
+            address: address.to_string(),
+            kdf,
+            nonce: String::new(),
+            ciphertext: String::new(),
+            tag: String::new(),
+            hd,
+        };
+        keystore.seal(key, secret)?;
+        Ok(keystore)
+    }
+
+    // Encrypts `secret` again, under a fresh nonce, after the fields in the clear changed.
+    pub fn seal(&mut self, key: &SealingKey, secret: &[u8]) -> Result<(), KeystoreError> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_SIZE];
+        let ciphertext = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.0,
+            Some(&nonce),
+            &self.associated_data(),
+            secret,
+            &mut tag,
+        )?;
+
+        self.nonce = hex::encode(nonce);
+        self.ciphertext = hex::encode(ciphertext);
+        self.tag = hex::encode(tag);
+        Ok(())
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<KeyPair, KeystoreError> {
+        let secret = self.decrypt_secret(passphrase)?;
+        match KeyPair::from_secret(self.key_type, &secret) {
+            Some(keys) if keys.address() == self.address => Ok(keys),
+            _ => Err(KeystoreError::InvalidKey),
+        }
+    }
+
+    // The secret as encrypted; checking it against `address` is up to the caller.
+    pub fn decrypt_secret(&self, passphrase: &str) -> Result<Vec<u8>, KeystoreError> {
+        self.open(&self.kdf.derive_key(passphrase)?)
+    }
+
+    pub fn open(&self, key: &SealingKey) -> Result<Vec<u8>, KeystoreError> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::UnsupportedVersion(self.version));
+        }
+
+        let decode = |field: &str| hex::decode(field).map_err(|_| KeystoreError::InvalidKey);
+        let (nonce, ciphertext, tag) = (
+            decode(&self.nonce)?,
+            decode(&self.ciphertext)?,
+            decode(&self.tag)?,
+        );
+        if nonce.len() != NONCE_SIZE || tag.len() != TAG_SIZE {
+            return Err(KeystoreError::InvalidKey);
+        }
+
+        symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.0,
+            Some(&nonce),
+            &self.associated_data(),
+            &ciphertext,
+            &tag,
+        )
+        .map_err(|_| KeystoreError::WrongPassphrase)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, KeystoreError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    // Written next to `path` first and renamed over it, so that a crash leaves either the old
+    // keystore or the new one, never a truncated file and a lost key. Only the owner may read
+    // either of them.
+    pub fn save(&self, path: &Path) -> Result<(), KeystoreError> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent)?;
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        // a leftover of an earlier crash would keep its permissions
+        match fs::remove_file(&temporary) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temporary, path)?;
+        // makes the rename itself durable
+        #[cfg(unix)]
+        File::open(parent)?.sync_all()?;
+        Ok(())
+    }
+
+    // Variable-length fields are prefixed with their length, so that no two keystores
+    // authenticate the same bytes.
+    fn associated_data(&self) -> Vec<u8> {
+        fn push(data: &mut Vec<u8>, field: &str) {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+
+        let mut data = self.version.to_be_bytes().to_vec();
+        data.push(self.key_type.to_byte());
+        push(&mut data, &self.address);
+        if let Some(hd) = &self.hd {
+            push(&mut data, &hd.path);
+            data.extend_from_slice(&(hd.addresses.len() as u32).to_be_bytes());
+            for address in &hd.addresses {
+                push(&mut data, address);
+            }
+            // left out when empty, for the keystores without a change chain to still open
+            if !hd.change_addresses.is_empty() {
+                data.extend_from_slice(&(hd.change_addresses.len() as u32).to_be_bytes());
+                for address in &hd.change_addresses {
+                    push(&mut data, address);
+                }
+            }
+        }
+        data
+    }
+}
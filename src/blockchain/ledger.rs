@@ -0,0 +1,152 @@
+use super::id::*;
+use super::transaction::*;
+
+use serde::Serialize;
+use std::fmt;
+
+// Blocks after which a transaction of ours that never made it into the chain is given up on,
+// and the outputs it was spending are ours to spend again.
+pub const PENDING_EXPIRY: u128 = 72;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    // A coinbase paying us.
+    Mined,
+    Received,
+    Sent,
+    // Between our own addresses: only the fee leaves the wallet.
+    Internal,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryKind::Mined => write!(f, "mined"),
+            EntryKind::Received => write!(f, "received"),
+            EntryKind::Sent => write!(f, "sent"),
+            EntryKind::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+// A transaction as far as the wallet is concerned.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+    // Hash of the signed transaction. Until the wallet sees it signed, in the mempool or in a
+    // block, a transaction it created goes by the hash of the unsigned one.
+    pub txid: String,
+    pub kind: EntryKind,
+    // None while unconfirmed.
+    pub height: Option<u128>,
+    pub confirmations: u128,
+    // Paid to our addresses, change included.
+    pub received: u128,
+    // Our outputs spent by the inputs.
+    pub sent: u128,
+    // Paid by us to the miner; zero for transactions from someone else.
+    pub fee: u128,
+    // The senders of an incoming payment, the outside recipients of an outgoing one.
+    pub counterparties: Vec<String>,
+    pub timestamp: u128,
+}
+
+impl LedgerEntry {
+    // `spent` is what our outputs spent by the inputs were worth, if there were any, and
+    // `senders` the addresses the inputs are spent from.
+    pub fn new(
+        txid: &str,
+        tx: &Transaction,
+        spent: Option<u128>,
+        senders: &[String],
+        owns: &dyn Fn(&str) -> bool,
+    ) -> Option<Self> {
+        let received = tx
+            .outputs
+            .iter()
+            .filter(|output| owns(&output.recipient))
+            .fold(0u128, |total, output| total.saturating_add(output.amount));
+        let outside: Vec<String> = tx
+            .outputs
+            .iter()
+            .filter(|output| !owns(&output.recipient))
+            .map(|output| output.recipient.clone())
+            .collect();
+
+        let (kind, sent, counterparties) = match spent {
+            _ if tx.is_coinbase() => (EntryKind::Mined, 0, vec![]),
+            Some(spent) if outside.is_empty() => (EntryKind::Internal, spent, vec![]),
+            Some(spent) => (EntryKind::Sent, spent, outside),
+            None => (EntryKind::Received, 0, senders.to_vec()),
+        };
+        if kind != EntryKind::Sent && kind != EntryKind::Internal && received == 0 {
+            return None;
+        }
+
+        Some(Self {
+            txid: txid.to_string(),
+            kind,
+            height: None,
+            confirmations: 0,
+            received,
+            sent,
+            fee: sent.saturating_sub(tx.output_total()),
+            counterparties,
+            timestamp: tx.timestamp,
+        })
+    }
+
+    pub fn confirmed(mut self, height: u128, tip: u128) -> Self {
+        self.height = Some(height);
+        self.confirmations = tip.saturating_sub(height) + 1;
+        self
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.height.is_some()
+    }
+
+    // What the transaction changed our balance by.
+    pub fn net(&self) -> i128 {
+        (self.received as i128).saturating_sub(self.sent as i128)
+    }
+}
+
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:+} {}", &self.txid, self.net(), self.kind)?;
+        match self.height {
+            Some(height) => write!(
+                f,
+                " in block {} ({} confirmations)",
+                height, self.confirmations
+            )?,
+            None => write!(f, " unconfirmed")?,
+        }
+        if self.fee > 0 {
+            write!(f, ", fee {}", self.fee)?;
+        }
+        for counterparty in &self.counterparties {
+            write!(f, ", {}...", Id::new(counterparty))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Balance {
+    // Everything our addresses hold in the active chain.
+    pub confirmed: u128,
+    // Coming to us in transactions not mined yet, change of our own included.
+    pub unconfirmed: u128,
+    // What can be spent right now: mature, and not spent by a pending transaction.
+    pub spendable: u128,
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Confirmed: {} coins", self.confirmed)?;
+        writeln!(f, "Unconfirmed: {} coins", self.unconfirmed)?;
+        write!(f, "Spendable: {} coins", self.spendable)
+    }
+}
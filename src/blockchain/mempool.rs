@@ -0,0 +1,359 @@
+use super::block::*;
+use super::chain::*;
+use super::error::*;
+use super::signedtransaction::*;
+use super::transaction::*;
+use super::tree::*;
+use super::*;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// Upper bound for the serialized transactions of a block template.
+pub const DEFAULT_BLOCK_SIZE: usize = 1_000_000;
+
+// Serialized transactions a mempool holds at most, a few blocks worth of them. Once it is full,
+// a transaction only gets in by paying a better fee rate than the ones it pushes out.
+pub const DEFAULT_MEMPOOL_SIZE: usize = 50 * DEFAULT_BLOCK_SIZE;
+
+// Coins per 1000 bytes a transaction has to pay to be pooled. Transactions paying nothing are
+// taken while there is room.
+pub const DEFAULT_MIN_FEE_RATE: u128 = 0;
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: SignedTransaction,
+    pub size: usize,
+    // Whatever the inputs hold beyond the outputs.
+    pub fee: u128,
+    sequence: u64,
+}
+
+impl MempoolEntry {
+    // Higher fee per byte first, then first come.
+    fn priority(&self, other: &Self) -> Ordering {
+        // fee_a / size_a > fee_b / size_b, without dividing
+        let rate = self.fee.saturating_mul(other.size.max(1) as u128);
+        let other_rate = other.fee.saturating_mul(self.size.max(1) as u128);
+        match other_rate.cmp(&rate) {
+            Ordering::Equal => self.sequence.cmp(&other.sequence),
+            ordering => ordering,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    spent_by: HashMap<OutPoint, String>,
+    next_sequence: u64,
+    // Sum of the sizes of the entries.
+    size: usize,
+    max_size: usize,
+    min_fee_rate: u128,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::with_limits(DEFAULT_MEMPOOL_SIZE, DEFAULT_MIN_FEE_RATE)
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Holds at most `max_size` bytes of transactions, each paying at least `min_fee_rate` coins
+    // per 1000 bytes.
+    pub fn with_limits(max_size: usize, min_fee_rate: u128) -> Self {
+        Self {
+            entries: HashMap::new(),
+            spent_by: HashMap::new(),
+            next_sequence: 0,
+            size: 0,
+            max_size,
+            min_fee_rate,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&SignedTransaction> {
+        self.entries.get(txid).map(|entry| &entry.tx)
+    }
+
+    pub fn entry(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &MempoolEntry)> {
+        self.entries.iter()
+    }
+
+    // Pooled transaction spending `outpoint`, if any.
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&str> {
+        self.spent_by.get(outpoint).map(|txid| txid.as_str())
+    }
+
+    // Validates `tx` against the UTXO set of `chain` and the transactions already pooled.
+    pub fn add(
+        &mut self,
+        chain: &BlockChain,
+        tx: SignedTransaction,
+    ) -> Result<String, MempoolError> {
+        let txid = tx.hash();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown(txid));
+        }
+
+        let transaction = &tx.transaction;
+        if transaction.is_coinbase() {
+            return Err(MempoolError::Coinbase(txid));
+        }
+
+        // timelocks are checked against the block the transaction would be mined in
+        let next_height = chain.get_last_index().map_or(0, |index| index + 1);
+        if transaction.lock_height > next_height {
+            return Err(MempoolError::TransactionLocked {
+                txid,
+                lock_height: transaction.lock_height,
+            });
+        }
+
+        let mut owners = vec![];
+        let mut available: u128 = 0;
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            if let Some(spent_by) = self.spent_by.get(input) {
+                log::warn!("Rejecting {}: {} is spent by {}", txid, input, spent_by);
+                return Err(MempoolError::Conflict {
+                    txid,
+                    input: input.clone(),
+                    spent_by: spent_by.clone(),
+                });
+            }
+
+            if transaction.inputs[..index].contains(input) {
+                return Err(MempoolError::Conflict {
+                    input: input.clone(),
+                    spent_by: txid.clone(),
+                    txid,
+                });
+            }
+
+            let entry = match chain.utxos().get(input) {
+                Some(entry) => entry,
+                None => {
+                    return Err(MempoolError::MissingInput {
+                        txid,
+                        input: input.clone(),
+                    })
+                }
+            };
+
+            let maturity_height = chain.maturity_height(entry);
+            if next_height < maturity_height {
+                return Err(MempoolError::ImmatureCoinbase {
+                    txid,
+                    input: input.clone(),
+                    maturity_height,
+                });
+            }
+
+            owners.push(entry.recipient.as_str());
+            available = available.saturating_add(entry.amount);
+        }
+
+        if let Err(reason) = BlockChain::validate_transaction_signature(&tx, &owners) {
+            log::warn!("Rejecting {}: {}", txid, reason);
+            return Err(MempoolError::Signature { txid, reason });
+        }
+
+        let requested = transaction.output_total();
+        if requested > available {
+            return Err(MempoolError::InsufficientInputs {
+                txid,
+                available,
+                requested,
+            });
+        }
+
+        // the same rounding as the fee rates of the wallet
+        let size = serde_json::to_vec(&tx).map_or(0, |bytes| bytes.len());
+        let fee = available - requested;
+        let required = self
+            .min_fee_rate
+            .saturating_mul(size as u128)
+            .div_ceil(1000);
+        if fee < required {
+            return Err(MempoolError::FeeTooLow {
+                txid,
+                fee,
+                required,
+            });
+        }
+
+        let entry = MempoolEntry {
+            tx,
+            size,
+            fee,
+            sequence: self.next_sequence,
+        };
+        let evicted = self
+            .make_room(&entry)
+            .ok_or_else(|| MempoolError::Full { txid: txid.clone() })?;
+        for evicted in evicted.iter() {
+            log::debug!("Transaction {} evicted for {}", evicted, txid);
+            self.remove(evicted);
+        }
+
+        for input in entry.tx.transaction.inputs.iter() {
+            self.spent_by.insert(input.clone(), txid.clone());
+        }
+        self.size += entry.size;
+        self.entries.insert(txid.clone(), entry);
+        self.next_sequence += 1;
+
+        log::debug!("Transaction {} added to the mempool", txid);
+        Ok(txid)
+    }
+
+    // Transactions to evict for `entry` to fit, the worst fee rates first, or None if that
+    // would take one paying as much as `entry` does.
+    fn make_room(&self, entry: &MempoolEntry) -> Option<Vec<String>> {
+        if entry.size > self.max_size {
+            return None;
+        }
+
+        let mut pooled: Vec<(&String, &MempoolEntry)> = self.entries.iter().collect();
+        pooled.sort_by(|(_, a), (_, b)| b.priority(a));
+
+        let mut size = self.size;
+        let mut evicted = vec![];
+        for (txid, pooled) in pooled {
+            if size + entry.size <= self.max_size {
+                break;
+            }
+            if pooled.priority(entry) != Ordering::Greater {
+                return None;
+            }
+            size -= pooled.size;
+            evicted.push(txid.clone());
+        }
+        (size + entry.size <= self.max_size).then_some(evicted)
+    }
+
+    pub fn remove(&mut self, txid: &str) -> Option<SignedTransaction> {
+        let entry = self.entries.remove(txid)?;
+        self.size -= entry.size;
+        for input in entry.tx.transaction.inputs.iter() {
+            self.spent_by.remove(input);
+        }
+        Some(entry.tx)
+    }
+
+    // Drops every transaction spending an output that is no longer unspent in `chain`: the
+    // ones mined by the new blocks as well as the ones conflicting with them.
+    pub fn evict_stale(&mut self, chain: &BlockChain) -> Vec<String> {
+        let stale: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .tx
+                    .transaction
+                    .inputs
+                    .iter()
+                    .any(|input| !chain.utxos().contains(input))
+            })
+            .map(|(txid, _)| txid.clone())
+            .collect();
+
+        for txid in stale.iter() {
+            self.remove(txid);
+        }
+
+        if !stale.is_empty() {
+            log::debug!("{} transactions evicted from the mempool", stale.len());
+        }
+
+        stale
+    }
+
+    // Called once `chain` switched branches: evicts what the new branch made stale and gives
+    // the transactions of the abandoned blocks another chance. Those were mined before anything
+    // pooled that conflicts with them, so they go first, in block order. The next block may be
+    // lower than before, so the pooled transactions are validated again, timelocks and coinbase
+    // maturity included, in the order they came in.
+    pub fn reorganized(&mut self, chain: &BlockChain, report: &ReorgReport) {
+        self.evict_stale(chain);
+
+        let mut pooled: Vec<MempoolEntry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        pooled.sort_by_key(|entry| entry.sequence);
+        self.spent_by.clear();
+        self.size = 0;
+
+        for tx in report.orphaned_transactions.iter() {
+            if let Err(error) = self.add(chain, tx.clone()) {
+                log::debug!("Orphaned transaction not re-added: {}", error);
+            }
+        }
+
+        for entry in pooled {
+            if let Err(error) = self.add(chain, entry.tx) {
+                log::debug!("Transaction evicted after the reorg: {}", error);
+            }
+        }
+    }
+
+    // Next block for `chain`: a coinbase paying the subsidy and the collected fees to `miner`,
+    // then the pooled transactions with the best fee rate first, as long as they fit in
+    // `max_size` serialized bytes.
+    pub fn block_template(&self, chain: &BlockChain, miner: &str, max_size: usize) -> Block {
+        let mut candidates: Vec<&MempoolEntry> = self.entries.values().collect();
+        candidates.sort_by(|a, b| a.priority(b));
+
+        // room for the coinbase, whatever its reward ends up being
+        let coinbase_size = serde_json::to_vec(&SignedTransaction::unsigned(
+            Transaction::coinbase(miner, u128::MAX),
+        ))
+        .map_or(0, |bytes| bytes.len());
+
+        let mut size = coinbase_size;
+        let mut fees: u128 = 0;
+        let mut transactions = vec![];
+        for candidate in candidates {
+            if size + candidate.size > max_size {
+                continue;
+            }
+            size += candidate.size;
+            fees = fees.saturating_add(candidate.fee);
+            transactions.push(candidate.tx.clone());
+        }
+
+        let height = chain.get_last_index().map_or(0, |index| index + 1);
+        let reward = chain.block_subsidy(height).saturating_add(fees);
+        // coinbase transactions are not signed
+        let coinbase = SignedTransaction::unsigned(Transaction::coinbase(miner, reward));
+        transactions.insert(0, coinbase);
+
+        log::debug!(
+            "Block template: {} transactions, {} bytes, {} coins of fees",
+            transactions.len(),
+            size,
+            fees
+        );
+
+        Block::new(transactions)
+    }
+}
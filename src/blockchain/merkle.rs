@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner node can never be
+// passed off as a transaction (RFC 6962). An odd node at the end of a level is promoted as is
+// instead of being paired with itself, which would let two different transaction lists share
+// the same root.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn hash_leaf(txid: &str) -> String {
+    let mut bytes = vec![LEAF_PREFIX];
+    bytes.extend(txid.bytes());
+    crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
+}
+
+fn hash_node(left: &str, right: &str) -> String {
+    let mut bytes = vec![NODE_PREFIX];
+    bytes.extend(left.bytes());
+    bytes.extend(right.bytes());
+    crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    // levels[0] holds the leaves, the last level holds the root alone.
+    levels: Vec<Vec<String>>,
+}
+
+impl MerkleTree {
+    pub fn new(txids: &[String]) -> Self {
+        let mut levels = vec![txids.iter().map(|txid| hash_leaf(txid)).collect::<Vec<_>>()];
+
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Self { levels }
+    }
+
+    // The root of a block without transactions is all zeros.
+    pub fn root(&self) -> String {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => root.clone(),
+            None => "0".repeat(64),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    pub fn proof(&self, index: usize, txid: &str) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let mut siblings = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                let side = if sibling < position {
+                    Side::Left
+                } else {
+                    Side::Right
+                };
+                siblings.push(ProofStep {
+                    hash: hash.clone(),
+                    side,
+                });
+            }
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            txid: txid.to_string(),
+            index,
+            siblings,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+// Path from a transaction to the merkle root of its block: enough to show that a block header
+// commits to the transaction without shipping the whole block.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub txid: String,
+    pub index: usize,
+    pub siblings: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    pub fn compute_root(&self) -> String {
+        self.siblings
+            .iter()
+            .fold(hash_leaf(&self.txid), |hash, step| match step.side {
+                Side::Left => hash_node(&step.hash, &hash),
+                Side::Right => hash_node(&hash, &step.hash),
+            })
+    }
+
+    pub fn verify(&self, merkle_root: &str) -> bool {
+        self.compute_root() == merkle_root
+    }
+}
+
+impl fmt::Display for MerkleProof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "txid:{}...;index:{};depth:{}",
+            super::abbreviate(&self.txid, 10),
+            self.index,
+            self.siblings.len()
+        )
+    }
+}
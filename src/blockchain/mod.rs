@@ -1,13 +1,45 @@
+pub mod address;
 pub mod block;
 pub mod chain;
+pub mod coinselect;
+pub mod crypto;
+pub mod error;
+pub mod hd;
 pub mod id;
+pub mod keystore;
+pub mod ledger;
+pub mod mempool;
+pub mod merkle;
+pub mod multisig;
+pub mod params;
+pub mod psbt;
+pub mod script;
 pub mod signedtransaction;
+pub mod store;
+pub mod target;
 pub mod transaction;
+pub mod tree;
+pub mod utxo;
 pub mod wallet;
 
 pub trait Hashable {
     fn hash(&self) -> String;
 }
 
+// Appends `field` after its length: the strings hashed are not of a fixed size, and where one
+// ends and the next begins has to be part of the hash too.
+pub fn extend_prefixed(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend(&(field.len() as u64).to_be_bytes());
+    bytes.extend(field);
+}
+
+// Display prefix of peer-supplied strings: never panics on short or non-ASCII input.
+pub fn abbreviate(text: &str, len: usize) -> &str {
+    match text.char_indices().nth(len) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
 #[cfg(test)]
 mod tests;
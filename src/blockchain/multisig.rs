@@ -0,0 +1,226 @@
+use super::address::*;
+use super::crypto::*;
+use super::error::*;
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+// Version byte of an encoded policy, next to PUBLIC_KEY_VERSION: both can be revealed by the
+// spender of an output.
+pub const MULTISIG_POLICY_VERSION: u8 = 2;
+
+pub const MAX_MULTISIG_KEYS: usize = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultisigError {
+    Encoding(String),
+    UnsupportedVersion(u8),
+    Threshold { required: usize, keys: usize },
+    TooManyKeys(usize),
+    DuplicateKey(String),
+    // Keys of an encoded policy come sorted, so that a policy has a single encoding.
+    Unsorted,
+    InvalidKey(String),
+}
+
+impl fmt::Display for MultisigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultisigError::Encoding(reason) => write!(f, "malformed multisig policy: {}", reason),
+            MultisigError::UnsupportedVersion(version) => {
+                write!(f, "unsupported multisig policy version {}", version)
+            }
+            MultisigError::Threshold { required, keys } => {
+                write!(f, "cannot require {} signatures of {} keys", required, keys)
+            }
+            MultisigError::TooManyKeys(keys) => write!(
+                f,
+                "{} keys, a multisig policy takes at most {}",
+                keys, MAX_MULTISIG_KEYS
+            ),
+            MultisigError::DuplicateKey(key) => write!(f, "key {} appears twice", key),
+            MultisigError::Unsorted => write!(f, "multisig policy keys are not sorted"),
+            MultisigError::InvalidKey(key) => write!(f, "{} is not a valid public key", key),
+        }
+    }
+}
+
+impl std::error::Error for MultisigError {}
+
+// Outputs sent to the address of a policy can only be spent with signatures of `required` of
+// its `keys`, which are encoded public keys. Every policy is checked when made, deserialized
+// ones included, and keeps its encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedPolicy")]
+pub struct MultisigPolicy {
+    required: usize,
+    keys: Vec<String>,
+    #[serde(skip)]
+    bytes: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct UncheckedPolicy {
+    required: usize,
+    keys: Vec<String>,
+}
+
+impl TryFrom<UncheckedPolicy> for MultisigPolicy {
+    type Error = MultisigError;
+
+    fn try_from(policy: UncheckedPolicy) -> Result<Self, Self::Error> {
+        Self::checked(policy.required, policy.keys)
+    }
+}
+
+impl MultisigPolicy {
+    // The keys are sorted: the same keys and threshold make the same address whatever the
+    // order they are given in.
+    pub fn new(required: usize, mut keys: Vec<String>) -> Result<Self, MultisigError> {
+        keys.sort();
+        Self::checked(required, keys)
+    }
+
+    fn checked(required: usize, keys: Vec<String>) -> Result<Self, MultisigError> {
+        let mut policy = Self {
+            required,
+            keys,
+            bytes: vec![],
+        };
+        policy.bytes = policy.check()?;
+        Ok(policy)
+    }
+
+    pub fn required(&self) -> usize {
+        self.required
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    // Checks the policy and returns its encoding.
+    fn check(&self) -> Result<Vec<u8>, MultisigError> {
+        if self.keys.len() > MAX_MULTISIG_KEYS {
+            return Err(MultisigError::TooManyKeys(self.keys.len()));
+        }
+        if self.required == 0 || self.required > self.keys.len() {
+            return Err(MultisigError::Threshold {
+                required: self.required,
+                keys: self.keys.len(),
+            });
+        }
+        for pair in self.keys.windows(2) {
+            if pair[0] == pair[1] {
+                return Err(MultisigError::DuplicateKey(pair[0].clone()));
+            }
+            if pair[0] > pair[1] {
+                return Err(MultisigError::Unsorted);
+            }
+        }
+
+        // both counts are at most MAX_MULTISIG_KEYS
+        let mut bytes = vec![
+            MULTISIG_POLICY_VERSION,
+            self.required as u8,
+            self.keys.len() as u8,
+        ];
+        for key in &self.keys {
+            let invalid = || MultisigError::InvalidKey(key.clone());
+            PublicKey::decode(key).map_err(|_| invalid())?;
+            let decoded = bs58::decode(key).into_vec().map_err(|_| invalid())?;
+            bytes.push(u8::try_from(decoded.len()).map_err(|_| invalid())?);
+            bytes.extend(decoded);
+        }
+        Ok(bytes)
+    }
+
+    // Base58 of the version, the threshold, the number of keys, and every key prefixed with
+    // its length.
+    pub fn encode(&self) -> String {
+        bs58::encode(&self.bytes).into_string()
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, MultisigError> {
+        let bytes = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|e| MultisigError::Encoding(e.to_string()))?;
+        let truncated = || MultisigError::Encoding(String::from("policy is truncated"));
+
+        let (required, count, mut rest) = match bytes.as_slice() {
+            [MULTISIG_POLICY_VERSION, required, count, rest @ ..] => {
+                (*required as usize, *count as usize, rest)
+            }
+            [MULTISIG_POLICY_VERSION, ..] | [] => return Err(truncated()),
+            [version, ..] => return Err(MultisigError::UnsupportedVersion(*version)),
+        };
+        let mut keys = vec![];
+        for _ in 0..count {
+            let (length, tail) = rest.split_first().ok_or_else(truncated)?;
+            if tail.len() < *length as usize {
+                return Err(truncated());
+            }
+            let (key, tail) = tail.split_at(*length as usize);
+            keys.push(bs58::encode(key).into_string());
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(MultisigError::Encoding(String::from(
+                "trailing bytes after the keys",
+            )));
+        }
+
+        Self::checked(required, keys)
+    }
+
+    pub fn address(&self) -> String {
+        Address::from_multisig(&self.bytes).to_string()
+    }
+
+    // Index of an encoded public key among the keys.
+    pub fn position(&self, public_key: &str) -> Option<usize> {
+        self.keys.iter().position(|key| key == public_key)
+    }
+}
+
+impl fmt::Display for MultisigPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {}", self.required, self.keys.len())
+    }
+}
+
+// The signatures spending from a policy, each with the index of its key: base58 of, for every
+// signature, the index, the length, then the signature itself.
+pub fn encode_signatures(signatures: &[(usize, Vec<u8>)]) -> String {
+    let mut bytes = vec![];
+    for (index, signature) in signatures {
+        bytes.push(*index as u8);
+        bytes.push(signature.len() as u8);
+        bytes.extend(signature);
+    }
+    bs58::encode(bytes).into_string()
+}
+
+pub fn decode_signatures(encoded: &str) -> Result<Vec<(usize, Vec<u8>)>, SignatureError> {
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| SignatureError::SignatureEncoding(e.to_string()))?;
+
+    let mut signatures = vec![];
+    let mut rest = bytes.as_slice();
+    while let [index, length, tail @ ..] = rest {
+        if tail.len() < *length as usize {
+            break;
+        }
+        let (signature, tail) = tail.split_at(*length as usize);
+        signatures.push((*index as usize, signature.to_vec()));
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(SignatureError::Malformed(String::from(
+            "signatures are truncated",
+        )));
+    }
+    Ok(signatures)
+}
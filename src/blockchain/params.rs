@@ -0,0 +1,53 @@
+use super::target::*;
+
+use serde::{Deserialize, Serialize};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+pub const DEFAULT_COINBASE_MATURITY: u128 = 100;
+
+// A block must be later than the median timestamp of this many blocks before it...
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// ...and no further than this ahead of the clock of the node checking it.
+pub const MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * NANOS_PER_SECOND;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainParams {
+    pub initial_target: Target,
+    // Easiest target retargeting may ever reach.
+    pub pow_limit: Target,
+    // Same unit as Block::timestamp (nanoseconds).
+    pub target_block_time: u128,
+    // Every `adjustment_interval` blocks the target is recomputed from the timestamps of the
+    // previous `adjustment_interval` blocks.
+    pub adjustment_interval: u128,
+    pub max_adjustment_factor: u128,
+    // Coins a coinbase may mint on top of the fees of its block, halved every
+    // `halving_interval` blocks.
+    pub block_subsidy: u128,
+    pub halving_interval: u128,
+    // Confirmations, its own block included, a coinbase needs before its outputs can be spent.
+    pub coinbase_maturity: u128,
+}
+
+impl ChainParams {
+    pub fn with_difficulty(hex_zeros: usize) -> Self {
+        let target = Target::from_leading_zeros(hex_zeros);
+        Self {
+            initial_target: target,
+            pow_limit: target,
+            target_block_time: 10 * NANOS_PER_SECOND,
+            adjustment_interval: 10,
+            max_adjustment_factor: 4,
+            block_subsidy: 50,
+            halving_interval: 210_000,
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::with_difficulty(0)
+    }
+}
@@ -0,0 +1,359 @@
+use super::chain::*;
+use super::crypto::*;
+use super::error::*;
+use super::multisig::*;
+use super::signedtransaction::*;
+use super::transaction::*;
+use super::wallet::*;
+use super::Hashable;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const PSBT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum PsbtError {
+    Serialization(serde_json::Error),
+    UnsupportedVersion(u32),
+    // The input details do not line up with the inputs of the transaction.
+    InputMismatch,
+    InsufficientInputs { available: u128, requested: u128 },
+    // The policy given does not hash to the address of every input.
+    PolicyMismatch,
+    // The transaction given for an input is not the one it spends from, or does not pay the
+    // amount and the address given.
+    PreviousOutput(OutPoint),
+    // The chain disagrees with the details given for an input, or no longer has it unspent.
+    UnknownInput(OutPoint),
+    // No signature of the key of some input yet.
+    Unsigned,
+    Signature(SignatureError),
+    // The wallet asked to sign is locked, watch-only, or has no key for any input.
+    Signer(WalletOperationResult),
+}
+
+impl From<serde_json::Error> for PsbtError {
+    fn from(error: serde_json::Error) -> Self {
+        PsbtError::Serialization(error)
+    }
+}
+
+impl From<SignatureError> for PsbtError {
+    fn from(error: SignatureError) -> Self {
+        PsbtError::Signature(error)
+    }
+}
+
+impl fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PsbtError::Serialization(error) => {
+                write!(f, "malformed partially signed transaction: {}", error)
+            }
+            PsbtError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported partially signed transaction version {}",
+                    version
+                )
+            }
+            PsbtError::InputMismatch => {
+                write!(f, "input details do not match the transaction inputs")
+            }
+            PsbtError::InsufficientInputs {
+                available,
+                requested,
+            } => write!(
+                f,
+                "inputs hold {} coins, outputs request {}",
+                available, requested
+            ),
+            PsbtError::PolicyMismatch => {
+                write!(f, "multisig policy does not match the addresses spent from")
+            }
+            PsbtError::PreviousOutput(input) => write!(
+                f,
+                "input {} does not match the transaction it spends from",
+                input
+            ),
+            PsbtError::UnknownInput(input) => {
+                write!(f, "input {} is not an unspent output of the chain", input)
+            }
+            PsbtError::Unsigned => write!(f, "an input is not signed yet"),
+            PsbtError::Signature(error) => write!(f, "invalid signature: {}", error),
+            PsbtError::Signer(WalletOperationResult::Locked) => write!(f, "wallet is locked"),
+            PsbtError::Signer(WalletOperationResult::WatchOnly) => {
+                write!(f, "watch-only wallet cannot sign")
+            }
+            PsbtError::Signer(_) => write!(f, "wallet holds no key for any input"),
+        }
+    }
+}
+
+impl std::error::Error for PsbtError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PsbtError::Serialization(error) => Some(error),
+            PsbtError::Signature(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// What a signer without the chain needs to know about an output being spent. The signature
+// does not cover the amount and the address, so the transaction that created the output comes
+// along: its hash is the txid of the outpoint, which the signature does cover.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtInput {
+    pub outpoint: OutPoint,
+    pub amount: u128,
+    pub block_id: u128,
+    pub address: String,
+    pub previous: SignedTransaction,
+}
+
+impl PsbtInput {
+    fn check_previous(&self) -> Result<(), PsbtError> {
+        let output = self
+            .previous
+            .transaction
+            .outputs
+            .get(self.outpoint.vout as usize);
+        if self.previous.hash() != self.outpoint.txid
+            || output != Some(&TxOutput::new(&self.address, self.amount))
+        {
+            return Err(PsbtError::PreviousOutput(self.outpoint.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+// A transaction on its way from the machine that built it to the one holding the key and back:
+// the unsigned transaction, the outputs it spends, so that the signer can check the amounts
+// and the fee, and the signatures collected so far. Spending from a multisig address, it goes
+// around every cosigner, and carries the policy their keys are checked against.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    pub version: u32,
+    pub transaction: Transaction,
+    pub inputs: Vec<PsbtInput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigPolicy>,
+    pub signatures: Vec<PartialSignature>,
+}
+
+impl PartiallySignedTransaction {
+    pub fn new(transaction: Transaction, inputs: Vec<PsbtInput>) -> Self {
+        Self {
+            version: PSBT_VERSION,
+            transaction,
+            inputs,
+            multisig: None,
+            signatures: vec![],
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, PsbtError> {
+        let psbt: Self = serde_json::from_str(json)?;
+        if psbt.version != PSBT_VERSION {
+            return Err(PsbtError::UnsupportedVersion(psbt.version));
+        }
+        Ok(psbt)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Checks the input details against the transaction and the ones it spends from, and returns
+    // the fee it pays.
+    pub fn check(&self) -> Result<u128, PsbtError> {
+        let tx = &self.transaction;
+        if let Some(policy) = &self.multisig {
+            if self
+                .inputs
+                .iter()
+                .any(|input| input.address != policy.address())
+            {
+                return Err(PsbtError::PolicyMismatch);
+            }
+        }
+        if self.inputs.len() != tx.inputs.len()
+            || self
+                .inputs
+                .iter()
+                .zip(&tx.inputs)
+                .any(|(details, input)| details.outpoint != *input)
+        {
+            return Err(PsbtError::InputMismatch);
+        }
+
+        let mut available: u128 = 0;
+        for input in &self.inputs {
+            input.check_previous()?;
+            available = available.saturating_add(input.amount);
+        }
+
+        let requested = tx.output_total();
+        available
+            .checked_sub(requested)
+            .ok_or(PsbtError::InsufficientInputs {
+                available,
+                requested,
+            })
+    }
+
+    // Checks the input details against the unspent outputs of `chain`, which the signer could
+    // not do.
+    pub fn check_inputs(&self, chain: &BlockChain) -> Result<u128, PsbtError> {
+        let fee = self.check()?;
+        for input in &self.inputs {
+            match chain.utxos().get(&input.outpoint) {
+                Some(entry)
+                    if entry.amount == input.amount
+                        && entry.block_id == input.block_id
+                        && entry.recipient == input.address => {}
+                _ => return Err(PsbtError::UnknownInput(input.outpoint.clone())),
+            }
+        }
+        Ok(fee)
+    }
+
+    // Whether `keys` are those of an address spent from, or of one of the cosigners.
+    pub fn can_sign(&self, keys: &dyn Signer) -> bool {
+        match &self.multisig {
+            Some(policy) => policy.position(&keys.encoded_public_key()).is_some(),
+            None => {
+                let address = keys.address();
+                self.inputs.iter().any(|input| input.address == address)
+            }
+        }
+    }
+
+    // Adds the signature of `keys`, after checking what is signed.
+    pub fn sign(&mut self, keys: &dyn Signer) -> Result<(), PsbtError> {
+        self.check()?;
+        if !self.can_sign(keys) {
+            return Err(PsbtError::Signer(WalletOperationResult::UnknownSender));
+        }
+        let signature = PartialSignature {
+            public_key: keys.encoded_public_key(),
+            signature: bs58::encode(keys.sign(self.transaction.hash().as_bytes())).into_string(),
+        };
+        if !self.signatures.contains(&signature) {
+            self.signatures.push(signature);
+        }
+        Ok(())
+    }
+
+    // Merges the signatures collected by another copy of the same transaction.
+    pub fn combine(&mut self, other: PartiallySignedTransaction) -> Result<(), PsbtError> {
+        if other.transaction != self.transaction
+            || other.inputs != self.inputs
+            || other.multisig != self.multisig
+        {
+            return Err(PsbtError::InputMismatch);
+        }
+        for signature in other.signatures {
+            if !self.signatures.contains(&signature) {
+                self.signatures.push(signature);
+            }
+        }
+        Ok(())
+    }
+
+    // The transaction ready to be submitted, every input signed by the key of its address, or
+    // by as many cosigners as the policy requires.
+    pub fn finalize(&self) -> Result<SignedTransaction, PsbtError> {
+        self.check()?;
+        if self.signatures.is_empty() {
+            return Err(PsbtError::Unsigned);
+        }
+        let hash = self.transaction.hash();
+        let unlocking = match &self.multisig {
+            Some(policy) => {
+                // the valid signatures, in the order of their keys
+                let signatures: Vec<(usize, Vec<u8>)> = policy
+                    .keys()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, key)| {
+                        let public_key = PublicKey::decode(key).ok()?;
+                        self.signatures
+                            .iter()
+                            .filter(|signature| signature.public_key == *key)
+                            .filter_map(|signature| {
+                                bs58::decode(&signature.signature).into_vec().ok()
+                            })
+                            .find(|signature| public_key.verify(hash.as_bytes(), signature).is_ok())
+                            .map(|signature| (index, signature))
+                    })
+                    .take(policy.required())
+                    .collect();
+                let unlocking = Unlocking::new(policy.encode(), encode_signatures(&signatures));
+                vec![unlocking; self.inputs.len()]
+            }
+            // for each input, the first signature of its key that verifies, whatever else was
+            // collected along the way; without any, the first one tells why
+            None => self
+                .inputs
+                .iter()
+                .map(|input| {
+                    let signatures: Vec<(PublicKey, &PartialSignature)> = self
+                        .signatures
+                        .iter()
+                        .filter_map(|signature| {
+                            let public_key = PublicKey::decode(&signature.public_key).ok()?;
+                            (public_key.address() == input.address)
+                                .then_some((public_key, signature))
+                        })
+                        .collect();
+                    signatures
+                        .iter()
+                        .find(|(public_key, signature)| {
+                            bs58::decode(&signature.signature)
+                                .into_vec()
+                                .is_ok_and(|bytes| {
+                                    public_key.verify(hash.as_bytes(), &bytes).is_ok()
+                                })
+                        })
+                        .or(signatures.first())
+                        .map(|(_, signature)| {
+                            Unlocking::new(
+                                signature.public_key.clone(),
+                                signature.signature.clone(),
+                            )
+                        })
+                        .ok_or(PsbtError::Unsigned)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        let signed = SignedTransaction::new(self.transaction.clone(), unlocking);
+        let owners: Vec<&str> = self
+            .inputs
+            .iter()
+            .map(|input| input.address.as_str())
+            .collect();
+        BlockChain::validate_transaction_signature(&signed, &owners)?;
+        Ok(signed)
+    }
+}
+
+impl fmt::Display for PartiallySignedTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}inputs:{};signatures:{};",
+            self.transaction,
+            self.inputs.len(),
+            self.signatures.len()
+        )
+    }
+}
@@ -0,0 +1,628 @@
+use super::address::*;
+use super::crypto::*;
+use super::multisig::*;
+
+use std::fmt;
+use std::str::FromStr;
+
+// Limits keeping the work of a script bounded. There are no loops: every opcode, executed or
+// skipped, counts as a step, and pushes are bounded by the size of the script.
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_SCRIPT_STEPS: usize = 201;
+pub const MAX_STACK_SIZE: usize = 1_000;
+pub const MAX_ELEMENT_SIZE: usize = 520;
+// Numbers are unsigned little-endian, as wide as a u128.
+pub const MAX_NUMBER_SIZE: usize = 16;
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    Encoding(String),
+    Parse(String),
+    TooLarge(usize),
+    Truncated,
+    UnknownOpcode(u8),
+    // An unlocking script only pushes data.
+    NotPushOnly,
+    // A push in an unlocking script not encoded the shortest way.
+    NonMinimalPush,
+    UnbalancedConditional,
+    StackUnderflow,
+    StackOverflow,
+    ElementTooLarge(usize),
+    TooManySteps,
+    InvalidNumber,
+    TooManyKeys(usize),
+    VerifyFailed,
+    Return,
+    // The lock height of the transaction is below the one the script requires.
+    Timelocked { until: u128, lock_height: u128 },
+    // The script ran to the end and left false on top of the stack.
+    Failed,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Encoding(reason) => write!(f, "script is not valid base58: {}", reason),
+            ScriptError::Parse(reason) => write!(f, "cannot parse script: {}", reason),
+            ScriptError::TooLarge(size) => write!(
+                f,
+                "script of {} bytes, at most {} allowed",
+                size, MAX_SCRIPT_SIZE
+            ),
+            ScriptError::Truncated => write!(f, "script ends in the middle of a push"),
+            ScriptError::UnknownOpcode(byte) => write!(f, "unknown opcode {:#04x}", byte),
+            ScriptError::NotPushOnly => write!(f, "unlocking script does more than push data"),
+            ScriptError::NonMinimalPush => write!(f, "push is not encoded the shortest way"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced OP_IF/OP_ELSE/OP_ENDIF"),
+            ScriptError::StackUnderflow => write!(f, "not enough items on the stack"),
+            ScriptError::StackOverflow => {
+                write!(f, "stack grows past {} items", MAX_STACK_SIZE)
+            }
+            ScriptError::ElementTooLarge(size) => write!(
+                f,
+                "pushes {} bytes, at most {} allowed",
+                size, MAX_ELEMENT_SIZE
+            ),
+            ScriptError::TooManySteps => {
+                write!(f, "script runs past {} steps", MAX_SCRIPT_STEPS)
+            }
+            ScriptError::InvalidNumber => write!(f, "number is out of range"),
+            ScriptError::TooManyKeys(keys) => write!(
+                f,
+                "{} keys, OP_CHECKMULTISIG takes at most {}",
+                keys, MAX_MULTISIG_KEYS
+            ),
+            ScriptError::VerifyFailed => write!(f, "verification failed"),
+            ScriptError::Return => write!(f, "OP_RETURN reached"),
+            ScriptError::Timelocked { until, lock_height } => write!(
+                f,
+                "locked until block {}, the transaction only until block {}",
+                until, lock_height
+            ),
+            ScriptError::Failed => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    Verify,
+    Return,
+    Drop,
+    Dup,
+    Swap,
+    Size,
+    Equal,
+    EqualVerify,
+    Sha256,
+    Hash256,
+    CheckSig,
+    CheckSigVerify,
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    CheckLockTimeVerify,
+}
+
+const OPCODES: [(Opcode, u8, &str); 19] = [
+    (Opcode::If, 0x63, "OP_IF"),
+    (Opcode::NotIf, 0x64, "OP_NOTIF"),
+    (Opcode::Else, 0x67, "OP_ELSE"),
+    (Opcode::EndIf, 0x68, "OP_ENDIF"),
+    (Opcode::Verify, 0x69, "OP_VERIFY"),
+    (Opcode::Return, 0x6a, "OP_RETURN"),
+    (Opcode::Drop, 0x75, "OP_DROP"),
+    (Opcode::Dup, 0x76, "OP_DUP"),
+    (Opcode::Swap, 0x7c, "OP_SWAP"),
+    (Opcode::Size, 0x82, "OP_SIZE"),
+    (Opcode::Equal, 0x87, "OP_EQUAL"),
+    (Opcode::EqualVerify, 0x88, "OP_EQUALVERIFY"),
+    (Opcode::Sha256, 0xa8, "OP_SHA256"),
+    (Opcode::Hash256, 0xaa, "OP_HASH256"),
+    (Opcode::CheckSig, 0xac, "OP_CHECKSIG"),
+    (Opcode::CheckSigVerify, 0xad, "OP_CHECKSIGVERIFY"),
+    (Opcode::CheckMultiSig, 0xae, "OP_CHECKMULTISIG"),
+    (Opcode::CheckMultiSigVerify, 0xaf, "OP_CHECKMULTISIGVERIFY"),
+    (Opcode::CheckLockTimeVerify, 0xb1, "OP_CHECKLOCKTIMEVERIFY"),
+];
+
+impl Opcode {
+    pub fn to_byte(self) -> u8 {
+        OPCODES.iter().find(|(op, _, _)| *op == self).unwrap().1
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        OPCODES
+            .iter()
+            .find(|(_, value, _)| *value == byte)
+            .map(|(op, _, _)| *op)
+    }
+
+    fn name(self) -> &'static str {
+        OPCODES.iter().find(|(op, _, _)| *op == self).unwrap().2
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    // Data, encoded with the shortest push: a single byte from 1 to 16 as its OP_N.
+    Push(Vec<u8>),
+    // OP_1 to OP_16.
+    Number(u8),
+    Op(Opcode),
+}
+
+impl Instruction {
+    // `number` with the shortest encoding.
+    pub fn number(number: u128) -> Self {
+        match number {
+            1..=16 => Instruction::Number(number as u8),
+            _ => Instruction::Push(encode_number(number)),
+        }
+    }
+}
+
+fn encode_number(number: u128) -> Vec<u8> {
+    let bytes = number.to_le_bytes();
+    let length = MAX_NUMBER_SIZE - number.leading_zeros() as usize / 8;
+    bytes[..length].to_vec()
+}
+
+fn decode_number(bytes: &[u8]) -> Result<u128, ScriptError> {
+    if bytes.len() > MAX_NUMBER_SIZE {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let mut number = [0; MAX_NUMBER_SIZE];
+    number[..bytes.len()].copy_from_slice(bytes);
+    Ok(u128::from_le_bytes(number))
+}
+
+// Any non-zero byte makes an element true.
+fn is_true(bytes: &[u8]) -> bool {
+    bytes.iter().any(|byte| *byte != 0)
+}
+
+fn from_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Script {
+    bytes: Vec<u8>,
+}
+
+impl Script {
+    // Pushes are refused past what the stack takes, which also keeps their length within
+    // OP_PUSHDATA2.
+    pub fn new(instructions: &[Instruction]) -> Result<Self, ScriptError> {
+        let mut bytes = vec![];
+        for instruction in instructions {
+            match instruction {
+                Instruction::Push(data) => {
+                    if let [number @ 1..=16] = data.as_slice() {
+                        bytes.push(OP_1 - 1 + number);
+                        continue;
+                    }
+                    match data.len() {
+                        0 => bytes.push(OP_0),
+                        length @ 1..0x4c => bytes.push(length as u8),
+                        length @ 0x4c..=0xff => bytes.extend([OP_PUSHDATA1, length as u8]),
+                        length @ 0x100..=MAX_ELEMENT_SIZE => {
+                            bytes.push(OP_PUSHDATA2);
+                            bytes.extend((length as u16).to_le_bytes());
+                        }
+                        length => return Err(ScriptError::ElementTooLarge(length)),
+                    }
+                    bytes.extend(data);
+                }
+                Instruction::Number(number @ 1..=16) => bytes.push(OP_1 - 1 + number),
+                Instruction::Number(_) => return Err(ScriptError::InvalidNumber),
+                Instruction::Op(op) => bytes.push(op.to_byte()),
+            }
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn encode(&self) -> String {
+        bs58::encode(&self.bytes).into_string()
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, ScriptError> {
+        bs58::decode(encoded)
+            .into_vec()
+            .map(Self::from_bytes)
+            .map_err(|e| ScriptError::Encoding(e.to_string()))
+    }
+
+    // Outputs sent to this address are locked by the script, which their spender reveals.
+    pub fn address(&self) -> String {
+        Address::from_script(&self.bytes).to_string()
+    }
+
+    pub fn instructions(&self) -> Result<Vec<Instruction>, ScriptError> {
+        self.parse(false)
+    }
+
+    // With `minimal`, refuses pushes that a shorter encoding would have made: anyone relaying
+    // an unlocking script could otherwise re-encode its pushes, and change the txid with them.
+    fn parse(&self, minimal: bool) -> Result<Vec<Instruction>, ScriptError> {
+        if self.bytes.len() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::TooLarge(self.bytes.len()));
+        }
+
+        let mut instructions = vec![];
+        let mut rest = self.bytes.as_slice();
+        while let Some((byte, tail)) = rest.split_first() {
+            let (length, tail) = match *byte {
+                OP_0 => (0, tail),
+                length @ 1..OP_PUSHDATA1 => (length as usize, tail),
+                OP_PUSHDATA1 => match tail {
+                    [length, tail @ ..] => (*length as usize, tail),
+                    _ => return Err(ScriptError::Truncated),
+                },
+                OP_PUSHDATA2 => match tail {
+                    [low, high, tail @ ..] => (u16::from_le_bytes([*low, *high]) as usize, tail),
+                    _ => return Err(ScriptError::Truncated),
+                },
+                number @ OP_1..=OP_16 => {
+                    instructions.push(Instruction::Number(number - OP_1 + 1));
+                    rest = tail;
+                    continue;
+                }
+                byte => {
+                    let op = Opcode::from_byte(byte).ok_or(ScriptError::UnknownOpcode(byte))?;
+                    instructions.push(Instruction::Op(op));
+                    rest = tail;
+                    continue;
+                }
+            };
+            if tail.len() < length {
+                return Err(ScriptError::Truncated);
+            }
+            let (data, tail) = tail.split_at(length);
+            if minimal && !is_minimal_push(*byte, data) {
+                return Err(ScriptError::NonMinimalPush);
+            }
+            instructions.push(Instruction::Push(data.to_vec()));
+            rest = tail;
+        }
+        Ok(instructions)
+    }
+
+    // <key> OP_CHECKSIG, for an encoded public key.
+    pub fn pay_to_public_key(public_key: &str) -> Result<Self, ScriptError> {
+        Self::new(&[
+            Instruction::Push(key_bytes(public_key)?),
+            Instruction::Op(Opcode::CheckSig),
+        ])
+    }
+
+    // OP_SHA256 <hash> OP_EQUALVERIFY <key> OP_CHECKSIG: spent by the key, along with the
+    // preimage of `hash`. The preimage alone would not do: anyone seeing it in the spend could
+    // put it in a transaction of their own.
+    pub fn hashlock(hash: &[u8], public_key: &str) -> Result<Self, ScriptError> {
+        Self::new(&[
+            Instruction::Op(Opcode::Sha256),
+            Instruction::Push(hash.to_vec()),
+            Instruction::Op(Opcode::EqualVerify),
+            Instruction::Push(key_bytes(public_key)?),
+            Instruction::Op(Opcode::CheckSig),
+        ])
+    }
+
+    // <height> OP_CHECKLOCKTIMEVERIFY OP_DROP <key> OP_CHECKSIG: spent by the key, in a
+    // transaction locked until the block at `height` or later.
+    pub fn timelock(height: u128, public_key: &str) -> Result<Self, ScriptError> {
+        Self::new(&[
+            Instruction::number(height),
+            Instruction::Op(Opcode::CheckLockTimeVerify),
+            Instruction::Op(Opcode::Drop),
+            Instruction::Push(key_bytes(public_key)?),
+            Instruction::Op(Opcode::CheckSig),
+        ])
+    }
+
+    // <m> <key>... <n> OP_CHECKMULTISIG for the keys of `policy`, in its order.
+    pub fn multisig(policy: &MultisigPolicy) -> Result<Self, ScriptError> {
+        let mut instructions = vec![Instruction::number(policy.required() as u128)];
+        for key in policy.keys() {
+            instructions.push(Instruction::Push(key_bytes(key)?));
+        }
+        instructions.push(Instruction::number(policy.keys().len() as u128));
+        instructions.push(Instruction::Op(Opcode::CheckMultiSig));
+        Self::new(&instructions)
+    }
+}
+
+// Whether `opcode` is the push `Script::new` would have encoded `data` with.
+fn is_minimal_push(opcode: u8, data: &[u8]) -> bool {
+    match data {
+        [] => opcode == OP_0,
+        [1..=16] => false,
+        _ if data.len() < OP_PUSHDATA1 as usize => opcode as usize == data.len(),
+        _ if data.len() <= 0xff => opcode == OP_PUSHDATA1,
+        _ => opcode == OP_PUSHDATA2,
+    }
+}
+
+fn key_bytes(public_key: &str) -> Result<Vec<u8>, ScriptError> {
+    bs58::decode(public_key)
+        .into_vec()
+        .map_err(|e| ScriptError::Encoding(e.to_string()))
+}
+
+// Opcodes by name, data in hex.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instructions = match self.instructions() {
+            Ok(instructions) => instructions,
+            Err(_) => return write!(f, "<invalid script {}>", hex::encode(&self.bytes)),
+        };
+        let words: Vec<String> = instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(data) if data.is_empty() => String::from("OP_0"),
+                Instruction::Push(data) => hex::encode(data),
+                Instruction::Number(number) => format!("OP_{}", number),
+                Instruction::Op(op) => op.name().to_string(),
+            })
+            .collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let instructions = text
+            .split_whitespace()
+            .map(|word| {
+                if let Some((op, _, _)) = OPCODES.iter().find(|(_, _, name)| *name == word) {
+                    return Ok(Instruction::Op(*op));
+                }
+                match word.strip_prefix("OP_").map(str::parse::<u8>) {
+                    Some(Ok(0)) => Ok(Instruction::Push(vec![])),
+                    Some(Ok(number @ 1..=16)) => Ok(Instruction::Number(number)),
+                    Some(_) => Err(ScriptError::Parse(format!("unknown opcode {}", word))),
+                    None => hex::decode(word)
+                        .map(Instruction::Push)
+                        .map_err(|e| ScriptError::Parse(format!("{}: {}", word, e))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(&instructions)
+    }
+}
+
+// What the scripts of a transaction are checked against.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptContext<'a> {
+    // Signed by OP_CHECKSIG and OP_CHECKMULTISIG: the hash of the transaction.
+    pub message: &'a [u8],
+    // Lock height of the transaction, for OP_CHECKLOCKTIMEVERIFY. The transaction cannot go in
+    // a block below it, so a script accepting it is past its own timelock too.
+    pub lock_height: u128,
+}
+
+struct Machine<'a> {
+    context: ScriptContext<'a>,
+    stack: Vec<Vec<u8>>,
+    steps: usize,
+}
+
+impl Machine<'_> {
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn pop_number(&mut self) -> Result<u128, ScriptError> {
+        decode_number(&self.pop()?)
+    }
+
+    fn push(&mut self, element: Vec<u8>) -> Result<(), ScriptError> {
+        if element.len() > MAX_ELEMENT_SIZE {
+            return Err(ScriptError::ElementTooLarge(element.len()));
+        }
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+        self.stack.push(element);
+        Ok(())
+    }
+
+    fn check_signature(&self, public_key: &[u8], signature: &[u8]) -> bool {
+        // a key that does not decode cannot sign: the check fails rather than the script
+        PublicKey::decode(&bs58::encode(public_key).into_string())
+            .is_ok_and(|key| key.verify(self.context.message, signature).is_ok())
+    }
+
+    // Pops <signature>... <m> <key>... <n>, the signatures in the order of their keys.
+    fn check_multisig(&mut self) -> Result<bool, ScriptError> {
+        let count = self.pop_number()? as usize;
+        if count > MAX_MULTISIG_KEYS {
+            return Err(ScriptError::TooManyKeys(count));
+        }
+        let mut keys = (0..count)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.reverse();
+        let required = self.pop_number()? as usize;
+        if required > count {
+            return Err(ScriptError::InvalidNumber);
+        }
+        let mut signatures = (0..required)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<_>, _>>()?;
+        signatures.reverse();
+
+        let mut keys = keys.iter();
+        Ok(signatures
+            .iter()
+            .all(|signature| keys.any(|key| self.check_signature(key, signature))))
+    }
+
+    fn run(&mut self, script: &Script, push_only: bool) -> Result<(), ScriptError> {
+        // whether each enclosing OP_IF branch is taken
+        let mut branches: Vec<bool> = vec![];
+        for instruction in script.parse(push_only)? {
+            if let Instruction::Op(_) = instruction {
+                self.steps += 1;
+                if self.steps > MAX_SCRIPT_STEPS {
+                    return Err(ScriptError::TooManySteps);
+                }
+            }
+            let executing = branches.iter().all(|taken| *taken);
+
+            let op = match instruction {
+                Instruction::Push(data) if executing => {
+                    self.push(data)?;
+                    continue;
+                }
+                Instruction::Number(number) if executing => {
+                    self.push(vec![number])?;
+                    continue;
+                }
+                Instruction::Push(_) | Instruction::Number(_) => continue,
+                Instruction::Op(_) if push_only => return Err(ScriptError::NotPushOnly),
+                Instruction::Op(op) => op,
+            };
+
+            match op {
+                Opcode::If | Opcode::NotIf => {
+                    let taken = executing && (is_true(&self.pop()?) == (op == Opcode::If));
+                    branches.push(taken);
+                }
+                Opcode::Else => {
+                    let taken = branches
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *taken = !*taken;
+                }
+                Opcode::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                _ if !executing => {}
+                Opcode::Verify => {
+                    if !is_true(&self.pop()?) {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                }
+                Opcode::Return => return Err(ScriptError::Return),
+                Opcode::Drop => {
+                    self.pop()?;
+                }
+                Opcode::Dup => {
+                    let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    self.push(top.clone())?;
+                }
+                Opcode::Swap => {
+                    let top = self.pop()?;
+                    let below = self.pop()?;
+                    self.push(top)?;
+                    self.push(below)?;
+                }
+                Opcode::Size => {
+                    let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    self.push(encode_number(top.len() as u128))?;
+                }
+                Opcode::Equal | Opcode::EqualVerify => {
+                    let equal = self.pop()? == self.pop()?;
+                    if op == Opcode::EqualVerify && !equal {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                    if op == Opcode::Equal {
+                        self.push(from_bool(equal))?;
+                    }
+                }
+                Opcode::Sha256 => {
+                    let data = self.pop()?;
+                    self.push(crypto_hash::digest(crypto_hash::Algorithm::SHA256, &data))?;
+                }
+                Opcode::Hash256 => {
+                    let data = self.pop()?;
+                    let once = crypto_hash::digest(crypto_hash::Algorithm::SHA256, &data);
+                    self.push(crypto_hash::digest(crypto_hash::Algorithm::SHA256, &once))?;
+                }
+                Opcode::CheckSig | Opcode::CheckSigVerify => {
+                    let public_key = self.pop()?;
+                    let signature = self.pop()?;
+                    let valid = self.check_signature(&public_key, &signature);
+                    if op == Opcode::CheckSigVerify && !valid {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                    if op == Opcode::CheckSig {
+                        self.push(from_bool(valid))?;
+                    }
+                }
+                Opcode::CheckMultiSig | Opcode::CheckMultiSigVerify => {
+                    let valid = self.check_multisig()?;
+                    if op == Opcode::CheckMultiSigVerify && !valid {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                    if op == Opcode::CheckMultiSig {
+                        self.push(from_bool(valid))?;
+                    }
+                }
+                Opcode::CheckLockTimeVerify => {
+                    // leaves the height on the stack, for OP_DROP to remove
+                    let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let until = decode_number(top)?;
+                    let lock_height = self.context.lock_height;
+                    if lock_height < until {
+                        return Err(ScriptError::Timelocked { until, lock_height });
+                    }
+                }
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(())
+    }
+}
+
+// Runs `unlocking`, which may only push data, then `locking` on the stack it leaves. The
+// spend is valid if that ends with true on top of the stack.
+pub fn verify_script(
+    unlocking: &Script,
+    locking: &Script,
+    context: ScriptContext,
+) -> Result<(), ScriptError> {
+    let mut machine = Machine {
+        context,
+        stack: vec![],
+        steps: 0,
+    };
+    machine.run(unlocking, true)?;
+    machine.run(locking, false)?;
+    match machine.stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::Failed),
+    }
+}
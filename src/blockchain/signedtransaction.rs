@@ -1,28 +1,84 @@
+use super::crypto::*;
+use super::multisig::*;
+use super::script::*;
 use super::transaction::*;
 use super::*;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+// What unlocks an input: the encoded key the address of the output spent was made from,
+// revealed now that its coins are spent, and the signature of the transaction. Spending from a
+// multisig address, the policy and the signatures of its cosigners take their place; spending
+// from a script address, the locking script and an unlocking script.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct Unlocking {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Unlocking {
+    pub fn new(public_key: String, signature: String) -> Self {
+        Self {
+            public_key,
+            signature,
+        }
+    }
+
+    // The address the revealed key, policy or script stands for, whether or not the input
+    // really was paid to it.
+    pub fn address(&self) -> Option<String> {
+        if let Ok(key) = PublicKey::decode(&self.public_key) {
+            return Some(key.address());
+        }
+        if let Ok(policy) = MultisigPolicy::decode(&self.public_key) {
+            return Some(policy.address());
+        }
+        Script::decode(&self.public_key)
+            .ok()
+            .map(|script| script.address())
+    }
+}
+
+// `unlocking` holds one entry per input, in the same order. Coinbase transactions carry none.
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
-    pub signature: String,
+    pub unlocking: Vec<Unlocking>,
 }
 
 impl SignedTransaction {
-    pub fn new(transaction: Transaction, signature: String) -> Self {
+    pub fn new(transaction: Transaction, unlocking: Vec<Unlocking>) -> Self {
         Self {
             transaction,
-            signature,
+            unlocking,
         }
     }
+
+    pub fn unsigned(transaction: Transaction) -> Self {
+        Self::new(transaction, vec![])
+    }
+
+    // The addresses the inputs are spent from, as far as the unlocking data tells.
+    pub fn senders(&self) -> Vec<String> {
+        let mut senders: Vec<String> = vec![];
+        for address in self.unlocking.iter().filter_map(Unlocking::address) {
+            if !senders.contains(&address) {
+                senders.push(address);
+            }
+        }
+        senders
+    }
 }
 
 impl Hashable for SignedTransaction {
     fn hash(&self) -> String {
         let mut bytes = vec![];
-        bytes.extend(self.transaction.hash().bytes());
-        bytes.extend(self.signature.bytes());
+        extend_prefixed(&mut bytes, self.transaction.hash().as_bytes());
+        bytes.extend(&(self.unlocking.len() as u64).to_be_bytes());
+        for unlocking in self.unlocking.iter() {
+            extend_prefixed(&mut bytes, unlocking.public_key.as_bytes());
+            extend_prefixed(&mut bytes, unlocking.signature.as_bytes());
+        }
         crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
     }
 }
@@ -31,11 +87,13 @@ impl fmt::Display for SignedTransaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}tx_hash:{}...;sign:{}...;txout:{}...;",
+            "{}tx_hash:{}...;sign:[",
             self.transaction,
-            &self.transaction.hash()[..10],
-            &self.signature[..10],
-            &self.hash()[..10]
-        )
+            &self.transaction.hash()[..10]
+        )?;
+        for unlocking in self.unlocking.iter() {
+            write!(f, "{}...;", abbreviate(&unlocking.signature, 10))?;
+        }
+        write!(f, "];txout:{}...;", &self.hash()[..10])
     }
 }
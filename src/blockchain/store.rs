@@ -0,0 +1,355 @@
+use super::block::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Every record on disk is laid out as:
+//   magic (4 bytes) | payload length (u32 BE) | SHA256(payload) (32 bytes) | payload (JSON block)
+const RECORD_MAGIC: &[u8; 4] = b"BLK0";
+const RECORD_HEADER_SIZE: u64 = 4 + 4 + 32;
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Serialization(serde_json::Error),
+    CorruptRecord { file: u32, offset: u64 },
+    UnknownBlockFile(PathBuf),
+}
+
+impl From<io::Error> for StoreError {
+    fn from(error: io::Error) -> Self {
+        StoreError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(error: serde_json::Error) -> Self {
+        StoreError::Serialization(error)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(error) => write!(f, "block store I/O error: {}", error),
+            StoreError::Serialization(error) => write!(f, "block serialization error: {}", error),
+            StoreError::CorruptRecord { file, offset } => write!(
+                f,
+                "corrupt block record in {} at offset {}",
+                BlockStore::file_name(*file),
+                offset
+            ),
+            StoreError::UnknownBlockFile(path) => {
+                write!(f, "unexpected file in block store: {}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockLocation {
+    pub file: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// Append-only block files (blkNNNNN.dat) plus an in-memory index by height and by hash.
+// The index is rebuilt while scanning the files on open, since the chain has to be
+// read back and re-verified anyway.
+#[derive(Debug)]
+pub struct BlockStore {
+    path: PathBuf,
+    locations: Vec<BlockLocation>,
+    by_hash: HashMap<String, u128>,
+    file_sizes: Vec<u64>,
+    max_file_size: u64,
+}
+
+impl BlockStore {
+    pub fn open(path: &Path) -> Result<(Self, Vec<Block>), StoreError> {
+        Self::open_with_file_size(path, MAX_FILE_SIZE)
+    }
+
+    pub fn open_with_file_size(
+        path: &Path,
+        max_file_size: u64,
+    ) -> Result<(Self, Vec<Block>), StoreError> {
+        fs::create_dir_all(path)?;
+
+        let mut store = Self {
+            path: path.to_path_buf(),
+            locations: vec![],
+            by_hash: HashMap::new(),
+            file_sizes: vec![],
+            max_file_size,
+        };
+
+        let file_count = store.count_block_files()?;
+        let mut blocks = vec![];
+
+        for file in 0..file_count {
+            let is_last_file = file + 1 == file_count;
+            let (file_blocks, valid_size) = store.scan_file(file, is_last_file)?;
+            for (block, location) in file_blocks {
+                store.index_block(&block, location);
+                blocks.push(block);
+            }
+            store.file_sizes.push(valid_size);
+        }
+
+        Ok((store, blocks))
+    }
+
+    pub fn file_name(file: u32) -> String {
+        format!("blk{:05}.dat", file)
+    }
+
+    fn file_path(&self, file: u32) -> PathBuf {
+        self.path.join(Self::file_name(file))
+    }
+
+    fn count_block_files(&self) -> Result<u32, StoreError> {
+        let mut count = 0;
+        while self.file_path(count).exists() {
+            count += 1;
+        }
+
+        // A gap in the numbering means a block file went missing.
+        for entry in fs::read_dir(&self.path)? {
+            let entry_path = entry?.path();
+            let name = entry_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("");
+            let is_block_file = name.starts_with("blk") && name.ends_with(".dat");
+            let is_indexed = (0..count).any(|file| Self::file_name(file) == name);
+            if is_block_file && !is_indexed {
+                return Err(StoreError::UnknownBlockFile(entry_path));
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Reads every record of a block file. A truncated or corrupt record is only tolerated at
+    // the tail of the last file (an interrupted append); it gets cut off so that the next
+    // append starts from a clean offset.
+    fn scan_file(
+        &self,
+        file: u32,
+        is_last_file: bool,
+    ) -> Result<(Vec<(Block, BlockLocation)>, u64), StoreError> {
+        let path = self.file_path(file);
+        let mut bytes = vec![];
+        File::open(&path)?.read_to_end(&mut bytes)?;
+
+        let mut blocks = vec![];
+        let mut offset = 0u64;
+
+        while offset < bytes.len() as u64 {
+            match Self::decode_record(&bytes[offset as usize..]) {
+                Some((block, length)) => {
+                    blocks.push((
+                        block,
+                        BlockLocation {
+                            file,
+                            offset,
+                            length,
+                        },
+                    ));
+                    offset += length;
+                }
+                None if is_last_file && Self::is_incomplete(&bytes[offset as usize..]) => {
+                    log::warn!(
+                        "Truncating corrupt tail of {} at offset {} ({} bytes dropped)",
+                        Self::file_name(file),
+                        offset,
+                        bytes.len() as u64 - offset
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(offset)?;
+                    break;
+                }
+                None => return Err(StoreError::CorruptRecord { file, offset }),
+            }
+        }
+
+        Ok((blocks, offset))
+    }
+
+    // Whether `bytes` are the start of a record cut short by the end of the file, as left by
+    // a crash in the middle of an append. Anything else failing to decode is corruption.
+    fn is_incomplete(bytes: &[u8]) -> bool {
+        let magic = bytes.len().min(RECORD_MAGIC.len());
+        if bytes[..magic] != RECORD_MAGIC[..magic] {
+            return false;
+        }
+        if (bytes.len() as u64) < 8 {
+            return true;
+        }
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&bytes[4..8]);
+        (bytes.len() as u64) < RECORD_HEADER_SIZE + u32::from_be_bytes(length) as u64
+    }
+
+    fn decode_record(bytes: &[u8]) -> Option<(Block, u64)> {
+        if (bytes.len() as u64) < RECORD_HEADER_SIZE || &bytes[..4] != RECORD_MAGIC {
+            return None;
+        }
+
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&bytes[4..8]);
+        let payload_length = u32::from_be_bytes(length) as u64;
+        let record_length = RECORD_HEADER_SIZE + payload_length;
+
+        if (bytes.len() as u64) < record_length {
+            return None;
+        }
+
+        let checksum = &bytes[8..RECORD_HEADER_SIZE as usize];
+        let payload = &bytes[RECORD_HEADER_SIZE as usize..record_length as usize];
+        if crypto_hash::digest(crypto_hash::Algorithm::SHA256, payload) != checksum {
+            return None;
+        }
+
+        let block = serde_json::from_slice(payload).ok()?;
+        Some((block, record_length))
+    }
+
+    fn encode_record(block: &Block) -> Result<Vec<u8>, StoreError> {
+        let payload = serde_json::to_vec(block)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+        record.extend(RECORD_MAGIC);
+        record.extend(&(payload.len() as u32).to_be_bytes());
+        record.extend(crypto_hash::digest(
+            crypto_hash::Algorithm::SHA256,
+            &payload,
+        ));
+        record.extend(payload);
+        Ok(record)
+    }
+
+    fn index_block(&mut self, block: &Block, location: BlockLocation) {
+        self.by_hash.insert(block.hash(), block.index);
+        self.locations.push(location);
+    }
+
+    pub fn append(&mut self, block: &Block) -> Result<BlockLocation, StoreError> {
+        let record = Self::encode_record(block)?;
+
+        let needs_new_file = match self.file_sizes.last() {
+            None => true,
+            Some(&size) => size > 0 && size + record.len() as u64 > self.max_file_size,
+        };
+
+        if needs_new_file {
+            self.file_sizes.push(0);
+        }
+
+        let file = self.file_sizes.len() as u32 - 1;
+        let offset = self.file_sizes[file as usize];
+
+        let mut handle = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.file_path(file))?;
+        handle.seek(SeekFrom::Start(offset))?;
+        handle.write_all(&record)?;
+        handle.sync_data()?;
+
+        let location = BlockLocation {
+            file,
+            offset,
+            length: record.len() as u64,
+        };
+
+        self.file_sizes[file as usize] += location.length;
+        self.index_block(block, location);
+        Ok(location)
+    }
+
+    // Drops every block at or above `height`.
+    pub fn truncate(&mut self, height: u128) -> Result<(), StoreError> {
+        if height as usize >= self.locations.len() {
+            return Ok(());
+        }
+
+        let cut = self.locations[height as usize];
+
+        for file in (cut.file + 1..self.file_sizes.len() as u32).rev() {
+            fs::remove_file(self.file_path(file))?;
+        }
+
+        let handle = OpenOptions::new()
+            .write(true)
+            .open(self.file_path(cut.file))?;
+        handle.set_len(cut.offset)?;
+        handle.sync_data()?;
+
+        self.file_sizes.truncate(cut.file as usize + 1);
+        self.file_sizes[cut.file as usize] = cut.offset;
+        self.locations.truncate(height as usize);
+        self.by_hash.retain(|_, index| *index < height);
+        Ok(())
+    }
+
+    pub fn read_block(&self, height: u128) -> Result<Option<Block>, StoreError> {
+        let location = match self.locations.get(height as usize) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+
+        let mut handle = File::open(self.file_path(location.file))?;
+        handle.seek(SeekFrom::Start(location.offset))?;
+        let mut bytes = vec![0u8; location.length as usize];
+        handle.read_exact(&mut bytes)?;
+
+        match Self::decode_record(&bytes) {
+            Some((block, _)) => Ok(Some(block)),
+            None => Err(StoreError::CorruptRecord {
+                file: location.file,
+                offset: location.offset,
+            }),
+        }
+    }
+
+    pub fn read_block_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError> {
+        match self.height_of(hash) {
+            Some(height) => self.read_block(height),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn height_of(&self, hash: &str) -> Option<u128> {
+        self.by_hash.get(hash).copied()
+    }
+
+    pub fn location(&self, height: u128) -> Option<BlockLocation> {
+        self.locations.get(height as usize).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,93 @@
+use primitive_types::{U256, U512};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+
+// A block is valid when its hash, read as a 256-bit big endian number, is lower or equal
+// than its target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub U256);
+
+impl Target {
+    pub const MAX: Target = Target(U256::MAX);
+
+    // Equivalent of the old "N leading hex zeros" difficulty.
+    pub fn from_leading_zeros(hex_zeros: usize) -> Self {
+        if hex_zeros >= 64 {
+            return Target(U256::zero());
+        }
+        Target(U256::MAX >> (4 * hex_zeros))
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.is_empty() || hex.len() > 64 {
+            return None;
+        }
+        U256::from_str_radix(hex, 16).ok().map(Target)
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("{:064x}", self.0)
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        self.0.to_big_endian(&mut bytes);
+        bytes
+    }
+
+    pub fn is_met_by(&self, hash: &str) -> bool {
+        hash.len() == 64 && Target::from_hex(hash).is_some_and(|hash| hash <= *self)
+    }
+
+    // Expected number of hashes needed to meet the target: 2^256 / (target + 1).
+    pub fn work(&self) -> U256 {
+        if self.0 == U256::MAX {
+            return U256::one();
+        }
+        (!self.0 / (self.0 + 1)) + 1
+    }
+
+    // Scales the target by actual / expected timespan, with the ratio clamped to
+    // [1 / max_factor, max_factor] and the result never easier than `limit`.
+    pub fn retarget(&self, actual: u128, expected: u128, max_factor: u128, limit: Target) -> Self {
+        let expected = expected.max(1);
+        let max_factor = max_factor.max(1);
+        let actual = actual.clamp(
+            (expected / max_factor).max(1),
+            expected.saturating_mul(max_factor),
+        );
+
+        let scaled = self.0.full_mul(U256::from(actual)) / U512::from(expected);
+        match U256::try_from(scaled) {
+            Ok(target) if Target(target) <= limit => Target(target),
+            _ => limit,
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::MAX
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl Serialize for Target {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Target::from_hex(&hex).ok_or_else(|| D::Error::custom("invalid 256-bit target"))
+    }
+}
@@ -1,41 +1,118 @@
+use super::address::*;
 use super::block::*;
 use super::chain::*;
+use super::coinselect::*;
+use super::crypto::*;
+use super::error::*;
+use super::hd::*;
+use super::keystore::*;
+use super::ledger::*;
+use super::mempool::*;
+use super::multisig::*;
+use super::params::*;
+use super::psbt::*;
+use super::script::*;
 use super::signedtransaction::*;
+use super::store::*;
+use super::target::*;
 use super::transaction::*;
+use super::tree::*;
+use super::utxo::*;
 use super::wallet::*;
-use super::{Hashable};
+use super::Hashable;
+
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+
+// Test chains spend their coinbases right away, unless a test is about maturity.
+fn test_params(difficulty: usize) -> ChainParams {
+    ChainParams {
+        coinbase_maturity: 0,
+        ..ChainParams::with_difficulty(difficulty)
+    }
+}
+
+fn test_chain(difficulty: usize) -> BlockChain {
+    BlockChain::with_params(test_params(difficulty))
+}
+
+fn temp_chain_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("rust-blockchain-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+// Mines a block on top of `parent`, keeping its target, without validating its transactions.
+fn mine_unchecked(parent: &Block, transactions: Vec<SignedTransaction>) -> Block {
+    let mut block = Block::new(transactions);
+    block.index = parent.index + 1;
+    block.previous_block = parent.hash();
+    block.target = parent.target;
+    while !block.target.is_met_by(&block.hash()) {
+        block.nonce += 1;
+    }
+    block
+}
+
+fn coinbase_block(wallet: &Wallet, amount: u128) -> Block {
+    let tx = Transaction::coinbase(&wallet.id.id, amount);
+    Block::new(vec![wallet.sign_transaction(&tx).unwrap()])
+}
+
+// Next block of `chain`, claiming no reward and carrying `transactions` after its coinbase.
+fn block_with(chain: &BlockChain, miner: &Wallet, transactions: Vec<SignedTransaction>) -> Block {
+    let coinbase = Transaction::new(vec![], vec![]);
+    let mut block = Block::new(vec![miner.sign_transaction(&coinbase).unwrap()]);
+    block.transactions.extend(transactions);
+    block.index = chain.chain.len() as u128;
+    block
+}
+
+fn spend(inputs: Vec<OutPoint>, sender: &Wallet, outputs: &[(&Wallet, u128)]) -> SignedTransaction {
+    let outputs = outputs
+        .iter()
+        .map(|(wallet, amount)| TxOutput::new(&wallet.id.id, *amount))
+        .collect();
+    sender
+        .sign_transaction(&Transaction::new(inputs, outputs))
+        .unwrap()
+}
 
 #[test]
 fn transaction_hash() {
     let mut tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(32),
-        &String::from("1").repeat(32),
-        &String::from("2").repeat(32),
-        123,
+        vec![OutPoint::new(&String::from("0").repeat(32), 0)],
+        vec![TxOutput::new(&String::from("2").repeat(32), 123)],
     );
 
     tx1.timestamp = 1234;
 
-    let transaction_hash = "d6ce8ef0a9b673007d04627f511a37d4f513263a35cf6df57cc8741ce8bb4643";
+    let transaction_hash = "55daee80ed21b085000062b32266a09aa98980f5e7ab97020b31b1b0cb75d173";
     assert_eq!(tx1.hash(), transaction_hash);
 }
 
 #[test]
 fn signed_transaction_hash() {
     let mut tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(32),
-        &String::from("1").repeat(32),
-        &String::from("2").repeat(32),
-        123,
+        vec![OutPoint::new(&String::from("0").repeat(32), 0)],
+        vec![TxOutput::new(&String::from("2").repeat(32), 123)],
     );
 
     tx1.timestamp = 1234;
     // no need for a valid signature here
-    let signed_tx1 = SignedTransaction::new(tx1, String::from("0").repeat(64));
-    let hash = "f59f9fd7af66717f94285cf0b3a7343986f29e1cf46b15b051dd1df65a991233";
+    let signed_tx1 = SignedTransaction::new(
+        tx1,
+        vec![Unlocking::new(String::new(), String::from("0").repeat(64))],
+    );
+    let hash = "28e2f0773ab17ea3d56eea96a4f0d72bc0f659a17c5bcc3f0992c602838fa1b8";
     assert_eq!(signed_tx1.hash(), hash);
+
+    // moving characters from the signature to the key makes another transaction
+    let mut moved = signed_tx1.clone();
+    let unlocking = &mut moved.unlocking[0];
+    unlocking.public_key = unlocking.signature.split_off(1);
+    assert_ne!(moved.hash(), signed_tx1.hash());
 }
 
 /*
@@ -48,36 +125,37 @@ fn signature() {
 #[test]
 fn block_hash() {
     let mut tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(32),
-        &String::from("1").repeat(32),
-        &String::from("2").repeat(32),
-        123,
+        vec![OutPoint::new(&String::from("0").repeat(32), 0)],
+        vec![TxOutput::new(&String::from("2").repeat(32), 123)],
     );
 
     let mut tx2 = Transaction::new(
-        0,
-        &String::from("0").repeat(32),
-        &String::from("2").repeat(32),
-        &String::from("3").repeat(32),
-        123,
+        vec![OutPoint::new(&String::from("0").repeat(32), 1)],
+        vec![TxOutput::new(&String::from("3").repeat(32), 123)],
     );
 
     tx1.timestamp = 11111;
     tx2.timestamp = 22222;
 
-    let signed_tx1 = SignedTransaction::new(tx1, String::from("0").repeat(64));
-    let signed_tx2 = SignedTransaction::new(tx2, String::from("0").repeat(64));
+    let signed_tx1 = SignedTransaction::new(
+        tx1,
+        vec![Unlocking::new(String::new(), String::from("0").repeat(64))],
+    );
+    let signed_tx2 = SignedTransaction::new(
+        tx2,
+        vec![Unlocking::new(String::new(), String::from("0").repeat(64))],
+    );
 
     let block = Block {
         index: 1,
         previous_block: String::from("0").repeat(64),
         timestamp: 3,
+        target: Target::from_leading_zeros(2),
         transactions: vec![signed_tx1, signed_tx2],
         nonce: 5,
     };
 
-    let block_hash = "249679bb6b4e1fe31add1e4d7e196a8676000911b0089582008e5aaa0a3f4e9c";
+    let block_hash = "622c27413c483e48f11f4d3f7af44c96fb9e5e5edc51d8681ab6d08392aef0cb";
     assert_eq!(block.hash(), block_hash);
 }
 
@@ -85,43 +163,73 @@ fn block_hash() {
 fn double_spend() {
     let mut wallet1 = Wallet::new();
 
-    let tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(64),
-        &wallet1.id.id,
-        &wallet1.id.id,
-        20,
-    );
+    let tx1 = Transaction::coinbase(&wallet1.id.id, 20);
 
     let wallet1_id = wallet1.id.clone();
 
-    let tx1_signed = wallet1.sign_transaction(&tx1);
+    let tx1_signed = wallet1.sign_transaction(&tx1).unwrap();
 
-    let mut chain = BlockChain::new(2);
+    let mut chain = test_chain(2);
     let genesis_block = Block::new(vec![tx1_signed]);
-    chain.mine_block(genesis_block);
+    chain.mine_block(genesis_block).unwrap();
 
     wallet1.read_wallet(&chain);
-    let transactions = wallet1.create_transaction(&wallet1_id, tx1.amount).unwrap();
+    let transaction = wallet1.create_transaction(&wallet1_id, 20).unwrap();
 
-    // clone txs to reuse INTX
-    let reused_transactions = transactions.clone();
-    let signed_transactions = wallet1.sign_transactions(transactions);
-    let new_block = Block::new(signed_transactions);
-
-    assert_eq!(
-        chain.mine_block(new_block),
-        BlockChainOperationResult::BlockChainOk
+    // clone tx to reuse its inputs
+    let reused_transaction = transaction.clone();
+    let new_block = block_with(
+        &chain,
+        &wallet1,
+        vec![wallet1.sign_transaction(&transaction).unwrap()],
     );
 
-    let bogus_block = Block::new(wallet1.sign_transactions(reused_transactions));
+    chain.mine_block(new_block).unwrap();
+
+    let bogus_block = block_with(
+        &chain,
+        &wallet1,
+        vec![wallet1.sign_transaction(&reused_transaction).unwrap()],
+    );
 
-    // leading to a DoubleSpend error
+    // its input is no longer unspent
     println!("################################################################################################################################");
     println!("{}", chain);
     println!("################################################################################################################################");
     let err = chain.mine_block(bogus_block);
-    assert_eq!(err, BlockChainOperationResult::DoubleSpendingError);
+    assert!(matches!(
+        err,
+        Err(ChainError::MissingInput { block: 2, .. })
+    ));
+
+    // spending the same output twice within a block, or within a transaction, is no better
+    let input = OutPoint::new(&chain.chain[1].transactions[1].hash(), 0);
+    let bogus_block = block_with(
+        &chain,
+        &wallet1,
+        vec![
+            spend(vec![input.clone()], &wallet1, &[(&wallet1, 10)]),
+            spend(vec![input.clone()], &wallet1, &[(&wallet1, 10)]),
+        ],
+    );
+    assert!(matches!(
+        chain.validate_block_transactions(&bogus_block),
+        Err(ChainError::DoubleSpending { .. })
+    ));
+
+    let bogus_block = block_with(
+        &chain,
+        &wallet1,
+        vec![spend(
+            vec![input.clone(), input],
+            &wallet1,
+            &[(&wallet1, 40)],
+        )],
+    );
+    assert!(matches!(
+        chain.validate_block_transactions(&bogus_block),
+        Err(ChainError::DoubleSpending { .. })
+    ));
 }
 
 #[test]
@@ -129,105 +237,2625 @@ fn spend_somebody_elses_uxtos() {
     let wallet1 = Wallet::new();
     let wallet2 = Wallet::new();
 
-    let tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(64),
-        &wallet1.id.id,
-        &wallet1.id.id,
-        20,
-    );
-
-    let tx2 = Transaction::new(
-        0,
-        &String::from("0").repeat(64),
-        &wallet2.id.id,
-        &wallet2.id.id,
-        20,
-    );
-
-    let tx1_signed = wallet1.sign_transaction(&tx1);
-    let tx2_signed = wallet2.sign_transaction(&tx2);
-    let tx2_uxto = tx2_signed.hash();
-    let mut chain = BlockChain::new(2);
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    chain.mine_block(coinbase_block(&wallet2, 20)).unwrap();
 
-    let genesis_block = Block::new(vec![tx1_signed, tx2_signed]);
-    chain.mine_block(genesis_block);
+    let tx1_uxto = OutPoint::new(&chain.chain[0].transactions[0].hash(), 0);
+    let tx2_uxto = OutPoint::new(&chain.chain[1].transactions[0].hash(), 0);
 
     //steal uxto from wallet2
-    let bogus_tx = Transaction::new(0, &tx2_uxto, &wallet1.id.id, &wallet2.id.id, 20);
+    let bogus_tx = spend(vec![tx2_uxto.clone()], &wallet1, &[(&wallet2, 20)]);
+    let bogus_block = block_with(&chain, &wallet1, vec![bogus_tx]);
+    assert!(matches!(
+        chain.validate_block_transactions(&bogus_block),
+        Err(ChainError::Signature {
+            reason: SignatureError::KeyMismatch,
+            ..
+        })
+    ));
 
-    let bogus_block = Block::new(wallet1.sign_transactions(vec![bogus_tx]));
-    assert_eq!(
+    // even when mixed with an input of our own
+    let bogus_tx = spend(
+        vec![tx1_uxto.clone(), tx2_uxto.clone()],
+        &wallet1,
+        &[(&wallet1, 40)],
+    );
+    let bogus_block = block_with(&chain, &wallet1, vec![bogus_tx.clone()]);
+    assert!(matches!(
         chain.validate_block_transactions(&bogus_block),
-        BlockChainOperationResult::InTxOwnershipError
+        Err(ChainError::Signature {
+            reason: SignatureError::KeyMismatch,
+            ..
+        })
+    ));
+
+    // each input is unlocked by the key of its own output, so both owners can spend together
+    let mut joint_tx = bogus_tx;
+    joint_tx.unlocking[1] =
+        sign_transaction_with(&joint_tx.transaction, wallet2.keys().unwrap()).unlocking[1].clone();
+    assert_eq!(
+        joint_tx.senders(),
+        vec![wallet1.id.id.clone(), wallet2.id.id.clone()]
     );
+    chain
+        .mine_block(block_with(&chain, &wallet1, vec![joint_tx]))
+        .unwrap();
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 40);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 0);
 }
 
 #[test]
 fn single_transaction_bigger_than_its_input() {
     let wallet1 = Wallet::new();
     let founds = 20;
-    let tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(64),
-        &wallet1.id.id,
-        &wallet1.id.id,
-        founds,
-    );
+    let tx1 = Transaction::coinbase(&wallet1.id.id, founds);
 
-    let tx1_signed = wallet1.sign_transaction(&tx1);
-    let tx1_uxto = tx1_signed.hash();
+    let tx1_signed = wallet1.sign_transaction(&tx1).unwrap();
+    let tx1_uxto = OutPoint::new(&tx1_signed.hash(), 0);
 
-    let mut chain = BlockChain::new(2);
+    let mut chain = test_chain(2);
     let genesis_block = Block::new(vec![tx1_signed]);
-    chain.mine_block(genesis_block);
+    chain.mine_block(genesis_block).unwrap();
 
     // transfer to itself twice as the amount avaiable in the InTX
-    let bogus_tx = Transaction::new(0, &tx1_uxto, &wallet1.id.id, &wallet1.id.id, founds * 2);
+    let bogus_tx = spend(vec![tx1_uxto], &wallet1, &[(&wallet1, founds * 2)]);
 
-    let bogus_block = Block::new(wallet1.sign_transactions(vec![bogus_tx]));
-    assert_eq!(
+    let bogus_block = block_with(&chain, &wallet1, vec![bogus_tx]);
+    assert!(matches!(
         chain.validate_block_transactions(&bogus_block),
-        BlockChainOperationResult::InTxTooSmallForTransaction
-    );
+        Err(ChainError::InTxTooSmallForTransaction { .. })
+    ));
 }
 
 #[test]
-fn transaction_set_bigger_than_its_input() {
+fn transaction_outputs_bigger_than_its_inputs() {
     let wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
     let founds = 20;
-    let tx1 = Transaction::new(
-        0,
-        &String::from("0").repeat(64),
-        &wallet1.id.id,
-        &wallet1.id.id,
-        founds,
+
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase_block(&wallet1, founds)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, founds)).unwrap();
+    let inputs = [
+        OutPoint::new(&chain.chain[0].transactions[0].hash(), 0),
+        OutPoint::new(&chain.chain[1].transactions[0].hash(), 0),
+    ];
+
+    // all that's available across both inputs, split in two outputs
+    let tx = spend(inputs.to_vec(), &wallet1, &[(&wallet2, 30), (&wallet1, 10)]);
+    assert!(chain
+        .validate_block_transactions(&block_with(&chain, &wallet1, vec![tx]))
+        .is_ok());
+
+    // an aditional coin
+    let bogus_tx = spend(inputs.to_vec(), &wallet1, &[(&wallet2, 30), (&wallet1, 11)]);
+    assert!(matches!(
+        chain.validate_block_transactions(&block_with(&chain, &wallet1, vec![bogus_tx])),
+        Err(ChainError::InTxTooSmallForTransaction {
+            available: 40,
+            requested: 41,
+            ..
+        })
+    ));
+
+    // outputs overflowing the amount type
+    let bogus_tx = spend(
+        inputs.to_vec(),
+        &wallet1,
+        &[(&wallet2, u128::MAX), (&wallet1, 2)],
     );
+    assert!(matches!(
+        chain.validate_block_transactions(&block_with(&chain, &wallet1, vec![bogus_tx])),
+        Err(ChainError::InTxTooSmallForTransaction { .. })
+    ));
+}
 
-    let tx1_signed = wallet1.sign_transaction(&tx1);
-    let tx1_uxto = tx1_signed.hash();
+#[test]
+fn store_reopen_chain() {
+    let path = temp_chain_path("reopen");
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
 
-    let mut chain = BlockChain::new(2);
-    let genesis_block = Block::new(vec![tx1_signed]);
-    chain.mine_block(genesis_block);
+    let hashes = {
+        let mut chain = BlockChain::create(&path, test_params(2)).unwrap();
+        chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+        wallet1.read_wallet(&chain);
+        let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+        chain
+            .mine_block(block_with(
+                &chain,
+                &wallet1,
+                vec![wallet1.sign_transaction(&transaction).unwrap()],
+            ))
+            .unwrap();
+        chain
+            .chain
+            .iter()
+            .map(|block| block.hash())
+            .collect::<Vec<String>>()
+    };
+
+    let chain = BlockChain::open(&path).unwrap();
+    assert_eq!(chain.chain.len(), 2);
+    assert!(chain.check_chain().is_ok());
+
+    let store = chain.store().unwrap();
+    for (height, hash) in hashes.iter().enumerate() {
+        assert_eq!(&chain.chain[height].hash(), hash);
+        assert_eq!(store.height_of(hash), Some(height as u128));
+        assert_eq!(
+            &store.read_block_by_hash(hash).unwrap().unwrap().hash(),
+            hash
+        );
+    }
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn store_truncated_tail_record() {
+    let path = temp_chain_path("truncated");
+    let wallet1 = Wallet::new();
 
-    // transfer to itself all that's available
-    let bogus_tx1 = Transaction::new(0, &tx1_uxto, &wallet1.id.id, &wallet1.id.id, founds);
+    let mut chain = BlockChain::create(&path, test_params(2)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    let location = chain.store().unwrap().location(1).unwrap();
+    drop(chain);
 
-    // transfer to itself an aditional coin
-    let bogus_tx2 = Transaction::new(0, &tx1_uxto, &wallet1.id.id, &wallet1.id.id, 1);
+    // simulate a crash in the middle of the last append
+    let block_file = path.join("blk00000.dat");
+    OpenOptions::new()
+        .write(true)
+        .open(&block_file)
+        .unwrap()
+        .set_len(location.offset + location.length - 5)
+        .unwrap();
 
-    let bogus_block = Block::new(wallet1.sign_transactions(vec![bogus_tx1, bogus_tx2]));
+    let mut chain = BlockChain::open(&path).unwrap();
+    assert_eq!(chain.chain.len(), 1);
+    assert_eq!(fs::metadata(&block_file).unwrap().len(), location.offset);
+
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    drop(chain);
+
+    let chain = BlockChain::open(&path).unwrap();
+    assert_eq!(chain.chain.len(), 2);
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn store_corrupt_tail_record() {
+    let path = temp_chain_path("corrupt");
+    let wallet1 = Wallet::new();
+
+    let mut chain = BlockChain::create(&path, test_params(2)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    let location = chain.store().unwrap().location(1).unwrap();
+    drop(chain);
+
+    // a complete record whose payload no longer matches its checksum is not a torn write
+    let block_file = path.join("blk00000.dat");
+    let mut bytes = fs::read(&block_file).unwrap();
+    let last = (location.offset + location.length - 1) as usize;
+    bytes[last] ^= 0xff;
+    fs::write(&block_file, &bytes).unwrap();
+
+    assert!(matches!(
+        BlockChain::open(&path),
+        Err(ChainError::Storage(StoreError::CorruptRecord { file: 0, offset })) if offset == location.offset
+    ));
+    assert_eq!(fs::metadata(&block_file).unwrap().len(), bytes.len() as u64);
+
+    assert!(matches!(
+        BlockChain::create(&path, test_params(3)),
+        Err(ChainError::AlreadyExists(_))
+    ));
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn store_consensus_write_through() {
+    let path = temp_chain_path("consensus");
+    let wallet1 = Wallet::new();
+
+    let mut chain = BlockChain::create(&path, test_params(2)).unwrap();
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    let abandoned = chain.get_last_hash().unwrap();
+
+    let mut longer = test_chain(2);
+    longer.mine_block(coinbase_block(&wallet1, 10)).unwrap();
+    longer.mine_block(coinbase_block(&wallet1, 10)).unwrap();
+    let tip = longer.get_last_hash();
+
+    assert!(matches!(
+        chain.consensus(longer).unwrap(),
+        ConsensusOutcome::Updated(_)
+    ));
+    let side = mine_unchecked(&chain.chain[0], coinbase_block(&wallet1, 5).transactions);
     assert_eq!(
-        chain.validate_block_transactions(&bogus_block),
-        BlockChainOperationResult::InTxTooSmallForTransactionSet
+        chain.accept_block(side.clone()).unwrap(),
+        BlockAcceptance::SideBranch
+    );
+    drop(chain);
+
+    // the side branches are kept: the disconnected block and the one that never made it
+    let mut chain = BlockChain::open(&path).unwrap();
+    assert_eq!(chain.chain.len(), 2);
+    assert_eq!(chain.get_last_hash(), tip);
+    assert!(chain.tree().contains(&abandoned));
+    assert!(chain.tree().contains(&side.hash()));
+
+    // and can still overtake the active chain
+    let parent = chain.tree().get(&side.hash()).unwrap().block.clone();
+    let child = mine_unchecked(&parent, coinbase_block(&wallet1, 5).transactions);
+    let grandchild = mine_unchecked(&child, coinbase_block(&wallet1, 5).transactions);
+    chain.accept_block(child).unwrap();
+    chain.accept_block(grandchild.clone()).unwrap();
+    assert_eq!(chain.get_last_hash(), Some(grandchild.hash()));
+    drop(chain);
+
+    let chain = BlockChain::open(&path).unwrap();
+    assert_eq!(chain.get_last_hash(), Some(grandchild.hash()));
+    assert!(chain.tree().contains(&tip.unwrap()));
+
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn duplicate_transactions_are_rejected() {
+    let wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+
+    // the same coinbase again would overwrite the unspent output of the first block
+    let copied = chain.chain[0].transactions[0].clone();
+    let mut block = block_with(&chain, &wallet2, vec![]);
+    block.transactions[0] = copied.clone();
+    assert!(matches!(
+        chain.validate_block_transactions(&block),
+        Err(ChainError::DuplicateTransaction { block: 1, txid }) if txid == copied.hash()
+    ));
+    assert!(chain.mine_block(block).is_err());
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 20);
+}
+
+#[test]
+fn utxo_set_tracks_spends() {
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let coinbase = coinbase_block(&wallet1, 20);
+    let coinbase_txid = OutPoint::new(&coinbase.transactions[0].hash(), 0);
+
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase).unwrap();
+    assert!(chain.utxos().contains(&coinbase_txid));
+
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    chain
+        .mine_block(block_with(
+            &chain,
+            &wallet1,
+            vec![wallet1.sign_transaction(&transaction).unwrap()],
+        ))
+        .unwrap();
+
+    assert!(!chain.utxos().contains(&coinbase_txid));
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 13);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 7);
+}
+
+#[test]
+fn utxo_set_rolled_back_on_reorg() {
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let genesis = coinbase_block(&wallet1, 20);
+    let coinbase_txid = OutPoint::new(&genesis.transactions[0].hash(), 0);
+
+    let mut chain = test_chain(2);
+    chain.mine_block(genesis.clone()).unwrap();
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    let transactions = vec![wallet1.sign_transaction(&transaction).unwrap()];
+    chain
+        .mine_block(block_with(&chain, &wallet1, transactions.clone()))
+        .unwrap();
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 7);
+    // the coinbase of the miner pays nothing, the transfer spends from wallet1
+    let at = |height, index| TxPosition { height, index };
+    assert_eq!(chain.utxos().history(&wallet1.id.id), [at(0, 0), at(1, 1)]);
+    assert_eq!(chain.utxos().history(&wallet2.id.id), [at(1, 1)]);
+
+    let mut longer = test_chain(2);
+    longer.mine_block(genesis).unwrap();
+    longer.mine_block(coinbase_block(&wallet2, 1)).unwrap();
+    longer.mine_block(coinbase_block(&wallet2, 1)).unwrap();
+
+    match chain.consensus(longer).unwrap() {
+        ConsensusOutcome::Updated(report) => {
+            assert_eq!(report.fork_height, 1);
+            assert_eq!(report.depth, 1);
+            assert_eq!(report.connected, 2);
+            assert_eq!(report.orphaned_transactions, transactions);
+        }
+        ConsensusOutcome::Kept => panic!("heavier chain was not adopted"),
+    }
+
+    assert!(chain.utxos().contains(&coinbase_txid));
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 20);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 2);
+    assert_eq!(chain.utxos().history(&wallet1.id.id), [at(0, 0)]);
+    assert_eq!(chain.utxos().history(&wallet2.id.id), [at(1, 0), at(2, 0)]);
+    assert!(chain.check_chain().is_ok());
+}
+
+#[test]
+fn chain_error_context() {
+    let wallet1 = Wallet::new();
+    let genesis_block = coinbase_block(&wallet1, 20);
+    let coinbase_txid = genesis_block.transactions[0].hash();
+
+    let mut chain = test_chain(2);
+    chain.mine_block(genesis_block).unwrap();
+
+    let missing = OutPoint::new(&coinbase_txid, 5);
+    let bogus_signed = spend(vec![missing.clone()], &wallet1, &[(&wallet1, 20)]);
+    let bogus_txid = bogus_signed.hash();
+
+    let err = chain
+        .mine_block(block_with(&chain, &wallet1, vec![bogus_signed]))
+        .unwrap_err();
+    match &err {
+        ChainError::MissingInput { block, txid, input } => {
+            assert_eq!(*block, 1);
+            assert_eq!(*txid, bogus_txid);
+            assert_eq!(*input, missing);
+        }
+        other => panic!("unexpected error: {}", other),
+    }
+    assert!(err.to_string().contains(&bogus_txid));
+    assert!(err.to_string().contains(&missing.to_string()));
+
+    // a longer but broken chain is reported instead of silently kept
+    let mut broken = test_chain(2);
+    broken.mine_block(coinbase_block(&wallet1, 1)).unwrap();
+    let bogus_tx = spend(
+        vec![OutPoint::new(&"f".repeat(64), 0)],
+        &wallet1,
+        &[(&wallet1, 1)],
+    );
+    let bogus_block = mine_unchecked(
+        &broken.chain[0],
+        block_with(&broken, &wallet1, vec![bogus_tx]).transactions,
+    );
+    broken.chain.push(bogus_block);
+    assert!(matches!(
+        chain.consensus(broken),
+        Err(ChainError::MissingInput { block: 1, .. })
+    ));
+    assert_eq!(chain.chain.len(), 1);
+}
+
+#[test]
+fn hostile_transactions_never_panic() {
+    // exercise the Display impls used by the validator logs as well
+    let _ = env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .try_init();
+
+    let wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let genesis_block = coinbase_block(&wallet1, 20);
+    let coinbase_txid = genesis_block.transactions[0].hash();
+    let mut chain = test_chain(2);
+    chain.mine_block(genesis_block).unwrap();
+
+    let valid_tx = Transaction::new(
+        vec![OutPoint::new(&coinbase_txid, 0)],
+        vec![TxOutput::new(&wallet2.id.id, 5)],
+    );
+    let owners = [wallet1.id.id.as_str()];
+    let valid_signature = wallet1.sign_transaction(&valid_tx).unwrap().unlocking[0]
+        .signature
+        .clone();
+    let foreign_signature =
+        bs58::encode(wallet2.keys().unwrap().sign(valid_tx.hash().as_bytes())).into_string();
+    let valid_key = wallet1.keys().unwrap().encoded_public_key();
+    let foreign_key = wallet2.keys().unwrap().encoded_public_key();
+
+    let public_keys = [
+        String::new(),
+        String::from("0OIl"),
+        String::from("ñ€"),
+        bs58::encode(vec![0x30u8; 64]).into_string(),
+        bs58::encode(vec![0xffu8; 4096]).into_string(),
+        valid_key[..valid_key.len() / 2].to_string(),
+        foreign_key.clone(),
+        valid_key.clone(),
+    ];
+
+    let signatures = [
+        String::new(),
+        String::from("x"),
+        String::from("0OIl"),
+        String::from("ñ€"),
+        bs58::encode(vec![0u8; 128]).into_string(),
+        bs58::encode(vec![0xffu8; 4096]).into_string(),
+        valid_signature[..valid_signature.len() / 2].to_string(),
+        foreign_signature.clone(),
+        valid_signature.clone(),
+    ];
+
+    for public_key in public_keys.iter() {
+        for signature in signatures.iter() {
+            let signed_tx = SignedTransaction::new(
+                valid_tx.clone(),
+                vec![Unlocking::new(public_key.clone(), signature.clone())],
+            );
+            println!("{}", signed_tx);
+
+            let is_genuine = *public_key == valid_key && *signature == valid_signature;
+            let result = BlockChain::validate_transaction_signature(&signed_tx, &owners);
+            assert_eq!(result.is_ok(), is_genuine, "{:?}", result);
+
+            let block = block_with(&chain, &wallet1, vec![signed_tx]);
+            println!("{}", block);
+            let result = chain.validate_block_transactions(&block);
+            if is_genuine {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(ChainError::Signature { .. })));
+            }
+        }
+    }
+
+    let with_key = |public_key: &str, signature: &str| {
+        BlockChain::validate_transaction_signature(
+            &SignedTransaction::new(
+                valid_tx.clone(),
+                vec![Unlocking::new(
+                    public_key.to_string(),
+                    signature.to_string(),
+                )],
+            ),
+            &owners,
+        )
+    };
+
+    // every input needs its own unlocking data
+    let unlocking = Unlocking::new(valid_key.clone(), valid_signature.clone());
+    for count in [0, 2] {
+        let signed_tx = SignedTransaction::new(valid_tx.clone(), vec![unlocking.clone(); count]);
+        assert!(matches!(
+            BlockChain::validate_transaction_signature(&signed_tx, &owners),
+            Err(SignatureError::UnlockingCount { inputs: 1, found }) if found == count
+        ));
+    }
+
+    assert!(matches!(
+        with_key(&valid_key, "0OIl"),
+        Err(SignatureError::SignatureEncoding(_))
+    ));
+    assert!(matches!(
+        with_key("0OIl", &valid_signature),
+        Err(SignatureError::KeyEncoding(_))
+    ));
+    assert!(matches!(
+        with_key(&public_keys[3], &valid_signature),
+        Err(SignatureError::UnsupportedVersion(0x30))
+    ));
+    assert!(matches!(
+        with_key(
+            &bs58::encode([1u8, 9, 1, 2, 3]).into_string(),
+            &valid_signature
+        ),
+        Err(SignatureError::UnknownKeyType(9))
+    ));
+    assert!(matches!(
+        with_key(
+            &encode_public_key(KeyType::Ed25519, &[1, 2, 3]),
+            &valid_signature
+        ),
+        Err(SignatureError::InvalidPublicKey(_))
+    ));
+    assert!(matches!(
+        with_key(
+            &encode_public_key(KeyType::Secp256k1, &[5; 33]),
+            &valid_signature
+        ),
+        Err(SignatureError::InvalidPublicKey(_))
+    ));
+    assert!(matches!(
+        with_key(&valid_key, &bs58::encode([1u8; 3]).into_string()),
+        Err(SignatureError::Malformed(_))
+    ));
+    // a key signing for an address it does not hash to
+    assert!(matches!(
+        with_key(&foreign_key, &foreign_signature),
+        Err(SignatureError::KeyMismatch)
+    ));
+    assert!(matches!(
+        with_key(&valid_key, &foreign_signature),
+        Err(SignatureError::Mismatch)
+    ));
+
+    // malformed inputs are rejected before the signature is even looked at
+    let mut bogus_inputs = vec![];
+    for (txid, vout) in [("", 0), ("ñ", 0), (coinbase_txid.as_str(), u32::MAX)] {
+        bogus_inputs.push(spend(
+            vec![OutPoint::new(txid, vout)],
+            &wallet1,
+            &[(&wallet2, 5)],
+        ));
+    }
+    for signed_tx in bogus_inputs {
+        let block = block_with(&chain, &wallet1, vec![signed_tx]);
+        println!("{}", block);
+        assert!(chain.validate_block_transactions(&block).is_err());
+    }
+
+    println!("{}", Block::new(vec![]));
+}
+
+#[test]
+fn addresses_are_checksummed_key_hashes() {
+    let wallet = Wallet::with_key_type(KeyType::Secp256k1);
+    let keys = wallet.keys().unwrap();
+    let address: Address = wallet.id.id.parse().unwrap();
+    assert_eq!(address.version, ADDRESS_VERSION);
+    assert_eq!(address.to_string(), wallet.id.id);
+    assert!(wallet.id.id.starts_with('B'));
+    assert_eq!(
+        address,
+        Address::from_public_key(KeyType::Secp256k1, &keys.public_key())
+    );
+    assert_ne!(
+        address,
+        Address::from_public_key(KeyType::Ed25519, &keys.public_key())
+    );
+
+    // any single mistyped character is caught
+    for position in 1..wallet.id.id.len() {
+        let mut typo = wallet.id.id.clone().into_bytes();
+        typo[position] = if typo[position] == b'z' { b'y' } else { b'z' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert_eq!(typo.parse::<Address>(), Err(AddressError::Checksum));
+    }
+
+    let other_version = Address {
+        version: 0,
+        ..address
+    };
+    assert_eq!(
+        other_version.to_string().parse::<Address>(),
+        Err(AddressError::UnknownVersion(0))
+    );
+    assert!(matches!(
+        wallet.id.id[..20].parse::<Address>(),
+        Err(AddressError::Length(_))
+    ));
+    assert!(matches!(
+        "0OIl".parse::<Address>(),
+        Err(AddressError::Encoding(_))
+    ));
+    // addresses do not reveal the key, so they cannot stand for one
+    assert!(PublicKey::decode(&wallet.id.id).is_err());
+    // nor does the same key encoded uncompressed
+    let uncompressed = k256::ecdsa::VerifyingKey::from_sec1_bytes(&keys.public_key())
+        .unwrap()
+        .to_encoded_point(false);
+    assert!(matches!(
+        PublicKey::decode(&encode_public_key(
+            KeyType::Secp256k1,
+            uncompressed.as_bytes()
+        )),
+        Err(SignatureError::InvalidPublicKey(_))
+    ));
+}
+
+#[test]
+fn deterministic_signatures() {
+    for key_type in [KeyType::Ed25519, KeyType::Secp256k1] {
+        let wallet = Wallet::with_key_type(key_type);
+        let tx = Transaction::new(
+            vec![OutPoint::new(&"0".repeat(64), 0)],
+            vec![TxOutput::new(&wallet.id.id, 20)],
+        );
+
+        let signed_tx = wallet.sign_transaction(&tx).unwrap();
+        assert_eq!(signed_tx, wallet.sign_transaction(&tx).unwrap());
+        assert!(BlockChain::validate_transaction_signature(&signed_tx, &[&wallet.id.id]).is_ok());
+
+        let public_key = PublicKey::decode(&signed_tx.unlocking[0].public_key).unwrap();
+        assert_eq!(public_key.address(), wallet.id.id);
+        let signature = wallet.keys().unwrap().sign(b"message");
+        assert!(public_key.verify(b"message", &signature).is_ok());
+        assert!(matches!(
+            public_key.verify(b"massage", &signature),
+            Err(SignatureError::Mismatch)
+        ));
+    }
+}
+
+#[test]
+fn wallets_are_kept_in_encrypted_keystores() {
+    let path = temp_chain_path("keystore").join("wallet.json");
+    let mut wallet = Wallet::with_key_type(KeyType::Secp256k1);
+    assert!(matches!(wallet.lock(), Err(KeystoreError::NotEncrypted)));
+    assert!(matches!(
+        wallet.save(&path),
+        Err(KeystoreError::NotEncrypted)
+    ));
+    wallet.encrypt("correct horse").unwrap();
+    wallet.save(&path).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    assert!(!fs::read_to_string(&path)
+        .unwrap()
+        .contains(&hex::encode(wallet.keys().unwrap().secret())));
+
+    // a loaded wallet knows its address, but cannot sign before being unlocked
+    let mut loaded = Wallet::load(&path).unwrap();
+    assert!(loaded.is_locked());
+    assert_eq!(loaded.id.id, wallet.id.id);
+    let tx = Transaction::coinbase(&loaded.id.id, 20);
+    assert!(matches!(
+        loaded.sign_transaction(&tx),
+        Err(WalletOperationResult::Locked)
+    ));
+    assert!(matches!(
+        loaded.unlock("battery staple"),
+        Err(KeystoreError::WrongPassphrase)
+    ));
+    loaded.unlock("correct horse").unwrap();
+    assert_eq!(
+        loaded.sign_transaction(&tx).unwrap(),
+        wallet.sign_transaction(&tx).unwrap()
+    );
+    loaded.lock().unwrap();
+    assert!(loaded.sign_transaction(&tx).is_err());
+
+    // the address in the clear is authenticated along with the key
+    let keys = KeyPair::generate(KeyType::Ed25519);
+    let kdf = KdfParams::with_cost(10, 8, 1).unwrap();
+    let mut keystore = Keystore::encrypt_with(&keys, "pass", kdf).unwrap();
+    assert_eq!(keystore.decrypt("pass").unwrap().address(), keys.address());
+    keystore.address = Wallet::new().id.id;
+    assert!(matches!(
+        keystore.decrypt("pass"),
+        Err(KeystoreError::WrongPassphrase)
+    ));
+
+    // a crafted keystore cannot make the unlock take forever
+    for (log_n, r, p) in [(63, 8, 1), (10, 1 << 20, 1), (10, 8, 1 << 20), (10, 8, 0)] {
+        keystore.kdf = KdfParams::with_cost(log_n, r, p).unwrap();
+        assert!(matches!(
+            keystore.decrypt("pass"),
+            Err(KeystoreError::KdfCost { .. })
+        ));
+    }
+}
+
+#[test]
+fn hd_keys_follow_the_test_vectors() {
+    // BIP32 test vector 1, and SLIP-0010 test vector 1 for ed25519
+    let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+    let master = ExtendedKey::master(KeyType::Secp256k1, &seed);
+    assert_eq!(
+        hex::encode(master.secret()),
+        "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+    );
+    assert_eq!(
+        hex::encode(master.chain_code()),
+        "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+    );
+    let child = master.derive(&"m/0'/1".parse().unwrap()).unwrap();
+    assert_eq!(
+        hex::encode(child.secret()),
+        "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+    );
+    assert_eq!(
+        hex::encode(child.chain_code()),
+        "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19"
+    );
+
+    let master = ExtendedKey::master(KeyType::Ed25519, &seed);
+    assert_eq!(
+        hex::encode(master.secret()),
+        "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+    );
+    let child = master.derive_child(HARDENED).unwrap();
+    assert_eq!(
+        hex::encode(child.secret()),
+        "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+    );
+    assert_eq!(
+        hex::encode(child.chain_code()),
+        "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69"
+    );
+    assert!(matches!(
+        master.derive_child(1),
+        Err(HdError::NonHardenedEd25519(1))
+    ));
+
+    let path: DerivationPath = "m/44'/1'/0'/0".parse().unwrap();
+    assert_eq!(path, DerivationPath::default_for(KeyType::Secp256k1));
+    assert_eq!(path.child(7).to_string(), "m/44'/1'/0'/0/7");
+    assert_eq!(path.internal().unwrap().to_string(), "m/44'/1'/0'/1");
+    assert_eq!(
+        DerivationPath::default_for(KeyType::Ed25519)
+            .internal()
+            .unwrap()
+            .to_string(),
+        "m/44'/1'/0'/1'"
+    );
+    assert_eq!(DerivationPath::default().internal(), None);
+    for invalid in ["44'/0", "m/x", "m/2147483648", "m//1"] {
+        assert!(invalid.parse::<DerivationPath>().is_err(), "{}", invalid);
+    }
+
+    // BIP39: the all-zero entropy phrase, under the reference vectors' passphrase
+    let phrase = "abandon abandon abandon abandon abandon abandon \
+                  abandon abandon abandon abandon abandon about";
+    assert_eq!(
+        hex::encode(mnemonic_to_seed(phrase, "TREZOR").unwrap()),
+        "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+    );
+    let bad_checksum = phrase.replace("about", "abandon");
+    assert!(matches!(
+        mnemonic_to_seed(&bad_checksum, ""),
+        Err(HdError::InvalidMnemonic(_))
+    ));
+
+    let generated = generate_mnemonic(24).unwrap();
+    assert_eq!(generated.split(' ').count(), 24);
+    assert!(mnemonic_to_seed(&generated, "").is_ok());
+    assert!(generate_mnemonic(13).is_err());
+}
+
+#[test]
+fn hd_wallets_discover_their_addresses() {
+    let phrase = "abandon abandon abandon abandon abandon abandon \
+                  abandon abandon abandon abandon abandon about";
+    let mut wallet = Wallet::from_mnemonic(KeyType::Secp256k1, phrase, "").unwrap();
+    assert!(wallet.is_hd());
+    assert_eq!(wallet.addresses().len(), GAP_LIMIT);
+    assert_eq!(wallet.id.id, wallet.addresses()[0]);
+
+    let mut probe = wallet.clone();
+    while probe.addresses().len() < 30 {
+        probe.new_address().unwrap();
+    }
+    let near = wallet.addresses()[3].clone();
+    let far = probe.addresses()[22].clone();
+
+    let mut chain = test_chain(1);
+    let mut mempool = Mempool::new();
+    for address in [&near, &far] {
+        chain
+            .mine_block(mempool.block_template(&chain, address, DEFAULT_BLOCK_SIZE))
+            .unwrap();
+    }
+
+    // the gap after the address at 3 reaches the one at 22, whose own gap is derived in turn
+    wallet.read_wallet(&chain);
+    assert_eq!(wallet.total_credits, 100);
+    assert_eq!(wallet.addresses().len(), 23 + GAP_LIMIT);
+    assert_eq!(wallet.addresses()[..30], probe.addresses()[..]);
+    assert_eq!(
+        wallet.unused_address(&chain),
+        Some(wallet.addresses()[0].as_str())
+    );
+
+    // coins of two addresses are spent together, and the change goes to the internal chain
+    assert_eq!(wallet.change_addresses().len(), GAP_LIMIT);
+    assert!(wallet
+        .change_addresses()
+        .iter()
+        .all(|change| !wallet.addresses().contains(change)));
+    let theirs = Wallet::new();
+    let transaction = wallet.create_transaction(&theirs.id, 60).unwrap();
+    assert_eq!(
+        transaction.outputs[1].recipient,
+        wallet.change_addresses()[0]
+    );
+    let signed = wallet.sign_transaction(&transaction).unwrap();
+    let mut senders = signed.senders();
+    senders.sort();
+    let mut spent = vec![near.clone(), far.clone()];
+    spent.sort();
+    assert_eq!(senders, spent);
+    mempool.add(&chain, signed).unwrap();
+    chain
+        .mine_block(mempool.block_template(&chain, &theirs.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    assert!(chain.check_chain().is_ok());
+    wallet.read_wallet(&chain);
+    assert_eq!(wallet.total_credits, 40);
+    assert_eq!(wallet.change_addresses().len(), 1 + GAP_LIMIT);
+
+    // locked, the wallet still watches every address it derived
+    let path = temp_chain_path("hd-keystore").join("wallet.json");
+    wallet.encrypt("correct horse").unwrap();
+    wallet.save(&path).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let mut loaded = Wallet::load(&path).unwrap();
+    assert!(loaded.is_locked());
+    loaded.read_wallet(&chain);
+    assert_eq!(loaded.total_credits, 40);
+    assert_eq!(loaded.change_addresses(), wallet.change_addresses());
+    let transaction = loaded.clone().create_transaction(&theirs.id, 10).unwrap();
+    assert_eq!(
+        transaction.outputs[1].recipient,
+        loaded.change_addresses()[1]
+    );
+    let signed = wallet.sign_transaction(&transaction).unwrap();
+    assert_eq!(signed.senders(), vec![wallet.change_addresses()[0].clone()]);
+    Mempool::new().add(&chain, signed).unwrap();
+    assert!(matches!(
+        loaded.new_address(),
+        Err(WalletOperationResult::Locked)
+    ));
+    loaded.unlock("correct horse").unwrap();
+    assert_eq!(loaded.new_address().unwrap(), wallet.new_address().unwrap());
+
+    // the addresses in the clear are sealed again with the new one, and cannot be swapped
+    loaded.save(&path).unwrap();
+    assert!(!path.with_extension("json.tmp").exists());
+    let mut reloaded = Wallet::load(&path).unwrap();
+    assert_eq!(reloaded.addresses(), loaded.addresses());
+    reloaded.unlock("correct horse").unwrap();
+    let mut keystore = Keystore::load(&path).unwrap();
+    let hd = keystore.hd.as_mut().unwrap();
+    hd.addresses.pop();
+    assert!(matches!(
+        keystore.decrypt_secret("correct horse"),
+        Err(KeystoreError::WrongPassphrase)
+    ));
+    let mut keystore = Keystore::load(&path).unwrap();
+    let hd = keystore.hd.as_mut().unwrap();
+    hd.change_addresses.pop();
+    assert!(matches!(
+        keystore.decrypt_secret("correct horse"),
+        Err(KeystoreError::WrongPassphrase)
+    ));
+
+    // ed25519 derivation is hardened all the way down, and its keys sign for their addresses
+    let mut other = Wallet::from_mnemonic(KeyType::Ed25519, phrase, "").unwrap();
+    assert!(!other.owns(&wallet.id.id));
+    chain
+        .mine_block(Mempool::new().block_template(
+            &chain,
+            &other.addresses()[7],
+            DEFAULT_BLOCK_SIZE,
+        ))
+        .unwrap();
+    other.read_wallet(&chain);
+    let transaction = other.create_transaction(&theirs.id, 1).unwrap();
+    let signed = other.sign_transaction(&transaction).unwrap();
+    assert_eq!(signed.senders(), vec![other.addresses()[7].clone()]);
+    Mempool::new().add(&chain, signed).unwrap();
+    // an output it knows nothing about could be paid to any of its addresses
+    let unknown = Transaction::new(vec![OutPoint::new(&"0".repeat(64), 0)], vec![]);
+    assert!(matches!(
+        other.sign_transaction(&unknown),
+        Err(WalletOperationResult::UnknownSender)
+    ));
+}
+
+#[test]
+fn mixed_key_types_in_one_chain() {
+    let mut wallet1 = Wallet::with_key_type(KeyType::Secp256k1);
+    let mut wallet2 = Wallet::with_key_type(KeyType::Ed25519);
+
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    chain
+        .mine_block(block_with(
+            &chain,
+            &wallet1,
+            vec![wallet1.sign_transaction(&transaction).unwrap()],
+        ))
+        .unwrap();
+
+    wallet2.read_wallet(&chain);
+    let transaction = wallet2.create_transaction(&wallet1.id, 3).unwrap();
+    chain
+        .mine_block(block_with(
+            &chain,
+            &wallet2,
+            vec![wallet2.sign_transaction(&transaction).unwrap()],
+        ))
+        .unwrap();
+
+    assert!(chain.check_chain().is_ok());
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 16);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 4);
+}
+
+#[test]
+fn fork_choice_by_cumulative_work() {
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let mut chain = test_chain(2);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    let genesis = chain.chain[0].clone();
+
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    let transactions = vec![wallet1.sign_transaction(&transaction).unwrap()];
+    chain
+        .mine_block(block_with(&chain, &wallet1, transactions.clone()))
+        .unwrap();
+    let our_tip = chain.get_last_hash().unwrap();
+
+    let coinbase = |wallet: &Wallet| coinbase_block(wallet, 1).transactions;
+    let b1 = mine_unchecked(&genesis, coinbase(&wallet2));
+    let b2 = mine_unchecked(&b1, coinbase(&wallet2));
+
+    // same amount of work as our tip: kept aside
+    assert_eq!(
+        chain.accept_block(b1.clone()).unwrap(),
+        BlockAcceptance::SideBranch
+    );
+    assert_eq!(
+        chain.accept_block(b1.clone()).unwrap(),
+        BlockAcceptance::AlreadyKnown
+    );
+    assert_eq!(chain.get_last_hash().unwrap(), our_tip);
+    assert_eq!(chain.tree().len(), 3);
+    assert_eq!(chain.tree().tips().len(), 2);
+
+    match chain.accept_block(b2.clone()).unwrap() {
+        BlockAcceptance::MainChain(report) => {
+            assert_eq!(report.fork_height, 1);
+            assert_eq!(report.depth, 1);
+            assert_eq!(report.connected, 2);
+            assert_eq!(report.orphaned_transactions, transactions);
+        }
+        other => panic!("unexpected acceptance: {:?}", other),
+    }
+    assert_eq!(chain.get_last_hash().unwrap(), b2.hash());
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 20);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 2);
+
+    // a heavier branch hiding an invalid block is rejected and our chain restored
+    let bogus_tx = spend(
+        vec![OutPoint::new(&"f".repeat(64), 0)],
+        &wallet1,
+        &[(&wallet2, 5)],
+    );
+    let c1 = mine_unchecked(&genesis, coinbase(&wallet1));
+    let c2 = mine_unchecked(
+        &c1,
+        block_with(&chain, &wallet1, vec![bogus_tx]).transactions,
+    );
+    let c3 = mine_unchecked(&c2, coinbase(&wallet1));
+
+    chain.accept_block(c1.clone()).unwrap();
+    chain.accept_block(c2.clone()).unwrap();
+    assert!(matches!(
+        chain.accept_block(c3.clone()),
+        Err(ChainError::MissingInput { block: 2, .. })
+    ));
+
+    assert_eq!(chain.get_last_hash().unwrap(), b2.hash());
+    assert!(chain.tree().contains(&c1.hash()));
+    assert!(!chain.tree().contains(&c2.hash()));
+    assert!(!chain.tree().contains(&c3.hash()));
+    assert!(chain.check_chain().is_ok());
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 2);
+
+    let stray = mine_unchecked(&c3, coinbase(&wallet1));
+    assert!(matches!(
+        chain.accept_block(stray),
+        Err(ChainError::UnknownParent { block: 4, .. })
+    ));
+}
+
+#[test]
+fn target_retargeting_rules() {
+    let target = Target::from_leading_zeros(2);
+    assert!(target.is_met_by(&format!("00{}", "f".repeat(62))));
+    assert!(!target.is_met_by(&format!("01{}", "0".repeat(62))));
+    assert!(!target.is_met_by("00"));
+    assert_eq!(Target::from_hex(&target.to_hex()), Some(target));
+    assert!(Target::from_leading_zeros(3).work() > target.work());
+
+    let limit = Target::from_leading_zeros(1);
+    // twice as fast halves the target, twice as slow doubles it
+    assert_eq!(target.retarget(50, 100, 4, limit).0, target.0 / 2);
+    assert_eq!(target.retarget(200, 100, 4, limit).0, target.0 * 2);
+    // adjustments are clamped to the factor and never go easier than the limit
+    assert_eq!(target.retarget(0, 100, 4, limit).0, target.0 / 4);
+    assert_eq!(target.retarget(100_000, 100, 4, limit).0, target.0 * 4);
+    assert_eq!(target.retarget(100_000, 100, 64, limit), limit);
+}
+
+#[test]
+fn chain_retargets_and_checks_claimed_target() {
+    let wallet = Wallet::new();
+    let params = ChainParams {
+        adjustment_interval: 3,
+        // blocks are mined way faster than one per hour
+        target_block_time: 3_600_000_000_000,
+        ..test_params(1)
+    };
+
+    let mut chain = BlockChain::with_params(params.clone());
+    for _ in 0..4 {
+        chain.mine_block(coinbase_block(&wallet, 1)).unwrap();
+    }
+
+    let initial = params.initial_target;
+    assert_eq!(chain.chain[2].target, initial);
+    assert_eq!(chain.chain[3].target.0, initial.0 / 4);
+    assert_eq!(
+        chain.expected_target(&chain.chain[3]).unwrap(),
+        chain.chain[3].target
+    );
+    assert!(chain.cumulative_work() > initial.work() * 4);
+    assert!(chain.check_chain().is_ok());
+
+    // a block that keeps the old, easier target right at the boundary
+    let mut easy = mine_unchecked(&chain.chain[2], coinbase_block(&wallet, 1).transactions);
+    easy.target = initial;
+    while !easy.target.is_met_by(&easy.hash()) {
+        easy.nonce += 1;
+    }
+    assert!(matches!(
+        chain.accept_block(easy),
+        Err(ChainError::TargetMismatch { block: 3, .. })
+    ));
+
+    // same for a block built on the tip claiming a harder target than required
+    let mut hard = mine_unchecked(&chain.chain[3], coinbase_block(&wallet, 1).transactions);
+    hard.target = Target::from_leading_zeros(3);
+    while !hard.target.is_met_by(&hard.hash()) {
+        hard.nonce += 1;
+    }
+    assert!(matches!(
+        chain.accept_block(hard),
+        Err(ChainError::TargetMismatch { block: 4, .. })
+    ));
+    assert_eq!(chain.chain.len(), 4);
+}
+
+#[test]
+fn block_timestamps_are_bounded() {
+    let wallet = Wallet::new();
+    let mut chain = test_chain(1);
+    for _ in 0..3 {
+        chain.mine_block(coinbase_block(&wallet, 1)).unwrap();
+    }
+    let tip = chain.chain[2].clone();
+    let solve = |timestamp| {
+        let mut block = mine_unchecked(&tip, coinbase_block(&wallet, 1).transactions);
+        block.timestamp = timestamp;
+        block.solve();
+        block
+    };
+
+    // not after the median of the blocks before it, even if after some of them
+    let median = chain.chain[1].timestamp;
+    let old = solve(median);
+    assert!(matches!(
+        chain.accept_header(old.header()),
+        Err(ChainError::TimestampTooOld { block: 3, .. })
+    ));
+    assert!(matches!(
+        chain.accept_block(old),
+        Err(ChainError::TimestampTooOld { block: 3, .. })
+    ));
+
+    let future = solve(tip.timestamp + MAX_FUTURE_DRIFT + 60_000_000_000);
+    assert!(matches!(
+        chain.accept_header(future.header()),
+        Err(ChainError::TimestampTooNew { block: 3, .. })
+    ));
+    assert!(matches!(
+        chain.accept_block(future),
+        Err(ChainError::TimestampTooNew { block: 3, .. })
+    ));
+
+    assert!(matches!(
+        chain.accept_block(solve(median + 1)),
+        Ok(BlockAcceptance::MainChain(_))
+    ));
+
+    // templates timestamped behind the chain are moved after its median
+    let mut late = coinbase_block(&wallet, 1);
+    late.timestamp = 0;
+    assert!(chain.prepare_block(late).unwrap().timestamp > median);
+}
+
+#[test]
+fn merkle_inclusion_proofs() {
+    let wallet = Wallet::new();
+    let transactions: Vec<SignedTransaction> = (1..=7)
+        .map(|amount| coinbase_block(&wallet, amount).transactions.remove(0))
+        .collect();
+
+    for count in 0..=transactions.len() {
+        let block = Block::new(transactions[..count].to_vec());
+        let header = block.header();
+        assert_eq!(header.hash(), block.hash());
+
+        for tx in block.transactions.iter() {
+            let proof = block.merkle_proof(&tx.hash()).unwrap();
+            assert!(header.verify_inclusion(&proof));
+        }
+    }
+
+    let block = Block::new(transactions[..5].to_vec());
+    let txid = block.transactions[2].hash();
+    let proof = block.merkle_proof(&txid).unwrap();
+    assert!(proof.verify(&block.merkle_root()));
+
+    let mut forged = proof.clone();
+    forged.txid = transactions[6].hash();
+    assert!(!proof.verify(&Block::new(transactions[..4].to_vec()).merkle_root()));
+    assert!(!forged.verify(&block.merkle_root()));
+
+    let mut forged = proof.clone();
+    forged.siblings.pop();
+    assert!(!forged.verify(&block.merkle_root()));
+
+    assert!(block.merkle_proof(&transactions[6].hash()).is_none());
+    assert_eq!(Block::new(vec![]).merkle_root(), "0".repeat(64));
+
+    // reordering transactions changes the root, and so the block hash
+    let mut reordered = block.clone();
+    reordered.transactions.swap(0, 1);
+    assert_ne!(reordered.merkle_root(), block.merkle_root());
+    assert_ne!(reordered.hash(), block.hash());
+
+    let mut chain = test_chain(1);
+    chain.mine_block(coinbase_block(&wallet, 10)).unwrap();
+    let txid = chain.chain[0].transactions[0].hash();
+    let (header, proof) = chain.transaction_proof(&txid).unwrap();
+    assert_eq!(header.hash(), chain.chain[0].hash());
+    assert!(header.verify_inclusion(&proof));
+    assert!(chain.transaction_proof(&"f".repeat(64)).is_none());
+}
+
+#[test]
+fn mempool_validates_and_builds_templates() {
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    let mut chain = test_chain(1);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    chain.mine_block(coinbase_block(&wallet2, 20)).unwrap();
+    let coinbase1 = OutPoint::new(&chain.chain[0].transactions[0].hash(), 0);
+    let coinbase2 = OutPoint::new(&chain.chain[1].transactions[0].hash(), 0);
+
+    let mut mempool = Mempool::new();
+
+    // 7 coins to wallet2 and the change back: no fee
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    let payment = wallet1.sign_transaction(&transaction).unwrap();
+    mempool.add(&chain, payment.clone()).unwrap();
+    assert_eq!(mempool.len(), 1);
+    assert_eq!(mempool.entry(&payment.hash()).unwrap().fee, 0);
+    assert!(matches!(
+        mempool.add(&chain, payment.clone()),
+        Err(MempoolError::AlreadyKnown(_))
+    ));
+
+    // spending the same output again conflicts with the pooled transaction
+    let extra = spend(vec![coinbase1.clone()], &wallet1, &[(&wallet2, 1)]);
+    assert!(matches!(
+        mempool.add(&chain, extra),
+        Err(MempoolError::Conflict { spent_by, .. }) if spent_by == payment.hash()
+    ));
+
+    let theft = spend(vec![coinbase2.clone()], &wallet1, &[(&wallet1, 1)]);
+    assert!(matches!(
+        mempool.add(&chain, theft),
+        Err(MempoolError::Signature {
+            reason: SignatureError::KeyMismatch,
+            ..
+        })
+    ));
+
+    let mut forged = spend(vec![coinbase2.clone()], &wallet2, &[(&wallet2, 1)]);
+    forged.transaction.outputs[0].recipient = wallet1.id.id.clone();
+    assert!(matches!(
+        mempool.add(&chain, forged),
+        Err(MempoolError::Signature { .. })
+    ));
+
+    let unknown = spend(
+        vec![OutPoint::new(&"f".repeat(64), 0)],
+        &wallet2,
+        &[(&wallet2, 1)],
+    );
+    assert!(matches!(
+        mempool.add(&chain, unknown),
+        Err(MempoolError::MissingInput { .. })
+    ));
+
+    let greedy = spend(vec![coinbase2.clone()], &wallet2, &[(&wallet2, 21)]);
+    assert!(matches!(
+        mempool.add(&chain, greedy),
+        Err(MempoolError::InsufficientInputs {
+            available: 20,
+            requested: 21,
+            ..
+        })
+    ));
+
+    assert!(matches!(
+        mempool.add(&chain, coinbase_block(&wallet2, 5).transactions.remove(0)),
+        Err(MempoolError::Coinbase(_))
+    ));
+    assert_eq!(mempool.len(), 1);
+
+    // wallet2 leaves 5 coins as a fee: mined first
+    let paying = spend(vec![coinbase2], &wallet2, &[(&wallet1, 15)]);
+    mempool.add(&chain, paying.clone()).unwrap();
+    assert_eq!(mempool.entry(&paying.hash()).unwrap().fee, 5);
+
+    let miner = Wallet::new();
+    let template = mempool.block_template(&chain, &miner.id.id, DEFAULT_BLOCK_SIZE);
+    assert_eq!(
+        template.transactions[1..],
+        [paying.clone(), payment.clone()]
+    );
+    let coinbase = &template.transactions[0].transaction;
+    assert!(coinbase.is_coinbase());
+    assert_eq!(coinbase.outputs, vec![TxOutput::new(&miner.id.id, 50 + 5)]);
+
+    let coinbase_size = serde_json::to_vec(&SignedTransaction::unsigned(Transaction::coinbase(
+        &miner.id.id,
+        u128::MAX,
+    )))
+    .unwrap()
+    .len();
+    let paying_size = serde_json::to_vec(&paying).unwrap().len();
+    let template = mempool.block_template(&chain, &miner.id.id, coinbase_size + paying_size);
+    assert_eq!(template.transactions.len(), 2);
+    assert_eq!(template.transactions[1], paying);
+
+    let mut before_block = test_chain(1);
+    before_block.accept_block(chain.chain[0].clone()).unwrap();
+    before_block.accept_block(chain.chain[1].clone()).unwrap();
+
+    chain
+        .mine_block(mempool.block_template(&chain, &miner.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    assert_eq!(mempool.evict_stale(&chain).len(), 2);
+    assert!(mempool.is_empty());
+    assert_eq!(chain.utxos().balance(&wallet1.id.id), 28);
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 7);
+    assert_eq!(chain.utxos().balance(&miner.id.id), 55);
+
+    // the block is abandoned by a reorg: its transactions go back to the pool
+    let report = ReorgReport {
+        fork_height: 2,
+        depth: 1,
+        connected: 0,
+        orphaned_transactions: chain.chain[2].transactions[1..].to_vec(),
+    };
+    mempool.reorganized(&before_block, &report);
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&paying.hash()));
+}
+
+#[test]
+fn mempool_limits_and_reorg_order() {
+    let wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+    let mut chain = test_chain(1);
+    chain.mine_block(coinbase_block(&wallet1, 20)).unwrap();
+    chain.mine_block(coinbase_block(&wallet2, 20)).unwrap();
+    let coinbase1 = OutPoint::new(&chain.chain[0].transactions[0].hash(), 0);
+    let coinbase2 = OutPoint::new(&chain.chain[1].transactions[0].hash(), 0);
+
+    let cheap = spend(vec![coinbase1.clone()], &wallet1, &[(&wallet2, 19)]);
+    let generous = spend(vec![coinbase2], &wallet2, &[(&wallet1, 15)]);
+    let size = |tx: &SignedTransaction| serde_json::to_vec(tx).unwrap().len();
+
+    // a coin per 1000 bytes, rounded up
+    let mut mempool = Mempool::with_limits(DEFAULT_MEMPOOL_SIZE, 1);
+    let free = spend(vec![coinbase1.clone()], &wallet1, &[(&wallet2, 20)]);
+    let required = (size(&free) as u128).div_ceil(1000);
+    assert!(matches!(
+        mempool.add(&chain, free),
+        Err(MempoolError::FeeTooLow { fee: 0, required: r, .. }) if r == required
+    ));
+    mempool.add(&chain, generous.clone()).unwrap();
+
+    // room for one of them: the better fee rate stays
+    let mut mempool = Mempool::with_limits(size(&cheap).max(size(&generous)), 0);
+    mempool.add(&chain, cheap.clone()).unwrap();
+    mempool.add(&chain, generous.clone()).unwrap();
+    assert!(!mempool.contains(&cheap.hash()));
+    assert!(matches!(
+        mempool.add(&chain, cheap.clone()),
+        Err(MempoolError::Full { .. })
+    ));
+    assert_eq!(mempool.len(), 1);
+
+    // a transaction of an abandoned block wins over a pooled one spending the same output
+    let mut mempool = Mempool::new();
+    let pooled = spend(vec![coinbase1], &wallet1, &[(&wallet1, 20)]);
+    mempool.add(&chain, pooled.clone()).unwrap();
+    let report = ReorgReport {
+        orphaned_transactions: vec![cheap.clone()],
+        ..ReorgReport::default()
+    };
+    mempool.reorganized(&chain, &report);
+    assert!(mempool.contains(&cheap.hash()));
+    assert!(!mempool.contains(&pooled.hash()));
+}
+
+#[test]
+fn fees_and_block_reward() {
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+    let miner = Wallet::new();
+
+    let mut chain = test_chain(1);
+    let subsidy = chain.block_subsidy(0);
+    chain.mine_block(coinbase_block(&wallet1, subsidy)).unwrap();
+    assert!(matches!(
+        chain.mine_block(coinbase_block(&wallet1, subsidy + 1)),
+        Err(ChainError::CoinbaseTooLarge {
+            block: 1,
+            allowed,
+            found,
+        }) if allowed == subsidy && found == subsidy + 1
+    ));
+
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1
+        .create_transaction_with_fee(&wallet2.id, 7, Fee::Absolute(3))
+        .unwrap();
+    assert_eq!(
+        transaction.outputs,
+        vec![
+            TxOutput::new(&wallet2.id.id, 7),
+            TxOutput::new(&wallet1.id.id, subsidy - 10),
+        ]
+    );
+    let paying = wallet1.sign_transaction(&transaction).unwrap();
+
+    // the fee can be claimed by the coinbase, but not a coin more
+    let height = chain.chain.len() as u128;
+    let reward = |amount| {
+        let mut block = coinbase_block(&miner, amount);
+        block.index = height;
+        block.transactions.push(paying.clone());
+        block
+    };
+    assert!(matches!(
+        chain.validate_block_transactions(&reward(subsidy + 4)),
+        Err(ChainError::CoinbaseTooLarge { .. })
+    ));
+    chain.mine_block(reward(subsidy + 3)).unwrap();
+    assert_eq!(chain.utxos().balance(&miner.id.id), subsidy + 3);
+
+    // a fee rate is paid on the size of the signed transaction
+    wallet1.read_wallet(&chain);
+    let transaction = wallet1
+        .create_transaction_with_fee(&wallet2.id, 1, Fee::PerKilobyte(20))
+        .unwrap();
+    let size = serde_json::to_vec(&wallet1.sign_transaction(&transaction).unwrap())
+        .unwrap()
+        .len() as u128;
+    let fee = subsidy - 10 - transaction.output_total();
+    assert!(fee >= (20 * size).div_ceil(1000));
+    assert!(fee > 0);
+
+    let mut mempool = Mempool::new();
+    mempool
+        .add(&chain, wallet1.sign_transaction(&transaction).unwrap())
+        .unwrap();
+    chain
+        .mine_block(mempool.block_template(&chain, &miner.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    assert_eq!(chain.utxos().balance(&miner.id.id), 2 * subsidy + 3 + fee);
+
+    assert!(matches!(
+        wallet1.create_transaction_with_fee(&wallet2.id, 1, Fee::Absolute(u128::MAX)),
+        Err(WalletOperationResult::NotEnoughtCoinsError)
+    ));
+}
+
+#[test]
+fn coin_selectors_follow_their_strategies() {
+    let candidates: Vec<UXTO> = [8, 1, 5, 20, 3, 2]
+        .iter()
+        .enumerate()
+        .map(|(vout, amount)| UXTO {
+            block_id: 0,
+            outpoint: OutPoint::new(&"0".repeat(64), vout as u32),
+            amount: *amount,
+            address: String::new(),
+        })
+        .collect();
+    let amounts = |selected: Option<Vec<UXTO>>| {
+        selected.map(|selected| selected.iter().map(|uxto| uxto.amount).collect::<Vec<_>>())
+    };
+
+    let target = SelectionTarget::new(10);
+    assert_eq!(
+        amounts(LargestFirst.select(&candidates, &target)),
+        Some(vec![20])
+    );
+    assert_eq!(
+        amounts(SmallestFirst.select(&candidates, &target)),
+        Some(vec![1, 2, 3, 5])
+    );
+    assert_eq!(
+        amounts(BranchAndBound::default().select(&candidates, &target)),
+        Some(vec![8, 2])
+    );
+    // covered after 1, 2 and 8, then improved towards twice the target
+    assert_eq!(
+        amounts(RandomImprove::with_seed(7).select(&candidates, &target)),
+        Some(vec![1, 2, 8, 5, 3])
+    );
+    for seed in 0..20 {
+        let selected = RandomImprove::with_seed(seed)
+            .select(&candidates, &target)
+            .unwrap();
+        assert!(target.leftover(&selected).is_some());
+    }
+
+    // inputs pay for themselves: the 1-coin output is not worth spending, and branch and bound
+    // accepts leftovers up to the cost of the change it saves
+    let target = SelectionTarget {
+        amount: 10,
+        input_fee: 1,
+        change_cost: 2,
+    };
+    let selected = BranchAndBound::default()
+        .select(&candidates, &target)
+        .unwrap();
+    assert_eq!(amounts(Some(selected.clone())), Some(vec![8, 3, 2]));
+    assert_eq!(target.leftover(&selected), Some(0));
+    assert_eq!(target.change(&selected), None);
+    let selected = SmallestFirst.select(&candidates, &target).unwrap();
+    assert_eq!(amounts(Some(selected.clone())), Some(vec![2, 3, 5, 8]));
+    assert_eq!(target.change(&selected), Some(2));
+
+    // without a changeless match, branch and bound finds nothing and the default selector falls
+    // back to the largest outputs
+    let mut eights = vec![candidates[0].clone(), candidates[0].clone()];
+    eights[1].outpoint.vout = 6;
+    let target = SelectionTarget::new(10);
+    assert!(BranchAndBound::default().select(&eights, &target).is_none());
+    assert_eq!(
+        amounts(DefaultSelector.select(&eights, &target)),
+        Some(vec![8, 8])
+    );
+    // the search goes as deep as there are candidates, which takes no stack
+    let many: Vec<UXTO> = (0..60_000)
+        .map(|vout| UXTO {
+            outpoint: OutPoint::new(&candidates[0].outpoint.txid, vout),
+            amount: 1,
+            ..candidates[0].clone()
+        })
+        .collect();
+    let selected = BranchAndBound::default()
+        .select(&many, &SelectionTarget::new(50_000))
+        .unwrap();
+    assert_eq!(selected.len(), 50_000);
+    for selection in [
+        CoinSelection::Default,
+        CoinSelection::LargestFirst,
+        CoinSelection::SmallestFirst,
+        CoinSelection::BranchAndBound,
+        CoinSelection::RandomImprove,
+    ] {
+        let selector = selection.selector();
+        assert!(selector
+            .select(&candidates, &SelectionTarget::new(40))
+            .is_none());
+    }
+
+    // the wallet spends whatever the selector picks
+    let mut wallet = Wallet::new();
+    let theirs = Wallet::new();
+    let mut chain = test_chain(1);
+    for amount in [8, 1, 5, 20, 3, 2] {
+        let mut block = coinbase_block(&wallet, amount);
+        block.index = chain.chain.len() as u128;
+        chain.mine_block(block).unwrap();
+    }
+    wallet.read_wallet(&chain);
+    let paying = |selector: &dyn CoinSelector| {
+        let transaction = wallet
+            .clone()
+            .create_transaction_with(&theirs.id, 9, Fee::Absolute(1), selector)
+            .unwrap();
+        let spent: Vec<u128> = transaction
+            .inputs
+            .iter()
+            .map(|input| chain.utxos().get(input).unwrap().amount)
+            .collect();
+        (spent, transaction.outputs.len())
+    };
+    assert_eq!(paying(&BranchAndBound::default()), (vec![8, 2], 1));
+    assert_eq!(paying(&DefaultSelector), (vec![8, 2], 1));
+    assert_eq!(paying(&LargestFirst), (vec![20], 2));
+    assert_eq!(paying(&SmallestFirst), (vec![1, 2, 3, 5], 2));
+    let transaction = wallet
+        .create_transaction_with_fee(&theirs.id, 9, Fee::Absolute(1))
+        .unwrap();
+    assert_eq!(transaction.outputs, vec![TxOutput::new(&theirs.id.id, 9)]);
+    assert_eq!(wallet.total_credits, 39 - 10);
+}
+
+#[test]
+fn wallet_ledger_tracks_pending_transactions() {
+    let mut wallet1 = Wallet::new();
+    let mut wallet2 = Wallet::new();
+    let miner = Wallet::new();
+    // blocks come far faster than targeted, keep the difficulty from rising
+    let mut params = test_params(1);
+    params.adjustment_interval = 1000;
+    let mut chain = BlockChain::with_params(params);
+    let mut mempool = Mempool::new();
+    let mine = |chain: &mut BlockChain, mempool: &mut Mempool| {
+        chain
+            .mine_block(mempool.block_template(chain, &miner.id.id, DEFAULT_BLOCK_SIZE))
+            .unwrap();
+        mempool.evict_stale(chain);
+    };
+    chain.mine_block(coinbase_block(&wallet1, 50)).unwrap();
+
+    wallet1.read_wallet(&chain);
+    let balance = |confirmed, unconfirmed, spendable| Balance {
+        confirmed,
+        unconfirmed,
+        spendable,
+    };
+    assert_eq!(wallet1.balance(), balance(50, 0, 50));
+    let mined: Vec<&LedgerEntry> = wallet1.history().collect();
+    assert_eq!(mined.len(), 1);
+    assert_eq!(mined[0].kind, EntryKind::Mined);
+    assert_eq!((mined[0].height, mined[0].confirmations), (Some(0), 1));
+
+    // the outputs a transaction spends are set aside until it is mined, its change is pending
+    let transaction = wallet1
+        .create_transaction_with_fee(&wallet2.id, 20, Fee::Absolute(2))
+        .unwrap();
+    assert_eq!(wallet1.balance(), balance(50, 28, 0));
+    wallet1.read_wallet(&chain);
+    assert_eq!(wallet1.balance(), balance(50, 28, 0));
+    let sent = wallet1.history().last().unwrap().clone();
+    assert_eq!(sent.kind, EntryKind::Sent);
+    assert_eq!((sent.sent, sent.received, sent.fee), (50, 28, 2));
+    assert_eq!(sent.net(), -22);
+    assert_eq!(sent.counterparties, vec![wallet2.id.id.clone()]);
+    assert!(!sent.is_confirmed());
+
+    // a transaction missing from the mempool was dropped: its outputs come back
+    let mut dropped = wallet1.clone();
+    dropped.read_mempool(&mempool);
+    assert_eq!(dropped.balance(), balance(50, 0, 50));
+
+    let signed = wallet1.sign_transaction(&transaction).unwrap();
+    let txid = mempool.add(&chain, signed).unwrap();
+    wallet1.read_mempool(&mempool);
+    assert_eq!(wallet1.balance(), balance(50, 28, 0));
+    assert_eq!(wallet1.history().last().unwrap().txid, txid);
+    wallet2.read_wallet(&chain);
+    wallet2.read_mempool(&mempool);
+    assert_eq!(wallet2.balance(), balance(0, 20, 0));
+    let received = wallet2.history().last().unwrap().clone();
+    assert_eq!(received.kind, EntryKind::Received);
+    assert_eq!((received.received, received.fee), (20, 0));
+    assert_eq!(received.counterparties, vec![wallet1.id.id.clone()]);
+
+    mine(&mut chain, &mut mempool);
+    mine(&mut chain, &mut mempool);
+    for wallet in [&mut wallet1, &mut wallet2] {
+        wallet.read_wallet(&chain);
+        wallet.read_mempool(&mempool);
+    }
+    assert_eq!(wallet1.balance(), balance(28, 0, 28));
+    assert_eq!(wallet2.balance(), balance(20, 0, 20));
+    let history: Vec<&LedgerEntry> = wallet1.history().collect();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].txid, txid);
+    assert_eq!((history[1].height, history[1].confirmations), (Some(1), 2));
+    assert_eq!(wallet2.history().next().unwrap().txid, txid);
+
+    // a conflicting spend gets the pending transaction forgotten, not its outputs restored
+    wallet1
+        .create_transaction_with_fee(&wallet2.id, 5, Fee::Absolute(0))
+        .unwrap();
+    let mut replacing = wallet1.clone();
+    replacing.read_wallet(&chain);
+    replacing.read_mempool(&Mempool::new());
+    let conflict = replacing.create_transaction(&miner.id, 28).unwrap();
+    mempool
+        .add(&chain, replacing.sign_transaction(&conflict).unwrap())
+        .unwrap();
+    mine(&mut chain, &mut mempool);
+    wallet1.read_wallet(&chain);
+    assert_eq!(wallet1.balance(), balance(0, 0, 0));
+    assert_eq!(wallet1.history().count(), 3);
+
+    // a transaction never submitted is given up on after PENDING_EXPIRY blocks
+    wallet2.read_wallet(&chain);
+    wallet2
+        .create_transaction_with_fee(&wallet1.id, 5, Fee::Absolute(0))
+        .unwrap();
+    for _ in 1..PENDING_EXPIRY {
+        mine(&mut chain, &mut mempool);
+    }
+    wallet2.read_wallet(&chain);
+    assert_eq!(wallet2.balance(), balance(20, 15, 0));
+    mine(&mut chain, &mut mempool);
+    wallet2.read_wallet(&chain);
+    assert_eq!(wallet2.balance(), balance(20, 0, 20));
+}
+
+#[test]
+fn watch_only_wallets_leave_signing_to_the_key_holder() {
+    let holder = Wallet::with_key_type(KeyType::Secp256k1);
+    let public_key = holder.keys().unwrap().encoded_public_key();
+    let mut watcher = Wallet::watch_public_key(&public_key).unwrap();
+    assert_eq!(watcher.id.id, holder.id.id);
+    assert_eq!(
+        Wallet::watch_only(&holder.id.id).unwrap().addresses(),
+        watcher.addresses()
+    );
+    assert!(matches!(
+        Wallet::watch_only("0OIl"),
+        Err(AddressError::Encoding(_))
+    ));
+    assert!(Wallet::watch_public_key(&holder.id.id).is_err());
+
+    let theirs = Wallet::new();
+    let mut chain = test_chain(1);
+    chain.mine_block(coinbase_block(&holder, 50)).unwrap();
+    watcher.read_wallet(&chain);
+    assert_eq!(watcher.total_credits, 50);
+    assert_eq!(watcher.history().next().unwrap().kind, EntryKind::Mined);
+
+    assert!(watcher.is_watch_only() && watcher.is_locked());
+    assert!(!holder.is_watch_only());
+    assert!(watcher.keys().is_none());
+    assert!(matches!(
+        watcher.unlock("anything"),
+        Err(KeystoreError::NotEncrypted)
+    ));
+    assert!(matches!(
+        watcher.encrypt("anything"),
+        Err(KeystoreError::Locked)
+    ));
+
+    // the watcher builds the transaction, paying a fee rate on the size it will have signed
+    let transaction = watcher
+        .create_transaction_with_fee(&theirs.id, 10, Fee::PerKilobyte(10))
+        .unwrap();
+    assert!(matches!(
+        watcher.sign_transaction(&transaction),
+        Err(WalletOperationResult::WatchOnly)
+    ));
+    let signed = sign_transaction_with(&transaction, holder.keys().unwrap());
+    assert_eq!(signed, holder.sign_transaction(&transaction).unwrap());
+    let size = serde_json::to_vec(&signed).unwrap().len() as u128;
+    let fee = 50 - transaction.output_total();
+    assert!(fee >= (10 * size).div_ceil(1000));
+
+    let mut mempool = Mempool::new();
+    assert!(matches!(
+        mempool.add(
+            &chain,
+            sign_transaction_with(&transaction, theirs.keys().unwrap())
+        ),
+        Err(MempoolError::Signature {
+            reason: SignatureError::KeyMismatch,
+            ..
+        })
+    ));
+    mempool.add(&chain, signed).unwrap();
+    watcher.read_mempool(&mempool);
+    assert_eq!(watcher.balance().unconfirmed, 40 - fee);
+    chain
+        .mine_block(mempool.block_template(&chain, &theirs.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    watcher.read_wallet(&chain);
+    assert_eq!(watcher.total_credits, 40 - fee);
+    assert_eq!(watcher.history().last().unwrap().kind, EntryKind::Sent);
+}
+
+#[test]
+fn partially_signed_transactions_travel_between_watcher_and_signer() {
+    let holder = Wallet::new();
+    let mut watcher = Wallet::watch_only(&holder.id.id).unwrap();
+    let theirs = Wallet::new();
+    let mut chain = test_chain(1);
+    chain.mine_block(coinbase_block(&holder, 50)).unwrap();
+    chain.mine_block(coinbase_block(&holder, 50)).unwrap();
+    watcher.read_wallet(&chain);
+
+    let psbt = watcher
+        .create_psbt(&chain, &theirs.id, 60, Fee::Absolute(2), &LargestFirst)
+        .unwrap();
+    assert_eq!(psbt.inputs.len(), 2);
+    assert_eq!(psbt.check().unwrap(), 2);
+    assert_eq!(psbt.check_inputs(&chain).unwrap(), 2);
+    assert!(matches!(psbt.finalize(), Err(PsbtError::Unsigned)));
+
+    // the signer only sees the serialized form
+    let json = psbt.to_json();
+    let mut received = PartiallySignedTransaction::from_json(&json).unwrap();
+    assert_eq!(received, psbt);
+    assert!(matches!(
+        watcher.sign_psbt(&mut received),
+        Err(PsbtError::Signer(WalletOperationResult::WatchOnly))
+    ));
+    assert!(matches!(
+        theirs.sign_psbt(&mut received),
+        Err(PsbtError::Signer(WalletOperationResult::UnknownSender))
+    ));
+    holder.sign_psbt(&mut received).unwrap();
+    holder.sign_psbt(&mut received).unwrap();
+    assert_eq!(received.signatures.len(), 1);
+
+    // a lie about what an input is worth would hide the fee from the signer, who checks it
+    // against the transaction the input spends from
+    let mut inflated = psbt.clone();
+    inflated.inputs[0].amount += 100;
+    assert!(matches!(
+        holder.sign_psbt(&mut inflated),
+        Err(PsbtError::PreviousOutput(_))
+    ));
+    let mut deflated = psbt.clone();
+    deflated.inputs[0].amount = 1;
+    assert!(matches!(
+        deflated.check(),
+        Err(PsbtError::PreviousOutput(_))
+    ));
+    let mut forged = psbt.clone();
+    forged.inputs[0].amount += 100;
+    forged.inputs[0].previous.transaction.outputs[0].amount += 100;
+    assert!(matches!(forged.check(), Err(PsbtError::PreviousOutput(_))));
+    let mut swapped = psbt.clone();
+    swapped.inputs.reverse();
+    assert!(matches!(swapped.check(), Err(PsbtError::InputMismatch)));
+    let mut foreign = psbt.clone();
+    foreign.inputs[1].address = theirs.id.id.clone();
+    assert!(matches!(foreign.check(), Err(PsbtError::PreviousOutput(_))));
+    let mut newer = json.replacen("\"version\": 1", "\"version\": 2", 1);
+    assert!(matches!(
+        PartiallySignedTransaction::from_json(&newer),
+        Err(PsbtError::UnsupportedVersion(2))
+    ));
+    newer.truncate(10);
+    assert!(matches!(
+        PartiallySignedTransaction::from_json(&newer),
+        Err(PsbtError::Serialization(_))
+    ));
+
+    // a signature over anything else is refused
+    let mut tampered = received.clone();
+    tampered.transaction.outputs[0].amount += 1;
+    assert!(matches!(
+        tampered.finalize(),
+        Err(PsbtError::Signature(SignatureError::Mismatch))
+    ));
+    let mut other = psbt.clone();
+    other.transaction.timestamp += 1;
+    assert!(matches!(
+        other.combine(received.clone()),
+        Err(PsbtError::InputMismatch)
+    ));
+
+    // copies signed apart are merged back before finalizing, where signatures of other keys
+    // are passed over
+    let mut combined = psbt.clone();
+    combined.signatures.push(PartialSignature {
+        public_key: theirs.keys().unwrap().encoded_public_key(),
+        signature: bs58::encode(
+            theirs
+                .keys()
+                .unwrap()
+                .sign(psbt.transaction.hash().as_bytes()),
+        )
+        .into_string(),
+    });
+    combined.combine(received).unwrap();
+    assert_eq!(combined.signatures.len(), 2);
+    let signed = combined.finalize().unwrap();
+    let mut mempool = Mempool::new();
+    let txid = mempool.add(&chain, signed).unwrap();
+    watcher.read_mempool(&mempool);
+    assert_eq!(watcher.balance().unconfirmed, 100 - 62);
+    chain
+        .mine_block(mempool.block_template(&chain, &theirs.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    assert!(chain.transaction_proof(&txid).is_some());
+    assert!(matches!(
+        psbt.check_inputs(&chain),
+        Err(PsbtError::UnknownInput(_))
+    ));
+}
+
+#[test]
+fn multisig_outputs_take_the_required_signatures() {
+    let cosigners = [
+        Wallet::new(),
+        Wallet::with_key_type(KeyType::Secp256k1),
+        Wallet::new(),
+    ];
+    let keys: Vec<String> = cosigners
+        .iter()
+        .map(|wallet| wallet.keys().unwrap().encoded_public_key())
+        .collect();
+    let policy = MultisigPolicy::new(2, keys.clone()).unwrap();
+    let reversed = MultisigPolicy::new(2, keys.iter().rev().cloned().collect()).unwrap();
+    assert_eq!(policy, reversed);
+    assert_eq!(policy.to_string(), "2 of 3");
+    assert_eq!(MultisigPolicy::decode(&policy.encode()).unwrap(), policy);
+    let address = policy.address();
+    assert!(address.starts_with('M'));
+    assert!(address.parse::<Address>().unwrap().is_multisig());
+    assert_ne!(
+        MultisigPolicy::new(1, keys.clone()).unwrap().address(),
+        address
+    );
+
+    assert_eq!(
+        MultisigPolicy::new(4, keys.clone()),
+        Err(MultisigError::Threshold {
+            required: 4,
+            keys: 3
+        })
+    );
+    assert!(matches!(
+        MultisigPolicy::new(0, keys.clone()),
+        Err(MultisigError::Threshold { .. })
+    ));
+    assert!(matches!(
+        MultisigPolicy::new(1, vec![keys[0].clone(), keys[0].clone()]),
+        Err(MultisigError::DuplicateKey(_))
+    ));
+    assert!(matches!(
+        MultisigPolicy::new(1, vec![address.clone()]),
+        Err(MultisigError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        MultisigPolicy::decode(&keys[0]),
+        Err(MultisigError::UnsupportedVersion(1))
+    ));
+    // deserialized policies are checked as well
+    let json = serde_json::to_string(&policy).unwrap();
+    assert_eq!(
+        serde_json::from_str::<MultisigPolicy>(&json).unwrap(),
+        policy
+    );
+    let unsorted = json.replacen(&keys[0], "unsorted", 1);
+    assert!(serde_json::from_str::<MultisigPolicy>(&unsorted).is_err());
+    let overdrawn = json.replacen("\"required\":2", "\"required\":4", 1);
+    assert!(serde_json::from_str::<MultisigPolicy>(&overdrawn).is_err());
+
+    let payee = Wallet::new();
+    let mut chain = test_chain(1);
+    chain
+        .mine_block(Block::new(vec![SignedTransaction::unsigned(
+            Transaction::coinbase(&address, 50),
+        )]))
+        .unwrap();
+    let mut treasury = Wallet::watch_multisig(policy.clone());
+    treasury.read_wallet(&chain);
+    assert_eq!(treasury.total_credits, 50);
+
+    // the key of a single cosigner does not own the coins
+    let outpoint = treasury.uxtos[0].outpoint.clone();
+    let alone = Transaction::new(
+        vec![outpoint.clone()],
+        vec![TxOutput::new(&payee.id.id, 50)],
+    );
+    assert!(matches!(
+        Mempool::new().add(&chain, cosigners[0].sign_transaction(&alone).unwrap()),
+        Err(MempoolError::Signature {
+            reason: SignatureError::Policy(_),
+            ..
+        })
+    ));
+
+    // the treasury builds the spend, the cosigners sign copies of it apart
+    let psbt = treasury
+        .create_psbt(
+            &chain,
+            &payee.id,
+            20,
+            Fee::PerKilobyte(10),
+            &DefaultSelector,
+        )
+        .unwrap();
+    assert_eq!(psbt.multisig, Some(policy.clone()));
+    let mut first = PartiallySignedTransaction::from_json(&psbt.to_json()).unwrap();
+    let mut second = first.clone();
+    cosigners[0].sign_psbt(&mut first).unwrap();
+    cosigners[2].sign_psbt(&mut second).unwrap();
+    assert!(matches!(
+        payee.sign_psbt(&mut second),
+        Err(PsbtError::Signer(WalletOperationResult::UnknownSender))
+    ));
+    assert!(matches!(
+        first.finalize(),
+        Err(PsbtError::Signature(SignatureError::SignatureCount {
+            required: 2,
+            found: 1
+        }))
+    ));
+    let mut forged = psbt.clone();
+    forged.multisig = Some(MultisigPolicy::new(1, keys.clone()).unwrap());
+    assert!(matches!(forged.check(), Err(PsbtError::PolicyMismatch)));
+
+    first.combine(second).unwrap();
+    let signed = first.finalize().unwrap();
+    assert_eq!(signed.unlocking[0].public_key, policy.encode());
+    let size = serde_json::to_vec(&signed).unwrap().len() as u128;
+    let fee = 50 - psbt.transaction.output_total();
+    assert!(fee >= (10 * size).div_ceil(1000));
+
+    // the signatures have to be exactly as many as required, by distinct keys, in key order
+    let tx = &psbt.transaction;
+    let sign = |index: usize| {
+        let keys = cosigners
+            .iter()
+            .find(|wallet| wallet.keys().unwrap().encoded_public_key() == policy.keys()[index])
+            .unwrap()
+            .keys()
+            .unwrap();
+        (index, keys.sign(tx.hash().as_bytes()))
+    };
+    let with_policy = |policy: &MultisigPolicy, signatures: String| {
+        BlockChain::validate_transaction_signature(
+            &SignedTransaction::new(
+                tx.clone(),
+                vec![Unlocking::new(policy.encode(), signatures)],
+            ),
+            &[&address],
+        )
+    };
+    let spend =
+        |signatures: &[(usize, Vec<u8>)]| with_policy(&policy, encode_signatures(signatures));
+    assert!(spend(&[sign(0), sign(2)]).is_ok());
+    assert!(spend(&[sign(1), sign(2)]).is_ok());
+    assert_eq!(
+        spend(&[sign(0), sign(1), sign(2)]),
+        Err(SignatureError::SignatureCount {
+            required: 2,
+            found: 3
+        })
+    );
+    assert!(matches!(
+        spend(&[sign(1), sign(1)]),
+        Err(SignatureError::Malformed(_))
+    ));
+    assert!(matches!(
+        spend(&[sign(2), sign(0)]),
+        Err(SignatureError::Malformed(_))
+    ));
+    let (_, misplaced) = sign(0);
+    assert!(spend(&[(1, misplaced), sign(2)]).is_err());
+    assert_eq!(
+        with_policy(
+            &MultisigPolicy::new(1, keys.clone()).unwrap(),
+            encode_signatures(&[sign(0)]),
+        ),
+        Err(SignatureError::KeyMismatch)
+    );
+    // a single key in place of the policy
+    let (_, single) = sign(0);
+    assert!(matches!(
+        BlockChain::validate_transaction_signature(
+            &SignedTransaction::new(
+                tx.clone(),
+                vec![Unlocking::new(
+                    policy.keys()[0].clone(),
+                    bs58::encode(single).into_string(),
+                )],
+            ),
+            &[&address],
+        ),
+        Err(SignatureError::Policy(MultisigError::UnsupportedVersion(1)))
+    ));
+
+    let mut mempool = Mempool::new();
+    mempool.add(&chain, signed).unwrap();
+    chain
+        .mine_block(mempool.block_template(&chain, &payee.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    treasury.read_wallet(&chain);
+    assert_eq!(treasury.total_credits, 50 - 20 - fee);
+    assert_eq!(chain.utxos().balance(&payee.id.id), 20 + 50 + fee);
+}
+
+#[test]
+fn scripts_run_within_their_limits() {
+    let key = Wallet::new();
+    let other = Wallet::with_key_type(KeyType::Secp256k1);
+    let message = b"transaction hash";
+    let context = ScriptContext {
+        message,
+        lock_height: 10,
+    };
+    let signature = |wallet: &Wallet| hex::encode(wallet.keys().unwrap().sign(message));
+    let public_key = |wallet: &Wallet| {
+        hex::encode(
+            bs58::decode(wallet.keys().unwrap().encoded_public_key())
+                .into_vec()
+                .unwrap(),
+        )
+    };
+    let run = |unlocking: &str, locking: &str| {
+        verify_script(
+            &unlocking.parse().unwrap(),
+            &locking.parse().unwrap(),
+            context,
+        )
+    };
+
+    let locking = format!(
+        "OP_DUP OP_SHA256 {} OP_EQUALVERIFY OP_SIZE OP_16 OP_EQUAL",
+        hex::encode(crypto_hash::digest(
+            crypto_hash::Algorithm::SHA256,
+            b"sixteen byte key"
+        ))
+    );
+    let script: Script = locking.parse().unwrap();
+    assert_eq!(script.to_string(), locking);
+    assert_eq!(
+        Script::decode(&script.encode())
+            .unwrap()
+            .instructions()
+            .unwrap()[0],
+        Instruction::Op(Opcode::Dup)
+    );
+    let preimage = hex::encode(b"sixteen byte key");
+    assert_eq!(run(&preimage, &locking), Ok(()));
+    assert_eq!(run("00", &locking), Err(ScriptError::VerifyFailed));
+    assert_eq!(
+        run(&format!("{} OP_DUP", preimage), &locking),
+        Err(ScriptError::NotPushOnly)
+    );
+    assert!(matches!(
+        "OP_NOPE".parse::<Script>(),
+        Err(ScriptError::Parse(_))
+    ));
+    // unlocking scripts push their data the shortest way, and numbers as numbers
+    assert_eq!(
+        "05".parse::<Script>().unwrap().instructions(),
+        Ok(vec![Instruction::Number(5)])
+    );
+    assert_eq!(run("05 OP_16", "OP_16 OP_EQUAL"), Ok(()));
+    let sixteen_bytes = [0x2a; 16];
+    for unlocking in [
+        [&[0x4c, 0x10][..], &sixteen_bytes].concat(),
+        [&[0x4d, 0x10, 0x00][..], &sixteen_bytes].concat(),
+        vec![0x4c, 0x00],
+        vec![0x01, 0x05],
+    ] {
+        let unlocking = Script::from_bytes(unlocking);
+        assert!(unlocking.instructions().is_ok());
+        assert_eq!(
+            verify_script(&unlocking, &"OP_1".parse().unwrap(), context),
+            Err(ScriptError::NonMinimalPush)
+        );
+    }
+    assert_eq!(
+        Script::from_bytes(vec![0x05, 1, 2]).instructions(),
+        Err(ScriptError::Truncated)
+    );
+    assert_eq!(
+        Script::from_bytes(vec![0xff]).instructions(),
+        Err(ScriptError::UnknownOpcode(0xff))
+    );
+
+    // claimed with the preimage and the key of one, refunded to the other after block 10
+    let hash = crypto_hash::digest(crypto_hash::Algorithm::SHA256, b"secret");
+    let contract = format!(
+        "OP_IF OP_SHA256 {} OP_EQUALVERIFY {} OP_CHECKSIG \
+         OP_ELSE 0a OP_CHECKLOCKTIMEVERIFY OP_DROP {} OP_CHECKSIG OP_ENDIF",
+        hex::encode(&hash),
+        public_key(&key),
+        public_key(&other)
+    );
+    let claim = format!("{} {} OP_1", signature(&key), hex::encode(b"secret"));
+    assert_eq!(run(&claim, &contract), Ok(()));
+    let refund = format!("{} OP_0", signature(&other));
+    assert_eq!(run(&refund, &contract), Ok(()));
+    assert_eq!(
+        run(&format!("{} OP_0", signature(&key)), &contract),
+        Err(ScriptError::Failed)
+    );
+    let early = ScriptContext {
+        lock_height: 9,
+        ..context
+    };
+    assert_eq!(
+        verify_script(&refund.parse().unwrap(), &contract.parse().unwrap(), early),
+        Err(ScriptError::Timelocked {
+            until: 10,
+            lock_height: 9
+        })
+    );
+
+    // signatures in the order of their keys
+    let policy = MultisigPolicy::new(
+        2,
+        vec![
+            key.keys().unwrap().encoded_public_key(),
+            other.keys().unwrap().encoded_public_key(),
+            Wallet::new().keys().unwrap().encoded_public_key(),
+        ],
+    )
+    .unwrap();
+    let multisig = Script::multisig(&policy).unwrap().to_string();
+    let first = policy
+        .position(&key.keys().unwrap().encoded_public_key())
+        .unwrap();
+    let second = policy
+        .position(&other.keys().unwrap().encoded_public_key())
+        .unwrap();
+    let (a, b) = if first < second {
+        (&key, &other)
+    } else {
+        (&other, &key)
+    };
+    assert_eq!(
+        run(&format!("{} {}", signature(a), signature(b)), &multisig),
+        Ok(())
+    );
+    assert_eq!(
+        run(&format!("{} {}", signature(b), signature(a)), &multisig),
+        Err(ScriptError::Failed)
+    );
+    assert_eq!(
+        run(&signature(a), &multisig),
+        Err(ScriptError::StackUnderflow)
+    );
+
+    // the limits
+    assert_eq!(run("", "OP_IF"), Err(ScriptError::StackUnderflow));
+    assert_eq!(
+        run("OP_1", "OP_IF OP_1"),
+        Err(ScriptError::UnbalancedConditional)
+    );
+    assert_eq!(
+        run("OP_1", "OP_ELSE"),
+        Err(ScriptError::UnbalancedConditional)
+    );
+    assert_eq!(run("OP_1", "OP_RETURN"), Err(ScriptError::Return));
+    assert_eq!(
+        run("OP_1", &"OP_DUP OP_DROP ".repeat(101)),
+        Err(ScriptError::TooManySteps)
+    );
+    assert_eq!(run("", &"OP_1 ".repeat(MAX_STACK_SIZE)), Ok(()));
+    assert_eq!(
+        run("", &"OP_1 ".repeat(MAX_STACK_SIZE + 1)),
+        Err(ScriptError::StackOverflow)
+    );
+    // pushes too large for the stack cannot be written, and are refused when decoded
+    assert_eq!(
+        "00".repeat(MAX_ELEMENT_SIZE + 1).parse::<Script>(),
+        Err(ScriptError::ElementTooLarge(MAX_ELEMENT_SIZE + 1))
+    );
+    assert_eq!(
+        Script::new(&[Instruction::Push(vec![0; 0x10000])]),
+        Err(ScriptError::ElementTooLarge(0x10000))
+    );
+    assert_eq!(
+        Script::new(&[Instruction::Number(17)]),
+        Err(ScriptError::InvalidNumber)
+    );
+    let mut oversized = vec![0x4d];
+    oversized.extend((MAX_ELEMENT_SIZE as u16 + 1).to_le_bytes());
+    oversized.extend(vec![0; MAX_ELEMENT_SIZE + 1]);
+    assert_eq!(
+        verify_script(
+            &Script::from_bytes(oversized),
+            &"OP_1".parse().unwrap(),
+            context
+        ),
+        Err(ScriptError::ElementTooLarge(MAX_ELEMENT_SIZE + 1))
+    );
+    assert_eq!(
+        Script::from_bytes(vec![0x51; MAX_SCRIPT_SIZE + 1]).instructions(),
+        Err(ScriptError::TooLarge(MAX_SCRIPT_SIZE + 1))
+    );
+}
+
+#[test]
+fn script_addresses_are_spent_by_unlocking_scripts() {
+    let holder = Wallet::new();
+    let payee = Wallet::new();
+    let mut chain = test_chain(1);
+
+    let holder_key = holder.keys().unwrap().encoded_public_key();
+    let hashlock = Script::hashlock(
+        &crypto_hash::digest(crypto_hash::Algorithm::SHA256, b"secret"),
+        &holder_key,
+    )
+    .unwrap();
+    let timelock = Script::timelock(3, &holder_key).unwrap();
+    assert!(hashlock.address().starts_with('S'));
+    assert!(hashlock.address().parse::<Address>().unwrap().is_script());
+    assert_ne!(hashlock.address(), timelock.address());
+    chain
+        .mine_block(Block::new(vec![SignedTransaction::unsigned(
+            Transaction::new(
+                vec![],
+                vec![
+                    TxOutput::new(&hashlock.address(), 15),
+                    TxOutput::new(&timelock.address(), 20),
+                    TxOutput::new(&holder.id.id, 15),
+                ],
+            ),
+        )]))
+        .unwrap();
+    let coinbase = chain.chain[0].transactions[0].hash();
+
+    let pay = |vout: u32, lock_height: u128| {
+        let mut tx = Transaction::new(
+            vec![OutPoint::new(&coinbase, vout)],
+            vec![TxOutput::new(&payee.id.id, 10)],
+        );
+        tx.lock_height = lock_height;
+        tx
+    };
+    let unlock = |tx: &Transaction, locking: &Script, unlocking: &[Instruction]| {
+        SignedTransaction::new(
+            tx.clone(),
+            vec![Unlocking::new(
+                locking.encode(),
+                Script::new(unlocking).unwrap().encode(),
+            )],
+        )
+    };
+    let signature = |wallet: &Wallet, tx: &Transaction| {
+        Instruction::Push(wallet.keys().unwrap().sign(tx.hash().as_bytes()))
+    };
+    let secret = Instruction::Push(b"secret".to_vec());
+
+    // the hashlock takes the preimage and the key, and the locking script has to be the one
+    // paid to
+    let tx = pay(0, 0);
+    let mut mempool = Mempool::new();
+    let guess = Instruction::Push(b"guess".to_vec());
+    assert!(matches!(
+        mempool.add(
+            &chain,
+            unlock(&tx, &hashlock, &[signature(&holder, &tx), guess])
+        ),
+        Err(MempoolError::Signature {
+            reason: SignatureError::Script(ScriptError::VerifyFailed),
+            ..
+        })
+    ));
+    // whoever saw the preimage cannot claim the coins with it
+    assert!(matches!(
+        mempool.add(
+            &chain,
+            unlock(&tx, &hashlock, std::slice::from_ref(&secret))
+        ),
+        Err(MempoolError::Signature {
+            reason: SignatureError::Script(ScriptError::StackUnderflow),
+            ..
+        })
+    ));
+    assert!(matches!(
+        mempool.add(
+            &chain,
+            unlock(&tx, &hashlock, &[signature(&payee, &tx), secret.clone()])
+        ),
+        Err(MempoolError::Signature {
+            reason: SignatureError::Script(ScriptError::Failed),
+            ..
+        })
+    ));
+    let claim = [signature(&holder, &tx), secret];
+    assert_eq!(
+        BlockChain::validate_transaction_signature(
+            &unlock(&tx, &timelock, &claim),
+            &[&hashlock.address()]
+        ),
+        Err(SignatureError::KeyMismatch)
+    );
+    mempool.add(&chain, unlock(&tx, &hashlock, &claim)).unwrap();
+
+    // the timelocked output takes a transaction locked until block 3, which cannot be mined
+    // any earlier
+    let early = pay(1, 1);
+    let early = unlock(&early, &timelock, &[signature(&holder, &early)]);
+    assert!(matches!(
+        mempool.add(&chain, early.clone()),
+        Err(MempoolError::Signature {
+            reason: SignatureError::Script(ScriptError::Timelocked {
+                until: 3,
+                lock_height: 1
+            }),
+            ..
+        })
+    ));
+    assert!(matches!(
+        chain.validate_block_transactions(&block_with(&chain, &payee, vec![early])),
+        Err(ChainError::Signature {
+            block: 1,
+            reason: SignatureError::Script(ScriptError::Timelocked { .. }),
+            ..
+        })
+    ));
+    // along with coins paid to the key itself: each input is unlocked its own way
+    let mut tx = pay(1, 3);
+    tx.inputs.push(OutPoint::new(&coinbase, 2));
+    tx.outputs.push(TxOutput::new(&holder.id.id, 15));
+    let mut spend = unlock(&tx, &timelock, &[signature(&holder, &tx)]);
+    let by_key = sign_transaction_with(&tx, holder.keys().unwrap());
+    let mut swapped = spend.clone();
+    swapped.unlocking.insert(0, by_key.unlocking[1].clone());
+    let owners = [timelock.address(), holder.id.id.clone()];
+    let owners: Vec<&str> = owners.iter().map(|owner| owner.as_str()).collect();
+    assert!(BlockChain::validate_transaction_signature(&swapped, &owners).is_err());
+    spend.unlocking.push(by_key.unlocking[1].clone());
+    assert_eq!(
+        BlockChain::validate_transaction_signature(&spend, &owners),
+        Ok(())
+    );
+    assert!(matches!(
+        mempool.add(&chain, spend.clone()),
+        Err(MempoolError::TransactionLocked { lock_height: 3, .. })
+    ));
+    assert!(matches!(
+        chain.validate_block_transactions(&block_with(&chain, &payee, vec![spend.clone()])),
+        Err(ChainError::TransactionLocked {
+            block: 1,
+            lock_height: 3,
+            ..
+        })
+    ));
+
+    chain
+        .mine_block(mempool.block_template(&chain, &payee.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    mempool.evict_stale(&chain);
+    let mut before_block = test_chain(1);
+    for block in chain.chain.iter() {
+        before_block.accept_block(block.clone()).unwrap();
+    }
+    chain.mine_block(coinbase_block(&payee, 50)).unwrap();
+    mempool.add(&chain, spend.clone()).unwrap();
+
+    // a reorg back below the timelock takes the spend out of the pool again
+    mempool.reorganized(&before_block, &ReorgReport::default());
+    assert!(!mempool.contains(&spend.hash()));
+    mempool.add(&chain, spend).unwrap();
+    chain
+        .mine_block(mempool.block_template(&chain, &payee.id.id, DEFAULT_BLOCK_SIZE))
+        .unwrap();
+    assert_eq!(chain.utxos().balance(&hashlock.address()), 0);
+    assert_eq!(chain.utxos().balance(&timelock.address()), 0);
+    assert_eq!(chain.utxos().balance(&holder.id.id), 15);
+    assert_eq!(
+        chain.utxos().balance(&payee.id.id),
+        10 + 10 + (50 + 5) + 50 + (50 + 10)
+    );
+}
+
+#[test]
+fn coinbase_consensus_rules() {
+    assert_eq!(
+        ChainParams::with_difficulty(0).coinbase_maturity,
+        DEFAULT_COINBASE_MATURITY
+    );
+    let mut params = test_params(0);
+    params.halving_interval = 3;
+    params.coinbase_maturity = 2;
+    let mut chain = BlockChain::with_params(params);
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+
+    assert_eq!(chain.block_subsidy(0), 50);
+    assert_eq!(chain.block_subsidy(2), 50);
+    assert_eq!(chain.block_subsidy(3), 25);
+    assert_eq!(chain.block_subsidy(7), 12);
+    assert_eq!(chain.block_subsidy(u128::MAX), 0);
+
+    // exactly one coinbase, and it comes first
+    assert!(matches!(
+        chain.validate_block_transactions(&Block::new(vec![])),
+        Err(ChainError::MissingCoinbase { block: 0 })
+    ));
+    let mut twice = coinbase_block(&wallet1, 25);
+    twice
+        .transactions
+        .extend(coinbase_block(&wallet2, 25).transactions);
+    assert!(matches!(
+        chain.mine_block(twice),
+        Err(ChainError::MisplacedCoinbase {
+            block: 0,
+            position: 1,
+            ..
+        })
+    ));
+    chain.mine_block(coinbase_block(&wallet1, 50)).unwrap();
+
+    // the coinbase of block #0 may be spent from block #2 on
+    wallet1.read_wallet(&chain);
+    assert_eq!(wallet1.total_credits, 0);
+    let coinbase = OutPoint::new(&chain.chain[0].transactions[0].hash(), 0);
+    let early = spend(vec![coinbase], &wallet1, &[(&wallet2, 50)]);
+    let mut mempool = Mempool::new();
+    assert!(matches!(
+        mempool.add(&chain, early.clone()),
+        Err(MempoolError::ImmatureCoinbase {
+            maturity_height: 2,
+            ..
+        })
+    ));
+    assert!(matches!(
+        chain.mine_block(block_with(&chain, &wallet2, vec![early.clone()])),
+        Err(ChainError::ImmatureCoinbase {
+            block: 1,
+            maturity_height: 2,
+            ..
+        })
+    ));
+
+    chain
+        .mine_block(block_with(&chain, &wallet2, vec![]))
+        .unwrap();
+    wallet1.read_wallet(&chain);
+    assert_eq!(wallet1.total_credits, 50);
+    mempool.add(&chain, early.clone()).unwrap();
+    chain
+        .mine_block(block_with(&chain, &wallet2, vec![early]))
+        .unwrap();
+    assert_eq!(chain.utxos().balance(&wallet2.id.id), 50);
+
+    // the subsidy halves at block #3
+    assert!(matches!(
+        chain.mine_block(coinbase_block(&wallet1, 26)),
+        Err(ChainError::CoinbaseTooLarge {
+            block: 3,
+            allowed: 25,
+            found: 26,
+        })
+    ));
+    chain.mine_block(coinbase_block(&wallet1, 25)).unwrap();
+    assert!(chain.check_chain().is_ok());
+}
+
+#[test]
+fn locators_find_the_fork_point() {
+    let mut chain = test_chain(0);
+    let wallet = Wallet::new();
+    assert!(chain.locator().is_empty());
+    for _ in 0..20 {
+        chain.mine_block(coinbase_block(&wallet, 1)).unwrap();
+    }
+
+    // ten recent blocks, then exponentially sparser down to genesis
+    let locator = chain.locator();
+    let heights: Vec<u128> = locator
+        .iter()
+        .map(|hash| chain.tree().get(hash).unwrap().block.index)
+        .collect();
+    assert_eq!(
+        heights,
+        vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 8, 4, 0]
+    );
+
+    let mut peer = test_chain(0);
+    for block in chain.chain[..12].iter() {
+        peer.accept_block(block.clone()).unwrap();
+    }
+    peer.mine_block(coinbase_block(&wallet, 2)).unwrap();
+
+    // the peer's branch forks after block #11
+    let missing = chain.blocks_after(&peer.locator(), 5);
+    assert_eq!(missing.len(), 5);
+    assert_eq!(missing[0].index, 12);
+    assert_eq!(chain.blocks_after(&peer.locator(), 500).len(), 8);
+    assert_eq!(chain.blocks_after(&["unknown".to_string()], 500).len(), 20);
+    assert!(chain.blocks_after(&chain.locator(), 500).is_empty());
+}
+
+#[test]
+fn headers_are_validated_before_blocks() {
+    let mut chain = test_chain(1);
+    let wallet = Wallet::new();
+    for _ in 0..12 {
+        chain.mine_block(coinbase_block(&wallet, 1)).unwrap();
+    }
+    let headers: Vec<BlockHeader> = chain.chain.iter().map(|block| block.header()).collect();
+
+    let mut syncing = test_chain(1);
+    assert!(matches!(
+        syncing.accept_header(headers[1].clone()),
+        Err(ChainError::UnknownParent { block: 1, .. })
+    ));
+
+    let mut forged = headers[0].clone();
+    forged.target = Target::MAX;
+    assert!(matches!(
+        syncing.accept_header(forged),
+        Err(ChainError::TargetMismatch { block: 0, .. })
+    ));
+
+    let mut forged = headers[0].clone();
+    while forged.target.is_met_by(&forged.hash()) {
+        forged.nonce += 1;
+    }
+    assert!(matches!(
+        syncing.accept_header(forged),
+        Err(ChainError::ProofOfWork { block: 0, .. })
+    ));
+
+    for header in headers.iter() {
+        assert!(syncing.accept_header(header.clone()).unwrap());
+    }
+    assert!(!syncing.accept_header(headers[3].clone()).unwrap());
+    assert_eq!(
+        syncing.best_header().unwrap().hash,
+        chain.get_last_hash().unwrap()
+    );
+    assert_eq!(
+        syncing.best_header().unwrap().cumulative_work,
+        chain.cumulative_work()
+    );
+    assert!(syncing.chain.is_empty());
+
+    let wanted = syncing.blocks_to_download(5);
+    let heights: Vec<u128> = wanted.iter().map(|(_, height)| *height).collect();
+    assert_eq!(heights, vec![0, 1, 2, 3, 4]);
+    assert_eq!(wanted[0].0, chain.chain[0].hash());
+
+    for block in chain.chain[..7].iter() {
+        syncing.accept_block(block.clone()).unwrap();
+    }
+    assert_eq!(syncing.blocks_to_download(100).len(), 5);
+    assert_eq!(syncing.blocks_to_download(100)[0].1, 7);
+    assert_eq!(
+        syncing.headers_after(&syncing.locator(), 100),
+        Vec::<BlockHeader>::new()
     );
+    assert_eq!(chain.headers_after(&syncing.locator(), 100).len(), 5);
 }
 
 /*
 #[allow(dead_code)]
 fn generate_chain() -> BlockChain {
-    let mut chain = BlockChain::new(
+    let mut chain = test_chain(
         4,
         vec![
             Transaction::new(0, &String::from(""), 128, 0, 0),
@@ -323,7 +2951,7 @@ fn test_chain_break_transaction() {
 #[test]
 fn test_genesis_chain() {
     assert_eq!(
-        BlockChain::new(
+        test_chain(
             4,
             vec![
                 Transaction::new(0, &String::from(""), 128, 0, 0,),
@@ -337,7 +2965,7 @@ fn test_genesis_chain() {
 
 #[test]
 fn test_add_block() {
-    let mut chain = BlockChain::new(
+    let mut chain = test_chain(
         4,
         vec![
             Transaction::new(0, &String::from(""), 128, 0, 0),
@@ -5,63 +5,122 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use super::id::*;
 use super::*;
 
+// A reference to an output of an earlier transaction: the transaction id (hash of the signed
+// transaction) and the position of the output in it.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: &str, vout: u32) -> Self {
+        Self {
+            txid: txid.to_string(),
+            vout,
+        }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
-pub struct Transaction {
-    pub input_block_id: u128,
-    pub intx: String,
-    pub sender: String,
+pub struct TxOutput {
     pub recipient: String,
     pub amount: u128,
-    pub timestamp: u128,
 }
 
-impl Transaction {
-    pub fn new(
-        input_block_id: u128,
-        intx: &str,
-        sender: &str,
-        recipient: &str,
-        amount: u128,
-    ) -> Self {
+impl TxOutput {
+    pub fn new(recipient: &str, amount: u128) -> Self {
         Self {
-            input_block_id,
-            intx: intx.to_string(),
-            sender: sender.to_string(),
             recipient: recipient.to_string(),
             amount,
+        }
+    }
+}
+
+impl fmt::Display for TxOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r:{}...;a:{};", Id::new(&self.recipient), self.amount)
+    }
+}
+
+// Each input is unlocked by whoever holds the address of the output it spends, with the data the
+// signed transaction carries for it. Whatever the inputs hold beyond the sum of the outputs is
+// left to the miner. A transaction without inputs is a coinbase.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct Transaction {
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<TxOutput>,
+    pub timestamp: u128,
+    // Lowest block the transaction can go in; scripts checking a timelock compare against it.
+    #[serde(default)]
+    pub lock_height: u128,
+}
+
+impl Transaction {
+    pub fn new(inputs: Vec<OutPoint>, outputs: Vec<TxOutput>) -> Self {
+        Self {
+            inputs,
+            outputs,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_nanos(),
+            lock_height: 0,
         }
     }
+
+    pub fn coinbase(recipient: &str, amount: u128) -> Self {
+        Self::new(vec![], vec![TxOutput::new(recipient, amount)])
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // Saturates, so that an overflowing set of outputs can never look affordable.
+    pub fn output_total(&self) -> u128 {
+        self.outputs
+            .iter()
+            .fold(0u128, |total, output| total.saturating_add(output.amount))
+    }
 }
 
 impl Hashable for Transaction {
     fn hash(&self) -> String {
         let mut bytes = vec![];
-        bytes.extend(&self.input_block_id.to_be_bytes());
-        bytes.extend(self.intx.bytes());
-        bytes.extend(self.sender.bytes());
-        bytes.extend(self.recipient.bytes());
-        bytes.extend(&self.amount.to_be_bytes());
+        bytes.extend(&(self.inputs.len() as u64).to_be_bytes());
+        for input in self.inputs.iter() {
+            extend_prefixed(&mut bytes, input.txid.as_bytes());
+            bytes.extend(&input.vout.to_be_bytes());
+        }
+        bytes.extend(&(self.outputs.len() as u64).to_be_bytes());
+        for output in self.outputs.iter() {
+            extend_prefixed(&mut bytes, output.recipient.as_bytes());
+            bytes.extend(&output.amount.to_be_bytes());
+        }
         bytes.extend(&self.timestamp.to_be_bytes());
+        bytes.extend(&self.lock_height.to_be_bytes());
         crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &bytes)
     }
 }
 
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "in_id:{};intx:{}...;trans_time:{:x};s:{}...;r:{}...;a:{};",
-            self.input_block_id,
-            &self.intx[..10],
-            self.timestamp,
-            Id::new(&self.sender),
-            Id::new(&self.recipient),
-            self.amount,
-        )
+        write!(f, "trans_time:{:x};in:[", self.timestamp)?;
+        for input in self.inputs.iter() {
+            write!(f, "{}...:{};", abbreviate(&input.txid, 10), input.vout)?;
+        }
+        write!(f, "];out:[")?;
+        for output in self.outputs.iter() {
+            write!(f, "{}", output)?;
+        }
+        write!(f, "];")
     }
 }
 
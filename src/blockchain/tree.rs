@@ -0,0 +1,232 @@
+use super::block::*;
+use super::signedtransaction::*;
+use super::*;
+
+use primitive_types::U256;
+use std::collections::{HashMap, HashSet};
+
+// Removes `hash` and everything built on top of it from a tree kept as `nodes` by hash and
+// `children` by parent hash, and returns the hashes removed.
+fn remove_descendants<N>(
+    nodes: &mut HashMap<String, N>,
+    children: &mut HashMap<String, Vec<String>>,
+    hash: &str,
+    parent: impl Fn(&N) -> &String,
+) -> Vec<String> {
+    let mut removed = vec![];
+    let mut pending = vec![hash.to_string()];
+
+    while let Some(hash) = pending.pop() {
+        if let Some(descendants) = children.remove(&hash) {
+            pending.extend(descendants);
+        }
+
+        if let Some(node) = nodes.remove(&hash) {
+            let previous = parent(&node);
+            if let Some(siblings) = children.get_mut(previous) {
+                siblings.retain(|sibling| *sibling != hash);
+                if siblings.is_empty() {
+                    children.remove(previous);
+                }
+            }
+            removed.push(hash);
+        }
+    }
+
+    removed
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    pub hash: String,
+    pub block: Block,
+    pub cumulative_work: U256,
+}
+
+// Every block we have seen whose ancestry is known, whether it is part of the active chain or
+// of a competing branch. Several roots are allowed, since a peer may present a chain with a
+// different genesis block.
+#[derive(Debug, Default)]
+pub struct BlockTree {
+    nodes: HashMap<String, TreeNode>,
+    children: HashMap<String, Vec<String>>,
+}
+
+impl BlockTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&TreeNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn insert(&mut self, block: Block, hash: String, cumulative_work: U256) -> &TreeNode {
+        self.children
+            .entry(block.previous_block.clone())
+            .or_default()
+            .push(hash.clone());
+
+        self.nodes.entry(hash.clone()).or_insert(TreeNode {
+            hash,
+            block,
+            cumulative_work,
+        })
+    }
+
+    // Tips of every branch, i.e. blocks nobody builds upon yet.
+    pub fn tips(&self) -> Vec<&TreeNode> {
+        self.nodes
+            .values()
+            .filter(|node| !self.children.contains_key(&node.hash))
+            .collect()
+    }
+
+    // Drops a block that failed validation together with everything built on top of it.
+    pub fn invalidate(&mut self, hash: &str) -> Vec<String> {
+        remove_descendants(&mut self.nodes, &mut self.children, hash, |node| {
+            &node.block.previous_block
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderNode {
+    pub hash: String,
+    pub header: BlockHeader,
+    pub cumulative_work: U256,
+}
+
+// Headers whose proof of work and target have been checked, with or without their block. While
+// syncing it runs ahead of the block tree: the best header tells which blocks to download.
+#[derive(Debug, Default)]
+pub struct HeaderTree {
+    nodes: HashMap<String, HeaderNode>,
+    children: HashMap<String, Vec<String>>,
+    best: Option<String>,
+}
+
+impl HeaderTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&HeaderNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // The header with the most cumulative work, the first one seen on ties.
+    pub fn best(&self) -> Option<&HeaderNode> {
+        self.best.as_ref().and_then(|hash| self.nodes.get(hash))
+    }
+
+    pub fn insert(&mut self, header: BlockHeader, hash: String, cumulative_work: U256) {
+        if self.nodes.contains_key(&hash) {
+            return;
+        }
+
+        if self
+            .best()
+            .is_none_or(|best| cumulative_work > best.cumulative_work)
+        {
+            self.best = Some(hash.clone());
+        }
+
+        self.children
+            .entry(header.previous_block.clone())
+            .or_default()
+            .push(hash.clone());
+        self.nodes.insert(
+            hash.clone(),
+            HeaderNode {
+                hash,
+                header,
+                cumulative_work,
+            },
+        );
+    }
+
+    // Drops the header of an invalid block together with its descendants.
+    pub fn invalidate(&mut self, hash: &str) -> Vec<String> {
+        let removed = remove_descendants(&mut self.nodes, &mut self.children, hash, |node| {
+            &node.header.previous_block
+        });
+
+        if self
+            .best
+            .as_ref()
+            .is_some_and(|best| !self.nodes.contains_key(best))
+        {
+            self.best = self
+                .nodes
+                .values()
+                .max_by_key(|node| node.cumulative_work)
+                .map(|node| node.hash.clone());
+        }
+
+        removed
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReorgReport {
+    pub fork_height: u128,
+    pub depth: usize,
+    pub connected: usize,
+    pub orphaned_transactions: Vec<SignedTransaction>,
+}
+
+impl ReorgReport {
+    pub fn is_reorg(&self) -> bool {
+        self.depth > 0
+    }
+
+    // Folds a later report into this one. Transactions orphaned by the first reorg but mined
+    // again on the final branch are not orphaned anymore.
+    pub fn merge(&mut self, other: ReorgReport, active_chain: &[Block]) {
+        self.fork_height = self.fork_height.min(other.fork_height);
+        self.depth += other.depth;
+        self.connected += other.connected;
+        self.orphaned_transactions
+            .extend(other.orphaned_transactions);
+
+        let mined: HashSet<String> = active_chain
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(|tx| tx.hash()))
+            .collect();
+
+        self.orphaned_transactions
+            .retain(|tx| !mined.contains(&tx.hash()));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockAcceptance {
+    MainChain(ReorgReport),
+    SideBranch,
+    AlreadyKnown,
+}
@@ -0,0 +1,195 @@
+use super::block::*;
+use super::transaction::*;
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoEntry {
+    pub block_id: u128,
+    pub outpoint: OutPoint,
+    pub recipient: String,
+    pub amount: u128,
+    pub is_coinbase: bool,
+}
+
+impl fmt::Display for UtxoEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "in_block: {}; UXTO: {}...:{}; amount: {};",
+            self.block_id,
+            abbreviate(&self.outpoint.txid, 10),
+            self.outpoint.vout,
+            self.amount
+        )
+    }
+}
+
+// Where a transaction of the active chain is: the height of its block and its position in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxPosition {
+    pub height: u128,
+    pub index: usize,
+}
+
+// What a block changed in the UTXO set, so that it can be disconnected again.
+#[derive(Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<UtxoEntry>,
+    pub created: Vec<OutPoint>,
+    // Addresses the block added transactions to the history of.
+    pub touched: Vec<String>,
+}
+
+// Besides the unspent outputs, every address keeps the transactions of the active chain it
+// sent or received, so that wallets read their history without going through every block.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    entries: HashMap<OutPoint, UtxoEntry>,
+    by_owner: HashMap<String, HashSet<OutPoint>>,
+    history: HashMap<String, Vec<TxPosition>>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.entries.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.entries.contains_key(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn owned_by(&self, owner: &str) -> Vec<&UtxoEntry> {
+        let mut entries: Vec<&UtxoEntry> = match self.by_owner.get(owner) {
+            Some(outpoints) => outpoints
+                .iter()
+                .filter_map(|outpoint| self.entries.get(outpoint))
+                .collect(),
+            None => vec![],
+        };
+
+        entries.sort_by(|a, b| (a.block_id, &a.outpoint).cmp(&(b.block_id, &b.outpoint)));
+        entries
+    }
+
+    pub fn balance(&self, owner: &str) -> u128 {
+        self.owned_by(owner).iter().map(|entry| entry.amount).sum()
+    }
+
+    // Transactions sent by or paying `address`, oldest first.
+    pub fn history(&self, address: &str) -> &[TxPosition] {
+        self.history.get(address).map_or(&[], |positions| positions)
+    }
+
+    // Whether the active chain has a transaction sent by or paying `address`.
+    pub fn is_used(&self, address: &str) -> bool {
+        self.history.contains_key(address)
+    }
+
+    fn insert(&mut self, entry: UtxoEntry) {
+        self.by_owner
+            .entry(entry.recipient.clone())
+            .or_default()
+            .insert(entry.outpoint.clone());
+        self.entries.insert(entry.outpoint.clone(), entry);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        let entry = self.entries.remove(outpoint)?;
+        if let Some(outpoints) = self.by_owner.get_mut(&entry.recipient) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.by_owner.remove(&entry.recipient);
+            }
+        }
+        Some(entry)
+    }
+
+    // Inputs are looked up in the set as it was before the block, so a transaction cannot spend
+    // an output created in the same block.
+    pub fn apply_block(&mut self, block: &Block) -> BlockUndo {
+        let mut undo = BlockUndo::default();
+
+        // the addresses each transaction spends from
+        let mut senders = vec![];
+        for signed_tx in block.transactions.iter() {
+            let mut spent_from = vec![];
+            for input in signed_tx.transaction.inputs.iter() {
+                if let Some(spent) = self.remove(input) {
+                    spent_from.push(spent.recipient.clone());
+                    undo.spent.push(spent);
+                }
+            }
+            senders.push(spent_from);
+        }
+
+        for (index, (signed_tx, spent_from)) in block.transactions.iter().zip(senders).enumerate() {
+            let tx = &signed_tx.transaction;
+            let position = TxPosition {
+                height: block.index,
+                index,
+            };
+            let addresses = spent_from
+                .into_iter()
+                .chain(tx.outputs.iter().map(|output| output.recipient.clone()));
+            for address in addresses {
+                let positions = self.history.entry(address.clone()).or_default();
+                if positions.last() != Some(&position) {
+                    positions.push(position);
+                    undo.touched.push(address.clone());
+                }
+            }
+        }
+
+        for signed_tx in block.transactions.iter() {
+            let txid = signed_tx.hash();
+            for (vout, output) in signed_tx.transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(&txid, vout as u32);
+                self.insert(UtxoEntry {
+                    block_id: block.index,
+                    outpoint: outpoint.clone(),
+                    recipient: output.recipient.clone(),
+                    amount: output.amount,
+                    is_coinbase: signed_tx.transaction.is_coinbase(),
+                });
+                undo.created.push(outpoint);
+            }
+        }
+
+        undo
+    }
+
+    pub fn rollback_block(&mut self, undo: BlockUndo) {
+        for outpoint in undo.created.iter() {
+            self.remove(outpoint);
+        }
+
+        // the block's transactions are the last ones of every address it touched
+        for address in undo.touched.iter() {
+            if let Some(positions) = self.history.get_mut(address) {
+                positions.pop();
+                if positions.is_empty() {
+                    self.history.remove(address);
+                }
+            }
+        }
+
+        for entry in undo.spent {
+            self.insert(entry);
+        }
+    }
+}
@@ -1,18 +1,30 @@
+use super::address::*;
 use super::chain::*;
+use super::coinselect::*;
+use super::crypto::*;
+use super::error::*;
+use super::hd::*;
 use super::id::*;
+use super::keystore::*;
+use super::ledger::*;
+use super::mempool::*;
+use super::multisig::*;
+use super::psbt::*;
 use super::signedtransaction::*;
 use super::transaction::*;
+use super::utxo::*;
 use super::*;
 
-use openssl::rsa::{Padding, Rsa};
-use std::cmp;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
+use std::path::Path;
 
 #[derive(Debug, Clone)]
 pub struct UXTO {
     pub block_id: u128,
-    pub hash: String,
+    pub outpoint: OutPoint,
     pub amount: u128,
+    pub address: String,
 }
 
 impl fmt::Display for UXTO {
@@ -20,7 +32,7 @@ impl fmt::Display for UXTO {
         write!(
             f,
             "in_block: {}; UXTO: {}; amount: {};",
-            self.block_id, self.hash, self.amount
+            self.block_id, self.outpoint, self.amount
         )
     }
 }
@@ -31,177 +43,996 @@ impl Default for Wallet {
     }
 }
 
+// What a transaction leaves to the miner: a fixed amount, or coins per 1000 bytes of the signed
+// transaction as serialized in the mempool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fee {
+    Absolute(u128),
+    PerKilobyte(u128),
+}
+
 #[derive(Debug, Clone)]
 pub enum WalletOperationResult {
     ResultOk,
     NotEnoughtCoinsError,
+    Locked,
+    // The transaction spends from an address this wallet holds no key for.
+    UnknownSender,
+    // Only hierarchical deterministic wallets derive new addresses.
+    SingleKey,
+    // The wallet holds no private key: whoever does has to sign.
+    WatchOnly,
+}
+
+// Longest encodings of a public key and of a signature, for the size of a transaction signed
+// by someone else.
+const MAX_PUBLIC_KEY_SIZE: usize = 35;
+const MAX_SIGNATURE_SIZE: usize = 64;
+
+// Signs every input of `tx` with `keys`: they have to be those of the address of every output
+// it spends.
+pub fn sign_transaction_with(tx: &Transaction, keys: &dyn Signer) -> SignedTransaction {
+    let unlocking = unlocking_with(tx, keys);
+    SignedTransaction::new(tx.clone(), vec![unlocking; tx.inputs.len()])
+}
+
+fn unlocking_with(tx: &Transaction, keys: &dyn Signer) -> Unlocking {
+    let signature = keys.sign(tx.hash().as_bytes());
+    Unlocking::new(
+        keys.encoded_public_key(),
+        bs58::encode(signature).into_string(),
+    )
+}
+
+// The private side of a wallet, dropped while it is locked.
+#[derive(Debug, Clone)]
+struct Secrets {
+    // One per address, in the same order.
+    keys: Vec<KeyPair>,
+    // One per change address.
+    change_keys: Vec<KeyPair>,
+    hd: Option<HdSecrets>,
+}
+
+impl Secrets {
+    fn keys_of(&self, address: &str) -> Option<&KeyPair> {
+        self.keys
+            .iter()
+            .chain(&self.change_keys)
+            .find(|keys| keys.address() == address)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HdSecrets {
+    seed: Vec<u8>,
+    // The key at the path the addresses are derived under.
+    parent: ExtendedKey,
+    // The one of the change chain, if the path has one.
+    change_parent: Option<ExtendedKey>,
+    // Seals the keystore again over the addresses derived since it was written.
+    sealing: Option<SealingKey>,
+}
+
+// A transaction this wallet created that is not in the chain yet, and the outputs it spends,
+// kept out of `uxtos` until it is mined or dropped.
+#[derive(Debug, Clone)]
+struct Pending {
+    transaction: Transaction,
+    spends: Vec<UXTO>,
+    // Height of the tip when it was created.
+    created_at: u128,
+}
+
+// What the wallet learnt from the chain and the mempool when it last read them.
+#[derive(Debug, Clone, Default)]
+struct History {
+    pending: Vec<Pending>,
+    confirmed: Vec<LedgerEntry>,
+    unconfirmed: Vec<LedgerEntry>,
+    confirmed_balance: u128,
+    tip: u128,
+    // Position of the first change address unused in the chain.
+    next_change: usize,
+}
+
+impl History {
+    fn reserves(&self, outpoint: &OutPoint) -> bool {
+        self.pending
+            .iter()
+            .any(|pending| pending.transaction.inputs.contains(outpoint))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Wallet {
+    // Spendable outputs, and what they add up to.
     pub uxtos: Vec<UXTO>,
     pub total_credits: u128,
-    pub rsa_pair: openssl::rsa::Rsa<openssl::pkey::Private>,
+    history: History,
+    // Every address the wallet receives to. An HD wallet derives the one at position i at
+    // `hd_path`/i, and keeps GAP_LIMIT unused ones ahead of the last used one.
+    addresses: Vec<String>,
+    // Where an HD wallet sends its change, derived the same way along the internal chain of
+    // `hd_path`.
+    change_addresses: Vec<String>,
+    hd_path: Option<DerivationPath>,
+    // None while the wallet is locked: only the keystore holds the private keys then.
+    secrets: Option<Secrets>,
+    keystore: Option<Keystore>,
+    // The policy of the address, for a wallet watching a multisig address.
+    multisig: Option<MultisigPolicy>,
+    // The first address, where mined coins go by default.
     pub id: Id,
 }
 
 impl Wallet {
     pub fn new() -> Self {
-        let rsa_pair = Rsa::generate(1024).unwrap();
-        let id = Id::new(&bs58::encode(rsa_pair.public_key_to_der().unwrap()).into_string());
+        Self::with_key_type(KeyType::Ed25519)
+    }
+
+    pub fn with_key_type(key_type: KeyType) -> Self {
+        Self::from_keys(KeyPair::generate(key_type))
+    }
+
+    pub fn from_keys(keys: KeyPair) -> Self {
+        let address = keys.address();
         Self {
             uxtos: vec![],
             total_credits: 0,
-            rsa_pair,
-            id,
+            history: History::default(),
+            addresses: vec![address.clone()],
+            change_addresses: vec![],
+            hd_path: None,
+            secrets: Some(Secrets {
+                keys: vec![keys],
+                change_keys: vec![],
+                hd: None,
+            }),
+            keystore: None,
+            multisig: None,
+            id: Id::new(&address),
         }
     }
 
-    pub fn read_wallet(&mut self, chain: &BlockChain) {
-        let is_sender_of_tx = |id: &Id, tx: &SignedTransaction| id.id == tx.transaction.sender;
+    // An HD wallet deriving its addresses from `seed` along the default path of `key_type`.
+    pub fn from_seed(key_type: KeyType, seed: &[u8]) -> Result<Self, HdError> {
+        Self::from_seed_at(key_type, seed, DerivationPath::default_for(key_type))
+    }
+
+    pub fn from_seed_at(
+        key_type: KeyType,
+        seed: &[u8],
+        path: DerivationPath,
+    ) -> Result<Self, HdError> {
+        let master = ExtendedKey::master(key_type, seed);
+        let parent = master.derive(&path)?;
+        let change_parent = path
+            .internal()
+            .map(|internal| master.derive(&internal))
+            .transpose()?;
+        let mut wallet = Self {
+            uxtos: vec![],
+            total_credits: 0,
+            history: History::default(),
+            addresses: vec![],
+            change_addresses: vec![],
+            hd_path: Some(path),
+            secrets: Some(Secrets {
+                keys: vec![],
+                change_keys: vec![],
+                hd: Some(HdSecrets {
+                    seed: seed.to_vec(),
+                    parent,
+                    change_parent,
+                    sealing: None,
+                }),
+            }),
+            keystore: None,
+            multisig: None,
+            id: Id::new(""),
+        };
+        wallet.derive_addresses(GAP_LIMIT, false)?;
+        wallet.derive_addresses(GAP_LIMIT, true)?;
+        wallet.id = Id::new(&wallet.addresses[0]);
+        Ok(wallet)
+    }
+
+    // Restores the HD wallet a BIP39 recovery phrase (plus its optional passphrase) stands for.
+    pub fn from_mnemonic(
+        key_type: KeyType,
+        phrase: &str,
+        passphrase: &str,
+    ) -> Result<Self, HdError> {
+        Self::from_seed(key_type, &mnemonic_to_seed(phrase, passphrase)?)
+    }
+
+    // A wallet without keys, watching `address`: it reads its coins and its history, and
+    // builds the transactions spending them for the holder of the key to sign.
+    pub fn watch_only(address: &str) -> Result<Self, AddressError> {
+        address.parse::<Address>()?;
+        Ok(Self {
+            uxtos: vec![],
+            total_credits: 0,
+            history: History::default(),
+            addresses: vec![address.to_string()],
+            change_addresses: vec![],
+            hd_path: None,
+            secrets: None,
+            keystore: None,
+            multisig: None,
+            id: Id::new(address),
+        })
+    }
 
-        let is_recipient_of_tx =
-            |id: &Id, tx: &SignedTransaction| id.id == tx.transaction.recipient;
+    // Same, for the address of a multisig policy. The transactions it builds go around the
+    // cosigners, who sign them with `sign_psbt`.
+    pub fn watch_multisig(policy: MultisigPolicy) -> Self {
+        // the address was just made from a valid policy
+        let mut wallet = Self::watch_only(&policy.address()).unwrap();
+        wallet.multisig = Some(policy);
+        wallet
+    }
+
+    // Same, for the address of an encoded public key.
+    pub fn watch_public_key(public_key: &str) -> Result<Self, SignatureError> {
+        let address = PublicKey::decode(public_key)?.address();
+        // the address was just made from a valid key
+        Ok(Self::watch_only(&address).unwrap())
+    }
+
+    // A locked wallet for the keys in `keystore`: it tracks its coins, but cannot spend them
+    // before `unlock`.
+    pub fn from_keystore(keystore: Keystore) -> Result<Self, KeystoreError> {
+        let (addresses, change_addresses, hd_path) = match &keystore.hd {
+            Some(hd) => (
+                hd.addresses.clone(),
+                hd.change_addresses.clone(),
+                Some(
+                    hd.path
+                        .parse::<DerivationPath>()
+                        .map_err(|_| KeystoreError::InvalidKey)?,
+                ),
+            ),
+            None => (vec![keystore.address.clone()], vec![], None),
+        };
+        if addresses.first() != Some(&keystore.address) {
+            return Err(KeystoreError::InvalidKey);
+        }
+
+        Ok(Self {
+            uxtos: vec![],
+            total_credits: 0,
+            history: History::default(),
+            addresses,
+            change_addresses,
+            hd_path,
+            secrets: None,
+            multisig: None,
+            id: Id::new(&keystore.address),
+            keystore: Some(keystore),
+        })
+    }
 
-        let filter_txs_in_chain = |chain: &BlockChain,
-                                   predicate: fn(&Id, &SignedTransaction) -> bool|
-         -> Vec<(u128, SignedTransaction)> {
-            chain
-                .chain
+    pub fn load(path: &Path) -> Result<Self, KeystoreError> {
+        Self::from_keystore(Keystore::load(path)?)
+    }
+
+    // Writes the encrypted keys, along with the addresses derived so far; `encrypt` has to be
+    // called first.
+    pub fn save(&self, path: &Path) -> Result<(), KeystoreError> {
+        let mut keystore = self.keystore.clone().ok_or(KeystoreError::NotEncrypted)?;
+        if let Some(hd) = keystore.hd.as_mut() {
+            if hd.addresses != self.addresses || hd.change_addresses != self.change_addresses {
+                // the addresses are authenticated along with the seed; only an unlocked wallet
+                // derives new ones, and it holds the key to seal them
+                let secrets = self
+                    .secrets
+                    .as_ref()
+                    .and_then(|secrets| secrets.hd.as_ref())
+                    .ok_or(KeystoreError::Locked)?;
+                let sealing = secrets.sealing.as_ref().ok_or(KeystoreError::Locked)?;
+                hd.addresses = self.addresses.clone();
+                hd.change_addresses = self.change_addresses.clone();
+                keystore.seal(sealing, &secrets.seed)?;
+            }
+        }
+        keystore.save(path)
+    }
+
+    // The key of the first address.
+    pub fn keys(&self) -> Option<&KeyPair> {
+        self.secrets
+            .as_ref()
+            .and_then(|secrets| secrets.keys.first())
+    }
+
+    pub fn keystore(&self) -> Option<&Keystore> {
+        self.keystore.as_ref()
+    }
+
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    pub fn change_addresses(&self) -> &[String] {
+        &self.change_addresses
+    }
+
+    // Every address of ours, the change ones after the receiving ones.
+    fn all_addresses(&self) -> impl Iterator<Item = &String> {
+        self.addresses.iter().chain(&self.change_addresses)
+    }
+
+    pub fn owns(&self, address: &str) -> bool {
+        self.all_addresses().any(|owned| owned == address)
+    }
+
+    pub fn is_hd(&self) -> bool {
+        self.hd_path.is_some()
+    }
+
+    // (Re-)encrypts the keys under `passphrase`, which also changes the passphrase of an
+    // already encrypted wallet. An HD wallet encrypts its seed. The wallet stays unlocked.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<(), KeystoreError> {
+        let secrets = self.secrets.as_mut().ok_or(KeystoreError::Locked)?;
+        let keystore = match (&mut secrets.hd, &self.hd_path) {
+            (Some(hd), Some(path)) => {
+                let kdf = KdfParams::new()?;
+                let sealing = kdf.derive_key(passphrase)?;
+                let keystore = Keystore::encrypt_secret(
+                    hd.parent.key_type,
+                    &self.id.id,
+                    &hd.seed,
+                    Some(HdKeystore {
+                        path: path.to_string(),
+                        addresses: self.addresses.clone(),
+                        change_addresses: self.change_addresses.clone(),
+                    }),
+                    kdf,
+                    &sealing,
+                )?;
+                hd.sealing = Some(sealing);
+                keystore
+            }
+            _ => Keystore::encrypt(&secrets.keys[0], passphrase)?,
+        };
+        self.keystore = Some(keystore);
+        Ok(())
+    }
+
+    // Watch-only wallets are always locked.
+    pub fn is_locked(&self) -> bool {
+        self.secrets.is_none()
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.secrets.is_none() && self.keystore.is_none()
+    }
+
+    // Forgets the private keys, which only an encrypted wallet can get back.
+    pub fn lock(&mut self) -> Result<(), KeystoreError> {
+        if self.keystore.is_none() {
+            return Err(KeystoreError::NotEncrypted);
+        }
+        self.secrets = None;
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), KeystoreError> {
+        let keystore = self.keystore.as_ref().ok_or(KeystoreError::NotEncrypted)?;
+        let path = match &self.hd_path {
+            Some(path) => path,
+            None => {
+                self.secrets = Some(Secrets {
+                    keys: vec![keystore.decrypt(passphrase)?],
+                    change_keys: vec![],
+                    hd: None,
+                });
+                return Ok(());
+            }
+        };
+
+        // every address derived so far has to come out of the seed again
+        let sealing = keystore.kdf.derive_key(passphrase)?;
+        let seed = keystore.open(&sealing)?;
+        let master = ExtendedKey::master(keystore.key_type, &seed);
+        let parent = master.derive(path).map_err(|_| KeystoreError::InvalidKey)?;
+        let change_parent = path
+            .internal()
+            .map(|internal| master.derive(&internal))
+            .transpose()
+            .map_err(|_| KeystoreError::InvalidKey)?;
+        let rederive = |parent: Option<&ExtendedKey>, addresses: &[String]| {
+            let keys = addresses
                 .iter()
-                .flat_map(|block| {
-                    block
-                        .transactions
-                        .iter()
-                        .filter_map(|tx| {
-                            if predicate(&self.id, tx) {
-                                Some((block.index, tx.clone()))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<(u128, SignedTransaction)>>()
+                .enumerate()
+                .map(|(index, address)| {
+                    let keys = Self::derive_key(parent?, index).ok()?;
+                    (keys.address() == *address).then_some(keys)
                 })
-                .collect()
+                .collect::<Option<Vec<_>>>();
+            keys.ok_or(KeystoreError::InvalidKey)
+        };
+        let keys = rederive(Some(&parent), &self.addresses)?;
+        let change_keys = rederive(change_parent.as_ref(), &self.change_addresses)?;
+
+        self.secrets = Some(Secrets {
+            keys,
+            change_keys,
+            hd: Some(HdSecrets {
+                seed,
+                parent,
+                change_parent,
+                sealing: Some(sealing),
+            }),
+        });
+        Ok(())
+    }
+
+    fn derive_key(parent: &ExtendedKey, index: usize) -> Result<KeyPair, HdError> {
+        let mut child = index as u32;
+        if parent.key_type == KeyType::Ed25519 {
+            child |= HARDENED;
+        }
+        Ok(parent.derive_child(child)?.key_pair())
+    }
+
+    // Appends `count` addresses to an unlocked HD wallet, to the change ones if `internal`.
+    fn derive_addresses(&mut self, count: usize, internal: bool) -> Result<(), HdError> {
+        let secrets = match self.secrets.as_mut() {
+            Some(secrets) => secrets,
+            None => return Ok(()),
         };
+        let (parent, keys, addresses) = match (&secrets.hd, internal) {
+            (Some(hd), false) => (&hd.parent, &mut secrets.keys, &mut self.addresses),
+            (Some(hd), true) => match &hd.change_parent {
+                Some(parent) => (parent, &mut secrets.change_keys, &mut self.change_addresses),
+                None => return Ok(()),
+            },
+            (None, _) => return Ok(()),
+        };
+        (0..count).try_for_each(|_| {
+            let key = Self::derive_key(parent, keys.len())?;
+            addresses.push(key.address());
+            keys.push(key);
+            Ok(())
+        })
+    }
 
-        let received_txs = filter_txs_in_chain(chain, is_recipient_of_tx);
-        let spent_txs = filter_txs_in_chain(chain, is_sender_of_tx);
+    // Derives the next address of an HD wallet, whether or not the ones before it were used.
+    pub fn new_address(&mut self) -> Result<String, WalletOperationResult> {
+        if !self.is_hd() {
+            return Err(WalletOperationResult::SingleKey);
+        }
+        if self.is_locked() {
+            return Err(WalletOperationResult::Locked);
+        }
+        self.derive_addresses(1, false)
+            .map_err(|_| WalletOperationResult::SingleKey)?;
+        Ok(self.addresses.last().unwrap().clone())
+    }
 
-        // gather UXTOs => {recived - spent}
-        self.uxtos = received_txs
+    // The first address nothing was sent to yet, to hand out for receiving.
+    pub fn unused_address(&self, chain: &BlockChain) -> Option<&str> {
+        self.addresses
             .iter()
-            .filter_map(|(index, in_tx)| {
-                let is_uxto = spent_txs
-                    .iter()
-                    .any(|(_, out_uxto)| out_uxto.transaction.intx == in_tx.hash());
-                if !is_uxto {
-                    Some(UXTO {
-                        block_id: *index,
-                        hash: in_tx.hash(),
-                        amount: in_tx.transaction.amount,
-                    })
-                } else {
-                    None
+            .map(|address| address.as_str())
+            .find(|address| !chain.utxos().is_used(address))
+    }
+
+    // Keeps GAP_LIMIT unused addresses past the last used one, on both chains, deriving more
+    // while unlocked. A locked wallet only sees the addresses derived before it was locked.
+    fn discover_addresses(&mut self, chain: &BlockChain) {
+        if !self.is_hd() {
+            return;
+        }
+        for internal in [false, true] {
+            loop {
+                let addresses = match internal {
+                    false => &self.addresses,
+                    true => &self.change_addresses,
+                };
+                let before = addresses.len();
+                let missing =
+                    (Self::unused_from(addresses, chain) + GAP_LIMIT).saturating_sub(before);
+                if missing == 0 || self.derive_addresses(missing, internal).is_err() {
+                    break;
                 }
-            })
+                let derived = match internal {
+                    false => self.addresses.len(),
+                    true => self.change_addresses.len(),
+                } - before;
+                if derived == 0 {
+                    break;
+                }
+                log::debug!("Derived {} more addresses", derived);
+            }
+        }
+    }
+
+    // Position of the first of `addresses` after the last one used in the chain.
+    fn unused_from(addresses: &[String], chain: &BlockChain) -> usize {
+        addresses
+            .iter()
+            .rposition(|address| chain.utxos().is_used(address))
+            .map_or(0, |index| index + 1)
+    }
+
+    // Reads our outputs and transactions from the active chain. Only outputs that can be spent
+    // by the next block, and that no pending transaction of ours spends, are picked up. Pending
+    // transactions are forgotten once their inputs are spent, by them or by a conflicting
+    // transaction, or after PENDING_EXPIRY blocks without confirmation: the outputs of those
+    // that expired can be spent again.
+    pub fn read_wallet(&mut self, chain: &BlockChain) {
+        self.discover_addresses(chain);
+        let addresses: HashSet<&str> = self.all_addresses().map(|a| a.as_str()).collect();
+        let owns = |address: &str| addresses.contains(address);
+        let tip = chain.get_last_index().unwrap_or(0);
+        let next_height = chain.get_last_index().map_or(0, |index| index + 1);
+
+        let mut history = History {
+            tip,
+            next_change: Self::unused_from(&self.change_addresses, chain),
+            ..History::default()
+        };
+        // only the transactions of our addresses, in chain order
+        let positions: BTreeSet<TxPosition> = self
+            .all_addresses()
+            .flat_map(|address| chain.utxos().history(address))
+            .copied()
             .collect();
+        let mut received: HashMap<OutPoint, u128> = HashMap::new();
+        for position in positions {
+            let signed_tx = &chain.chain[position.height as usize].transactions[position.index];
+            let tx = &signed_tx.transaction;
+            let txid = signed_tx.hash();
+            let ours: Vec<u128> = tx
+                .inputs
+                .iter()
+                .filter_map(|input| received.get(input).copied())
+                .collect();
+            let spent = (!ours.is_empty()).then(|| {
+                ours.iter()
+                    .fold(0u128, |total, amount| total.saturating_add(*amount))
+            });
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                if owns(&output.recipient) {
+                    received.insert(OutPoint::new(&txid, vout as u32), output.amount);
+                }
+            }
+            let senders = signed_tx.senders();
+            if let Some(entry) = LedgerEntry::new(&txid, tx, spent, &senders, &owns) {
+                history
+                    .confirmed
+                    .push(entry.confirmed(position.height, tip));
+            }
+        }
 
+        for pending in self.history.pending.drain(..) {
+            let txid = pending.transaction.hash();
+            if !pending
+                .transaction
+                .inputs
+                .iter()
+                .all(|input| chain.utxos().contains(input))
+            {
+                log::debug!("Pending transaction {} was mined or replaced", txid);
+            } else if next_height > pending.created_at.saturating_add(PENDING_EXPIRY) {
+                log::info!("Giving up on transaction {}, never confirmed", txid);
+            } else {
+                history.pending.push(pending);
+            }
+        }
+
+        let entries: Vec<&UtxoEntry> = self
+            .all_addresses()
+            .flat_map(|address| chain.utxos().owned_by(address))
+            .collect();
+        history.confirmed_balance = entries
+            .iter()
+            .fold(0u128, |total, entry| total.saturating_add(entry.amount));
+        self.uxtos = entries
+            .into_iter()
+            .filter(|entry| chain.maturity_height(entry) <= next_height)
+            .filter(|entry| !history.reserves(&entry.outpoint))
+            .map(|entry| UXTO {
+                block_id: entry.block_id,
+                outpoint: entry.outpoint.clone(),
+                amount: entry.amount,
+                address: entry.recipient.clone(),
+            })
+            .collect();
         self.total_credits = self.uxtos.iter().fold(0, |acc, uxto| acc + uxto.amount);
+
+        history.unconfirmed = history
+            .pending
+            .iter()
+            .filter_map(|pending| self.pending_entry(pending))
+            .collect();
+        self.history = history;
+    }
+
+    // Takes in what the mempool knows: the outputs its transactions spend are set aside, and
+    // its transactions paying or spent by us are listed as unconfirmed. Our pending
+    // transactions missing from it were dropped, and the outputs they spent come back, so this
+    // is to be called once the transactions created have been submitted.
+    pub fn read_mempool(&mut self, mempool: &Mempool) {
+        // what our outputs spent by the pooled transactions are worth
+        let ours: HashMap<OutPoint, u128> = self
+            .uxtos
+            .iter()
+            .chain(
+                self.history
+                    .pending
+                    .iter()
+                    .flat_map(|pending| &pending.spends),
+            )
+            .map(|uxto| (uxto.outpoint.clone(), uxto.amount))
+            .collect();
+
+        for pending in std::mem::take(&mut self.history.pending) {
+            let pooled = pending
+                .transaction
+                .inputs
+                .first()
+                .and_then(|input| mempool.spender(input))
+                .and_then(|txid| mempool.get(txid))
+                .is_some_and(|pooled| pooled.transaction == pending.transaction);
+            if pooled {
+                self.history.pending.push(pending);
+            } else {
+                log::info!(
+                    "Transaction {} left the mempool, its outputs are spendable again",
+                    pending.transaction.hash()
+                );
+                self.uxtos.extend(pending.spends);
+            }
+        }
+
+        self.uxtos
+            .retain(|uxto| mempool.spender(&uxto.outpoint).is_none());
+        self.total_credits = self.uxtos.iter().map(|uxto| uxto.amount).sum();
+
+        let addresses: HashSet<&str> = self.all_addresses().map(|a| a.as_str()).collect();
+        let owns = |address: &str| addresses.contains(address);
+        let mut unconfirmed: Vec<LedgerEntry> = mempool
+            .entries()
+            .filter_map(|(txid, entry)| {
+                let tx = &entry.tx.transaction;
+                let spends: Vec<u128> = tx
+                    .inputs
+                    .iter()
+                    .filter_map(|input| ours.get(input).copied())
+                    .collect();
+                let spent = (!spends.is_empty()).then(|| {
+                    spends
+                        .iter()
+                        .fold(0u128, |total, amount| total.saturating_add(*amount))
+                });
+                LedgerEntry::new(txid, tx, spent, &entry.tx.senders(), &owns)
+            })
+            .collect();
+        unconfirmed.sort_by(|a, b| (a.timestamp, &a.txid).cmp(&(b.timestamp, &b.txid)));
+        self.history.unconfirmed = unconfirmed;
+    }
+
+    fn pending_entry(&self, pending: &Pending) -> Option<LedgerEntry> {
+        let spent = pending
+            .spends
+            .iter()
+            .fold(0u128, |total, uxto| total.saturating_add(uxto.amount));
+        LedgerEntry::new(
+            &pending.transaction.hash(),
+            &pending.transaction,
+            Some(spent),
+            &[],
+            &|address| self.owns(address),
+        )
+    }
+
+    // Our transactions, oldest first, the unconfirmed ones last.
+    pub fn history(&self) -> impl Iterator<Item = &LedgerEntry> {
+        self.history
+            .confirmed
+            .iter()
+            .chain(self.history.unconfirmed.iter())
     }
 
+    pub fn balance(&self) -> Balance {
+        Balance {
+            confirmed: self.history.confirmed_balance,
+            unconfirmed: self
+                .history
+                .unconfirmed
+                .iter()
+                .fold(0u128, |total, entry| total.saturating_add(entry.received)),
+            spendable: self.total_credits,
+        }
+    }
+
+    // A single transaction paying `amount` to `recipient`, with the change sent back to us.
     pub fn create_transaction(
         &mut self,
         recipient: &Id,
         amount: u128,
-    ) -> Result<Vec<Transaction>, WalletOperationResult> {
+    ) -> Result<Transaction, WalletOperationResult> {
+        self.create_transaction_with_fee(recipient, amount, Fee::Absolute(0))
+    }
+
+    pub fn create_transaction_with_fee(
+        &mut self,
+        recipient: &Id,
+        amount: u128,
+        fee: Fee,
+    ) -> Result<Transaction, WalletOperationResult> {
+        self.create_transaction_with(recipient, amount, fee, &DefaultSelector)
+    }
+
+    // Same, with `selector` picking which of our outputs are spent.
+    pub fn create_transaction_with(
+        &mut self,
+        recipient: &Id,
+        amount: u128,
+        fee: Fee,
+        selector: &dyn CoinSelector,
+    ) -> Result<Transaction, WalletOperationResult> {
         log::debug!(
             "##################### Creating transaction for {} coins #####################",
             amount
         );
 
-        let mut sum: u128 = 0;
-        let mut intxs = vec![];
-
-        log::debug!("Gathering UXTOs:");
-
-        for uxto in self.uxtos.iter() {
-            if sum >= amount {
-                break;
+        // With a fee rate, inputs and change are charged for the bytes they add, so that the
+        // selector can weigh them. The rest of the fee depends on the size of the whole
+        // transaction: grow it until the transaction pays the rate.
+        let (mut base_fee, input_fee, change_cost) = match fee {
+            Fee::Absolute(fee_amount) => (fee_amount, 0, 0),
+            Fee::PerKilobyte(rate) => {
+                let fee_of = |bytes: Vec<u8>| rate.saturating_mul(bytes.len() as u128 + 1) / 1000;
+                let input = OutPoint::new(&"0".repeat(64), u32::MAX);
+                let change = TxOutput::new(&self.id.id, u128::MAX);
+                (
+                    0,
+                    serde_json::to_vec(&input).map_or(0, fee_of),
+                    serde_json::to_vec(&change).map_or(0, fee_of),
+                )
             }
-            log::debug!("\tAdding UXTO: {}", uxto);
-            intxs.push(uxto);
-            sum += uxto.amount;
-        }
+        };
 
-        if sum < amount {
-            return Err(WalletOperationResult::NotEnoughtCoinsError);
-        }
+        let transaction = loop {
+            let target = SelectionTarget {
+                amount: amount
+                    .checked_add(base_fee)
+                    .ok_or(WalletOperationResult::NotEnoughtCoinsError)?,
+                input_fee,
+                change_cost,
+            };
+            let (transaction, paid) =
+                self.build_transaction(recipient, amount, &target, selector)?;
+            let required = match fee {
+                Fee::Absolute(fee_amount) => fee_amount,
+                Fee::PerKilobyte(rate) => {
+                    let size = self.signed_size(&transaction)?;
+                    rate.saturating_mul(size as u128).div_ceil(1000)
+                }
+            };
 
-        log::debug!("Gathered INTXs worth of {} coins", sum);
+            if required <= paid {
+                log::debug!("\tFee: {} coins", paid);
+                break transaction;
+            }
+            base_fee += required - paid;
+        };
 
-        assert!(sum >= amount);
+        // the outputs spent stay reserved until the transaction is mined or dropped
+        let (spends, uxtos) = self
+            .uxtos
+            .drain(..)
+            .partition(|uxto| transaction.inputs.contains(&uxto.outpoint));
+        self.uxtos = uxtos;
+        self.total_credits = self.uxtos.iter().map(|uxto| uxto.amount).sum();
+        let pending = Pending {
+            transaction: transaction.clone(),
+            spends,
+            created_at: self.history.tip,
+        };
+        self.history
+            .unconfirmed
+            .extend(self.pending_entry(&pending));
+        self.history.pending.push(pending);
 
-        log::debug!("Preparing transactions:");
-        let mut transfers = vec![];
-        let mut processed_transfer = 0;
-        for intx in intxs.iter() {
-            let fraction_to_transfer = cmp::min(amount - processed_transfer, intx.amount);
+        log::debug!("\tTransaction: {}", transaction);
+        log::debug!("##################### Transaction created #####################");
+        Ok(transaction)
+    }
 
-            processed_transfer += fraction_to_transfer;
+    // Same as a transaction for whoever holds the keys of the addresses spent from to sign, along with what
+    // they need to check it without the chain. Does not take the keys.
+    pub fn create_psbt(
+        &mut self,
+        chain: &BlockChain,
+        recipient: &Id,
+        amount: u128,
+        fee: Fee,
+        selector: &dyn CoinSelector,
+    ) -> Result<PartiallySignedTransaction, WalletOperationResult> {
+        let transaction = self.create_transaction_with(recipient, amount, fee, selector)?;
+        let spends = &self.history.pending.last().unwrap().spends;
+        let inputs = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| spends.iter().find(|uxto| uxto.outpoint == *input))
+            .filter_map(|uxto| {
+                let previous = chain
+                    .chain
+                    .get(uxto.block_id as usize)
+                    .and(chain.find_txid_in_block(uxto.block_id, &uxto.outpoint.txid))?;
+                Some(PsbtInput {
+                    outpoint: uxto.outpoint.clone(),
+                    amount: uxto.amount,
+                    block_id: uxto.block_id,
+                    address: uxto.address.clone(),
+                    previous: previous.clone(),
+                })
+            })
+            .collect();
+        let mut psbt = PartiallySignedTransaction::new(transaction, inputs);
+        psbt.multisig = self.multisig.clone();
+        Ok(psbt)
+    }
 
-            let transaction = Transaction::new(
-                intx.block_id,
-                &intx.hash,
-                &self.id.id,
-                &recipient.id,
-                fraction_to_transfer,
-            );
+    // The transaction, and the fee it pays.
+    fn build_transaction(
+        &self,
+        recipient: &Id,
+        amount: u128,
+        target: &SelectionTarget,
+        selector: &dyn CoinSelector,
+    ) -> Result<(Transaction, u128), WalletOperationResult> {
+        // the inputs may come from any of our addresses, each unlocked with its own key
+        log::debug!("Gathering UXTOs of {}:", self.id);
+        let selected = selector
+            .select(&self.uxtos, target)
+            .ok_or(WalletOperationResult::NotEnoughtCoinsError)?;
 
-            log::debug!("\tTransaction from UXTO: {}", transaction);
+        let mut inputs = vec![];
+        let mut sum: u128 = 0;
+        for uxto in &selected {
+            log::debug!("\tAdding UXTO: {}", uxto);
+            inputs.push(uxto.outpoint.clone());
+            sum += uxto.amount;
+        }
+        log::debug!("Gathered INTXs worth of {} coins", sum);
 
-            transfers.push(transaction);
+        let mut outputs = vec![TxOutput::new(&recipient.id, amount)];
+        if let Some(change) = target.change(&selected) {
+            log::debug!("\tSending {} coins back", change);
+            outputs.push(TxOutput::new(self.change_address(), change));
+        }
 
-            let fraction_to_send_back = intx.amount - fraction_to_transfer;
-            if fraction_to_send_back > 0 {
-                let transfer_difference = Transaction::new(
-                    intx.block_id,
-                    &intx.hash,
-                    &self.id.id,
-                    &self.id.id,
-                    fraction_to_send_back,
-                );
+        let transaction = Transaction::new(inputs, outputs);
+        let paid = sum - transaction.output_total();
+        Ok((transaction, paid))
+    }
 
-                log::debug!("\tTransfer back from UXTO: {}", transfer_difference);
-                transfers.push(transfer_difference);
+    // The first change address unused in the chain that no pending transaction pays either,
+    // or the last one once they all are. Wallets without a change chain get their change on
+    // their first address.
+    fn change_address(&self) -> &str {
+        let unused = self
+            .change_addresses
+            .iter()
+            .skip(self.history.next_change)
+            .find(|address| {
+                !self.history.pending.iter().any(|pending| {
+                    pending
+                        .transaction
+                        .outputs
+                        .iter()
+                        .any(|output| output.recipient == **address)
+                })
+            });
+        unused
+            .or(self.change_addresses.last())
+            .unwrap_or(&self.addresses[0])
+    }
+    // Size of `tx` once signed. Without the keys, the public key and the signature of every
+    // input are taken to be as long as their encodings get; spending from a multisig address
+    // reveals its policy instead, and as many signatures as it requires.
+    fn signed_size(&self, tx: &Transaction) -> Result<usize, WalletOperationResult> {
+        let unsigned = |unlocking: Unlocking| {
+            SignedTransaction::new(tx.clone(), vec![unlocking; tx.inputs.len()])
+        };
+        let signed = match self.sign_transaction(tx) {
+            Err(WalletOperationResult::WatchOnly) if self.multisig.is_some() => {
+                let policy = self.multisig.as_ref().unwrap();
+                let signatures: Vec<(usize, Vec<u8>)> = (0..policy.required())
+                    .map(|index| (index, vec![0xff; MAX_SIGNATURE_SIZE]))
+                    .collect();
+                unsigned(Unlocking::new(
+                    policy.encode(),
+                    encode_signatures(&signatures),
+                ))
             }
-        }
-
-        //remove used UXTOS from the wallet
-        for transfer in &transfers {
-            let used_uxto = &transfer.intx;
-            self.total_credits = self.total_credits.saturating_sub(transfer.amount);
-            if let Some(index) = self.uxtos.iter().position(|u| *used_uxto == u.hash) {
-                self.uxtos.remove(index);
+            Ok(signed) => signed,
+            Err(WalletOperationResult::Locked | WalletOperationResult::WatchOnly) => {
+                unsigned(Unlocking::new(
+                    bs58::encode([0xff; MAX_PUBLIC_KEY_SIZE]).into_string(),
+                    bs58::encode([0xff; MAX_SIGNATURE_SIZE]).into_string(),
+                ))
             }
-        }
+            Err(error) => return Err(error),
+        };
+        Ok(serde_json::to_vec(&signed).map_or(0, |bytes| bytes.len()))
+    }
 
-        assert_eq!(amount, processed_transfer);
-        log::debug!("##################### Transaction created #####################");
-        Ok(transfers)
+    fn secrets(&self) -> Result<&Secrets, WalletOperationResult> {
+        self.secrets.as_ref().ok_or(if self.is_watch_only() {
+            WalletOperationResult::WatchOnly
+        } else {
+            WalletOperationResult::Locked
+        })
+    }
+
+    // The address of an output of ours, spendable or spent by a pending transaction.
+    fn owner_of(&self, outpoint: &OutPoint) -> Option<&str> {
+        self.uxtos
+            .iter()
+            .chain(
+                self.history
+                    .pending
+                    .iter()
+                    .flat_map(|pending| &pending.spends),
+            )
+            .find(|uxto| uxto.outpoint == *outpoint)
+            .map(|uxto| uxto.address.as_str())
     }
 
-    pub fn sign_transaction(&self, tx: &Transaction) -> SignedTransaction {
-        let mut signature = vec![0; self.rsa_pair.size() as usize];
-        let _ = self
-            .rsa_pair
-            .private_encrypt(tx.hash().as_bytes(), &mut signature, Padding::PKCS1)
-            .unwrap();
+    // Signs every input with the key of the address of the output it spends. A wallet of a
+    // single key signs the inputs it does not know about with it too.
+    pub fn sign_transaction(
+        &self,
+        tx: &Transaction,
+    ) -> Result<SignedTransaction, WalletOperationResult> {
+        let secrets = self.secrets()?;
+        let unlocking = tx
+            .inputs
+            .iter()
+            .map(|input| {
+                let keys = match self.owner_of(input) {
+                    Some(address) => secrets.keys_of(address),
+                    None if secrets.keys.len() == 1 => secrets.keys.first(),
+                    None => None,
+                };
+                keys.map(|keys| unlocking_with(tx, keys))
+                    .ok_or(WalletOperationResult::UnknownSender)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SignedTransaction::new(tx.clone(), unlocking))
+    }
 
-        SignedTransaction::new(tx.clone(), bs58::encode(signature).into_string())
+    // Adds our signatures to `psbt`, once what it spends and pays adds up: those of the keys of
+    // the addresses spent from, or of every key of ours a multisig address takes.
+    pub fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), PsbtError> {
+        psbt.check()?;
+        let secrets = self.secrets().map_err(PsbtError::Signer)?;
+        let mut signed = false;
+        for keys in secrets.keys.iter().chain(&secrets.change_keys) {
+            if psbt.can_sign(keys) {
+                psbt.sign(keys)?;
+                signed = true;
+            }
+        }
+        if !signed {
+            return Err(PsbtError::Signer(WalletOperationResult::UnknownSender));
+        }
+        Ok(())
     }
 
-    pub fn sign_transactions(&self, transfers: Vec<Transaction>) -> Vec<SignedTransaction> {
+    pub fn sign_transactions(
+        &self,
+        transfers: Vec<Transaction>,
+    ) -> Result<Vec<SignedTransaction>, WalletOperationResult> {
         transfers
             .iter()
             .map(|tx| self.sign_transaction(tx))
@@ -0,0 +1,170 @@
+use super::error::*;
+
+use crate::blockchain::chain::*;
+use crate::blockchain::mempool::*;
+use crate::blockchain::params::*;
+use crate::blockchain::signedtransaction::*;
+use crate::blockchain::wallet::*;
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub const CONFIG_FILE: &str = "config.toml";
+pub const CHAIN_DIR: &str = "chain";
+pub const WALLET_FILE: &str = "wallet.json";
+pub const MEMPOOL_FILE: &str = "mempool.json";
+pub const COOKIE_FILE: &str = ".cookie";
+pub const LOCK_FILE: &str = ".lock";
+
+// Settings read from `config.toml` in the data directory; every field is optional and
+// command-line flags take precedence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Leading hex zeros of the proof of work target, used when the chain is first created.
+    pub difficulty: usize,
+    // Blocks a coinbase waits before its outputs can be spent, also fixed when the chain is
+    // first created.
+    pub coinbase_maturity: u64,
+    pub listen: String,
+    pub rpc: String,
+    // Token JSON-RPC clients authenticate with. Without one, a node draws a new token every
+    // time it starts and writes it to the cookie file of the data directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpcauth: Option<String>,
+    pub peers: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            difficulty: 3,
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY as u64,
+            listen: String::from("127.0.0.1:18444"),
+            rpc: String::from("127.0.0.1:18443"),
+            rpcauth: None,
+            peers: vec![],
+        }
+    }
+}
+
+// Where the chain, the wallet, the transactions waiting to be mined and the configuration live.
+// Once the chain is opened, the directory stays locked until the `DataDir` is dropped.
+pub struct DataDir {
+    path: PathBuf,
+    lock: OnceLock<File>,
+}
+
+impl DataDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: OnceLock::new(),
+        }
+    }
+
+    // `$HOME/.rust-blockchain`, or a directory of that name under the working one.
+    pub fn default_path() -> PathBuf {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_default()
+            .join(".rust-blockchain")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn config(&self) -> Result<Config, CliError> {
+        let path = self.path.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Two processes appending to the same block files, or rewriting the same pending
+    // transactions, would lose each other's writes.
+    fn lock(&self) -> Result<(), CliError> {
+        if self.lock.get().is_some() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.path)?;
+        let path = self.path.join(LOCK_FILE);
+        let file = File::create(&path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                let _ = self.lock.set(file);
+                Ok(())
+            }
+            Err(TryLockError::WouldBlock) => Err(CliError::DataDirLocked(self.path.clone())),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    pub fn open_chain(&self, config: &Config) -> Result<BlockChain, CliError> {
+        self.lock()?;
+        let path = self.path.join(CHAIN_DIR);
+        let chain = if BlockChain::exists(&path) {
+            BlockChain::open(&path)?
+        } else {
+            let params = ChainParams {
+                coinbase_maturity: config.coinbase_maturity.into(),
+                ..ChainParams::with_difficulty(config.difficulty)
+            };
+            BlockChain::create(&path, params)?
+        };
+        Ok(chain)
+    }
+
+    pub fn cookie_path(&self) -> PathBuf {
+        self.path.join(COOKIE_FILE)
+    }
+
+    pub fn wallet_path(&self) -> PathBuf {
+        self.path.join(WALLET_FILE)
+    }
+
+    // The wallet comes back locked: its address is enough to mine and to read balances.
+    pub fn load_wallet(&self) -> Result<Wallet, CliError> {
+        let path = self.wallet_path();
+        if !path.exists() {
+            return Err(CliError::NoWallet(path));
+        }
+        Ok(Wallet::load(&path)?)
+    }
+
+    pub fn save_wallet(&self, wallet: &Wallet) -> Result<(), CliError> {
+        Ok(wallet.save(&self.wallet_path())?)
+    }
+
+    // Transactions sent but not mined yet. Those the chain no longer accepts are dropped.
+    pub fn load_mempool(&self, chain: &BlockChain) -> Result<Mempool, CliError> {
+        let mut mempool = Mempool::new();
+        let path = self.path.join(MEMPOOL_FILE);
+        if !path.exists() {
+            return Ok(mempool);
+        }
+
+        let transactions: Vec<SignedTransaction> = serde_json::from_slice(&fs::read(path)?)?;
+        for tx in transactions {
+            if let Err(error) = mempool.add(chain, tx) {
+                log::debug!("Dropping a pending transaction: {}", error);
+            }
+        }
+        Ok(mempool)
+    }
+
+    pub fn save_mempool(&self, mempool: &Mempool) -> Result<(), CliError> {
+        let transactions: Vec<&SignedTransaction> =
+            mempool.entries().map(|(_, entry)| &entry.tx).collect();
+        fs::create_dir_all(&self.path)?;
+        fs::write(
+            self.path.join(MEMPOOL_FILE),
+            serde_json::to_vec_pretty(&transactions)?,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::blockchain::address::*;
+use crate::blockchain::error::*;
+use crate::blockchain::hd::*;
+use crate::blockchain::keystore::*;
+use crate::blockchain::psbt::*;
+use crate::p2p::error::*;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use super::PASSPHRASE_ENV;
+
+#[derive(Debug)]
+pub enum CliError {
+    Io(io::Error),
+    Config(toml::de::Error),
+    Json(serde_json::Error),
+    Chain(Box<ChainError>),
+    Mempool(MempoolError),
+    Network(NetworkError),
+    InvalidAddress(AddressError),
+    Keystore(KeystoreError),
+    Hd(HdError),
+    Psbt(PsbtError),
+    PassphraseRequired,
+    NoWallet(PathBuf),
+    WalletExists(PathBuf),
+    DataDirLocked(PathBuf),
+    InsufficientFunds { available: u128, requested: u128 },
+    UnknownBlock(String),
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for CliError {
+    fn from(error: toml::de::Error) -> Self {
+        CliError::Config(error)
+    }
+}
+
+impl From<KeystoreError> for CliError {
+    fn from(error: KeystoreError) -> Self {
+        CliError::Keystore(error)
+    }
+}
+
+impl From<HdError> for CliError {
+    fn from(error: HdError) -> Self {
+        CliError::Hd(error)
+    }
+}
+
+impl From<PsbtError> for CliError {
+    fn from(error: PsbtError) -> Self {
+        CliError::Psbt(error)
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(error: serde_json::Error) -> Self {
+        CliError::Json(error)
+    }
+}
+
+impl From<ChainError> for CliError {
+    fn from(error: ChainError) -> Self {
+        CliError::Chain(Box::new(error))
+    }
+}
+
+impl From<MempoolError> for CliError {
+    fn from(error: MempoolError) -> Self {
+        CliError::Mempool(error)
+    }
+}
+
+impl From<NetworkError> for CliError {
+    fn from(error: NetworkError) -> Self {
+        CliError::Network(error)
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Io(error) => write!(f, "I/O error: {}", error),
+            CliError::Config(error) => write!(f, "invalid configuration: {}", error),
+            CliError::Json(error) => write!(f, "malformed data file: {}", error),
+            CliError::Chain(error) => write!(f, "{}", error),
+            CliError::Mempool(error) => write!(f, "transaction rejected: {}", error),
+            CliError::Network(error) => write!(f, "{}", error),
+            CliError::InvalidAddress(error) => write!(f, "invalid address: {}", error),
+            CliError::Keystore(error) => write!(f, "{}", error),
+            CliError::Hd(error) => write!(f, "{}", error),
+            CliError::Psbt(error) => write!(f, "{}", error),
+            CliError::PassphraseRequired => write!(
+                f,
+                "the wallet passphrase is required, pass --passphrase or set {}",
+                PASSPHRASE_ENV
+            ),
+            CliError::NoWallet(path) => write!(
+                f,
+                "no wallet at {}, create one with `wallet new`",
+                path.display()
+            ),
+            CliError::WalletExists(path) => write!(
+                f,
+                "a wallet already exists at {}, pass --force to replace it",
+                path.display()
+            ),
+            CliError::DataDirLocked(path) => write!(
+                f,
+                "{} is in use by another process, is a node running on it?",
+                path.display()
+            ),
+            CliError::InsufficientFunds {
+                available,
+                requested,
+            } => write!(
+                f,
+                "insufficient funds: {} coins spendable, {} needed",
+                available, requested
+            ),
+            CliError::UnknownBlock(id) => write!(f, "unknown block {}", id),
+        }
+    }
+}
+
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CliError::Io(error) => Some(error),
+            CliError::Config(error) => Some(error),
+            CliError::Json(error) => Some(error),
+            CliError::Chain(error) => Some(error.as_ref()),
+            CliError::Mempool(error) => Some(error),
+            CliError::Network(error) => Some(error),
+            CliError::InvalidAddress(error) => Some(error),
+            CliError::Keystore(error) => Some(error),
+            CliError::Hd(error) => Some(error),
+            CliError::Psbt(error) => Some(error),
+            _ => None,
+        }
+    }
+}
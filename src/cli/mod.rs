@@ -0,0 +1,492 @@
+pub mod config;
+pub mod error;
+
+use config::*;
+use error::*;
+
+use crate::blockchain::address::*;
+use crate::blockchain::coinselect::*;
+use crate::blockchain::crypto::*;
+use crate::blockchain::hd::*;
+use crate::blockchain::id::*;
+use crate::blockchain::keystore::*;
+use crate::blockchain::mempool::*;
+use crate::blockchain::psbt::*;
+use crate::blockchain::wallet::*;
+use crate::p2p::node::*;
+use crate::rpc::methods::*;
+use crate::rpc::server::*;
+
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+
+// Environment variable the wallet passphrase can be given in, instead of on the command line.
+pub const PASSPHRASE_ENV: &str = "RUST_BLOCKCHAIN_PASSPHRASE";
+
+/// Blockchain node and wallet
+#[derive(Debug, Parser)]
+#[command(name = "rust-blockchain", version)]
+pub struct Cli {
+    /// Directory holding the chain, the wallet and config.toml [default: ~/.rust-blockchain]
+    #[arg(long, global = true)]
+    pub datadir: Option<PathBuf>,
+
+    /// Passphrase encrypting the wallet key
+    #[arg(long, global = true, env = PASSPHRASE_ENV, hide_env_values = true)]
+    pub passphrase: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a networked node; the RPC wallet is unlocked if a passphrase is given
+    #[command(subcommand)]
+    Node(NodeCommand),
+    /// Inspect the local chain
+    #[command(subcommand)]
+    Chain(ChainCommand),
+    /// Manage the wallet
+    #[command(subcommand)]
+    Wallet(WalletCommand),
+    /// Mine blocks holding the pending transactions
+    Mine {
+        #[arg(long, default_value_t = 1)]
+        blocks: usize,
+        /// Address collecting the rewards [default: the wallet's]
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Inspect blocks
+    #[command(subcommand)]
+    Block(BlockCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NodeCommand {
+    /// Serve the chain to peers and JSON-RPC clients until interrupted
+    Run {
+        /// Peer-to-peer listening address [default: from config.toml]
+        #[arg(long)]
+        listen: Option<String>,
+        /// JSON-RPC listening address [default: from config.toml]
+        #[arg(long)]
+        rpc: Option<String>,
+        /// Peer to connect to, on top of those in config.toml
+        #[arg(long)]
+        connect: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ChainCommand {
+    /// Print every block
+    Show,
+    /// Check every block against the consensus rules
+    Verify,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WalletCommand {
+    /// Generate a new HD wallet, encrypted under the passphrase, and print its recovery phrase
+    New {
+        #[arg(long, default_value = "ed25519", value_parser = parse_key_type)]
+        key_type: KeyType,
+        /// Length of the recovery phrase
+        #[arg(long, default_value_t = 12)]
+        words: usize,
+        /// Replace the existing wallet
+        #[arg(long)]
+        force: bool,
+    },
+    /// Rebuild an HD wallet from its recovery phrase
+    Restore {
+        /// The recovery phrase, quoted
+        phrase: String,
+        #[arg(long, default_value = "ed25519", value_parser = parse_key_type)]
+        key_type: KeyType,
+        /// Passphrase the recovery phrase was extended with, if any
+        #[arg(long, default_value = "")]
+        mnemonic_passphrase: String,
+        /// Replace the existing wallet
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print an address nothing was sent to yet
+    Address,
+    /// Print the spendable outputs and the balances
+    Balance,
+    /// List the transactions paying or spent by the wallet
+    History,
+    /// Pay <amount> coins to <address>; the transaction is mined by the next `mine`
+    Send {
+        address: String,
+        amount: u128,
+        #[arg(long, default_value_t = 0)]
+        fee: u128,
+        /// How the outputs to spend are picked: largest-first, smallest-first,
+        /// branch-and-bound or random-improve
+        #[arg(long, default_value = "default", value_parser = parse_coin_selection)]
+        coin_selection: CoinSelection,
+    },
+    /// Build transactions on one machine and sign them on another
+    #[command(subcommand)]
+    Psbt(PsbtCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PsbtCommand {
+    /// Print an unsigned transaction paying <amount> coins to <address>; needs no passphrase
+    Create {
+        address: String,
+        amount: u128,
+        #[arg(long, default_value_t = 0)]
+        fee: u128,
+        #[arg(long, default_value = "default", value_parser = parse_coin_selection)]
+        coin_selection: CoinSelection,
+    },
+    /// Print what a partially signed transaction spends and pays
+    Inspect { file: PathBuf },
+    /// Print the transaction in <file> with the wallet's signature added
+    Sign { file: PathBuf },
+    /// Add a signed transaction to the pending ones, to be mined by the next `mine`
+    Submit { file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BlockCommand {
+    /// Print a block of the active chain
+    Get {
+        /// Height or hash
+        id: String,
+    },
+}
+
+fn parse_key_type(name: &str) -> Result<KeyType, String> {
+    match name {
+        "ed25519" => Ok(KeyType::Ed25519),
+        "secp256k1" => Ok(KeyType::Secp256k1),
+        _ => Err(String::from("expected ed25519 or secp256k1")),
+    }
+}
+
+fn parse_coin_selection(name: &str) -> Result<CoinSelection, String> {
+    match name {
+        "default" => Ok(CoinSelection::Default),
+        "largest-first" => Ok(CoinSelection::LargestFirst),
+        "smallest-first" => Ok(CoinSelection::SmallestFirst),
+        "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+        "random-improve" => Ok(CoinSelection::RandomImprove),
+        _ => Err(String::from(
+            "expected default, largest-first, smallest-first, branch-and-bound or random-improve",
+        )),
+    }
+}
+
+fn check_replace(datadir: &DataDir, force: bool) -> Result<(), CliError> {
+    let path = datadir.wallet_path();
+    if path.exists() && !force {
+        return Err(CliError::WalletExists(path));
+    }
+    Ok(())
+}
+
+fn unlock(wallet: &mut Wallet, passphrase: Option<&str>) -> Result<(), CliError> {
+    let passphrase = passphrase.ok_or(CliError::PassphraseRequired)?;
+    Ok(wallet.unlock(passphrase)?)
+}
+
+fn read_psbt(file: &Path) -> Result<PartiallySignedTransaction, CliError> {
+    Ok(PartiallySignedTransaction::from_json(&fs::read_to_string(
+        file,
+    )?)?)
+}
+
+fn check_address(address: &str) -> Result<(), CliError> {
+    address
+        .parse::<Address>()
+        .map(|_| ())
+        .map_err(CliError::InvalidAddress)
+}
+
+pub fn run(cli: Cli, out: &mut dyn Write) -> Result<(), CliError> {
+    let datadir = DataDir::new(cli.datadir.unwrap_or_else(DataDir::default_path));
+    let config = datadir.config()?;
+    let passphrase = cli.passphrase.as_deref();
+
+    match cli.command {
+        Command::Node(NodeCommand::Run {
+            listen,
+            rpc,
+            connect,
+        }) => {
+            let config = Config {
+                listen: listen.unwrap_or(config.listen),
+                rpc: rpc.unwrap_or(config.rpc),
+                peers: config.peers.into_iter().chain(connect).collect(),
+                ..config
+            };
+            let (node, server) = start_node(&datadir, &config, passphrase)?;
+            writeln!(out, "Listening for peers on {}", node.local_addr())?;
+            writeln!(out, "Serving JSON-RPC on {}", server.local_addr())?;
+            out.flush()?;
+
+            let (interrupt, interrupted) = mpsc::channel();
+            ctrlc::set_handler(move || {
+                let _ = interrupt.send(());
+            })
+            .map_err(io::Error::other)?;
+            let _ = interrupted.recv();
+            stop_node(&datadir, node, server)?;
+            writeln!(out, "Node stopped")?;
+        }
+        Command::Chain(ChainCommand::Show) => {
+            let chain = datadir.open_chain(&config)?;
+            writeln!(out, "{}", chain)?;
+        }
+        Command::Chain(ChainCommand::Verify) => {
+            let chain = datadir.open_chain(&config)?;
+            chain.check_chain()?;
+            match chain.get_last_hash() {
+                Some(tip) => writeln!(out, "{} blocks verified, tip {}", chain.chain.len(), tip)?,
+                None => writeln!(out, "Empty chain")?,
+            }
+        }
+        Command::Wallet(WalletCommand::New {
+            key_type,
+            words,
+            force,
+        }) => {
+            check_replace(&datadir, force)?;
+            let phrase = generate_mnemonic(words)?;
+            let mut wallet = Wallet::from_mnemonic(key_type, &phrase, "")?;
+            wallet.encrypt(passphrase.ok_or(CliError::PassphraseRequired)?)?;
+            datadir.save_wallet(&wallet)?;
+            writeln!(out, "{}", wallet.id.id)?;
+            writeln!(out, "Recovery phrase: {}", phrase)?;
+        }
+        Command::Wallet(WalletCommand::Restore {
+            phrase,
+            key_type,
+            mnemonic_passphrase,
+            force,
+        }) => {
+            check_replace(&datadir, force)?;
+            let mut wallet = Wallet::from_mnemonic(key_type, &phrase, &mnemonic_passphrase)?;
+            wallet.encrypt(passphrase.ok_or(CliError::PassphraseRequired)?)?;
+            wallet.read_wallet(&datadir.open_chain(&config)?);
+            datadir.save_wallet(&wallet)?;
+            writeln!(out, "{}", wallet.id.id)?;
+            writeln!(out, "Spendable: {} coins", wallet.total_credits)?;
+        }
+        Command::Wallet(WalletCommand::Address) => {
+            let chain = datadir.open_chain(&config)?;
+            let mut wallet = datadir.load_wallet()?;
+            // unlocked, the wallet derives addresses past those it already knows
+            if passphrase.is_some() {
+                unlock(&mut wallet, passphrase)?;
+                wallet.read_wallet(&chain);
+                datadir.save_wallet(&wallet)?;
+            }
+            let address = match wallet.unused_address(&chain) {
+                Some(address) => address,
+                None if !wallet.is_hd() => &wallet.id.id,
+                // deriving past the known addresses takes the keys
+                None => return Err(CliError::PassphraseRequired),
+            };
+            writeln!(out, "{}", address)?;
+        }
+        Command::Wallet(WalletCommand::Balance) => {
+            let chain = datadir.open_chain(&config)?;
+            let mempool = datadir.load_mempool(&chain)?;
+            let mut wallet = datadir.load_wallet()?;
+            wallet.read_wallet(&chain);
+            wallet.read_mempool(&mempool);
+            writeln!(out, "{}", wallet)?;
+            writeln!(out, "{}", wallet.balance())?;
+        }
+        Command::Wallet(WalletCommand::History) => {
+            let chain = datadir.open_chain(&config)?;
+            let mempool = datadir.load_mempool(&chain)?;
+            let mut wallet = datadir.load_wallet()?;
+            wallet.read_wallet(&chain);
+            wallet.read_mempool(&mempool);
+            for entry in wallet.history() {
+                writeln!(out, "{}", entry)?;
+            }
+        }
+        Command::Wallet(WalletCommand::Send {
+            address,
+            amount,
+            fee,
+            coin_selection,
+        }) => {
+            check_address(&address)?;
+            let chain = datadir.open_chain(&config)?;
+            let mut mempool = datadir.load_mempool(&chain)?;
+            let mut wallet = datadir.load_wallet()?;
+            unlock(&mut wallet, passphrase)?;
+            wallet.read_wallet(&chain);
+            wallet.read_mempool(&mempool);
+            datadir.save_wallet(&wallet)?;
+
+            let transaction = wallet
+                .create_transaction_with(
+                    &Id::new(&address),
+                    amount,
+                    Fee::Absolute(fee),
+                    coin_selection.selector().as_ref(),
+                )
+                .map_err(|_| CliError::InsufficientFunds {
+                    available: wallet.total_credits,
+                    requested: amount.saturating_add(fee),
+                })?;
+            let signed = wallet
+                .sign_transaction(&transaction)
+                .map_err(|_| KeystoreError::Locked)?;
+            let txid = mempool.add(&chain, signed)?;
+            datadir.save_mempool(&mempool)?;
+            writeln!(out, "{}", txid)?;
+        }
+        Command::Wallet(WalletCommand::Psbt(PsbtCommand::Create {
+            address,
+            amount,
+            fee,
+            coin_selection,
+        })) => {
+            check_address(&address)?;
+            let chain = datadir.open_chain(&config)?;
+            let mempool = datadir.load_mempool(&chain)?;
+            let mut wallet = datadir.load_wallet()?;
+            wallet.read_wallet(&chain);
+            wallet.read_mempool(&mempool);
+
+            let psbt = wallet
+                .create_psbt(
+                    &chain,
+                    &Id::new(&address),
+                    amount,
+                    Fee::Absolute(fee),
+                    coin_selection.selector().as_ref(),
+                )
+                .map_err(|_| CliError::InsufficientFunds {
+                    available: wallet.total_credits,
+                    requested: amount.saturating_add(fee),
+                })?;
+            writeln!(out, "{}", psbt.to_json())?;
+        }
+        Command::Wallet(WalletCommand::Psbt(PsbtCommand::Inspect { file })) => {
+            let psbt = read_psbt(&file)?;
+            let fee = psbt.check()?;
+            for input in &psbt.inputs {
+                writeln!(
+                    out,
+                    "Spend: {} ({} coins) from {}",
+                    input.outpoint, input.amount, input.address
+                )?;
+            }
+            for output in &psbt.transaction.outputs {
+                writeln!(out, "Pay: {} coins to {}", output.amount, output.recipient)?;
+            }
+            writeln!(out, "Fee: {} coins", fee)?;
+            writeln!(out, "Signatures: {}", psbt.signatures.len())?;
+        }
+        Command::Wallet(WalletCommand::Psbt(PsbtCommand::Sign { file })) => {
+            let mut psbt = read_psbt(&file)?;
+            let mut wallet = datadir.load_wallet()?;
+            unlock(&mut wallet, passphrase)?;
+            wallet.sign_psbt(&mut psbt)?;
+            writeln!(out, "{}", psbt.to_json())?;
+        }
+        Command::Wallet(WalletCommand::Psbt(PsbtCommand::Submit { file })) => {
+            let psbt = read_psbt(&file)?;
+            let signed = psbt.finalize()?;
+            let chain = datadir.open_chain(&config)?;
+            psbt.check_inputs(&chain)?;
+            let mut mempool = datadir.load_mempool(&chain)?;
+            let txid = mempool.add(&chain, signed)?;
+            datadir.save_mempool(&mempool)?;
+            writeln!(out, "{}", txid)?;
+        }
+        Command::Mine { blocks, address } => {
+            let miner = match address {
+                Some(address) => check_address(&address).map(|_| address)?,
+                None => datadir.load_wallet()?.id.id,
+            };
+            let mut chain = datadir.open_chain(&config)?;
+            let mut mempool = datadir.load_mempool(&chain)?;
+
+            for _ in 0..blocks {
+                chain.mine_block(mempool.block_template(&chain, &miner, DEFAULT_BLOCK_SIZE))?;
+                mempool.evict_stale(&chain);
+                let block = chain.chain.last().unwrap();
+                writeln!(out, "Block {}: {}", block.index, block.hash())?;
+            }
+            datadir.save_mempool(&mempool)?;
+        }
+        Command::Block(BlockCommand::Get { id }) => {
+            let chain = datadir.open_chain(&config)?;
+            let block = match id.parse::<usize>() {
+                Ok(height) => chain.chain.get(height),
+                Err(_) => chain.tree().get(&id).map(|node| &node.block),
+            }
+            .ok_or(CliError::UnknownBlock(id))?;
+            writeln!(out, "Hash: {}", block.hash())?;
+            writeln!(out, "{}", block)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Starts the peer-to-peer node on the data directory's chain and pending transactions, connects
+// it to the configured peers and serves JSON-RPC with the wallet, unlocked with `passphrase`, to
+// the clients holding the configured token or the cookie.
+pub fn start_node(
+    datadir: &DataDir,
+    config: &Config,
+    passphrase: Option<&str>,
+) -> Result<(Arc<Node>, RpcServer), CliError> {
+    let chain = datadir.open_chain(config)?;
+    let pending = datadir.load_mempool(&chain)?;
+    let mut wallet = datadir.load_wallet()?;
+    if passphrase.is_some() {
+        unlock(&mut wallet, passphrase)?;
+    }
+
+    let node = Arc::new(Node::start(config.listen.as_str(), chain)?);
+    for (_, entry) in pending.entries() {
+        node.submit_transaction(entry.tx.clone())?;
+    }
+    for peer in &config.peers {
+        if let Err(error) = node.connect(peer.as_str()) {
+            log::warn!("Could not connect to {}: {}", peer, error);
+        }
+    }
+
+    let auth = match config.rpcauth.as_deref() {
+        Some(token) if !token.is_empty() => RpcAuth::token(token),
+        _ => RpcAuth::cookie(&datadir.cookie_path())?,
+    };
+    let context = Arc::new(RpcContext::new(Arc::clone(&node), wallet));
+    let server = RpcServer::start(config.rpc.as_str(), context, auth)?;
+    Ok((node, server))
+}
+
+// Stops serving, then keeps what the mempool holds for the next start.
+pub fn stop_node(
+    datadir: &DataDir,
+    node: Arc<Node>,
+    mut server: RpcServer,
+) -> Result<(), CliError> {
+    server.shutdown();
+    datadir.save_mempool(&node.mempool())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,320 @@
+use super::config::*;
+use super::*;
+
+use crate::blockchain::chain::*;
+use crate::blockchain::params::*;
+
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::Path;
+
+fn temp_datadir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "rust-blockchain-cli-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    fs::write(
+        path.join(CONFIG_FILE),
+        "difficulty = 1\ncoinbase_maturity = 0\n",
+    )
+    .unwrap();
+    path
+}
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+// Runs the command line `args` against `datadir`, returning what it printed.
+fn execute(datadir: &Path, args: &[&str]) -> Result<String, CliError> {
+    let mut args = args.to_vec();
+    args.extend(["--passphrase", PASSPHRASE]);
+    execute_locked(datadir, &args)
+}
+
+// Same, without the wallet passphrase.
+fn execute_locked(datadir: &Path, args: &[&str]) -> Result<String, CliError> {
+    let datadir = datadir.to_str().unwrap();
+    let cli =
+        Cli::try_parse_from(["rust-blockchain", "--datadir", datadir].iter().chain(args)).unwrap();
+    let mut out = vec![];
+    run(cli, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn wallet_and_chain_commands_share_a_data_directory() {
+    let datadir = temp_datadir("commands");
+    let theirs = Wallet::new().id.id;
+
+    assert!(matches!(
+        execute_locked(&datadir, &["wallet", "new"]),
+        Err(CliError::PassphraseRequired)
+    ));
+    let created = execute(&datadir, &["wallet", "new"]).unwrap();
+    let (address, phrase) = created.split_once('\n').unwrap();
+    let phrase = phrase.trim().strip_prefix("Recovery phrase: ").unwrap();
+    assert_eq!(phrase.split(' ').count(), 12);
+    assert!(matches!(
+        execute(&datadir, &["wallet", "new"]),
+        Err(CliError::WalletExists(_))
+    ));
+    assert!(matches!(
+        execute(&datadir, &["wallet", "send", &theirs, "1"]),
+        Err(CliError::InsufficientFunds { .. })
+    ));
+
+    // mining and balances only need the address, spending needs the key
+    let mined = execute_locked(&datadir, &["mine", "--blocks", "2"]).unwrap();
+    assert_eq!(mined.lines().count(), 2);
+    assert!(execute_locked(&datadir, &["wallet", "balance"])
+        .unwrap()
+        .contains("Spendable: 100 coins"));
+
+    assert!(matches!(
+        execute_locked(&datadir, &["wallet", "send", &theirs, "30"]),
+        Err(CliError::PassphraseRequired)
+    ));
+    assert!(matches!(
+        execute_locked(
+            &datadir,
+            &["wallet", "send", &theirs, "30", "--passphrase", "guess"]
+        ),
+        Err(CliError::Keystore(KeystoreError::WrongPassphrase))
+    ));
+
+    // the payment waits in the data directory until the next block is mined
+    let txid = execute(
+        &datadir,
+        &[
+            "wallet",
+            "send",
+            &theirs,
+            "30",
+            "--fee",
+            "1",
+            "--coin-selection",
+            "smallest-first",
+        ],
+    )
+    .unwrap();
+    let balance = execute(&datadir, &["wallet", "balance"]).unwrap();
+    assert!(balance.contains("Confirmed: 100 coins"));
+    assert!(balance.contains("Unconfirmed: 19 coins"));
+    assert!(balance.contains("Spendable: 50 coins"));
+    assert!(execute(&datadir, &["wallet", "history"])
+        .unwrap()
+        .ends_with(&format!(
+            "{} -31 sent unconfirmed, fee 1, {}...\n",
+            txid.trim(),
+            Id::new(&theirs)
+        )));
+    assert!(matches!(
+        execute(&datadir, &["wallet", "send", "not an address", "1"]),
+        Err(CliError::InvalidAddress(_))
+    ));
+    let typo = theirs.replacen(
+        &theirs[5..6],
+        if &theirs[5..6] == "x" { "y" } else { "x" },
+        1,
+    );
+    assert!(matches!(
+        execute(&datadir, &["wallet", "send", &typo, "1"]),
+        Err(CliError::InvalidAddress(AddressError::Checksum))
+    ));
+
+    execute(&datadir, &["mine", "--address", &theirs]).unwrap();
+    let chain = BlockChain::open(&datadir.join(CHAIN_DIR)).unwrap();
+    assert_eq!(chain.chain.len(), 3);
+    assert_eq!(chain.utxos().balance(&theirs), 30 + 50 + 1);
+    // the change goes to the first address of the internal chain
+    let change = Wallet::load(&datadir.join(WALLET_FILE))
+        .unwrap()
+        .change_addresses()[0]
+        .clone();
+    assert_eq!(chain.utxos().balance(address), 50);
+    assert_eq!(chain.utxos().balance(&change), 50 - 31);
+    assert!(chain.transaction_proof(txid.trim()).is_some());
+
+    let history = execute(&datadir, &["wallet", "history"]).unwrap();
+    assert_eq!(history.lines().count(), 3);
+    assert!(history.contains(" -31 sent in block 2 (1 confirmations)"));
+
+    let verified = execute(&datadir, &["chain", "verify"]).unwrap();
+    assert!(verified.starts_with("3 blocks verified"));
+    assert!(execute(&datadir, &["chain", "show"])
+        .unwrap()
+        .contains("Block: 2:"));
+
+    let tip = chain.get_last_hash().unwrap();
+    let by_height = execute(&datadir, &["block", "get", "2"]).unwrap();
+    assert_eq!(
+        by_height,
+        execute(&datadir, &["block", "get", &tip]).unwrap()
+    );
+    assert!(by_height.starts_with(&format!("Hash: {}", tip)));
+    assert!(matches!(
+        execute(&datadir, &["block", "get", "7"]),
+        Err(CliError::UnknownBlock(_))
+    ));
+
+    // the key survives a restart of the wallet
+    let mut wallet = DataDir::new(datadir.clone()).load_wallet().unwrap();
+    assert_eq!(wallet.id.id, address);
+    wallet.unlock(PASSPHRASE).unwrap();
+
+    // fresh addresses come after the used one, whether the wallet is locked or not
+    let fresh = execute_locked(&datadir, &["wallet", "address"]).unwrap();
+    assert_eq!(fresh.trim(), wallet.addresses()[1]);
+    assert_eq!(fresh, execute(&datadir, &["wallet", "address"]).unwrap());
+
+    // the recovery phrase brings the wallet and its coins back elsewhere
+    let elsewhere = temp_datadir("restore");
+    fs::create_dir_all(elsewhere.join(CHAIN_DIR)).unwrap();
+    for entry in fs::read_dir(datadir.join(CHAIN_DIR)).unwrap() {
+        // the active chain is enough, side branches are left behind
+        let entry = entry.unwrap();
+        if !entry.file_type().unwrap().is_file() {
+            continue;
+        }
+        fs::copy(
+            entry.path(),
+            elsewhere.join(CHAIN_DIR).join(entry.file_name()),
+        )
+        .unwrap();
+    }
+    assert!(matches!(
+        execute(&elsewhere, &["wallet", "restore", "abandon ship"]),
+        Err(CliError::Hd(_))
+    ));
+    let restored = execute(&elsewhere, &["wallet", "restore", phrase]).unwrap();
+    assert_eq!(
+        restored,
+        format!("{}\nSpendable: {} coins\n", address, 2 * 50 - 31)
+    );
+}
+
+#[test]
+fn partially_signed_transactions_are_signed_from_a_file() {
+    let datadir = temp_datadir("psbt");
+    let theirs = Wallet::new().id.id;
+    let created = execute(&datadir, &["wallet", "new"]).unwrap();
+    let address = created.lines().next().unwrap();
+    execute_locked(&datadir, &["mine", "--blocks", "2"]).unwrap();
+
+    // built without the passphrase, signed with it, submitted without it again
+    let unsigned = datadir.join("unsigned.json");
+    let created = execute_locked(
+        &datadir,
+        &["wallet", "psbt", "create", &theirs, "30", "--fee", "1"],
+    )
+    .unwrap();
+    fs::write(&unsigned, created).unwrap();
+    let unsigned = unsigned.to_str().unwrap();
+    let inspected = execute_locked(&datadir, &["wallet", "psbt", "inspect", unsigned]).unwrap();
+    assert!(inspected.contains(&format!("Pay: 30 coins to {}", theirs)));
+    assert!(inspected.ends_with("Fee: 1 coins\nSignatures: 0\n"));
+    assert!(matches!(
+        execute_locked(&datadir, &["wallet", "psbt", "submit", unsigned]),
+        Err(CliError::Psbt(PsbtError::Unsigned))
+    ));
+    assert!(matches!(
+        execute_locked(&datadir, &["wallet", "psbt", "sign", unsigned]),
+        Err(CliError::PassphraseRequired)
+    ));
+
+    let signed = datadir.join("signed.json");
+    fs::write(
+        &signed,
+        execute(&datadir, &["wallet", "psbt", "sign", unsigned]).unwrap(),
+    )
+    .unwrap();
+    let signed = signed.to_str().unwrap();
+    let txid = execute_locked(&datadir, &["wallet", "psbt", "submit", signed]).unwrap();
+    execute_locked(&datadir, &["mine", "--address", &theirs]).unwrap();
+    let chain = BlockChain::open(&datadir.join(CHAIN_DIR)).unwrap();
+    assert!(chain.transaction_proof(txid.trim()).is_some());
+    let change = Wallet::load(&datadir.join(WALLET_FILE))
+        .unwrap()
+        .change_addresses()[0]
+        .clone();
+    assert_eq!(chain.utxos().balance(address), 50);
+    assert_eq!(chain.utxos().balance(&change), 50 - 31);
+    assert!(matches!(
+        execute_locked(&datadir, &["wallet", "psbt", "submit", signed]),
+        Err(CliError::Psbt(PsbtError::UnknownInput(_)))
+    ));
+}
+
+#[test]
+fn configuration_is_read_from_the_data_directory() {
+    let datadir = temp_datadir("config");
+    fs::write(
+        datadir.join(CONFIG_FILE),
+        "difficulty = 2\ncoinbase_maturity = 0\nlisten = \"127.0.0.1:0\"\nrpc = \"127.0.0.1:0\"\n",
+    )
+    .unwrap();
+    let dir = DataDir::new(datadir.clone());
+    assert_eq!(
+        Config::default().coinbase_maturity as u128,
+        DEFAULT_COINBASE_MATURITY
+    );
+    let config = dir.config().unwrap();
+    assert_eq!(config.difficulty, 2);
+    assert_eq!(config.coinbase_maturity, 0);
+    assert!(config.peers.is_empty());
+
+    // a node started on the data directory serves its chain and its wallet over RPC
+    execute(&datadir, &["wallet", "new", "--key-type", "secp256k1"]).unwrap();
+    execute(&datadir, &["mine"]).unwrap();
+    let (node, server) = start_node(&dir, &config, None).unwrap();
+    assert_eq!(node.chain().chain.len(), 1);
+
+    // without a token in the configuration, clients read the one the node left in its cookie
+    let token = fs::read_to_string(dir.cookie_path()).unwrap();
+    let request = json!({ "jsonrpc": "2.0", "method": "getbalance", "id": 1 }).to_string();
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        token,
+        request.len(),
+        request
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(serde_json::from_str::<Value>(body).unwrap()["result"], 50);
+
+    // the data directory is the node's until it stops
+    assert!(matches!(
+        execute_locked(&datadir, &["mine"]),
+        Err(CliError::DataDirLocked(_))
+    ));
+
+    // transactions the node received are kept for its next start
+    let mut wallet = dir.load_wallet().unwrap();
+    wallet.unlock(PASSPHRASE).unwrap();
+    wallet.read_wallet(&node.chain());
+    let transaction = wallet
+        .create_transaction(&Id::new(&Wallet::new().id.id), 5)
+        .unwrap();
+    let txid = node
+        .submit_transaction(wallet.sign_transaction(&transaction).unwrap())
+        .unwrap();
+    stop_node(&dir, node, server).unwrap();
+    assert!(!dir.cookie_path().exists());
+    drop(dir);
+    let dir = DataDir::new(datadir.clone());
+    let chain = dir.open_chain(&config).unwrap();
+    assert_eq!(chain.params().coinbase_maturity, 0);
+    assert!(dir.load_mempool(&chain).unwrap().entry(&txid).is_some());
+
+    fs::write(datadir.join(CONFIG_FILE), "difficulty = \"hard\"\n").unwrap();
+    assert!(matches!(dir.config(), Err(CliError::Config(_))));
+}
@@ -0,0 +1,69 @@
+use crate::blockchain::error::*;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum NetworkError {
+    Io(io::Error),
+    Decode(serde_json::Error),
+    MessageTooLarge(usize),
+    TooManyHeaders(usize),
+    InvalidHeader(Box<ChainError>),
+    InvalidBlock(Box<ChainError>),
+    UnexpectedMessage(&'static str),
+    IncompatibleVersion(u32),
+    SelfConnection,
+    Disconnected,
+}
+
+impl From<io::Error> for NetworkError {
+    fn from(error: io::Error) -> Self {
+        NetworkError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for NetworkError {
+    fn from(error: serde_json::Error) -> Self {
+        NetworkError::Decode(error)
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Io(error) => write!(f, "network I/O error: {}", error),
+            NetworkError::Decode(error) => write!(f, "malformed message: {}", error),
+            NetworkError::MessageTooLarge(limit) => {
+                write!(f, "message exceeds {} bytes", limit)
+            }
+            NetworkError::TooManyHeaders(count) => {
+                write!(f, "{} headers in a single message", count)
+            }
+            NetworkError::InvalidHeader(error) => write!(f, "invalid header: {}", error),
+            NetworkError::InvalidBlock(error) => write!(f, "invalid block: {}", error),
+            NetworkError::UnexpectedMessage(name) => {
+                write!(f, "unexpected {} message before the handshake", name)
+            }
+            NetworkError::IncompatibleVersion(version) => {
+                write!(f, "peer speaks protocol version {}", version)
+            }
+            NetworkError::SelfConnection => write!(f, "connected to ourselves"),
+            NetworkError::Disconnected => write!(f, "peer disconnected"),
+        }
+    }
+}
+
+impl Error for NetworkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NetworkError::Io(error) => Some(error),
+            NetworkError::Decode(error) => Some(error),
+            NetworkError::InvalidHeader(error) | NetworkError::InvalidBlock(error) => {
+                Some(error.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
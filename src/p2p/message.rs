@@ -0,0 +1,73 @@
+use crate::blockchain::block::*;
+use crate::blockchain::signedtransaction::*;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "hash", rename_all = "snake_case")]
+pub enum Inventory {
+    Block(String),
+    Tx(String),
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inventory::Block(hash) => {
+                write!(f, "block {}", crate::blockchain::abbreviate(hash, 10))
+            }
+            Inventory::Tx(txid) => write!(f, "tx {}", crate::blockchain::abbreviate(txid, 10)),
+        }
+    }
+}
+
+// First message on every connection, in both directions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u32,
+    // Random per-node value, used to notice connections to ourselves.
+    pub nonce: u64,
+    // Where the sender accepts connections, if anywhere.
+    pub listen_addr: Option<String>,
+    pub height: Option<u128>,
+    pub tip: Option<String>,
+    // Cumulative work of the tip, as a hex number.
+    pub work: String,
+}
+
+// Every message travels as a single line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum Message {
+    Version(Version),
+    Verack,
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    // Asks for the headers following the first locator hash we share.
+    GetHeaders(Vec<String>),
+    Headers(Vec<BlockHeader>),
+    Block(Block),
+    Tx(SignedTransaction),
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+        }
+    }
+}
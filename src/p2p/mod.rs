@@ -0,0 +1,29 @@
+pub mod error;
+pub mod message;
+pub mod node;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Longest line a peer may send; a block template is capped well below it.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+// Connections accepted from other nodes; ours to them are not counted.
+pub const MAX_INBOUND_PEERS: usize = 32;
+
+// Most entries in an inventory or getdata message.
+pub const MAX_INVENTORY: usize = 500;
+
+// Most headers in a headers message. A full batch means the peer has more to send.
+pub const MAX_HEADERS: usize = 2000;
+
+// Blocks requested from a single peer at once while syncing.
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+
+// How far past our tip blocks get downloaded, out of order and from several peers.
+pub const DOWNLOAD_WINDOW: usize = 128;
+
+// A block not delivered within this many seconds is asked to another peer.
+pub const BLOCK_DOWNLOAD_TIMEOUT: u64 = 10;
+
+#[cfg(test)]
+mod tests;
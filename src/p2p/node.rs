@@ -0,0 +1,731 @@
+use super::error::*;
+use super::message::*;
+use super::*;
+
+use crate::blockchain::block::*;
+use crate::blockchain::chain::*;
+use crate::blockchain::error::*;
+use crate::blockchain::mempool::*;
+use crate::blockchain::signedtransaction::*;
+use crate::blockchain::tree::*;
+use crate::blockchain::Hashable;
+
+use primitive_types::U256;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub type PeerId = u64;
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    pub inbound: bool,
+    // Set once the peer introduced itself.
+    pub version: Option<Version>,
+    // Highest block the peer is known to have, from its version, headers and blocks.
+    pub best_height: Option<u128>,
+}
+
+struct Peer {
+    info: PeerInfo,
+    outgoing: Sender<Message>,
+    stream: TcpStream,
+    // Inventory the peer is known to have, so it is never announced back.
+    known: HashSet<Inventory>,
+    // Blocks the peer answered `notfound` for, never asked to it again.
+    unavailable: HashSet<String>,
+}
+
+// Headers-first download state: the blocks of the best header chain are requested out of order
+// from every peer that has them, and connected once their parent made it into the tree.
+#[derive(Default)]
+struct SyncState {
+    // Requested blocks, with the peer asked and when.
+    in_flight: HashMap<String, (PeerId, Instant)>,
+    // Blocks received ahead of their parent, by hash.
+    downloaded: HashMap<String, Block>,
+}
+
+struct Shared {
+    nonce: u64,
+    listen_addr: SocketAddr,
+    chain: Mutex<BlockChain>,
+    mempool: Mutex<Mempool>,
+    peers: Mutex<HashMap<PeerId, Peer>>,
+    sync: Mutex<SyncState>,
+    next_peer: AtomicU64,
+    shutting_down: AtomicBool,
+}
+
+// A node owns a chain and a mempool and keeps them in sync with its peers. Every connection
+// gets a reader thread, handling the messages of the peer, and a writer thread, draining a
+// queue so that a slow peer never blocks the others.
+//
+// Locks are always taken in the order chain, mempool, peers, sync.
+pub struct Node {
+    shared: Arc<Shared>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Node {
+    pub fn start<A: ToSocketAddrs>(addr: A, chain: BlockChain) -> Result<Self, NetworkError> {
+        let listener = TcpListener::bind(addr)?;
+        let mut nonce = [0u8; 8];
+        openssl::rand::rand_bytes(&mut nonce).map_err(std::io::Error::other)?;
+
+        let shared = Arc::new(Shared {
+            nonce: u64::from_be_bytes(nonce),
+            listen_addr: listener.local_addr()?,
+            chain: Mutex::new(chain),
+            mempool: Mutex::new(Mempool::new()),
+            peers: Mutex::new(HashMap::new()),
+            sync: Mutex::new(SyncState::default()),
+            next_peer: AtomicU64::new(0),
+            shutting_down: AtomicBool::new(false),
+        });
+
+        log::info!("Listening on {}", shared.listen_addr);
+        let accepting = Arc::clone(&shared);
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) if accepting.inbound_full() => {
+                        log::debug!("Refusing {:?}: too many inbound peers", stream.peer_addr());
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    Ok(stream) => {
+                        if let Err(error) = accepting.register(stream, true) {
+                            log::warn!("Inbound connection failed: {}", error);
+                        }
+                    }
+                    Err(error) => log::warn!("Accept failed: {}", error),
+                }
+            }
+        });
+
+        Ok(Self {
+            shared,
+            listener: Some(listener),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.listen_addr
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<PeerId, NetworkError> {
+        let stream = TcpStream::connect(addr)?;
+        self.shared.register(stream, false)
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let peers = self.shared.peers.lock().unwrap();
+        let mut infos: Vec<PeerInfo> = peers.values().map(|peer| peer.info.clone()).collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, BlockChain> {
+        self.shared.chain.lock().unwrap()
+    }
+
+    // Height of the best validated header, ahead of our tip while blocks are downloading.
+    pub fn best_header_height(&self) -> Option<u128> {
+        self.chain().best_header().map(|node| node.header.index)
+    }
+
+    pub fn mempool(&self) -> MutexGuard<'_, Mempool> {
+        self.shared.mempool.lock().unwrap()
+    }
+
+    // Adds a transaction to our mempool and announces it.
+    pub fn submit_transaction(&self, tx: SignedTransaction) -> Result<String, MempoolError> {
+        let txid = {
+            let chain = self.shared.chain.lock().unwrap();
+            self.shared.mempool.lock().unwrap().add(&chain, tx)?
+        };
+        self.shared.announce(Inventory::Tx(txid.clone()), None);
+        Ok(txid)
+    }
+
+    pub fn submit_block(&self, block: Block) -> Result<BlockAcceptance, ChainError> {
+        self.shared.process_block(block, None)
+    }
+
+    // Mines the mempool's block template on top of our tip, paying `miner`, and announces it.
+    // The chain is only locked to build the template and to submit the block, so peers and RPC
+    // clients are served while the nonce is searched for; if another block took the tip
+    // meanwhile, mining starts over on top of it.
+    pub fn mine_block(&self, miner: &str) -> Result<Block, ChainError> {
+        loop {
+            let mut block = {
+                let chain = self.shared.chain.lock().unwrap();
+                let template = self.shared.mempool.lock().unwrap().block_template(
+                    &chain,
+                    miner,
+                    DEFAULT_BLOCK_SIZE,
+                );
+                chain.prepare_block(template)?
+            };
+            block.solve();
+
+            if let BlockAcceptance::MainChain(_) = self.shared.process_block(block.clone(), None)? {
+                return Ok(block);
+            }
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        for (_, peer) in self.shared.peers.lock().unwrap().drain() {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+
+        // wakes the listener up so that it notices the shutdown
+        let _ = TcpStream::connect(self.shared.listen_addr);
+        let _ = listener.join();
+        log::info!("Node {} stopped", self.shared.listen_addr);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Shared {
+    fn register(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        inbound: bool,
+    ) -> Result<PeerId, NetworkError> {
+        let addr = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let mut writer = stream.try_clone()?;
+
+        let id = self.next_peer.fetch_add(1, Ordering::SeqCst);
+        let (outgoing, queue) = mpsc::channel::<Message>();
+
+        thread::spawn(move || {
+            for message in queue {
+                let mut line = match serde_json::to_vec(&message) {
+                    Ok(line) => line,
+                    Err(error) => {
+                        log::warn!("Cannot encode {}: {}", message.name(), error);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                if writer
+                    .write_all(&line)
+                    .and_then(|_| writer.flush())
+                    .is_err()
+                {
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+
+        let version = self.version();
+        let _ = outgoing.send(Message::Version(version));
+
+        self.peers.lock().unwrap().insert(
+            id,
+            Peer {
+                info: PeerInfo {
+                    id,
+                    addr,
+                    inbound,
+                    version: None,
+                    best_height: None,
+                },
+                outgoing,
+                stream,
+                known: HashSet::new(),
+                unavailable: HashSet::new(),
+            },
+        );
+
+        log::debug!(
+            "Peer {} connected ({})",
+            addr,
+            if inbound { "inbound" } else { "outbound" }
+        );
+
+        let shared = Arc::clone(self);
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let result = loop {
+                let handled =
+                    read_message(&mut reader).and_then(|message| shared.handle(id, message));
+                if let Err(error) = handled {
+                    break error;
+                }
+            };
+
+            match result {
+                NetworkError::Disconnected => log::debug!("Peer {} disconnected", addr),
+                error if !shared.shutting_down.load(Ordering::SeqCst) => {
+                    log::warn!("Dropping peer {}: {}", addr, error)
+                }
+                _ => {}
+            }
+
+            if let Some(peer) = shared.peers.lock().unwrap().remove(&id) {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+            }
+
+            // whatever it was sending us gets asked to the remaining peers
+            shared
+                .sync
+                .lock()
+                .unwrap()
+                .in_flight
+                .retain(|_, (peer, _)| *peer != id);
+            if !shared.shutting_down.load(Ordering::SeqCst) {
+                shared.schedule_downloads();
+            }
+        });
+
+        Ok(id)
+    }
+
+    fn inbound_full(&self) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.values().filter(|peer| peer.info.inbound).count() >= MAX_INBOUND_PEERS
+    }
+
+    fn version(&self) -> Version {
+        let chain = self.chain.lock().unwrap();
+        Version {
+            version: PROTOCOL_VERSION,
+            nonce: self.nonce,
+            listen_addr: Some(self.listen_addr.to_string()),
+            height: chain.get_last_index(),
+            tip: chain.get_last_hash(),
+            work: format!("{:x}", chain.cumulative_work()),
+        }
+    }
+
+    fn send(&self, id: PeerId, message: Message) {
+        if let Some(peer) = self.peers.lock().unwrap().get(&id) {
+            let _ = peer.outgoing.send(message);
+        }
+    }
+
+    fn mark_known(&self, id: PeerId, items: &[Inventory]) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+            peer.known.extend(items.iter().cloned());
+        }
+    }
+
+    // Tells every ready peer but `source` about `item`, unless it already has it.
+    fn announce(&self, item: Inventory, source: Option<PeerId>) {
+        let mut peers = self.peers.lock().unwrap();
+        for (id, peer) in peers.iter_mut() {
+            if Some(*id) == source || peer.info.version.is_none() || peer.known.contains(&item) {
+                continue;
+            }
+            peer.known.insert(item.clone());
+            let _ = peer.outgoing.send(Message::Inv(vec![item.clone()]));
+        }
+    }
+
+    fn process_block(
+        &self,
+        block: Block,
+        source: Option<PeerId>,
+    ) -> Result<BlockAcceptance, ChainError> {
+        let hash = block.hash();
+        let result = {
+            let mut chain = self.chain.lock().unwrap();
+            let result = chain.accept_block(block);
+            if let Ok(BlockAcceptance::MainChain(report)) = &result {
+                let mut mempool = self.mempool.lock().unwrap();
+                if report.is_reorg() {
+                    mempool.reorganized(&chain, report);
+                } else {
+                    mempool.evict_stale(&chain);
+                }
+            }
+            result
+        };
+
+        match &result {
+            Ok(BlockAcceptance::MainChain(_)) | Ok(BlockAcceptance::SideBranch) => {
+                self.announce(Inventory::Block(hash), source)
+            }
+            Ok(BlockAcceptance::AlreadyKnown) => {}
+            Err(ChainError::UnknownParent { .. }) => {
+                // we missed some blocks: ask the peer for the headers leading to it
+                if let Some(id) = source {
+                    let locator = header_locator(&self.chain.lock().unwrap());
+                    self.send(id, Message::GetHeaders(locator));
+                }
+            }
+            Err(error) => log::warn!("Rejected block {}: {}", hash, error),
+        }
+
+        result
+    }
+
+    // Connects the downloaded blocks that were waiting for `parent`, and their own children.
+    fn connect_downloaded(&self, parent: String) {
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Block> = {
+                let mut sync = self.sync.lock().unwrap();
+                let hashes: Vec<String> = sync
+                    .downloaded
+                    .iter()
+                    .filter(|(_, block)| block.previous_block == parent)
+                    .map(|(hash, _)| hash.clone())
+                    .collect();
+                hashes
+                    .iter()
+                    .filter_map(|hash| sync.downloaded.remove(hash))
+                    .collect()
+            };
+
+            for child in children {
+                let hash = child.hash();
+                if self.process_block(child, None).is_ok() {
+                    parents.push(hash);
+                }
+            }
+        }
+    }
+
+    // Spreads the missing blocks of the best header chain over the peers having them, least
+    // busy first. Requests of gone peers and requests timing out are handed out again.
+    fn schedule_downloads(&self) {
+        let wanted = self
+            .chain
+            .lock()
+            .unwrap()
+            .blocks_to_download(DOWNLOAD_WINDOW);
+        if wanted.is_empty() {
+            return;
+        }
+
+        let peers = self.peers.lock().unwrap();
+        let mut sync = self.sync.lock().unwrap();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(BLOCK_DOWNLOAD_TIMEOUT);
+        sync.in_flight.retain(|hash, (peer, since)| {
+            let pending = peers.contains_key(peer) && now.duration_since(*since) < timeout;
+            if !pending {
+                log::debug!("Block {} is asked again", hash);
+            }
+            pending
+        });
+
+        let mut load: HashMap<PeerId, usize> = HashMap::new();
+        for (peer, _) in sync.in_flight.values() {
+            *load.entry(*peer).or_default() += 1;
+        }
+
+        let mut requests: HashMap<PeerId, Vec<Inventory>> = HashMap::new();
+        for (hash, height) in wanted {
+            if sync.in_flight.contains_key(&hash) || sync.downloaded.contains_key(&hash) {
+                continue;
+            }
+
+            let candidate = peers
+                .values()
+                .filter(|peer| {
+                    peer.info.version.is_some()
+                        && peer.info.best_height.is_some_and(|best| best >= height)
+                        && !peer.unavailable.contains(&hash)
+                })
+                .map(|peer| (load.get(&peer.info.id).copied().unwrap_or(0), peer.info.id))
+                .filter(|(busy, _)| *busy < MAX_BLOCKS_IN_FLIGHT)
+                .min();
+
+            if let Some((_, id)) = candidate {
+                *load.entry(id).or_default() += 1;
+                sync.in_flight.insert(hash.clone(), (id, now));
+                requests.entry(id).or_default().push(Inventory::Block(hash));
+            }
+        }
+
+        for (id, items) in requests {
+            if let Some(peer) = peers.get(&id) {
+                log::debug!("Requesting {} blocks from {}", items.len(), peer.info.addr);
+                let _ = peer.outgoing.send(Message::GetData(items));
+            }
+        }
+    }
+
+    fn raise_best_height(&self, id: PeerId, height: u128) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+            if peer.info.best_height.is_none_or(|best| best < height) {
+                peer.info.best_height = Some(height);
+            }
+        }
+    }
+
+    fn handle(&self, id: PeerId, message: Message) -> Result<(), NetworkError> {
+        let handshaken = match self.peers.lock().unwrap().get(&id) {
+            Some(peer) => peer.info.version.is_some(),
+            None => return Err(NetworkError::Disconnected),
+        };
+
+        match message {
+            Message::Version(version) => {
+                if version.version != PROTOCOL_VERSION {
+                    return Err(NetworkError::IncompatibleVersion(version.version));
+                }
+                if version.nonce == self.nonce {
+                    return Err(NetworkError::SelfConnection);
+                }
+
+                let (locator, our_work) = {
+                    let chain = self.chain.lock().unwrap();
+                    let work = chain
+                        .best_header()
+                        .map_or(U256::zero(), |node| node.cumulative_work);
+                    (header_locator(&chain), work)
+                };
+                let their_work = U256::from_str_radix(&version.work, 16).unwrap_or_default();
+
+                if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+                    peer.info.best_height = version.height;
+                    peer.info.version = Some(version);
+                    let _ = peer.outgoing.send(Message::Verack);
+                    if their_work > our_work {
+                        let _ = peer.outgoing.send(Message::GetHeaders(locator));
+                    }
+                }
+
+                // a new peer may take over blocks nobody else could provide
+                self.schedule_downloads();
+            }
+            message if !handshaken => return Err(NetworkError::UnexpectedMessage(message.name())),
+            Message::Verack | Message::Pong(_) => {}
+            Message::Ping(nonce) => self.send(id, Message::Pong(nonce)),
+            Message::Inv(items) => {
+                self.mark_known(id, &items);
+                // new blocks are fetched headers first, transactions right away
+                let (headers_needed, wanted, locator) = {
+                    let chain = self.chain.lock().unwrap();
+                    let mempool = self.mempool.lock().unwrap();
+                    let headers_needed = items.iter().any(|item| match item {
+                        Inventory::Block(hash) => !chain.headers().contains(hash),
+                        Inventory::Tx(_) => false,
+                    });
+                    let wanted: Vec<Inventory> = items
+                        .iter()
+                        .filter(|item| match item {
+                            Inventory::Block(_) => false,
+                            Inventory::Tx(txid) => !mempool.contains(txid),
+                        })
+                        .cloned()
+                        .collect();
+                    (headers_needed, wanted, header_locator(&chain))
+                };
+
+                if let Some(peer) = self.peers.lock().unwrap().get(&id) {
+                    if headers_needed {
+                        let _ = peer.outgoing.send(Message::GetHeaders(locator));
+                    }
+                    if !wanted.is_empty() {
+                        let _ = peer.outgoing.send(Message::GetData(wanted));
+                    }
+                }
+            }
+            Message::GetData(items) => {
+                let mut replies = vec![];
+                let mut missing = vec![];
+                {
+                    let chain = self.chain.lock().unwrap();
+                    let mempool = self.mempool.lock().unwrap();
+                    for item in items.into_iter().take(MAX_INVENTORY) {
+                        let reply = match &item {
+                            Inventory::Block(hash) => chain
+                                .tree()
+                                .get(hash)
+                                .map(|node| Message::Block(node.block.clone())),
+                            Inventory::Tx(txid) => mempool.get(txid).cloned().map(Message::Tx),
+                        };
+                        match reply {
+                            Some(reply) => replies.push((item, reply)),
+                            None => missing.push(item),
+                        }
+                    }
+                }
+
+                if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+                    for (item, reply) in replies {
+                        peer.known.insert(item);
+                        let _ = peer.outgoing.send(reply);
+                    }
+                    if !missing.is_empty() {
+                        let _ = peer.outgoing.send(Message::NotFound(missing));
+                    }
+                }
+            }
+            Message::NotFound(items) => {
+                log::debug!("Peer {} does not have {} items", id, items.len());
+                let blocks: Vec<String> = items
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        Inventory::Block(hash) => Some(hash),
+                        Inventory::Tx(_) => None,
+                    })
+                    .collect();
+
+                if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+                    peer.unavailable.extend(blocks.iter().cloned());
+                }
+                let mut sync = self.sync.lock().unwrap();
+                for hash in blocks.iter() {
+                    if sync
+                        .in_flight
+                        .get(hash)
+                        .is_some_and(|(peer, _)| *peer == id)
+                    {
+                        sync.in_flight.remove(hash);
+                    }
+                }
+                drop(sync);
+                self.schedule_downloads();
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self
+                    .chain
+                    .lock()
+                    .unwrap()
+                    .headers_after(&locator, MAX_HEADERS);
+
+                if !headers.is_empty() {
+                    let items: Vec<Inventory> = headers
+                        .iter()
+                        .map(|header| Inventory::Block(header.hash()))
+                        .collect();
+                    self.mark_known(id, &items);
+                    self.send(id, Message::Headers(headers));
+                }
+            }
+            Message::Headers(headers) => {
+                if headers.len() > MAX_HEADERS {
+                    return Err(NetworkError::TooManyHeaders(headers.len()));
+                }
+
+                let full_batch = headers.len() == MAX_HEADERS;
+                let mut last = None;
+                let mut next_request = None;
+                {
+                    let mut chain = self.chain.lock().unwrap();
+                    for header in headers {
+                        let (hash, index) = (header.hash(), header.index);
+                        match chain.accept_header(header) {
+                            Ok(_) => last = Some((hash, index)),
+                            Err(ChainError::UnknownParent { .. }) if last.is_none() => {
+                                // not connected to our headers: start over from the best one
+                                next_request = Some(header_locator(&chain));
+                                break;
+                            }
+                            Err(error) => return Err(NetworkError::InvalidHeader(Box::new(error))),
+                        }
+                    }
+
+                    if full_batch && next_request.is_none() {
+                        next_request = last
+                            .as_ref()
+                            .map(|(hash, _)| chain.locator_from(Some(hash.clone())));
+                    }
+                }
+
+                if let Some((_, index)) = last {
+                    self.raise_best_height(id, index);
+                }
+                if let Some(locator) = next_request {
+                    self.send(id, Message::GetHeaders(locator));
+                }
+                self.schedule_downloads();
+            }
+            Message::Block(block) => {
+                let hash = block.hash();
+                self.mark_known(id, &[Inventory::Block(hash.clone())]);
+                self.raise_best_height(id, block.index);
+                self.sync.lock().unwrap().in_flight.remove(&hash);
+
+                let waits_for_parent = {
+                    let chain = self.chain.lock().unwrap();
+                    block.index > 0
+                        && !chain.tree().contains(&block.previous_block)
+                        && chain.headers().contains(&hash)
+                };
+
+                if waits_for_parent {
+                    self.sync.lock().unwrap().downloaded.insert(hash, block);
+                } else {
+                    match self.process_block(block, Some(id)) {
+                        Ok(_) => self.connect_downloaded(hash),
+                        // the headers leading to it have been asked for
+                        Err(ChainError::UnknownParent { .. }) => {}
+                        // our own failure, not the peer's
+                        Err(ChainError::Storage(_)) => {}
+                        Err(error) => return Err(NetworkError::InvalidBlock(Box::new(error))),
+                    }
+                }
+                self.schedule_downloads();
+            }
+            Message::Tx(tx) => {
+                let txid = tx.hash();
+                self.mark_known(id, &[Inventory::Tx(txid.clone())]);
+                let added = {
+                    let chain = self.chain.lock().unwrap();
+                    self.mempool.lock().unwrap().add(&chain, tx)
+                };
+                match added {
+                    Ok(txid) => self.announce(Inventory::Tx(txid), Some(id)),
+                    Err(error) => log::debug!("Transaction {} not relayed: {}", txid, error),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Locator of our best header, so that a peer only sends headers we do not have yet.
+fn header_locator(chain: &BlockChain) -> Vec<String> {
+    chain.locator_from(chain.best_header().map(|node| node.hash.clone()))
+}
+
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Message, NetworkError> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_line(&mut line)?;
+
+    if read == 0 {
+        return Err(NetworkError::Disconnected);
+    }
+    if read > MAX_MESSAGE_SIZE {
+        return Err(NetworkError::MessageTooLarge(MAX_MESSAGE_SIZE));
+    }
+
+    Ok(serde_json::from_str(line.trim_end())?)
+}
@@ -0,0 +1,278 @@
+use super::message::*;
+use super::node::*;
+use super::*;
+
+use crate::blockchain::block::*;
+use crate::blockchain::chain::*;
+use crate::blockchain::params::*;
+use crate::blockchain::transaction::*;
+use crate::blockchain::wallet::*;
+use crate::blockchain::Hashable;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The tests spend what they mine right away.
+fn start_node() -> Node {
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        ..ChainParams::with_difficulty(1)
+    };
+    Node::start("127.0.0.1:0", BlockChain::with_params(params)).unwrap()
+}
+
+// Polls `condition` until it holds, failing the test after a few seconds.
+fn wait_until<F: FnMut() -> bool>(what: &str, mut condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn handshaken(node: &Node, count: usize) -> bool {
+    let peers = node.peers();
+    peers.len() == count && peers.iter().all(|peer| peer.version.is_some())
+}
+
+fn same_tip(nodes: &[&Node]) -> bool {
+    let tip = nodes[0].chain().get_last_hash();
+    tip.is_some() && nodes.iter().all(|node| node.chain().get_last_hash() == tip)
+}
+
+// Speaks the protocol by hand, claiming to hold `chain`, to drive a node step by step.
+struct FakePeer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl FakePeer {
+    fn connect(node: &Node, chain: &BlockChain, nonce: u64) -> Self {
+        let writer = TcpStream::connect(node.local_addr()).unwrap();
+        writer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut peer = FakePeer {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        };
+        peer.send(&Message::Version(Version {
+            version: PROTOCOL_VERSION,
+            nonce,
+            listen_addr: None,
+            height: chain.get_last_index(),
+            tip: chain.get_last_hash(),
+            work: format!("{:x}", chain.cumulative_work()),
+        }));
+        peer
+    }
+
+    fn send(&mut self, message: &Message) {
+        let mut line = serde_json::to_vec(message).unwrap();
+        line.push(b'\n');
+        self.writer.write_all(&line).unwrap();
+    }
+
+    // Skips messages until `select` picks one; None once the node hung up.
+    fn expect<T, F: Fn(Message) -> Option<T>>(&mut self, select: F) -> Option<T> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return None;
+            }
+            if let Some(found) = select(serde_json::from_str(&line).unwrap()) {
+                return Some(found);
+            }
+        }
+    }
+}
+
+#[test]
+fn blocks_and_transactions_are_relayed() {
+    let a = start_node();
+    let b = start_node();
+    let c = start_node();
+
+    // a line: a <-> b <-> c, so that c only hears from a through b
+    b.connect(a.local_addr()).unwrap();
+    c.connect(b.local_addr()).unwrap();
+    wait_until("handshakes", || {
+        handshaken(&a, 1) && handshaken(&b, 2) && handshaken(&c, 1)
+    });
+
+    let mut wallet1 = Wallet::new();
+    let wallet2 = Wallet::new();
+    let block = a.mine_block(&wallet1.id.id).unwrap();
+    wait_until("block relay", || same_tip(&[&a, &b, &c]));
+    assert_eq!(c.chain().get_last_hash(), Some(block.hash()));
+
+    wallet1.read_wallet(&c.chain());
+    let transaction = wallet1.create_transaction(&wallet2.id, 7).unwrap();
+    let txid = c
+        .submit_transaction(wallet1.sign_transaction(&transaction).unwrap())
+        .unwrap();
+    wait_until("transaction relay", || a.mempool().contains(&txid));
+    assert!(b.mempool().contains(&txid));
+
+    // mining it on a clears every mempool
+    a.mine_block(&wallet2.id.id).unwrap();
+    wait_until("second block", || {
+        same_tip(&[&a, &b, &c]) && c.chain().chain.len() == 2
+    });
+    wait_until("mempool eviction", || {
+        a.mempool().is_empty() && b.mempool().is_empty() && c.mempool().is_empty()
+    });
+    assert!(c.chain().transaction_proof(&txid).is_some());
+}
+
+#[test]
+fn late_peers_catch_up_on_the_heaviest_chain() {
+    let a = start_node();
+    let b = start_node();
+    let wallet = Wallet::new();
+
+    for _ in 0..3 {
+        a.mine_block(&wallet.id.id).unwrap();
+    }
+    b.mine_block(&wallet.id.id).unwrap();
+
+    // b drops its own one-block chain for the longer one of a
+    b.connect(a.local_addr()).unwrap();
+    wait_until("initial sync", || same_tip(&[&a, &b]));
+    assert_eq!(b.chain().chain.len(), 3);
+    assert!(b.chain().check_chain().is_ok());
+
+    let c = start_node();
+    c.connect(b.local_addr()).unwrap();
+    wait_until("sync through b", || same_tip(&[&a, &b, &c]));
+
+    a.mine_block(&wallet.id.id).unwrap();
+    wait_until("relay to c", || same_tip(&[&a, &b, &c]));
+    assert_eq!(c.chain().chain.len(), 4);
+}
+
+#[test]
+fn connections_to_ourselves_are_dropped() {
+    let mut a = start_node();
+    a.connect(a.local_addr()).unwrap();
+    wait_until("self connection drop", || a.peers().is_empty());
+
+    let b = start_node();
+    b.connect(a.local_addr()).unwrap();
+    wait_until("handshake", || handshaken(&a, 1) && handshaken(&b, 1));
+
+    a.shutdown();
+    wait_until("disconnection", || b.peers().is_empty());
+}
+
+#[test]
+fn headers_first_sync_spreads_and_resumes_downloads() {
+    let a = start_node();
+    let wallet = Wallet::new();
+    for _ in 0..40 {
+        a.mine_block(&wallet.id.id).unwrap();
+    }
+    let headers: Vec<BlockHeader> = a.chain().chain.iter().map(|block| block.header()).collect();
+
+    let d = start_node();
+    let mut f1 = FakePeer::connect(&d, &a.chain(), 1);
+    let locator = f1
+        .expect(|message| match message {
+            Message::GetHeaders(locator) => Some(locator),
+            _ => None,
+        })
+        .unwrap();
+    assert!(locator.is_empty());
+    f1.send(&Message::Headers(headers.clone()));
+
+    let get_data = |message| match message {
+        Message::GetData(items) => Some(items),
+        _ => None,
+    };
+    let first = f1.expect(get_data).unwrap();
+    assert_eq!(first.len(), MAX_BLOCKS_IN_FLIGHT);
+    assert_eq!(first[0], Inventory::Block(headers[0].hash()));
+    assert_eq!(d.best_header_height(), Some(39));
+    assert!(d.chain().chain.is_empty());
+
+    // a second peer holding the same headers gets the next blocks, not the same ones
+    let mut f2 = FakePeer::connect(&d, &a.chain(), 2);
+    let second = f2.expect(get_data).unwrap();
+    assert_eq!(second.len(), MAX_BLOCKS_IN_FLIGHT);
+    assert!(second.iter().all(|item| !first.contains(item)));
+
+    // both vanish without delivering: the download resumes from a real peer
+    drop(f1);
+    drop(f2);
+    wait_until("fake peers dropped", || d.peers().is_empty());
+    d.connect(a.local_addr()).unwrap();
+    wait_until("sync", || same_tip(&[&a, &d]));
+    assert_eq!(d.chain().chain.len(), 40);
+    assert!(d.chain().check_chain().is_ok());
+}
+
+#[test]
+fn invalid_headers_cost_the_connection() {
+    let a = start_node();
+    let wallet = Wallet::new();
+    for _ in 0..3 {
+        a.mine_block(&wallet.id.id).unwrap();
+    }
+    let mut headers: Vec<BlockHeader> =
+        a.chain().chain.iter().map(|block| block.header()).collect();
+    while headers[2].target.is_met_by(&headers[2].hash()) {
+        headers[2].nonce += 1;
+    }
+
+    let d = start_node();
+    let mut fake = FakePeer::connect(&d, &a.chain(), 1);
+    fake.send(&Message::Headers(headers));
+    assert!(fake.expect(|_| None::<()>).is_none());
+    wait_until("disconnection", || d.peers().is_empty());
+
+    // the valid part of the batch was kept
+    assert_eq!(d.best_header_height(), Some(1));
+}
+
+#[test]
+fn invalid_blocks_cost_the_connection() {
+    let d = start_node();
+
+    // a genesis minting more than the subsidy
+    let wallet = Wallet::new();
+    let coinbase = Transaction::coinbase(&wallet.id.id, 51);
+    let mut block = Block::new(vec![wallet.sign_transaction(&coinbase).unwrap()]);
+    block.target = ChainParams::with_difficulty(1).initial_target;
+    block.solve();
+
+    let mut fake = FakePeer::connect(&d, &BlockChain::new(1), 1);
+    fake.send(&Message::Block(block));
+    assert!(fake.expect(|_| None::<()>).is_none());
+    wait_until("disconnection", || d.peers().is_empty());
+    assert!(d.chain().chain.is_empty());
+}
+
+#[test]
+fn inbound_connections_are_limited() {
+    let d = start_node();
+    let chain = BlockChain::new(1);
+    let _peers: Vec<FakePeer> = (0..MAX_INBOUND_PEERS as u64)
+        .map(|nonce| FakePeer::connect(&d, &chain, nonce + 1))
+        .collect();
+    wait_until("inbound peers", || handshaken(&d, MAX_INBOUND_PEERS));
+
+    // closed before it says anything
+    let mut refused = TcpStream::connect(d.local_addr()).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+    assert_eq!(d.peers().len(), MAX_INBOUND_PEERS);
+
+    // outbound connections are still made
+    let a = start_node();
+    d.connect(a.local_addr()).unwrap();
+    wait_until("outbound peer", || handshaken(&d, MAX_INBOUND_PEERS + 1));
+}
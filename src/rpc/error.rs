@@ -0,0 +1,58 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+
+// Codes defined by the JSON-RPC 2.0 specification.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+// Server errors, from the range the specification leaves to implementations.
+pub const NOT_FOUND: i64 = -32001;
+pub const WALLET_ERROR: i64 = -32002;
+pub const REJECTED: i64 = -32003;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn parse_error(reason: &str) -> Self {
+        Self::new(PARSE_ERROR, &format!("parse error: {}", reason))
+    }
+
+    pub fn invalid_request(reason: &str) -> Self {
+        Self::new(INVALID_REQUEST, &format!("invalid request: {}", reason))
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(METHOD_NOT_FOUND, &format!("method not found: {}", method))
+    }
+
+    pub fn invalid_params(reason: &str) -> Self {
+        Self::new(INVALID_PARAMS, &format!("invalid params: {}", reason))
+    }
+
+    pub fn internal(reason: &str) -> Self {
+        Self::new(INTERNAL_ERROR, &format!("internal error: {}", reason))
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl Error for RpcError {}
@@ -0,0 +1,377 @@
+use super::error::*;
+
+use crate::blockchain::address::*;
+use crate::blockchain::block::*;
+use crate::blockchain::chain::*;
+use crate::blockchain::id::*;
+use crate::blockchain::mempool::*;
+use crate::blockchain::tree::*;
+use crate::blockchain::wallet::*;
+use crate::p2p::node::*;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Blocks a single `generate` call may mine: peers are still served meanwhile, since the chain is
+// only locked to build and to submit each block, but the RPC server answers nothing else.
+pub const MAX_GENERATE_BLOCKS: usize = 1000;
+
+// Everything the RPC methods act upon: the node (and through it the chain and the mempool)
+// plus the wallet paying for `sendtoaddress` and collecting what `generate` mines.
+pub struct RpcContext {
+    node: Arc<Node>,
+    wallet: Mutex<Wallet>,
+}
+
+// Parameters given either by position or by name.
+struct Params<'a>(&'a Value);
+
+impl Params<'_> {
+    fn get(&self, index: usize, name: &str) -> Option<&Value> {
+        let value = match self.0 {
+            Value::Array(values) => values.get(index),
+            Value::Object(values) => values.get(name),
+            _ => None,
+        };
+        value.filter(|value| !value.is_null())
+    }
+
+    fn optional<T: DeserializeOwned>(
+        &self,
+        index: usize,
+        name: &str,
+    ) -> Result<Option<T>, RpcError> {
+        self.get(index, name)
+            .map(|value| {
+                serde_json::from_value(value.clone())
+                    .map_err(|error| RpcError::invalid_params(&format!("{}: {}", name, error)))
+            })
+            .transpose()
+    }
+
+    fn required<T: DeserializeOwned>(&self, index: usize, name: &str) -> Result<T, RpcError> {
+        self.optional(index, name)?
+            .ok_or_else(|| RpcError::invalid_params(&format!("missing {}", name)))
+    }
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|error| RpcError::internal(&error.to_string()))
+}
+
+fn check_address(address: &str) -> Result<(), RpcError> {
+    address
+        .parse::<Address>()
+        .map(|_| ())
+        .map_err(|error| RpcError::invalid_params(&format!("invalid address: {}", error)))
+}
+
+impl RpcContext {
+    pub fn new(node: Arc<Node>, wallet: Wallet) -> Self {
+        Self {
+            node,
+            wallet: Mutex::new(wallet),
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn wallet(&self) -> MutexGuard<'_, Wallet> {
+        self.wallet.lock().unwrap()
+    }
+
+    pub fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        let params = Params(params);
+        match method {
+            "getchaininfo" => self.get_chain_info(),
+            "getblock" => self.get_block(params.required(0, "height")?),
+            "getblockbyhash" => self.get_block_by_hash(&params.required::<String>(0, "hash")?),
+            "gettransaction" => self.get_transaction(&params.required::<String>(0, "txid")?),
+            "getbalance" => self.get_balance(params.optional(0, "address")?),
+            "getbalances" => self.get_balances(),
+            "listtransactions" => self.list_transactions(),
+            "sendtoaddress" => self.send_to_address(
+                &params.required::<String>(0, "address")?,
+                params.required(1, "amount")?,
+                params.optional(2, "fee")?.unwrap_or(0),
+            ),
+            "walletpassphrase" => {
+                self.wallet_passphrase(&params.required::<String>(0, "passphrase")?)
+            }
+            "walletlock" => self.wallet_lock(),
+            "getnewaddress" => self.get_new_address(),
+            "getmempool" => self.get_mempool(),
+            "getblocktemplate" => self.get_block_template(params.optional(0, "address")?),
+            "submitblock" => self.submit_block(params.required(0, "block")?),
+            "generate" => self.generate(
+                params.optional(0, "blocks")?.unwrap_or(1),
+                params.optional(1, "address")?,
+            ),
+            _ => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    // The block as serialized on the wire and on disk, plus what can be derived from it.
+    fn block_json(chain: &BlockChain, block: &Block) -> Result<Value, RpcError> {
+        let hash = block.hash();
+        let in_active_chain = chain
+            .chain
+            .get(block.index as usize)
+            .is_some_and(|active| active.hash() == hash);
+        let confirmations = match chain.get_last_index() {
+            Some(tip) if in_active_chain => tip - block.index + 1,
+            _ => 0,
+        };
+
+        let mut json = to_json(block)?;
+        json["hash"] = json!(hash);
+        json["merkle_root"] = json!(block.merkle_root());
+        json["confirmations"] = json!(confirmations);
+        Ok(json)
+    }
+
+    fn get_chain_info(&self) -> Result<Value, RpcError> {
+        let chain = self.node.chain();
+        let next_height = chain.get_last_index().map_or(0, |index| index + 1);
+        Ok(json!({
+            "height": chain.get_last_index(),
+            "tip": chain.get_last_hash(),
+            "work": format!("{:x}", chain.cumulative_work()),
+            "target": chain.chain.last().map(|block| block.target.to_hex()),
+            "headers": chain.best_header().map(|node| node.header.index),
+            "subsidy": chain.block_subsidy(next_height),
+            "mempool": self.node.mempool().len(),
+            "peers": self.node.peers().len(),
+        }))
+    }
+
+    fn get_block(&self, height: u128) -> Result<Value, RpcError> {
+        let chain = self.node.chain();
+        match usize::try_from(height)
+            .ok()
+            .and_then(|height| chain.chain.get(height))
+        {
+            Some(block) => Self::block_json(&chain, block),
+            None => Err(RpcError::new(
+                NOT_FOUND,
+                &format!("no block at height {}", height),
+            )),
+        }
+    }
+
+    fn get_block_by_hash(&self, hash: &str) -> Result<Value, RpcError> {
+        let chain = self.node.chain();
+        match chain.tree().get(hash) {
+            Some(node) => Self::block_json(&chain, &node.block),
+            None => Err(RpcError::new(NOT_FOUND, &format!("unknown block {}", hash))),
+        }
+    }
+
+    fn get_transaction(&self, txid: &str) -> Result<Value, RpcError> {
+        let chain = self.node.chain();
+        if let Some(entry) = self.node.mempool().entry(txid) {
+            return Ok(json!({
+                "txid": txid,
+                "transaction": to_json(&entry.tx)?,
+                "fee": entry.fee,
+                "confirmations": 0,
+            }));
+        }
+
+        let not_found = || RpcError::new(NOT_FOUND, &format!("unknown transaction {}", txid));
+        let (header, proof) = chain.transaction_proof(txid).ok_or_else(not_found)?;
+        let tx = chain
+            .find_txid_in_block(header.index, txid)
+            .ok_or_else(not_found)?;
+        let confirmations = chain
+            .get_last_index()
+            .map_or(0, |tip| tip - header.index + 1);
+
+        Ok(json!({
+            "txid": txid,
+            "transaction": to_json(tx)?,
+            "block": chain.chain[header.index as usize].hash(),
+            "height": header.index,
+            "confirmations": confirmations,
+            "proof": to_json(&proof)?,
+        }))
+    }
+
+    // Reloads the wallet from the chain, leaving out the outputs pooled transactions spend.
+    fn refresh_wallet(&self, wallet: &mut Wallet) {
+        let chain = self.node.chain();
+        let mempool = self.node.mempool();
+        wallet.read_wallet(&chain);
+        wallet.read_mempool(&mempool);
+    }
+
+    // Without an address: what our wallet can spend right now.
+    fn get_balance(&self, address: Option<String>) -> Result<Value, RpcError> {
+        match address {
+            Some(address) => {
+                check_address(&address)?;
+                Ok(json!(self.node.chain().utxos().balance(&address)))
+            }
+            None => {
+                let mut wallet = self.wallet();
+                self.refresh_wallet(&mut wallet);
+                Ok(json!(wallet.total_credits))
+            }
+        }
+    }
+
+    fn get_balances(&self) -> Result<Value, RpcError> {
+        let mut wallet = self.wallet();
+        self.refresh_wallet(&mut wallet);
+        to_json(&wallet.balance())
+    }
+
+    // Oldest first, the unconfirmed ones last.
+    fn list_transactions(&self) -> Result<Value, RpcError> {
+        let mut wallet = self.wallet();
+        self.refresh_wallet(&mut wallet);
+        to_json(&wallet.history().collect::<Vec<_>>())
+    }
+
+    fn send_to_address(&self, address: &str, amount: u128, fee: u128) -> Result<Value, RpcError> {
+        check_address(address)?;
+        if amount == 0 {
+            return Err(RpcError::invalid_params("amount must be positive"));
+        }
+
+        let mut wallet = self.wallet();
+        if wallet.is_locked() {
+            return Err(RpcError::new(
+                WALLET_ERROR,
+                "wallet is locked, unlock it with walletpassphrase",
+            ));
+        }
+        self.refresh_wallet(&mut wallet);
+        let transaction = wallet
+            .create_transaction_with_fee(&Id::new(address), amount, Fee::Absolute(fee))
+            .map_err(|_| {
+                RpcError::new(
+                    WALLET_ERROR,
+                    &format!(
+                        "insufficient funds: {} coins spendable, {} needed",
+                        wallet.total_credits,
+                        amount.saturating_add(fee)
+                    ),
+                )
+            })?;
+
+        let signed = wallet
+            .sign_transaction(&transaction)
+            .map_err(|_| RpcError::new(WALLET_ERROR, "wallet is locked"))?;
+        let txid = self
+            .node
+            .submit_transaction(signed)
+            .map_err(|error| RpcError::new(REJECTED, &error.to_string()))?;
+        Ok(json!(txid))
+    }
+
+    fn wallet_passphrase(&self, passphrase: &str) -> Result<Value, RpcError> {
+        self.wallet()
+            .unlock(passphrase)
+            .map_err(|error| RpcError::new(WALLET_ERROR, &error.to_string()))?;
+        Ok(Value::Null)
+    }
+
+    // The first address of the wallet nothing was sent to yet.
+    fn get_new_address(&self) -> Result<Value, RpcError> {
+        let mut wallet = self.wallet();
+        self.refresh_wallet(&mut wallet);
+        let chain = self.node.chain();
+        if let Some(address) = wallet.unused_address(&chain) {
+            return Ok(json!(address));
+        }
+        match wallet.new_address() {
+            Ok(address) => Ok(json!(address)),
+            Err(WalletOperationResult::Locked) => Err(RpcError::new(
+                WALLET_ERROR,
+                "wallet is locked, unlock it with walletpassphrase",
+            )),
+            // a single-key wallet has no other address to give
+            Err(_) => Ok(json!(wallet.id.id)),
+        }
+    }
+
+    fn wallet_lock(&self) -> Result<Value, RpcError> {
+        self.wallet()
+            .lock()
+            .map_err(|error| RpcError::new(WALLET_ERROR, &error.to_string()))?;
+        Ok(Value::Null)
+    }
+
+    fn get_mempool(&self) -> Result<Value, RpcError> {
+        let mempool = self.node.mempool();
+        let mut entries: Vec<(&String, &MempoolEntry)> = mempool.entries().collect();
+        entries.sort_by_key(|(txid, _)| *txid);
+
+        Ok(Value::Array(
+            entries
+                .into_iter()
+                .map(|(txid, entry)| json!({ "txid": txid, "size": entry.size, "fee": entry.fee }))
+                .collect(),
+        ))
+    }
+
+    fn miner_address(&self, address: Option<String>) -> Result<String, RpcError> {
+        match address {
+            Some(address) => check_address(&address).map(|_| address),
+            None => Ok(self.wallet().id.id.clone()),
+        }
+    }
+
+    fn get_block_template(&self, address: Option<String>) -> Result<Value, RpcError> {
+        let miner = self.miner_address(address)?;
+        let chain = self.node.chain();
+        let mut template = self
+            .node
+            .mempool()
+            .block_template(&chain, &miner, DEFAULT_BLOCK_SIZE);
+        template.index = chain.get_last_index().map_or(0, |index| index + 1);
+        // on an empty chain, the template keeps the all-zero parent of a genesis block
+        if let Some(tip) = chain.get_last_hash() {
+            template.previous_block = tip;
+        }
+        template.target = chain
+            .expected_target(&template)
+            .map_err(|error| RpcError::internal(&error.to_string()))?;
+        to_json(&template)
+    }
+
+    fn submit_block(&self, block: Block) -> Result<Value, RpcError> {
+        let hash = block.hash();
+        let status = match self.node.submit_block(block) {
+            Ok(BlockAcceptance::MainChain(_)) => "accepted",
+            Ok(BlockAcceptance::SideBranch) => "side-branch",
+            Ok(BlockAcceptance::AlreadyKnown) => "duplicate",
+            Err(error) => return Err(RpcError::new(REJECTED, &error.to_string())),
+        };
+        Ok(json!({ "hash": hash, "status": status }))
+    }
+
+    fn generate(&self, blocks: usize, address: Option<String>) -> Result<Value, RpcError> {
+        if blocks > MAX_GENERATE_BLOCKS {
+            return Err(RpcError::invalid_params(&format!(
+                "cannot generate more than {} blocks at once",
+                MAX_GENERATE_BLOCKS
+            )));
+        }
+        let miner = self.miner_address(address)?;
+        let mut hashes = vec![];
+        for _ in 0..blocks {
+            let block = self
+                .node
+                .mine_block(&miner)
+                .map_err(|error| RpcError::new(REJECTED, &error.to_string()))?;
+            hashes.push(block.hash());
+        }
+        Ok(json!(hashes))
+    }
+}
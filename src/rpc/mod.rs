@@ -0,0 +1,6 @@
+pub mod error;
+pub mod methods;
+pub mod server;
+
+#[cfg(test)]
+mod tests;
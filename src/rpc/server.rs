@@ -0,0 +1,283 @@
+use super::error::*;
+use super::methods::*;
+
+use openssl::rand::rand_bytes;
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Larger request bodies are refused before being parsed.
+pub const MAX_REQUEST_SIZE: u64 = 8 * 1024 * 1024;
+
+// The secret every request presents, as `Authorization: Bearer <token>`: either set in the
+// configuration, or drawn at random when the server starts and left in a cookie file for the
+// clients able to read it.
+pub struct RpcAuth {
+    token: String,
+    cookie: Option<PathBuf>,
+}
+
+impl RpcAuth {
+    pub fn token(token: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            cookie: None,
+        }
+    }
+
+    // The file is only readable by its owner, and removed when the server stops.
+    pub fn cookie(path: &Path) -> io::Result<Self> {
+        let mut secret = [0u8; 32];
+        rand_bytes(&mut secret).map_err(io::Error::other)?;
+        let token = hex::encode(secret);
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(token.as_bytes())?;
+
+        Ok(Self {
+            token,
+            cookie: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.token
+    }
+
+    // Compares in constant time, not to leak how much of a guess was right.
+    fn accepts(&self, authorization: Option<&str>) -> bool {
+        let given = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(given) => given.trim().as_bytes(),
+            None => return false,
+        };
+        given.len() == self.token.len()
+            && given
+                .iter()
+                .zip(self.token.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+// Browsers send whatever name they resolved in the Host header: accepting only addresses and
+// localhost keeps a page whose name was rebound to our address from reaching the server.
+fn valid_host(host: &str, port: u16) -> bool {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return addr.port() == port;
+    }
+    if let Some(ip) = host.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        return ip.parse::<IpAddr>().is_ok();
+    }
+    let name = match host.split_once(':') {
+        Some((name, given)) if given.parse() == Ok(port) => name,
+        Some(_) => return false,
+        None => host,
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+}
+
+// A form posted by a web page cannot be sent as JSON without the browser asking first.
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+}
+
+// Why a request is refused before its body is read, if it is.
+fn refusal(request: &Request, auth: &RpcAuth, port: u16) -> Option<Response<io::Cursor<Vec<u8>>>> {
+    if *request.method() != Method::Post {
+        return Some(Response::from_string("only POST is supported").with_status_code(405));
+    }
+    if !header(request, "Host").is_some_and(|host| valid_host(host, port)) {
+        return Some(Response::from_string("invalid Host header").with_status_code(403));
+    }
+    if !auth.accepts(header(request, "Authorization")) {
+        return Some(
+            Response::from_string("authorization required")
+                .with_status_code(401)
+                .with_header(Header::from_bytes("WWW-Authenticate", "Bearer").unwrap()),
+        );
+    }
+    if !is_json(header(request, "Content-Type")) {
+        return Some(
+            Response::from_string("Content-Type must be application/json").with_status_code(415),
+        );
+    }
+    None
+}
+
+// JSON-RPC 2.0 over HTTP: every request is a POST whose body holds a call or a batch of calls.
+// Requests are served one at a time, which keeps wallet operations from interleaving.
+pub struct RpcServer {
+    server: Arc<Server>,
+    addr: SocketAddr,
+    auth: Arc<RpcAuth>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RpcServer {
+    pub fn start<A: ToSocketAddrs>(
+        addr: A,
+        context: Arc<RpcContext>,
+        auth: RpcAuth,
+    ) -> io::Result<Self> {
+        let server = Server::http(addr).map_err(io::Error::other)?;
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| io::Error::other("RPC server is not bound to an IP address"))?;
+        let server = Arc::new(server);
+        let auth = Arc::new(auth);
+
+        log::info!("RPC server listening on {}", addr);
+        let serving = Arc::clone(&server);
+        let checking = Arc::clone(&auth);
+        let worker = thread::spawn(move || {
+            for mut request in serving.incoming_requests() {
+                let response = if let Some(response) = refusal(&request, &checking, addr.port()) {
+                    log::debug!("Refused an RPC request from {:?}", request.remote_addr());
+                    response
+                } else {
+                    let mut body = String::new();
+                    let read = request
+                        .as_reader()
+                        .take(MAX_REQUEST_SIZE + 1)
+                        .read_to_string(&mut body);
+                    let reply = match read {
+                        Ok(size) if size as u64 > MAX_REQUEST_SIZE => Some(reply(
+                            Value::Null,
+                            Err(RpcError::invalid_request("body too large")),
+                        )),
+                        Ok(_) => handle_body(&context, &body),
+                        Err(error) => Some(reply(
+                            Value::Null,
+                            Err(RpcError::parse_error(&error.to_string())),
+                        )),
+                    };
+
+                    match reply {
+                        Some(reply) => Response::from_string(reply.to_string()).with_header(
+                            Header::from_bytes("Content-Type", "application/json").unwrap(),
+                        ),
+                        None => Response::from_string("").with_status_code(204),
+                    }
+                };
+
+                if let Err(error) = request.respond(response) {
+                    log::warn!("Failed to answer an RPC request: {}", error);
+                }
+            }
+        });
+
+        Ok(Self {
+            server,
+            addr,
+            auth,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.server.unblock();
+            let _ = worker.join();
+            if let Some(cookie) = &self.auth.cookie {
+                let _ = fs::remove_file(cookie);
+            }
+            log::info!("RPC server {} stopped", self.addr);
+        }
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+// Answer to a request body, if any: notifications, and batches made only of them, get none.
+pub fn handle_body(context: &RpcContext, body: &str) -> Option<Value> {
+    let request: Value = match serde_json::from_str(body) {
+        Ok(request) => request,
+        Err(error) => {
+            return Some(reply(
+                Value::Null,
+                Err(RpcError::parse_error(&error.to_string())),
+            ))
+        }
+    };
+
+    match request {
+        Value::Array(batch) if batch.is_empty() => Some(reply(
+            Value::Null,
+            Err(RpcError::invalid_request("empty batch")),
+        )),
+        Value::Array(batch) => {
+            let replies: Vec<Value> = batch
+                .iter()
+                .filter_map(|request| handle_request(context, request))
+                .collect();
+            (!replies.is_empty()).then_some(Value::Array(replies))
+        }
+        request => handle_request(context, &request),
+    }
+}
+
+fn handle_request(context: &RpcContext, request: &Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Value::as_str);
+    let params = request.get("params").unwrap_or(&Value::Null);
+
+    let malformed = if !request.is_object() {
+        Some("not an object")
+    } else if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        Some("jsonrpc must be \"2.0\"")
+    } else if method.is_none() {
+        Some("method must be a string")
+    } else if !(params.is_array() || params.is_object() || params.is_null()) {
+        Some("params must be an array or an object")
+    } else {
+        None
+    };
+
+    if let Some(reason) = malformed {
+        return Some(reply(
+            id.unwrap_or(Value::Null),
+            Err(RpcError::invalid_request(reason)),
+        ));
+    }
+
+    let method = method.unwrap();
+    let result = context.call(method, params);
+    if let Err(error) = &result {
+        log::debug!("RPC {} failed: {}", method, error);
+    }
+    id.map(|id| reply(id, result))
+}
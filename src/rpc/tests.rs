@@ -0,0 +1,296 @@
+use super::error::*;
+use super::methods::*;
+use super::server::*;
+
+use crate::blockchain::block::*;
+use crate::blockchain::chain::*;
+use crate::blockchain::merkle::*;
+use crate::blockchain::params::*;
+use crate::blockchain::wallet::*;
+use crate::p2p::node::*;
+
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+const TOKEN: &str = "rpc-test-token";
+
+// The tests spend what they mine right away.
+fn start_server() -> (RpcServer, Arc<RpcContext>) {
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        ..ChainParams::with_difficulty(1)
+    };
+    let node = Node::start("127.0.0.1:0", BlockChain::with_params(params)).unwrap();
+    let context = Arc::new(RpcContext::new(Arc::new(node), Wallet::new()));
+    let server =
+        RpcServer::start("127.0.0.1:0", Arc::clone(&context), RpcAuth::token(TOKEN)).unwrap();
+    (server, context)
+}
+
+// Bare HTTP/1.1 request with the given header lines, returning the status code and the body.
+fn send(addr: SocketAddr, method: &str, headers: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} / HTTP/1.1\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        headers,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+fn post(addr: SocketAddr, body: &str) -> (u16, String) {
+    let headers = format!(
+        "Host: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\n",
+        TOKEN
+    );
+    send(addr, "POST", &headers, body)
+}
+
+fn call(addr: SocketAddr, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+    let (status, body) = post(addr, &request.to_string());
+    assert_eq!(status, 200);
+    serde_json::from_str(&body).unwrap()
+}
+
+fn result(addr: SocketAddr, method: &str, params: Value) -> Value {
+    let response = call(addr, method, params);
+    assert_eq!(response["id"], 1);
+    assert!(response.get("error").is_none(), "{}: {}", method, response);
+    response["result"].clone()
+}
+
+fn error_code(addr: SocketAddr, method: &str, params: Value) -> i64 {
+    call(addr, method, params)["error"]["code"]
+        .as_i64()
+        .unwrap()
+}
+
+#[test]
+fn chain_wallet_and_mining_over_rpc() {
+    let (server, context) = start_server();
+    let addr = server.local_addr();
+    let theirs = Wallet::new().id.id;
+
+    let info = result(addr, "getchaininfo", json!([]));
+    assert_eq!(info["height"], Value::Null);
+    assert_eq!(info["subsidy"], 50);
+
+    // a block template on an empty chain builds a genesis block
+    let template: Block =
+        serde_json::from_value(result(addr, "getblocktemplate", json!([]))).unwrap();
+    assert_eq!(template.index, 0);
+    assert_eq!(template.previous_block, "0".repeat(64));
+
+    let hashes = result(addr, "generate", json!([2]));
+    assert_eq!(hashes.as_array().unwrap().len(), 2);
+    let info = result(addr, "getchaininfo", json!({}));
+    assert_eq!(info["height"], 1);
+    assert_eq!(info["tip"], hashes[1]);
+    assert_eq!(info["headers"], 1);
+
+    let block = result(addr, "getblock", json!([0]));
+    assert_eq!(block["hash"], hashes[0]);
+    assert_eq!(block["confirmations"], 2);
+    assert_eq!(
+        result(addr, "getblockbyhash", json!({ "hash": hashes[1] }))["index"],
+        1
+    );
+    assert_eq!(error_code(addr, "getblock", json!([7])), NOT_FOUND);
+    assert_eq!(error_code(addr, "getblockbyhash", json!(["00"])), NOT_FOUND);
+
+    assert_eq!(result(addr, "getbalance", json!([])), 100);
+    assert_eq!(result(addr, "getbalance", json!([theirs])), 0);
+
+    // the second payment does not reuse the output the first one spends
+    let first = result(addr, "sendtoaddress", json!([theirs, 30, 1]));
+    let second = result(
+        addr,
+        "sendtoaddress",
+        json!({ "address": theirs, "amount": 10 }),
+    );
+    assert_ne!(first, second);
+    assert_eq!(result(addr, "getbalance", json!([])), 0);
+    assert_eq!(
+        error_code(addr, "sendtoaddress", json!([theirs, 1])),
+        WALLET_ERROR
+    );
+    assert_eq!(
+        result(addr, "getbalances", json!([])),
+        json!({ "confirmed": 100, "unconfirmed": 50 - 31 + 50 - 10, "spendable": 0 })
+    );
+    let history = result(addr, "listtransactions", json!([]));
+    let history = history.as_array().unwrap();
+    assert_eq!(history.len(), 4);
+    assert_eq!(history[0]["kind"], "mined");
+    assert_eq!(history[0]["confirmations"], 2);
+    let mut sent: Vec<&Value> = history[2..].iter().map(|entry| &entry["txid"]).collect();
+    sent.sort_by_key(|txid| txid.as_str());
+    let mut expected = [&first, &second];
+    expected.sort_by_key(|txid| txid.as_str());
+    assert_eq!(sent, expected);
+    assert_eq!(history[2]["height"], Value::Null);
+    assert_eq!(history[2]["counterparties"], json!([theirs]));
+
+    let mempool = result(addr, "getmempool", json!([]));
+    assert_eq!(mempool.as_array().unwrap().len(), 2);
+    assert_eq!(
+        result(addr, "gettransaction", json!([first]))["confirmations"],
+        0
+    );
+
+    let template: Block =
+        serde_json::from_value(result(addr, "getblocktemplate", json!([]))).unwrap();
+    assert_eq!(template.index, 2);
+    assert_eq!(template.transactions.len(), 3);
+
+    result(addr, "generate", json!([]));
+    assert!(result(addr, "getmempool", json!([]))
+        .as_array()
+        .unwrap()
+        .is_empty());
+    assert_eq!(result(addr, "getbalance", json!([theirs])), 40);
+    // we mined the block, so the fee came back to us
+    assert_eq!(result(addr, "getbalance", json!([])), 3 * 50 - 40);
+
+    let tx = result(addr, "gettransaction", json!([first]));
+    assert_eq!(tx["height"], 2);
+    assert_eq!(tx["confirmations"], 1);
+    let proof: MerkleProof = serde_json::from_value(tx["proof"].clone()).unwrap();
+    let block = result(addr, "getblock", json!([2]));
+    assert!(proof.verify(block["merkle_root"].as_str().unwrap()));
+
+    // blocks round-trip through getblock and submitblock
+    let status = result(addr, "submitblock", json!([block]));
+    assert_eq!(status["status"], "duplicate");
+    let mut forged: Block = serde_json::from_value(block).unwrap();
+    forged.nonce += 1;
+    assert_eq!(error_code(addr, "submitblock", json!([forged])), REJECTED);
+    assert_eq!(context.node().chain().chain.len(), 3);
+
+    // a locked wallet refuses to pay until unlocked with its passphrase
+    assert_eq!(error_code(addr, "walletlock", json!([])), WALLET_ERROR);
+    context.wallet().encrypt("correct horse").unwrap();
+    result(addr, "walletlock", json!([]));
+    assert_eq!(
+        error_code(addr, "sendtoaddress", json!([theirs, 1])),
+        WALLET_ERROR
+    );
+    assert_eq!(
+        error_code(addr, "walletpassphrase", json!(["battery staple"])),
+        WALLET_ERROR
+    );
+    result(
+        addr,
+        "walletpassphrase",
+        json!({ "passphrase": "correct horse" }),
+    );
+    result(addr, "sendtoaddress", json!([theirs, 1]));
+}
+
+#[test]
+fn malformed_requests_follow_the_specification() {
+    let (server, _) = start_server();
+    let addr = server.local_addr();
+
+    let (_, body) = post(addr, "{ not json");
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["error"]["code"], PARSE_ERROR);
+    assert_eq!(response["id"], Value::Null);
+
+    let (_, body) = post(addr, r#"{"method": "getchaininfo", "id": 3}"#);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    assert_eq!(response["id"], 3);
+
+    assert_eq!(error_code(addr, "getwork", json!([])), METHOD_NOT_FOUND);
+    assert_eq!(
+        error_code(addr, "generate", json!([MAX_GENERATE_BLOCKS + 1])),
+        INVALID_PARAMS
+    );
+    assert_eq!(error_code(addr, "getblock", json!([])), INVALID_PARAMS);
+    assert_eq!(error_code(addr, "getblock", json!(["tip"])), INVALID_PARAMS);
+    assert_eq!(
+        error_code(addr, "getbalance", json!(["not an address"])),
+        INVALID_PARAMS
+    );
+
+    // notifications are not answered, even inside a batch
+    let (status, body) = post(addr, r#"{"jsonrpc": "2.0", "method": "getchaininfo"}"#);
+    assert_eq!(status, 204);
+    assert!(body.is_empty());
+
+    let (_, body) = post(
+        addr,
+        r#"[{"jsonrpc": "2.0", "method": "getchaininfo"},
+            {"jsonrpc": "2.0", "method": "getmempool", "id": "a"},
+            {"jsonrpc": "2.0", "method": "nope", "id": "b"}]"#,
+    );
+    let responses: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(responses.as_array().unwrap().len(), 2);
+    assert_eq!(responses[0]["id"], "a");
+    assert_eq!(responses[0]["result"], json!([]));
+    assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+
+    let (_, body) = post(addr, "[]");
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["error"]["code"], INVALID_REQUEST);
+}
+
+#[test]
+fn requests_from_outside_are_refused() {
+    let (server, _) = start_server();
+    let addr = server.local_addr();
+    let body = json!({ "jsonrpc": "2.0", "method": "getchaininfo", "id": 1 }).to_string();
+    let json = "Content-Type: application/json\r\n";
+    let authorized = format!("Authorization: Bearer {}\r\n", TOKEN);
+    let request = |host: &str, authorization: &str, content_type: &str| {
+        let headers = format!("{}{}{}", host, authorization, content_type);
+        send(addr, "POST", &headers, &body).0
+    };
+
+    assert_eq!(request("Host: localhost\r\n", &authorized, json), 200);
+    let host = format!("Host: {}\r\n", addr);
+    assert_eq!(request(&host, &authorized, json), 200);
+
+    assert_eq!(request("Host: localhost\r\n", "", json), 401);
+    assert_eq!(
+        request(
+            "Host: localhost\r\n",
+            "Authorization: Bearer rpc-test-tokem\r\n",
+            json
+        ),
+        401
+    );
+    assert_eq!(
+        request(
+            "Host: localhost\r\n",
+            &authorized,
+            "Content-Type: text/plain\r\n"
+        ),
+        415
+    );
+    assert_eq!(request("Host: localhost\r\n", &authorized, ""), 415);
+
+    // names other than localhost may have been rebound to our address
+    assert_eq!(
+        request("Host: attacker.example\r\n", &authorized, json),
+        403
+    );
+    let port = format!("Host: localhost:{}\r\n", addr.port().wrapping_add(1));
+    assert_eq!(request(&port, &authorized, json), 403);
+
+    let headers = format!("Host: localhost\r\n{}", authorized);
+    assert_eq!(send(addr, "GET", &headers, "").0, 405);
+}